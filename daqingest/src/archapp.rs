@@ -0,0 +1,349 @@
+pub mod pb;
+
+use crate::opts::ArchappImport;
+use async_channel::Sender;
+use dbpg::seriesbychannel::CanSendChannelInfoResult;
+use dbpg::seriesbychannel::ChannelInfoQuery;
+use dbpg::seriesbychannel::ChannelInfoResult;
+use err::Error;
+use log::*;
+use netfetch::conf::parse_config_opts;
+use netpod::timeunits::HOUR;
+use netpod::timeunits::SEC;
+use netpod::ScalarType;
+use netpod::Shape;
+use netpod::TS_MSP_GRID_SPACING;
+use netpod::TS_MSP_GRID_UNIT;
+use pb::PayloadInfo;
+use pb::PbFileReader;
+use pb::PbItem;
+use pb::Sample;
+use scywr::insertworker::InsertWorkerOpts;
+use scywr::iteminsertqueue::InsertItem;
+use scywr::iteminsertqueue::QueryItem;
+use scywr::sink::SinkOpts;
+use series::SeriesId;
+use stats::CaConnStats;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use taskrun::tokio;
+
+struct SeriesLookupSender {
+    tx: Sender<Result<ChannelInfoResult, dbpg::seriesbychannel::Error>>,
+}
+
+impl CanSendChannelInfoResult for SeriesLookupSender {
+    fn make_send(
+        &self,
+        item: Result<ChannelInfoResult, dbpg::seriesbychannel::Error>,
+    ) -> dbpg::seriesbychannel::BoxedSend {
+        let tx = self.tx.clone();
+        let fut = async move { tx.send(item).await.map_err(|_| ()) };
+        Box::pin(fut)
+    }
+}
+
+struct MspState {
+    ts_msp: u64,
+    inserted_in_ts_msp: u64,
+    ts_msp_grid_last: u32,
+}
+
+impl MspState {
+    fn new() -> Self {
+        Self {
+            ts_msp: 0,
+            inserted_in_ts_msp: u64::MAX,
+            ts_msp_grid_last: 0,
+        }
+    }
+
+    // Same partitioning as for live data, a sample older than the partition starts a new one.
+    // Returns ts_msp, whether the partition is new, and the grid if it changed.
+    fn next(&mut self, ts: u64) -> (u64, bool, Option<u32>) {
+        let (ts_msp, msp_bump) = if self.inserted_in_ts_msp >= 64000 || ts < self.ts_msp || self.ts_msp + HOUR <= ts {
+            let div = SEC * 10;
+            let ts_msp = ts / div * div;
+            self.inserted_in_ts_msp = 1;
+            if ts_msp == self.ts_msp {
+                (ts_msp, false)
+            } else {
+                self.ts_msp = ts_msp;
+                (ts_msp, true)
+            }
+        } else {
+            self.inserted_in_ts_msp += 1;
+            (self.ts_msp, false)
+        };
+        let ts_msp_grid = (ts / TS_MSP_GRID_UNIT / TS_MSP_GRID_SPACING * TS_MSP_GRID_SPACING) as u32;
+        let ts_msp_grid = if self.ts_msp_grid_last != ts_msp_grid {
+            self.ts_msp_grid_last = ts_msp_grid;
+            Some(ts_msp_grid)
+        } else {
+            None
+        };
+        (ts_msp, msp_bump, ts_msp_grid)
+    }
+}
+
+struct Chunk {
+    series: SeriesId,
+    scalar_type: ScalarType,
+    shape: Shape,
+}
+
+struct Importer {
+    backend: String,
+    lookup_tx: Sender<ChannelInfoQuery>,
+    item_tx: Sender<QueryItem>,
+    chunk: Option<Chunk>,
+    msp_states: BTreeMap<SeriesId, MspState>,
+    series_by_channel: BTreeMap<(String, i32, Vec<i32>), SeriesId>,
+}
+
+impl Importer {
+    async fn lookup_series(&mut self, name: &str, scalar_type: &ScalarType, shape: &Shape) -> Result<SeriesId, Error> {
+        let key = (name.to_string(), scalar_type.to_scylla_i32(), shape.to_scylla_vec());
+        if let Some(series) = self.series_by_channel.get(&key) {
+            return Ok(series.clone());
+        }
+        let (tx, rx) = async_channel::bounded(1);
+        let item = ChannelInfoQuery {
+            backend: self.backend.clone(),
+            channel: key.0.clone(),
+            scalar_type: key.1,
+            shape_dims: key.2.clone(),
+            tx: Box::pin(SeriesLookupSender { tx }),
+        };
+        self.lookup_tx
+            .send(item)
+            .await
+            .map_err(|_| Error::with_msg_no_trace("series lookup closed"))?;
+        let res = rx
+            .recv()
+            .await
+            .map_err(|_| Error::with_msg_no_trace("series lookup did not answer"))?
+            .map_err(|e| Error::from(e.to_string()))?;
+        let series = res.series.into_inner();
+        self.series_by_channel.insert(key, series.clone());
+        Ok(series)
+    }
+
+    async fn header(&mut self, info: PayloadInfo) -> Result<(), Error> {
+        let scalar_type = ScalarType::from_ca_id(info.ptype.ca_type().map_err(|e| Error::from(e.to_string()))?)?;
+        let shape = if info.ptype.is_waveform() {
+            match info.element_count {
+                Some(n) => Shape::Wave(n),
+                None => {
+                    return Err(Error::with_msg_no_trace(format!(
+                        "waveform {} without element count",
+                        info.pvname
+                    )))
+                }
+            }
+        } else {
+            Shape::Scalar
+        };
+        let series = self.lookup_series(&info.pvname, &scalar_type, &shape).await?;
+        debug!("{}  {}  {:?}  {:?}", info.pvname, info.year, scalar_type, shape);
+        self.chunk = Some(Chunk {
+            series,
+            scalar_type,
+            shape,
+        });
+        Ok(())
+    }
+
+    async fn sample(&mut self, sample: Sample) -> Result<(), Error> {
+        let chunk = self
+            .chunk
+            .as_ref()
+            .ok_or_else(|| Error::with_msg_no_trace("sample before header"))?;
+        let ts = sample.ts;
+        let st = self
+            .msp_states
+            .entry(chunk.series.clone())
+            .or_insert_with(MspState::new);
+        let (ts_msp, msp_bump, ts_msp_grid) = st.next(ts);
+        let item = InsertItem {
+            series: chunk.series.clone(),
+            ts_msp,
+            ts_lsp: ts - ts_msp,
+            msp_bump,
+            ts_msp_grid,
+            pulse: 0,
+            scalar_type: chunk.scalar_type.clone(),
+            shape: chunk.shape.clone(),
+            val: sample.val,
+            status: sample.status,
+            severity: sample.severity,
+        };
+        self.item_tx
+            .send(QueryItem::Insert(item))
+            .await
+            .map_err(|_| Error::with_msg_no_trace("insert queue closed"))?;
+        Ok(())
+    }
+
+    async fn import_file(&mut self, path: PathBuf) -> Result<u64, Error> {
+        let (tx, rx) = async_channel::bounded(1024);
+        let jh = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || {
+                let file = match std::fs::File::open(&path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let _ = tx.send_blocking(Err(pb::Error::from(e)));
+                        return;
+                    }
+                };
+                let mut rd = PbFileReader::new(BufReader::new(file));
+                loop {
+                    let item = rd.next_item().transpose();
+                    let stop = !matches!(item, Some(Ok(_)));
+                    if let Some(item) = item {
+                        if tx.send_blocking(item).is_err() {
+                            break;
+                        }
+                    }
+                    if stop {
+                        break;
+                    }
+                }
+            }
+        });
+        self.chunk = None;
+        let mut count = 0;
+        while let Ok(item) = rx.recv().await {
+            match item.map_err(|e| Error::with_msg_no_trace(format!("{}  {e:?}", path.display())))? {
+                PbItem::Header(info) => self.header(info).await?,
+                PbItem::Sample(sample) => {
+                    self.sample(sample).await?;
+                    count += 1;
+                }
+            }
+        }
+        jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
+        Ok(count)
+    }
+}
+
+fn collect_pb_files(path: &Path, out: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if path.is_dir() {
+        for e in std::fs::read_dir(path)? {
+            collect_pb_files(&e?.path(), out)?;
+        }
+    } else if path.extension().map_or(false, |x| x == "pb") {
+        out.push(path.into());
+    }
+    Ok(())
+}
+
+pub async fn import(k: ArchappImport) -> Result<(), Error> {
+    let conf = parse_config_opts(k.config.into()).await?;
+    let mut files = Vec::new();
+    for path in &k.paths {
+        collect_pb_files(path, &mut files)?;
+    }
+    // The partition files of a channel are named by time, in order they are imported oldest first.
+    files.sort();
+    info!("import {} files", files.len());
+    let mut pg = dbpg::conn::make_pg_client(conf.postgresql_config())
+        .await
+        .map_err(Error::from_string)?;
+    dbpg::schema::schema_check(&mut pg).await.map_err(Error::from_string)?;
+    let sink = conf.sink_opts()?;
+    if let SinkOpts::Scylla { scyconf, .. } = &sink {
+        scywr::schema::migrate_scylla_data_schema(scyconf)
+            .await
+            .map_err(Error::from_string)?;
+    }
+    let (lookup_tx, lookup_jhs, _) = dbpg::seriesbychannel::start_lookup_workers(1, conf.postgresql_config())
+        .await
+        .map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
+    let (item_tx, item_rx) = async_channel::bounded(conf.insert_item_queue_cap());
+    let insert_worker_opts = InsertWorkerOpts {
+        store_workers_rate: Arc::new(AtomicU64::new(conf.store_workers_rate())),
+        insert_workers_running: Arc::new(AtomicU64::new(0)),
+        // The import is not subject to the sampling of the live ingest.
+        insert_frac: Arc::new(AtomicU64::new(1000)),
+    };
+    let store_stats = Arc::new(CaConnStats::new());
+    let insert_jhs = scywr::insertworker::spawn_insert_workers(
+        sink.build().await?,
+        conf.insert_worker_count(),
+        item_rx,
+        Arc::new(insert_worker_opts),
+        store_stats,
+        false,
+        conf.archive_ttls(),
+        None,
+    )
+    .await?;
+    let mut importer = Importer {
+        backend: conf.backend().into(),
+        lookup_tx,
+        item_tx,
+        chunk: None,
+        msp_states: BTreeMap::new(),
+        series_by_channel: BTreeMap::new(),
+    };
+    let mut count_all = 0;
+    for path in files {
+        let count = importer.import_file(path.clone()).await?;
+        info!("{}  {count} samples", path.display());
+        count_all += count;
+    }
+    drop(importer);
+    for jh in insert_jhs {
+        jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
+    }
+    for jh in lookup_jhs {
+        jh.await
+            .map_err(|e| Error::with_msg_no_trace(e.to_string()))?
+            .map_err(|e| Error::from(e.to_string()))?;
+    }
+    info!("imported {count_all} samples");
+    Ok(())
+}
+
+#[test]
+fn msp_partitioning() {
+    let t0 = SEC * 1700000000 + 3 * SEC;
+    let msp0 = SEC * 1700000000;
+    let mut st = MspState::new();
+    let next = |st: &mut MspState, ts| {
+        let (ts_msp, bump, _) = st.next(ts);
+        (ts_msp, bump)
+    };
+    assert_eq!(next(&mut st, t0), (msp0, true));
+    // Stays in the partition for up to an hour.
+    assert_eq!(next(&mut st, t0 + SEC), (msp0, false));
+    assert_eq!(next(&mut st, msp0 + HOUR - 1), (msp0, false));
+    assert_eq!(next(&mut st, msp0 + HOUR), (msp0 + HOUR, true));
+    // An older sample opens a partition at its own time.
+    assert_eq!(next(&mut st, t0 + 5 * SEC), (msp0, true));
+    // A full partition restarts the count, a bump only if ts_msp differs.
+    let mut st = MspState::new();
+    next(&mut st, t0);
+    for i in 1..64000 {
+        assert_eq!(next(&mut st, t0 + i), (msp0, false));
+    }
+    assert_eq!(next(&mut st, t0 + 64000), (msp0, false));
+    assert_eq!(st.inserted_in_ts_msp, 1);
+    assert_eq!(next(&mut st, t0 + 20 * SEC), (msp0, false));
+    st.inserted_in_ts_msp = 64000;
+    assert_eq!(next(&mut st, t0 + 20 * SEC), (msp0 + 20 * SEC, true));
+}
+
+#[test]
+fn msp_grid_only_on_change() {
+    let ts = SEC * 1700000003;
+    let mut st = MspState::new();
+    assert!(st.next(ts).2.is_some());
+    assert_eq!(st.next(ts).2, None);
+}
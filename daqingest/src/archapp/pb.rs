@@ -0,0 +1,429 @@
+// Reader for the partition files of the EPICS Archiver Appliance.
+// Each line holds one escaped protobuf message: a chunk starts with a `PayloadInfo` header line
+// followed by one line per sample, an empty line announces the header of the next chunk.
+
+use chrono::TimeZone;
+use chrono::Utc;
+use err::thiserror;
+use err::ThisError;
+use netpod::timeunits::SEC;
+use scywr::iteminsertqueue::ArrayValue;
+use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::ScalarValue;
+use std::io::BufRead;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Escape,
+    Protobuf(&'static str),
+    MissingField(&'static str),
+    UnsupportedType(i32),
+    BadYear(i32),
+}
+
+const ESC: u8 = 0x1b;
+
+fn unescape(line: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut ret = Vec::with_capacity(line.len());
+    let mut it = line.iter();
+    while let Some(&b) = it.next() {
+        if b == ESC {
+            match it.next() {
+                Some(1) => ret.push(ESC),
+                Some(2) => ret.push(b'\n'),
+                Some(3) => ret.push(b'\r'),
+                _ => return Err(Error::Escape),
+            }
+        } else {
+            ret.push(b);
+        }
+    }
+    Ok(ret)
+}
+
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+struct PbReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> PbReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut ret = 0;
+        for i in 0..10 {
+            let (&b, rest) = self.buf.split_first().ok_or(Error::Protobuf("truncated varint"))?;
+            self.buf = rest;
+            ret |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                return Ok(ret);
+            }
+        }
+        Err(Error::Protobuf("varint too long"))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(Error::Protobuf("truncated field"));
+        }
+        let (a, b) = self.buf.split_at(n);
+        self.buf = b;
+        Ok(a)
+    }
+
+    fn field(&mut self) -> Result<Option<(u32, Wire<'a>)>, Error> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let val = match key & 7 {
+            0 => Wire::Varint(self.varint()?),
+            1 => Wire::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let n = self.varint()? as usize;
+                Wire::Bytes(self.take(n)?)
+            }
+            5 => Wire::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(Error::Protobuf("unsupported wire type")),
+        };
+        Ok(Some(((key >> 3) as u32, val)))
+    }
+}
+
+fn zigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+// The payload types are the channel access DBR types, the waveforms offset by 7.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PayloadType(i32);
+
+impl PayloadType {
+    pub fn ca_type(&self) -> Result<u16, Error> {
+        if self.0 >= 0 && self.0 < 14 {
+            Ok((self.0 % 7) as u16)
+        } else {
+            Err(Error::UnsupportedType(self.0))
+        }
+    }
+
+    pub fn is_waveform(&self) -> bool {
+        self.0 >= 7
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadInfo {
+    pub ptype: PayloadType,
+    pub pvname: String,
+    pub year: i32,
+    pub element_count: Option<u32>,
+}
+
+impl PayloadInfo {
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut ptype = None;
+        let mut pvname = None;
+        let mut year = None;
+        let mut element_count = None;
+        let mut rd = PbReader::new(buf);
+        while let Some((n, v)) = rd.field()? {
+            match (n, v) {
+                (1, Wire::Varint(x)) => ptype = Some(PayloadType(x as i32)),
+                (2, Wire::Bytes(x)) => pvname = Some(String::from_utf8_lossy(x).into_owned()),
+                (3, Wire::Varint(x)) => year = Some(x as i32),
+                (4, Wire::Varint(x)) => element_count = Some(x as u32),
+                _ => {}
+            }
+        }
+        let ret = Self {
+            ptype: ptype.ok_or(Error::MissingField("type"))?,
+            pvname: pvname.ok_or(Error::MissingField("pvname"))?,
+            year: year.ok_or(Error::MissingField("year"))?,
+            element_count,
+        };
+        ret.ptype.ca_type()?;
+        Ok(ret)
+    }
+
+    // Samples carry their time relative to the start of the year of the chunk.
+    pub fn year_start(&self) -> Result<u64, Error> {
+        let ts = Utc
+            .with_ymd_and_hms(self.year, 1, 1, 0, 0, 0)
+            .single()
+            .ok_or(Error::BadYear(self.year))?;
+        u64::try_from(ts.timestamp()).map_err(|_| Error::BadYear(self.year))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    // Nanoseconds since the unix epoch.
+    pub ts: u64,
+    pub val: DataValue,
+    pub status: u16,
+    pub severity: u16,
+}
+
+enum Vals {
+    String(Vec<String>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+impl Vals {
+    fn new(ca_type: u16) -> Self {
+        match ca_type {
+            0 => Vals::String(Vec::new()),
+            1 | 3 => Vals::I16(Vec::new()),
+            2 => Vals::F32(Vec::new()),
+            4 => Vals::I8(Vec::new()),
+            5 => Vals::I32(Vec::new()),
+            _ => Vals::F64(Vec::new()),
+        }
+    }
+
+    // Accepts the packed and the non-packed encoding of repeated values.
+    fn push(&mut self, v: Wire) -> Result<(), Error> {
+        let e = Error::Protobuf("unexpected wire type for value");
+        match (self, v) {
+            (Vals::String(a), Wire::Bytes(x)) => a.push(String::from_utf8_lossy(x).into_owned()),
+            (Vals::I8(a), Wire::Bytes(x)) => a.extend(x.iter().map(|&x| x as i8)),
+            (Vals::I16(a), Wire::Varint(x)) => a.push(zigzag(x) as i16),
+            (Vals::I16(a), Wire::Bytes(x)) => {
+                let mut rd = PbReader::new(x);
+                while !rd.buf.is_empty() {
+                    a.push(zigzag(rd.varint()?) as i16);
+                }
+            }
+            (Vals::I32(a), Wire::Fixed32(x)) => a.push(x as i32),
+            (Vals::I32(a), Wire::Bytes(x)) => {
+                a.extend(x.chunks_exact(4).map(|x| i32::from_le_bytes(x.try_into().unwrap())))
+            }
+            (Vals::F32(a), Wire::Fixed32(x)) => a.push(f32::from_bits(x)),
+            (Vals::F32(a), Wire::Bytes(x)) => {
+                a.extend(x.chunks_exact(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())))
+            }
+            (Vals::F64(a), Wire::Fixed64(x)) => a.push(f64::from_bits(x)),
+            (Vals::F64(a), Wire::Bytes(x)) => {
+                a.extend(x.chunks_exact(8).map(|x| f64::from_le_bytes(x.try_into().unwrap())))
+            }
+            _ => return Err(e),
+        }
+        Ok(())
+    }
+
+    fn into_data_value(self, ptype: PayloadType) -> Result<DataValue, Error> {
+        let missing = Error::MissingField("val");
+        let ret = if ptype.is_waveform() {
+            let v = match self {
+                Vals::String(a) => ArrayValue::String(a),
+                Vals::I8(a) => ArrayValue::I8(a),
+                Vals::I16(a) => ArrayValue::I16(a),
+                Vals::I32(a) => ArrayValue::I32(a),
+                Vals::F32(a) => ArrayValue::F32(a),
+                Vals::F64(a) => ArrayValue::F64(a),
+            };
+            DataValue::Array(v)
+        } else {
+            let v = match self {
+                Vals::String(a) => ScalarValue::String(a.into_iter().next().ok_or(missing)?),
+                Vals::I8(a) => ScalarValue::I8(a.first().copied().ok_or(missing)?),
+                Vals::I16(a) if ptype.0 == 3 => ScalarValue::Enum(a.first().copied().ok_or(missing)?),
+                Vals::I16(a) => ScalarValue::I16(a.first().copied().ok_or(missing)?),
+                Vals::I32(a) => ScalarValue::I32(a.first().copied().ok_or(missing)?),
+                Vals::F32(a) => ScalarValue::F32(a.first().copied().ok_or(missing)?),
+                Vals::F64(a) => ScalarValue::F64(a.first().copied().ok_or(missing)?),
+            };
+            DataValue::Scalar(v)
+        };
+        Ok(ret)
+    }
+}
+
+impl Sample {
+    fn decode(buf: &[u8], info: &PayloadInfo, year_start: u64) -> Result<Self, Error> {
+        let mut secs = None;
+        let mut nano = None;
+        let mut status = 0;
+        let mut severity = 0;
+        let mut vals = Vals::new(info.ptype.ca_type()?);
+        let mut rd = PbReader::new(buf);
+        while let Some((n, v)) = rd.field()? {
+            match (n, v) {
+                (1, Wire::Varint(x)) => secs = Some(x),
+                (2, Wire::Varint(x)) => nano = Some(x),
+                (3, v) => vals.push(v)?,
+                (4, Wire::Varint(x)) => severity = x as u16,
+                (5, Wire::Varint(x)) => status = x as u16,
+                _ => {}
+            }
+        }
+        let secs = secs.ok_or(Error::MissingField("secondsintoyear"))?;
+        let nano = nano.ok_or(Error::MissingField("nano"))?;
+        let ret = Self {
+            ts: (year_start + secs) * SEC + nano,
+            val: vals.into_data_value(info.ptype)?,
+            status,
+            severity,
+        };
+        Ok(ret)
+    }
+}
+
+#[derive(Debug)]
+pub enum PbItem {
+    Header(PayloadInfo),
+    Sample(Sample),
+}
+
+pub struct PbFileReader<R> {
+    inp: R,
+    buf: Vec<u8>,
+    info: Option<(PayloadInfo, u64)>,
+    expect_header: bool,
+}
+
+impl<R> PbFileReader<R>
+where
+    R: BufRead,
+{
+    pub fn new(inp: R) -> Self {
+        Self {
+            inp,
+            buf: Vec::new(),
+            info: None,
+            expect_header: true,
+        }
+    }
+
+    pub fn next_item(&mut self) -> Result<Option<PbItem>, Error> {
+        loop {
+            self.buf.clear();
+            if self.inp.read_until(b'\n', &mut self.buf)? == 0 {
+                return Ok(None);
+            }
+            if self.buf.last() == Some(&b'\n') {
+                self.buf.pop();
+            }
+            if self.buf.is_empty() {
+                self.expect_header = true;
+                continue;
+            }
+            let msg = unescape(&self.buf)?;
+            if self.expect_header {
+                self.expect_header = false;
+                let info = PayloadInfo::decode(&msg)?;
+                let year_start = info.year_start()?;
+                self.info = Some((info.clone(), year_start));
+                return Ok(Some(PbItem::Header(info)));
+            } else if let Some((info, year_start)) = &self.info {
+                let sample = Sample::decode(&msg, info, *year_start)?;
+                return Ok(Some(PbItem::Sample(sample)));
+            }
+        }
+    }
+}
+
+#[test]
+fn read_pb_file() {
+    fn varint(buf: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+    fn escape(buf: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for &b in buf {
+            match b {
+                ESC => ret.extend([ESC, 1]),
+                b'\n' => ret.extend([ESC, 2]),
+                b'\r' => ret.extend([ESC, 3]),
+                _ => ret.push(b),
+            }
+        }
+        ret.push(b'\n');
+        ret
+    }
+    let mut file = Vec::new();
+    let mut m = vec![0x08];
+    varint(&mut m, 6);
+    m.push(0x12);
+    varint(&mut m, 10);
+    m.extend(b"SAR:TEMP:1");
+    m.push(0x18);
+    varint(&mut m, 2023);
+    file.extend(escape(&m));
+    // Two scalar doubles, the second one has bytes to escape in the value.
+    for (secs, val, sev) in [(60u64, 21.5f64, 0u64), (61, f64::from_bits(0x0a0d1b), 2)] {
+        let mut m = vec![0x08];
+        varint(&mut m, secs);
+        m.push(0x10);
+        varint(&mut m, 500);
+        m.push(0x19);
+        m.extend(val.to_le_bytes());
+        m.push(0x20);
+        varint(&mut m, sev);
+        file.extend(escape(&m));
+    }
+    // Next chunk with a waveform of shorts.
+    file.push(b'\n');
+    let mut m = vec![0x08];
+    varint(&mut m, 8);
+    m.push(0x12);
+    varint(&mut m, 4);
+    m.extend(b"WF:1");
+    m.push(0x18);
+    varint(&mut m, 1970);
+    file.extend(escape(&m));
+    let mut m = vec![0x08, 0x05, 0x10, 0x00, 0x1a, 0x03, 0x02, 0x03, 0x04];
+    m.extend([0x28, 0x07]);
+    file.extend(escape(&m));
+    let mut rd = PbFileReader::new(file.as_slice());
+    match rd.next_item().unwrap() {
+        Some(PbItem::Header(h)) => {
+            assert_eq!(h.pvname, "SAR:TEMP:1");
+            assert_eq!(h.ptype.ca_type().unwrap(), 6);
+            assert_eq!(h.year_start().unwrap(), 1672531200);
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    match rd.next_item().unwrap() {
+        Some(PbItem::Sample(s)) => {
+            assert_eq!(s.ts, (1672531200 + 60) * SEC + 500);
+            assert!(matches!(s.val, DataValue::Scalar(ScalarValue::F64(x)) if x == 21.5));
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    match rd.next_item().unwrap() {
+        Some(PbItem::Sample(s)) => {
+            assert!(matches!(s.val, DataValue::Scalar(ScalarValue::F64(x)) if x.to_bits() == 0x0a0d1b));
+            assert_eq!(s.severity, 2);
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    assert!(matches!(rd.next_item().unwrap(), Some(PbItem::Header(h)) if h.ptype.is_waveform()));
+    match rd.next_item().unwrap() {
+        Some(PbItem::Sample(s)) => {
+            assert_eq!(s.ts, 5 * SEC);
+            assert_eq!(s.status, 7);
+            assert!(matches!(s.val, DataValue::Array(ArrayValue::I16(ref x)) if x == &[1, -2, 2]));
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    assert!(rd.next_item().unwrap().is_none());
+}
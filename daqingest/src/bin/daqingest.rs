@@ -1,8 +1,84 @@
 use clap::Parser;
 use daqingest::opts::DaqIngestOpts;
+use daqingest::opts::PgSchemaCmd;
+use daqingest::opts::SchemaCmd;
+use daqingest::tools::DbConf;
 use err::Error;
 use log::*;
 use netfetch::conf::parse_config;
+use netfetch::conf::parse_config_opts;
+use std::path::PathBuf;
+
+async fn scylla_schema(cmd: SchemaCmd) -> Result<(), Error> {
+    let conf = parse_config_opts(cmd.config().into()).await?;
+    let scyconf = conf
+        .scylla_config()
+        .ok_or_else(|| Error::with_msg_no_trace("config has no scylla section"))?;
+    match cmd {
+        SchemaCmd::Check(_) => {
+            let drift = scywr::schema::check_scylla_data_schema(scyconf)
+                .await
+                .map_err(|e| Error::from(e.to_string()))?;
+            for x in &drift {
+                println!("{x}");
+            }
+            if drift.len() != 0 {
+                return Err(Error::with_msg_no_trace(format!(
+                    "schema differs in {} places",
+                    drift.len()
+                )));
+            }
+            println!("schema ok");
+        }
+        SchemaCmd::Migrate(_) => {
+            scywr::schema::migrate_scylla_data_schema(scyconf)
+                .await
+                .map_err(|e| Error::from(e.to_string()))?;
+        }
+        SchemaCmd::Dump(_) => {
+            print!("{}", scywr::schema::dump_scylla_data_schema(scyconf));
+        }
+    }
+    Ok(())
+}
+
+async fn postgres_schema(cmd: PgSchemaCmd) -> Result<(), Error> {
+    let config = match &cmd {
+        PgSchemaCmd::Check(k) | PgSchemaCmd::Migrate(k) => k.config.clone(),
+        PgSchemaCmd::Dump => {
+            print!("{}", dbpg::schema::schema_dump());
+            return Ok(());
+        }
+    };
+    let conf = parse_config_opts(config.into()).await?;
+    let mut pg = dbpg::conn::make_pg_client(conf.postgresql_config())
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    match cmd {
+        PgSchemaCmd::Check(_) => {
+            let pending = dbpg::schema::schema_pending(&pg)
+                .await
+                .map_err(|e| Error::from(e.to_string()))?;
+            for (version, descr) in &pending {
+                println!("pending migration {version}  {descr}");
+            }
+            if pending.len() != 0 {
+                return Err(Error::with_msg_no_trace(format!(
+                    "{} migrations pending",
+                    pending.len()
+                )));
+            }
+            println!("schema ok");
+        }
+        PgSchemaCmd::Migrate(_) => {
+            dbpg::schema::schema_migrate(&mut pg)
+                .await
+                .map_err(|e| Error::from(e.to_string()))?;
+        }
+        PgSchemaCmd::Dump => {}
+    }
+    Ok(())
+}
 
 pub fn main() -> Result<(), Error> {
     let opts = DaqIngestOpts::parse();
@@ -13,40 +89,61 @@ pub fn main() -> Result<(), Error> {
         Err(()) => return Err(Error::with_msg_no_trace("tracing init failed")),
     }
     let res = runtime.block_on(async move {
+        use daqingest::opts::Archapp;
         use daqingest::opts::ChannelAccess;
+        use daqingest::opts::Postgres;
+        use daqingest::opts::Pulse;
+        use daqingest::opts::Scylla;
         use daqingest::opts::SubCmd;
         match opts.subcmd {
-            SubCmd::ListPkey => {
-                // TODO must take scylla config from CLI
-                let scylla_conf = err::todoval();
-                scywr::tools::list_pkey(&scylla_conf).await?
-            }
-            SubCmd::ListPulses => {
-                // TODO must take scylla config from CLI
-                let scylla_conf = err::todoval();
-                scywr::tools::list_pulses(&scylla_conf).await?
+            SubCmd::ListPkey(k) => {
+                let conf = DbConf::from_args(&k).await?;
+                scywr::tools::list_pkey(conf.scylla()?).await?
             }
-            SubCmd::FetchEvents(k) => {
-                // TODO must take scylla config from CLI
-                let scylla_conf = err::todoval();
-                scywr::tools::fetch_events(&k.backend, &k.channel, &scylla_conf).await?
+            SubCmd::ListPulses(k) => {
+                let conf = DbConf::from_args(&k).await?;
+                scywr::tools::list_pulses(conf.scylla()?).await?
             }
+            SubCmd::FetchEvents(k) => daqingest::tools::fetch_events(k).await?,
+            SubCmd::Export(k) => daqingest::export::export(k).await?,
+            SubCmd::Pulse(k) => match k {
+                Pulse::Lookup(k) => daqingest::tools::pulse_lookup(k).await?,
+            },
             SubCmd::ChannelAccess(k) => match k {
                 ChannelAccess::CaSearch(k) => {
                     info!("daqingest version {}", clap::crate_version!());
                     let (conf, channels) = parse_config(k.config.into()).await?;
+                    let channels = channels.into_iter().map(|x| x.name).collect();
                     netfetch::ca::search::ca_search(conf, &channels).await?
                 }
                 ChannelAccess::CaIngest(k) => {
                     info!("daqingest version {}", clap::crate_version!());
-                    let (conf, channels) = parse_config(k.config.into()).await?;
-                    daqingest::daemon::run(conf, channels).await?
+                    let config: PathBuf = k.config.into();
+                    let (conf, channels) = parse_config(config.clone()).await?;
+                    daqingest::daemon::run(config, conf, channels).await?
                 }
             },
+            SubCmd::PvaIngest(k) => {
+                info!("daqingest version {}", clap::crate_version!());
+                let (conf, channels) = parse_config(k.config.into()).await?;
+                daqingest::daemon::run_pva(conf, channels).await?
+            }
+            SubCmd::Archapp(k) => match k {
+                Archapp::Import(k) => daqingest::archapp::import(k).await?,
+            },
+            SubCmd::Scylla(k) => match k {
+                Scylla::Schema(k) => scylla_schema(k).await?,
+            },
+            SubCmd::Postgres(k) => match k {
+                Postgres::Schema(k) => postgres_schema(k).await?,
+            },
             #[cfg(feature = "bsread")]
-            SubCmd::Bsread(k) => ingest_bsread::zmtp::zmtp_client(k.into())
-                .await
-                .map_err(|e| Error::from(e.to_string()))?,
+            SubCmd::Bsread(k) => {
+                info!("daqingest version {}", clap::crate_version!());
+                let (conf, _) = parse_config(k.config.clone().into()).await?;
+                let zmtpopts = k.zmtp_opts(conf.backend().into());
+                daqingest::daemon::run_bsread(conf, zmtpopts).await?
+            }
             #[cfg(feature = "bsread")]
             SubCmd::BsreadDump(k) => {
                 let mut f = ingest_bsread::zmtp::dumper::BsreadDumper::new(k.source);
@@ -9,26 +9,36 @@ use log::*;
 use netfetch::ca::connset::CaConnSet;
 use netfetch::ca::connset::CaConnSetCtrl;
 use netfetch::ca::connset::CaConnSetItem;
+use netfetch::ca::finder::FinderSource;
+use netfetch::ca::policy::ArchivingPolicy;
 use netfetch::ca::IngestCommons;
+use netfetch::conf::channel_set_diff;
+use netfetch::conf::parse_config;
 use netfetch::conf::CaIngestOpts;
+use netfetch::conf::ChannelConfig;
+use netfetch::conf::ChannelProtocol;
 use netfetch::daemon_common::Channel;
 use netfetch::daemon_common::DaemonEvent;
 use netfetch::metrics::ExtraInsertsConf;
 use netfetch::metrics::StatsSet;
+use netfetch::pva::connset::PvaConnSet;
 use netpod::Database;
-use netpod::ScyllaConfig;
+use scywr::insertworker::InsertWorkerOpts;
 use scywr::insertworker::Ttls;
 use scywr::iteminsertqueue as scywriiq;
-use scywr::store::DataStore;
+use scywr::sink::SinkOpts;
+use scywr::spool::SpoolOpts;
 use scywriiq::QueryItem;
 use serde::Serialize;
 use series::ChannelStatusSeriesId;
 use series::SeriesId;
+use stats::CaConnStats;
 use stats::DaemonStats;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
+use std::path::PathBuf;
 use std::sync::atomic;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
@@ -44,6 +54,8 @@ const CA_CONN_INSERT_QUEUE_MAX: usize = 256;
 const CHANNEL_CHECK_INTERVAL: Duration = Duration::from_millis(5000);
 const PRINT_ACTIVE_INTERVAL: Duration = Duration::from_millis(60000);
 const PRINT_STATUS_INTERVAL: Duration = Duration::from_millis(20000);
+const CHANNEL_RELOAD_CHECK_INTERVAL: Duration = Duration::from_millis(2000);
+const CHANNEL_RELOAD_REPLY_TIMEOUT: Duration = Duration::from_millis(10000);
 
 pub struct DaemonOpts {
     backend: String,
@@ -51,12 +63,13 @@ pub struct DaemonOpts {
     array_truncate: usize,
     insert_item_queue_cap: usize,
     pgconf: Database,
-    scyconf: ScyllaConfig,
+    sink: SinkOpts,
     ttls: Ttls,
     #[allow(unused)]
     test_bsread_addr: Option<String>,
     insert_worker_count: usize,
-    insert_scylla_sessions: usize,
+    spool: Option<SpoolOpts>,
+    beacon_port: Option<u16>,
 }
 
 impl DaemonOpts {
@@ -85,14 +98,11 @@ pub struct Daemon {
     insert_rx_weak: WeakReceiver<QueryItem>,
     connset_ctrl: CaConnSetCtrl,
     connset_status_last: Instant,
+    store_stats: Arc<CaConnStats>,
 }
 
 impl Daemon {
     pub async fn new(opts: DaemonOpts) -> Result<Self, Error> {
-        let datastore = DataStore::new(&opts.scyconf)
-            .await
-            .map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
-        let datastore = Arc::new(datastore);
         let (daemon_ev_tx, daemon_ev_rx) = async_channel::bounded(32);
 
         // TODO keep join handles and await later
@@ -106,12 +116,16 @@ impl Daemon {
         // Insert queue hook
         let query_item_rx = inserthook::active_channel_insert_hook(query_item_rx);
 
+        #[cfg(feature = "bsread")]
+        let bsread_senders = (query_item_tx.clone(), channel_info_query_tx.clone());
+
         let conn_set_ctrl = CaConnSet::start(
             opts.backend.clone(),
             opts.local_epics_hostname.clone(),
             query_item_tx,
             channel_info_query_tx,
-            opts.pgconf.clone(),
+            FinderSource::Database(opts.pgconf.clone()),
+            opts.beacon_port,
         );
 
         // TODO remove
@@ -141,7 +155,6 @@ impl Daemon {
             pgconf: Arc::new(opts.pgconf.clone()),
             backend: opts.backend().into(),
             local_epics_hostname: opts.local_epics_hostname.clone(),
-            data_store: datastore.clone(),
             insert_ivl_min: Arc::new(AtomicU64::new(0)),
             extra_inserts_conf: tokio::sync::Mutex::new(ExtraInsertsConf::new()),
             store_workers_rate: Arc::new(AtomicU64::new(20000)),
@@ -156,33 +169,37 @@ impl Daemon {
         let store_stats = Arc::new(stats::CaConnStats::new());
         let ttls = opts.ttls.clone();
         let insert_worker_opts = Arc::new(ingest_commons.as_ref().into());
-        let insert_workers_jh = scywr::insertworker::spawn_scylla_insert_workers(
-            opts.scyconf.clone(),
-            opts.insert_scylla_sessions,
+        let sinks = opts.sink.build().await?;
+        let insert_workers_jh = scywr::insertworker::spawn_insert_workers(
+            sinks,
             opts.insert_worker_count,
             query_item_rx.clone(),
             insert_worker_opts,
             store_stats.clone(),
             use_rate_limit_queue,
             ttls,
+            opts.spool.clone(),
         )
         .await?;
 
+        // One dedup for the process: bsread sources share the pulse ids of the facility.
+        #[cfg(feature = "bsread")]
+        let pulse_map_dedup = Arc::new(ingest_bsread::bsreadclient::PulseMapDedup::new());
         #[cfg(feature = "bsread")]
         if let Some(bsaddr) = &opts.test_bsread_addr {
             //netfetch::zmtp::Zmtp;
             let zmtpopts = ingest_bsread::zmtp::ZmtpClientOpts {
                 backend: opts.backend().into(),
                 addr: bsaddr.parse().unwrap(),
-                do_pulse_id: false,
                 rcvbuf: None,
                 array_truncate: Some(1024),
                 process_channel_count_limit: Some(32),
             };
             let client = ingest_bsread::bsreadclient::BsreadClient::new(
                 zmtpopts,
-                ingest_commons.insert_item_queue.sender().unwrap().inner().clone(),
-                channel_info_query_tx.clone(),
+                bsread_senders.0,
+                bsread_senders.1,
+                pulse_map_dedup.clone(),
             )
             .await
             .map_err(|e| Error::from(e.to_string()))?;
@@ -221,6 +238,7 @@ impl Daemon {
             insert_rx_weak: query_item_rx.downgrade(),
             connset_ctrl: conn_set_ctrl,
             connset_status_last: Instant::now(),
+            store_stats,
         };
         Ok(ret)
     }
@@ -229,6 +247,10 @@ impl Daemon {
         &self.stats
     }
 
+    fn store_stats(&self) -> &Arc<CaConnStats> {
+        &self.store_stats
+    }
+
     async fn check_caconn_chans(&mut self) -> Result<(), Error> {
         if self.caconn_last_channel_check.elapsed() > CHANNEL_CHECK_INTERVAL {
             self.connset_ctrl.check_health().await?;
@@ -296,12 +318,13 @@ impl Daemon {
         Ok(())
     }
 
-    async fn handle_channel_add(&mut self, ch: Channel) -> Result<(), Error> {
+    async fn handle_channel_add(&mut self, ch: Channel, policy: ArchivingPolicy) -> Result<(), Error> {
         self.connset_ctrl
             .add_channel(
                 self.opts.backend.clone(),
                 ch.id().into(),
                 self.opts.local_epics_hostname.clone(),
+                policy,
             )
             .await?;
         Ok(())
@@ -425,8 +448,12 @@ impl Daemon {
                 let _ = ts1.elapsed();
                 ret
             }
-            ChannelAdd(ch) => self.handle_channel_add(ch).await,
+            ChannelAdd(ch, policy) => self.handle_channel_add(ch, policy).await,
             ChannelRemove(ch) => self.handle_channel_remove(ch).await,
+            FindChannel(pattern, tx) => self.connset_ctrl.find_channel(pattern, tx).await,
+            ChannelState(ch, tx) => self.connset_ctrl.channel_state(ch.id().into(), tx).await,
+            ChannelStatesAll(tx) => self.connset_ctrl.channel_states_all(tx).await,
+            ChannelPoliciesAll(tx) => self.connset_ctrl.channel_policies_all(tx).await,
             CaConnSetItem(item) => self.handle_ca_conn_set_item(item).await,
             Shutdown => self.handle_shutdown().await,
         };
@@ -519,6 +546,7 @@ impl Daemon {
 static SIGINT: AtomicUsize = AtomicUsize::new(0);
 static SIGTERM: AtomicUsize = AtomicUsize::new(0);
 static SHUTDOWN_SENT: AtomicUsize = AtomicUsize::new(0);
+static SIGHUP: AtomicUsize = AtomicUsize::new(0);
 
 fn handler_sigint(_a: libc::c_int, _b: *const libc::siginfo_t, _c: *const libc::c_void) {
     SIGINT.store(1, atomic::Ordering::Release);
@@ -530,20 +558,105 @@ fn handler_sigterm(_a: libc::c_int, _b: *const libc::siginfo_t, _c: *const libc:
     let _ = ingest_linux::signal::unset_signal_handler(libc::SIGTERM);
 }
 
-pub async fn run(opts: CaIngestOpts, channels: Vec<String>) -> Result<(), Error> {
+// Stays installed, every SIGHUP triggers a reload of the channel list.
+fn handler_sighup(_a: libc::c_int, _b: *const libc::siginfo_t, _c: *const libc::c_void) {
+    SIGHUP.store(1, atomic::Ordering::Release);
+}
+
+fn channels_file_mtime(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+// The channels which `ca-ingest` serves out of the configured list.
+fn ca_channels(opts: &CaIngestOpts, channels: Vec<ChannelConfig>) -> Vec<ChannelConfig> {
+    // With a test bsread source the configured channels are not used.
+    if opts.test_bsread_addr.is_some() {
+        return Vec::new();
+    }
+    // The pvAccess channels are served by `pva-ingest`.
+    channels
+        .into_iter()
+        .filter(|x| x.protocol == ChannelProtocol::Ca)
+        .collect()
+}
+
+// Applies the difference between the channel list in the config and the active set of channels.
+// Channels which are not in the list, e.g. added via the http api, get removed as well.
+async fn reload_channels(config: PathBuf, tx: &Sender<DaemonEvent>) -> Result<(), Error> {
+    let (opts, channels) = parse_config(config).await?;
+    let channels = ca_channels(&opts, channels);
+    let (tx2, rx2) = async_channel::bounded(1);
+    tx.send(DaemonEvent::ChannelPoliciesAll(tx2)).await?;
+    let active = tokio::time::timeout(CHANNEL_RELOAD_REPLY_TIMEOUT, rx2.recv())
+        .await
+        .map_err(|_| Error::with_msg_no_trace("timeout on active channel list"))?
+        .map_err(Error::from_string)?;
+    let (add, remove) = channel_set_diff(&active, &channels);
+    info!(
+        "channel reload  configured {}  active {}  add {}  remove {}",
+        channels.len(),
+        active.len(),
+        add.len(),
+        remove.len()
+    );
+    for name in remove {
+        tx.send(DaemonEvent::ChannelRemove(Channel::new(name))).await?;
+    }
+    for c in add {
+        tx.send(DaemonEvent::ChannelAdd(Channel::new(c.name), c.policy)).await?;
+    }
+    Ok(())
+}
+
+async fn channel_reload_task(config: PathBuf, channels_file: Option<PathBuf>, tx: Sender<DaemonEvent>) {
+    let mut mtime_last = channels_file.as_ref().and_then(channels_file_mtime);
+    loop {
+        tokio::time::sleep(CHANNEL_RELOAD_CHECK_INTERVAL).await;
+        if SHUTDOWN_SENT.load(atomic::Ordering::Acquire) != 0 {
+            break;
+        }
+        let mut reload = false;
+        if SIGHUP.swap(0, atomic::Ordering::AcqRel) != 0 {
+            info!("Received SIGHUP, reload channel list");
+            reload = true;
+        }
+        if let Some(path) = &channels_file {
+            let mtime = channels_file_mtime(path);
+            if mtime != mtime_last {
+                info!("channel list {} changed, reload", path.display());
+                mtime_last = mtime;
+                reload = true;
+            }
+        }
+        if reload {
+            if let Err(e) = reload_channels(config.clone(), &tx).await {
+                error!("channel reload failed, keep the current channels  {e}");
+            }
+            if tx.is_closed() {
+                break;
+            }
+        }
+    }
+}
+
+pub async fn run(config: PathBuf, opts: CaIngestOpts, channels: Vec<ChannelConfig>) -> Result<(), Error> {
     info!("start up {opts:?}");
     ingest_linux::signal::set_signal_handler(libc::SIGINT, handler_sigint).map_err(Error::from_string)?;
     ingest_linux::signal::set_signal_handler(libc::SIGTERM, handler_sigterm).map_err(Error::from_string)?;
+    ingest_linux::signal::set_signal_handler(libc::SIGHUP, handler_sighup).map_err(Error::from_string)?;
 
-    let pg = dbpg::conn::make_pg_client(opts.postgresql_config())
+    let mut pg = dbpg::conn::make_pg_client(opts.postgresql_config())
         .await
         .map_err(Error::from_string)?;
 
-    dbpg::schema::schema_check(&pg).await.map_err(Error::from_string)?;
+    dbpg::schema::schema_check(&mut pg).await.map_err(Error::from_string)?;
 
-    scywr::schema::migrate_scylla_data_schema(opts.scylla_config())
-        .await
-        .map_err(Error::from_string)?;
+    let sink = opts.sink_opts()?;
+    if let SinkOpts::Scylla { scyconf, .. } = &sink {
+        scywr::schema::migrate_scylla_data_schema(scyconf)
+            .await
+            .map_err(Error::from_string)?;
+    }
 
     info!("database check done");
 
@@ -552,10 +665,7 @@ pub async fn run(opts: CaIngestOpts, channels: Vec<String>) -> Result<(), Error>
     //let metrics_agg_fut = metrics_agg_task(ingest_commons.clone(), local_stats.clone(), store_stats.clone());
     //let metrics_agg_jh = tokio::spawn(metrics_agg_fut);
 
-    let mut channels = channels;
-    if opts.test_bsread_addr.is_some() {
-        channels.clear();
-    }
+    let channels = ca_channels(&opts, channels);
 
     let opts2 = DaemonOpts {
         backend: opts.backend().into(),
@@ -563,7 +673,7 @@ pub async fn run(opts: CaIngestOpts, channels: Vec<String>) -> Result<(), Error>
         array_truncate: opts.array_truncate(),
         insert_item_queue_cap: opts.insert_item_queue_cap(),
         pgconf: opts.postgresql_config().clone(),
-        scyconf: opts.scylla_config().clone(),
+        sink,
         ttls: Ttls {
             index: opts.ttl_index(),
             d0: opts.ttl_d0(),
@@ -572,28 +682,189 @@ pub async fn run(opts: CaIngestOpts, channels: Vec<String>) -> Result<(), Error>
         },
         test_bsread_addr: opts.test_bsread_addr.clone(),
         insert_worker_count: opts.insert_worker_count(),
-        insert_scylla_sessions: opts.insert_scylla_sessions(),
+        spool: opts.spool_dir().map(|dir| SpoolOpts {
+            dir,
+            segment_max: opts.spool_segment_max(),
+            total_max: opts.spool_max(),
+        }),
+        beacon_port: opts.beacon_port(),
     };
     let daemon = Daemon::new(opts2).await?;
     let tx = daemon.tx.clone();
     let daemon_stats = daemon.stats().clone();
+    let store_stats = daemon.store_stats().clone();
 
     let dcom = Arc::new(netfetch::metrics::DaemonComm::new(tx.clone()));
     let metrics_jh = {
-        let stats_set = StatsSet::new(daemon_stats);
+        let stats_set = StatsSet::new(daemon_stats, store_stats);
         let fut = netfetch::metrics::start_metrics_service(opts.api_bind(), dcom, stats_set);
         tokio::task::spawn(fut)
     };
 
     let daemon_jh = taskrun::spawn(daemon.daemon());
-    for s in &channels {
-        let ch = Channel::new(s.into());
-        tx.send(DaemonEvent::ChannelAdd(ch)).await?;
+    for c in &channels {
+        let ch = Channel::new(c.name.clone());
+        tx.send(DaemonEvent::ChannelAdd(ch, c.policy.clone())).await?;
     }
     debug!("{} configured channels applied", channels.len());
+    let channels_file = if opts.channels_reload_on_change() {
+        Some(opts.channels_file().clone())
+    } else {
+        None
+    };
+    taskrun::spawn(channel_reload_task(config, channels_file, tx.clone()));
     daemon_jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
     if false {
         metrics_jh.await.unwrap();
     }
     Ok(())
 }
+
+// Insert workers and series lookup of the ingest processes next to `ca-ingest`.
+struct StandaloneStore {
+    item_tx: Sender<QueryItem>,
+    lookup_tx: Sender<dbpg::seriesbychannel::ChannelInfoQuery>,
+    insert_jhs: Vec<JoinHandle<Result<(), Error>>>,
+    lookup_jhs: Vec<JoinHandle<Result<(), dbpg::seriesbychannel::Error>>>,
+}
+
+impl StandaloneStore {
+    async fn start(opts: &CaIngestOpts) -> Result<Self, Error> {
+        let mut pg = dbpg::conn::make_pg_client(opts.postgresql_config())
+            .await
+            .map_err(Error::from_string)?;
+        dbpg::schema::schema_check(&mut pg).await.map_err(Error::from_string)?;
+        let sink = opts.sink_opts()?;
+        if let SinkOpts::Scylla { scyconf, .. } = &sink {
+            scywr::schema::migrate_scylla_data_schema(scyconf)
+                .await
+                .map_err(Error::from_string)?;
+        }
+        let (lookup_tx, lookup_jhs, _) = dbpg::seriesbychannel::start_lookup_workers(4, opts.postgresql_config())
+            .await
+            .map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
+        let (item_tx, item_rx) = async_channel::bounded(opts.insert_item_queue_cap());
+        let insert_worker_opts = InsertWorkerOpts {
+            store_workers_rate: Arc::new(AtomicU64::new(opts.store_workers_rate())),
+            insert_workers_running: Arc::new(AtomicU64::new(0)),
+            insert_frac: Arc::new(AtomicU64::new(opts.insert_frac())),
+        };
+        let ttls = Ttls {
+            index: opts.ttl_index(),
+            d0: opts.ttl_d0(),
+            d1: opts.ttl_d1(),
+            binned: opts.ttl_binned(),
+        };
+        let insert_jhs = scywr::insertworker::spawn_insert_workers(
+            sink.build().await?,
+            opts.insert_worker_count(),
+            item_rx,
+            Arc::new(insert_worker_opts),
+            Arc::new(CaConnStats::new()),
+            opts.use_rate_limit_queue(),
+            ttls,
+            None,
+        )
+        .await?;
+        let ret = Self {
+            item_tx,
+            lookup_tx,
+            insert_jhs,
+            lookup_jhs,
+        };
+        Ok(ret)
+    }
+
+    // The workers finish once all senders are dropped.
+    async fn join(self) -> Result<(), Error> {
+        drop(self.item_tx);
+        drop(self.lookup_tx);
+        for jh in self.insert_jhs {
+            jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
+        }
+        for jh in self.lookup_jhs {
+            jh.await
+                .map_err(|e| Error::with_msg_no_trace(e.to_string()))?
+                .map_err(|e| Error::from(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+fn shutdown_requested() -> bool {
+    SIGINT.load(atomic::Ordering::Acquire) != 0 || SIGTERM.load(atomic::Ordering::Acquire) != 0
+}
+
+// Ingest of the channels configured with protocol pva, in its own process next to `ca-ingest`.
+pub async fn run_pva(opts: CaIngestOpts, channels: Vec<ChannelConfig>) -> Result<(), Error> {
+    info!("start up {opts:?}");
+    ingest_linux::signal::set_signal_handler(libc::SIGINT, handler_sigint).map_err(Error::from_string)?;
+    ingest_linux::signal::set_signal_handler(libc::SIGTERM, handler_sigterm).map_err(Error::from_string)?;
+    let channels: Vec<_> = channels
+        .into_iter()
+        .filter(|x| x.protocol == ChannelProtocol::Pva)
+        .collect();
+    let store = StandaloneStore::start(&opts).await?;
+    let search_addrs = netfetch::pva::search::search_addrs(&opts).await?;
+    info!("pva search addresses {search_addrs:?}");
+    let ctrl = PvaConnSet::start(
+        opts.backend().into(),
+        store.item_tx.clone(),
+        store.lookup_tx.clone(),
+        search_addrs,
+    );
+    for c in &channels {
+        // Deadband and scan need the Channel Access update semantics, pvAccess channels are monitored.
+        if c.policy != ArchivingPolicy::default() {
+            warn!("policy of pva channel {} ignored", c.name);
+        }
+        ctrl.add_channel(c.name.clone()).await?;
+    }
+    info!("{} pva channels configured", channels.len());
+    loop {
+        if shutdown_requested() {
+            info!("shutting down");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    ctrl.shutdown().await?;
+    ctrl.join().await?;
+    store.join().await?;
+    Ok(())
+}
+
+// Ingest of one bsread source, in its own process next to `ca-ingest`.
+#[cfg(feature = "bsread")]
+pub async fn run_bsread(opts: CaIngestOpts, zmtpopts: ingest_bsread::zmtp::ZmtpClientOpts) -> Result<(), Error> {
+    info!("start up {opts:?}");
+    ingest_linux::signal::set_signal_handler(libc::SIGINT, handler_sigint).map_err(Error::from_string)?;
+    ingest_linux::signal::set_signal_handler(libc::SIGTERM, handler_sigterm).map_err(Error::from_string)?;
+    let store = StandaloneStore::start(&opts).await?;
+    let pulse_map_dedup = Arc::new(ingest_bsread::bsreadclient::PulseMapDedup::new());
+    let client = ingest_bsread::zmtp::zmtp_client(
+        zmtpopts,
+        store.item_tx.clone(),
+        store.lookup_tx.clone(),
+        pulse_map_dedup,
+    );
+    let jh = tokio::spawn(client);
+    loop {
+        if shutdown_requested() {
+            info!("shutting down");
+            jh.abort();
+            break;
+        }
+        if jh.is_finished() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    let res = match jh.await {
+        Ok(res) => res.map_err(|e| Error::from(e.to_string())),
+        Err(e) if e.is_cancelled() => Ok(()),
+        Err(e) => Err(Error::with_msg_no_trace(e.to_string())),
+    };
+    store.join().await?;
+    res
+}
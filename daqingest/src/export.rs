@@ -0,0 +1,563 @@
+use crate::opts::Export;
+use crate::opts::ExportFormat;
+use crate::tools::csv_field;
+use crate::tools::read_series_events;
+use crate::tools::series_table;
+use crate::tools::ts_nanos;
+use crate::tools::ts_string;
+use crate::tools::DbConf;
+use chrono::Utc;
+use dbpg::findseries::SeriesRow;
+use err::Error;
+use log::*;
+use scywr::readevents::EventRow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+
+trait EventsOut {
+    fn write(&mut self, sr: &SeriesRow, rows: &[EventRow]) -> Result<(), Error>;
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+struct CsvOut<W> {
+    out: W,
+}
+
+impl<W> CsvOut<W>
+where
+    W: Write,
+{
+    fn new(mut out: W) -> Result<Self, Error> {
+        writeln!(out, "channel,series,ts,pulse,status,severity,value")?;
+        Ok(Self { out })
+    }
+}
+
+impl<W> EventsOut for CsvOut<W>
+where
+    W: Write,
+{
+    fn write(&mut self, sr: &SeriesRow, rows: &[EventRow]) -> Result<(), Error> {
+        let opt = |x: Option<i16>| x.map_or(String::new(), |x| x.to_string());
+        let channel = csv_field(&sr.channel);
+        for row in rows {
+            writeln!(
+                self.out,
+                "{},{},{},{},{},{},{}",
+                channel,
+                sr.series,
+                ts_string(row.ts),
+                row.pulse,
+                opt(row.status),
+                opt(row.severity),
+                csv_field(&row.value.to_string())
+            )?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct NdjsonOut<W> {
+    out: W,
+}
+
+impl<W> EventsOut for NdjsonOut<W>
+where
+    W: Write,
+{
+    fn write(&mut self, sr: &SeriesRow, rows: &[EventRow]) -> Result<(), Error> {
+        for row in rows {
+            let v = serde_json::json!({
+                "channel": sr.channel,
+                "series": sr.series,
+                "ts": row.ts,
+                "pulse": row.pulse,
+                "status": row.status,
+                "severity": row.severity,
+                "value": row.value,
+            });
+            writeln!(self.out, "{v}")?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Error> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquetout {
+    use super::EventsOut;
+    use arrow::array::ArrayRef;
+    use arrow::array::BooleanBuilder;
+    use arrow::array::Float64Builder;
+    use arrow::array::Int16Builder;
+    use arrow::array::Int64Builder;
+    use arrow::array::ListBuilder;
+    use arrow::array::StringBuilder;
+    use arrow::array::TimestampNanosecondBuilder;
+    use arrow::array::UInt64Builder;
+    use arrow::datatypes::DataType;
+    use arrow::datatypes::Field;
+    use arrow::datatypes::Schema;
+    use arrow::datatypes::SchemaRef;
+    use arrow::datatypes::TimeUnit;
+    use arrow::record_batch::RecordBatch;
+    use dbpg::findseries::SeriesRow;
+    use err::Error;
+    use netpod::ScalarType;
+    use parquet::arrow::ArrowWriter;
+    use scywr::readevents::EventRow;
+    use scywr::readevents::EventValue;
+    use std::fs::File;
+    use std::sync::Arc;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum ValueKind {
+        Int,
+        UInt,
+        Float,
+        Bool,
+        String,
+    }
+
+    impl ValueKind {
+        const ALL: [ValueKind; 5] = [Self::Int, Self::UInt, Self::Float, Self::Bool, Self::String];
+
+        fn of_value(v: &EventValue) -> Option<Self> {
+            match v {
+                EventValue::Int(_) => Some(Self::Int),
+                EventValue::UInt(_) => Some(Self::UInt),
+                EventValue::Float(_) => Some(Self::Float),
+                EventValue::Bool(_) => Some(Self::Bool),
+                EventValue::String(_) => Some(Self::String),
+                EventValue::Array(_) => None,
+            }
+        }
+
+        // Same classification as the events table suffix, used for arrays without elements.
+        fn of_series(sr: &SeriesRow) -> Result<Self, Error> {
+            let sty = ScalarType::from_scylla_i32(sr.scalar_type).map_err(|e| Error::from(e.to_string()))?;
+            let ret = match sty.to_variant_str() {
+                "bool" => Self::Bool,
+                "string" => Self::String,
+                x if x.starts_with('u') => Self::UInt,
+                x if x.starts_with('f') => Self::Float,
+                _ => Self::Int,
+            };
+            Ok(ret)
+        }
+
+        fn name(&self) -> &'static str {
+            match self {
+                Self::Int => "i64",
+                Self::UInt => "u64",
+                Self::Float => "f64",
+                Self::Bool => "bool",
+                Self::String => "string",
+            }
+        }
+
+        fn data_type(&self) -> DataType {
+            match self {
+                Self::Int => DataType::Int64,
+                Self::UInt => DataType::UInt64,
+                Self::Float => DataType::Float64,
+                Self::Bool => DataType::Boolean,
+                Self::String => DataType::Utf8,
+            }
+        }
+    }
+
+    // One nullable column per value type, `value_<type>` for scalars and `array_<type>` for waveforms.
+    // Each row fills exactly one of them, so integers keep their full range.
+    struct ValueCols {
+        int: Int64Builder,
+        uint: UInt64Builder,
+        float: Float64Builder,
+        bool: BooleanBuilder,
+        string: StringBuilder,
+        int_array: ListBuilder<Int64Builder>,
+        uint_array: ListBuilder<UInt64Builder>,
+        float_array: ListBuilder<Float64Builder>,
+        bool_array: ListBuilder<BooleanBuilder>,
+        string_array: ListBuilder<StringBuilder>,
+    }
+
+    impl ValueCols {
+        fn new() -> Self {
+            Self {
+                int: Int64Builder::new(),
+                uint: UInt64Builder::new(),
+                float: Float64Builder::new(),
+                bool: BooleanBuilder::new(),
+                string: StringBuilder::new(),
+                int_array: ListBuilder::new(Int64Builder::new()),
+                uint_array: ListBuilder::new(UInt64Builder::new()),
+                float_array: ListBuilder::new(Float64Builder::new()),
+                bool_array: ListBuilder::new(BooleanBuilder::new()),
+                string_array: ListBuilder::new(StringBuilder::new()),
+            }
+        }
+
+        fn append_scalar(&mut self, v: &EventValue) {
+            match v {
+                EventValue::Int(x) => self.int.append_value(*x),
+                EventValue::UInt(x) => self.uint.append_value(*x),
+                EventValue::Float(x) => self.float.append_value(*x),
+                EventValue::Bool(x) => self.bool.append_value(*x),
+                EventValue::String(x) => self.string.append_value(x),
+                EventValue::Array(_) => {}
+            }
+        }
+
+        fn append_element(&mut self, kind: ValueKind, v: &EventValue) {
+            match (kind, v) {
+                (ValueKind::Int, EventValue::Int(x)) => self.int_array.values().append_value(*x),
+                (ValueKind::UInt, EventValue::UInt(x)) => self.uint_array.values().append_value(*x),
+                (ValueKind::Float, EventValue::Float(x)) => self.float_array.values().append_value(*x),
+                (ValueKind::Bool, EventValue::Bool(x)) => self.bool_array.values().append_value(*x),
+                (ValueKind::String, EventValue::String(x)) => self.string_array.values().append_value(x),
+                (ValueKind::Int, _) => self.int_array.values().append_null(),
+                (ValueKind::UInt, _) => self.uint_array.values().append_null(),
+                (ValueKind::Float, _) => self.float_array.values().append_null(),
+                (ValueKind::Bool, _) => self.bool_array.values().append_null(),
+                (ValueKind::String, _) => self.string_array.values().append_null(),
+            }
+        }
+
+        // Closes the current row: the column of `filled` is already written, all others get a null.
+        fn finish_row(&mut self, filled: Option<ValueKind>, array: bool) {
+            for kind in ValueKind::ALL {
+                let scalar_set = !array && filled == Some(kind);
+                let array_set = array && filled == Some(kind);
+                if !scalar_set {
+                    match kind {
+                        ValueKind::Int => self.int.append_null(),
+                        ValueKind::UInt => self.uint.append_null(),
+                        ValueKind::Float => self.float.append_null(),
+                        ValueKind::Bool => self.bool.append_null(),
+                        ValueKind::String => self.string.append_null(),
+                    }
+                }
+                match kind {
+                    ValueKind::Int => self.int_array.append(array_set),
+                    ValueKind::UInt => self.uint_array.append(array_set),
+                    ValueKind::Float => self.float_array.append(array_set),
+                    ValueKind::Bool => self.bool_array.append(array_set),
+                    ValueKind::String => self.string_array.append(array_set),
+                }
+            }
+        }
+
+        fn append(&mut self, v: &EventValue, series_kind: ValueKind) {
+            match v {
+                EventValue::Array(x) => {
+                    let kind = x.first().and_then(ValueKind::of_value).unwrap_or(series_kind);
+                    for e in x {
+                        self.append_element(kind, e);
+                    }
+                    self.finish_row(Some(kind), true);
+                }
+                v => {
+                    self.append_scalar(v);
+                    self.finish_row(ValueKind::of_value(v), false);
+                }
+            }
+        }
+
+        fn finish(mut self) -> Vec<ArrayRef> {
+            vec![
+                Arc::new(self.int.finish()),
+                Arc::new(self.uint.finish()),
+                Arc::new(self.float.finish()),
+                Arc::new(self.bool.finish()),
+                Arc::new(self.string.finish()),
+                Arc::new(self.int_array.finish()),
+                Arc::new(self.uint_array.finish()),
+                Arc::new(self.float_array.finish()),
+                Arc::new(self.bool_array.finish()),
+                Arc::new(self.string_array.finish()),
+            ]
+        }
+    }
+
+    pub struct ParquetOut {
+        schema: SchemaRef,
+        writer: ArrowWriter<File>,
+    }
+
+    impl ParquetOut {
+        pub fn new(file: File) -> Result<Self, Error> {
+            let mut fields = vec![
+                Field::new("channel", DataType::Utf8, false),
+                Field::new("series", DataType::UInt64, false),
+                Field::new(
+                    "ts",
+                    DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+                    false,
+                ),
+                Field::new("pulse", DataType::UInt64, false),
+                Field::new("status", DataType::Int16, true),
+                Field::new("severity", DataType::Int16, true),
+            ];
+            for kind in ValueKind::ALL {
+                fields.push(Field::new(format!("value_{}", kind.name()), kind.data_type(), true));
+            }
+            for kind in ValueKind::ALL {
+                let item = Field::new("item", kind.data_type(), true);
+                fields.push(Field::new(
+                    format!("array_{}", kind.name()),
+                    DataType::List(Arc::new(item)),
+                    true,
+                ));
+            }
+            let schema = Arc::new(Schema::new(fields));
+            let writer = ArrowWriter::try_new(file, schema.clone(), None).map_err(|e| Error::from(e.to_string()))?;
+            Ok(Self { schema, writer })
+        }
+    }
+
+    impl EventsOut for ParquetOut {
+        fn write(&mut self, sr: &SeriesRow, rows: &[EventRow]) -> Result<(), Error> {
+            if rows.len() == 0 {
+                return Ok(());
+            }
+            let series_kind = ValueKind::of_series(sr)?;
+            let mut channel = StringBuilder::new();
+            let mut series = UInt64Builder::new();
+            let mut ts = TimestampNanosecondBuilder::new().with_timezone("UTC");
+            let mut pulse = UInt64Builder::new();
+            let mut status = Int16Builder::new();
+            let mut severity = Int16Builder::new();
+            let mut values = ValueCols::new();
+            for row in rows {
+                channel.append_value(&sr.channel);
+                series.append_value(sr.series);
+                ts.append_value(row.ts as i64);
+                pulse.append_value(row.pulse);
+                status.append_option(row.status);
+                severity.append_option(row.severity);
+                values.append(&row.value, series_kind);
+            }
+            let mut cols: Vec<ArrayRef> = vec![
+                Arc::new(channel.finish()),
+                Arc::new(series.finish()),
+                Arc::new(ts.finish()),
+                Arc::new(pulse.finish()),
+                Arc::new(status.finish()),
+                Arc::new(severity.finish()),
+            ];
+            cols.extend(values.finish());
+            let batch = RecordBatch::try_new(self.schema.clone(), cols).map_err(|e| Error::from(e.to_string()))?;
+            self.writer.write(&batch).map_err(|e| Error::from(e.to_string()))?;
+            Ok(())
+        }
+
+        fn finish(self: Box<Self>) -> Result<(), Error> {
+            self.writer.close().map_err(|e| Error::from(e.to_string()))?;
+            Ok(())
+        }
+    }
+}
+
+fn events_out(k: &Export) -> Result<Box<dyn EventsOut>, Error> {
+    let out = || -> Result<Box<dyn Write>, Error> {
+        let ret: Box<dyn Write> = match &k.out {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(std::io::stdout())),
+        };
+        Ok(ret)
+    };
+    let ret: Box<dyn EventsOut> = match k.format {
+        ExportFormat::Csv => Box::new(CsvOut::new(out()?)?),
+        ExportFormat::Ndjson => Box::new(NdjsonOut { out: out()? }),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            let path = k
+                .out
+                .as_ref()
+                .ok_or_else(|| Error::with_msg_no_trace("parquet export requires --out"))?;
+            Box::new(parquetout::ParquetOut::new(File::create(path)?)?)
+        }
+    };
+    Ok(ret)
+}
+
+// Events of all matching channels, one series after the other, each in time order.
+pub async fn export(k: Export) -> Result<(), Error> {
+    let conf = DbConf::from_args(&k.db).await?;
+    let backend = conf.backend(k.backend.as_ref())?;
+    let beg = ts_nanos(&k.beg)?;
+    let end = ts_nanos(&k.end.unwrap_or_else(Utc::now))?;
+    let pg = dbpg::conn::make_pg_client(conf.pg()?)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let mut series = Vec::new();
+    for ch in &k.channel {
+        let found = if k.regex {
+            dbpg::findseries::find_series_regex(&backend, ch, &pg).await
+        } else {
+            dbpg::findseries::find_series(&backend, ch, &pg).await
+        }
+        .map_err(|e| Error::from(e.to_string()))?;
+        if found.len() == 0 {
+            warn!("no series found for {ch:?}");
+        }
+        for sr in found {
+            if series.iter().all(|x: &SeriesRow| x.series != sr.series) {
+                series.push(sr);
+            }
+        }
+    }
+    info!("export {} series", series.len());
+    let scy = scywr::session::create_session(conf.scylla()?)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let mut out = events_out(&k)?;
+    for sr in &series {
+        let table = series_table(sr)?;
+        let count = read_series_events(&scy, &table, sr.series, beg, end, |rows| out.write(sr, rows)).await?;
+        info!("{}  series {}  {table}  {count} events", sr.channel, sr.series);
+    }
+    out.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_rows() -> (SeriesRow, Vec<EventRow>) {
+    use scywr::readevents::EventValue;
+    let sr = SeriesRow {
+        series: 42,
+        channel: "TEST:CH,1".into(),
+        scalar_type: 0,
+        shape_dims: Vec::new(),
+    };
+    let row = |ts, value| EventRow {
+        ts,
+        pulse: ts / 10,
+        value,
+        status: Some(0),
+        severity: None,
+    };
+    let rows = vec![
+        row(1_000_000_001, EventValue::UInt(u64::MAX)),
+        row(1_000_000_002, EventValue::Int(i64::MIN + 1)),
+        row(1_000_000_003, EventValue::String("a,\"b\"".into())),
+        row(
+            1_000_000_004,
+            EventValue::Array(vec![EventValue::Float(0.5), EventValue::Float(-2.)]),
+        ),
+    ];
+    (sr, rows)
+}
+
+#[test]
+fn csv_out_exact() {
+    let (sr, rows) = test_rows();
+    let mut out = CsvOut::new(Vec::new()).unwrap();
+    out.write(&sr, &rows).unwrap();
+    let s = String::from_utf8(out.out).unwrap();
+    let exp = [
+        "channel,series,ts,pulse,status,severity,value",
+        "\"TEST:CH,1\",42,1970-01-01T00:00:01.000000001Z,100000000,0,,18446744073709551615",
+        "\"TEST:CH,1\",42,1970-01-01T00:00:01.000000002Z,100000000,0,,-9223372036854775807",
+        "\"TEST:CH,1\",42,1970-01-01T00:00:01.000000003Z,100000000,0,,\"a,\"\"b\"\"\"",
+        "\"TEST:CH,1\",42,1970-01-01T00:00:01.000000004Z,100000000,0,,[0.5 -2]",
+    ];
+    assert_eq!(s.lines().collect::<Vec<_>>(), exp);
+}
+
+#[test]
+fn ndjson_out_exact() {
+    let (sr, rows) = test_rows();
+    let mut out = NdjsonOut { out: Vec::new() };
+    out.write(&sr, &rows).unwrap();
+    let s = String::from_utf8(out.out).unwrap();
+    let vals: Vec<serde_json::Value> = s.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+    let exp = |ts: u64, value: serde_json::Value| {
+        serde_json::json!({
+            "channel": "TEST:CH,1",
+            "series": 42,
+            "ts": ts,
+            "pulse": 100000000,
+            "status": 0,
+            "severity": null,
+            "value": value,
+        })
+    };
+    assert_eq!(
+        vals,
+        vec![
+            exp(1_000_000_001, serde_json::json!(u64::MAX)),
+            exp(1_000_000_002, serde_json::json!(i64::MIN + 1)),
+            exp(1_000_000_003, serde_json::json!("a,\"b\"")),
+            exp(1_000_000_004, serde_json::json!([0.5, -2.0])),
+        ]
+    );
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_out_read_back() {
+    use arrow::array::Array;
+    use arrow::array::AsArray;
+    use arrow::datatypes::Float64Type;
+    use arrow::datatypes::Int64Type;
+    use arrow::datatypes::TimestampNanosecondType;
+    use arrow::datatypes::UInt64Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    let path = std::env::temp_dir().join(format!("daqingest-export-{}.parquet", std::process::id()));
+    let (sr, rows) = test_rows();
+    let mut out: Box<dyn EventsOut> = Box::new(parquetout::ParquetOut::new(File::create(&path).unwrap()).unwrap());
+    out.write(&sr, &rows).unwrap();
+    out.finish().unwrap();
+    let rd = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+        .unwrap()
+        .build()
+        .unwrap();
+    let batches: Vec<_> = rd.collect::<Result<_, _>>().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(batches.len(), 1);
+    let b = &batches[0];
+    assert_eq!(b.num_rows(), 4);
+    let col = |name: &str| b.column_by_name(name).unwrap().clone();
+    let channel = col("channel");
+    assert!(channel.as_string::<i32>().iter().all(|x| x == Some("TEST:CH,1")));
+    let ts = col("ts");
+    let ts = ts.as_primitive::<TimestampNanosecondType>();
+    assert_eq!(
+        &ts.values()[..],
+        &[1_000_000_001, 1_000_000_002, 1_000_000_003, 1_000_000_004]
+    );
+    let pulse = col("pulse");
+    assert_eq!(pulse.as_primitive::<UInt64Type>().value(0), 100_000_000);
+    let severity = col("severity");
+    assert_eq!(severity.null_count(), 4);
+    let v = col("value_u64");
+    let v = v.as_primitive::<UInt64Type>();
+    assert_eq!(v.value(0), u64::MAX);
+    assert_eq!(v.null_count(), 3);
+    let v = col("value_i64");
+    let v = v.as_primitive::<Int64Type>();
+    assert_eq!(v.value(1), i64::MIN + 1);
+    assert_eq!(v.null_count(), 3);
+    let v = col("value_string");
+    let v = v.as_string::<i32>();
+    assert_eq!(v.value(2), "a,\"b\"");
+    assert_eq!(v.null_count(), 3);
+    assert_eq!(col("value_f64").null_count(), 4);
+    let a = col("array_f64");
+    let a = a.as_list::<i32>();
+    assert_eq!(a.null_count(), 3);
+    assert_eq!(&a.value(3).as_primitive::<Float64Type>().values()[..], &[0.5, -2.]);
+    assert_eq!(col("array_i64").null_count(), 4);
+}
@@ -1,3 +1,6 @@
+pub mod archapp;
 pub mod daemon;
+pub mod export;
 pub mod opts;
 pub mod query;
+pub mod tools;
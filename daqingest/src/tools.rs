@@ -0,0 +1,290 @@
+use crate::opts::DbArgs;
+use crate::opts::EventsFormat;
+use crate::opts::FetchEvents;
+use crate::opts::PulseLookup;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::TimeZone;
+use chrono::Utc;
+use dbpg::findseries::SeriesRow;
+use err::Error;
+use log::*;
+use netfetch::conf::parse_config_opts;
+use netpod::Database;
+use scywr::config::ScyllaConfig;
+use scywr::readevents::EventRow;
+use scywr::session::ScySession;
+use std::borrow::Cow;
+use std::io::Write;
+
+pub struct DbConf {
+    scylla: Option<ScyllaConfig>,
+    pg: Option<Database>,
+    backend: Option<String>,
+}
+
+impl DbConf {
+    pub async fn from_args(args: &DbArgs) -> Result<Self, Error> {
+        let mut ret = Self {
+            scylla: None,
+            pg: None,
+            backend: None,
+        };
+        if let Some(config) = &args.config {
+            let conf = parse_config_opts(config.into()).await?;
+            ret.scylla = conf.scylla_config().cloned();
+            ret.pg = Some(conf.postgresql_config().clone());
+            ret.backend = Some(conf.backend().into());
+        }
+        if args.scylla.len() != 0 {
+            let keyspace = args
+                .keyspace
+                .clone()
+                .ok_or_else(|| Error::with_msg_no_trace("--scylla requires --keyspace"))?;
+            ret.scylla = Some(ScyllaConfig {
+                hosts: args.scylla.clone(),
+                keyspace,
+                replication: Default::default(),
+                consistency_read: None,
+                consistency_write: None,
+                username: None,
+                password: None,
+                tls: None,
+            });
+        }
+        if let Some(url) = &args.pg {
+            ret.pg = Some(parse_pg_url(url)?);
+        }
+        Ok(ret)
+    }
+
+    pub fn scylla(&self) -> Result<&ScyllaConfig, Error> {
+        self.scylla
+            .as_ref()
+            .ok_or_else(|| Error::with_msg_no_trace("no scylla given, use --config or --scylla"))
+    }
+
+    pub fn pg(&self) -> Result<&Database, Error> {
+        self.pg
+            .as_ref()
+            .ok_or_else(|| Error::with_msg_no_trace("no postgres given, use --config or --pg"))
+    }
+
+    pub fn backend(&self, backend: Option<&String>) -> Result<String, Error> {
+        backend
+            .or(self.backend.as_ref())
+            .cloned()
+            .ok_or_else(|| Error::with_msg_no_trace("no backend given, use --config or --backend"))
+    }
+}
+
+fn parse_pg_url(url: &str) -> Result<Database, Error> {
+    let e = || Error::with_msg_no_trace(format!("can not parse postgres url {url:?}"));
+    let s = url
+        .strip_prefix("postgresql://")
+        .or_else(|| url.strip_prefix("postgres://"))
+        .ok_or_else(e)?;
+    let (cred, s) = s.rsplit_once('@').ok_or_else(e)?;
+    let (user, pass) = cred.split_once(':').unwrap_or((cred, ""));
+    let (hostport, name) = s.split_once('/').ok_or_else(e)?;
+    let (host, port) = match hostport.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| e())?),
+        None => (hostport, 5432),
+    };
+    let ret = Database {
+        name: name.into(),
+        host: host.into(),
+        port,
+        user: user.into(),
+        pass: pass.into(),
+    };
+    Ok(ret)
+}
+
+// Nanoseconds since the epoch, times before 1970 are rejected.
+pub fn ts_nanos(ts: &DateTime<Utc>) -> Result<u64, Error> {
+    u64::try_from(ts.timestamp())
+        .ok()
+        .and_then(|x| x.checked_mul(1_000_000_000))
+        .and_then(|x| x.checked_add(ts.timestamp_subsec_nanos() as u64))
+        .ok_or_else(|| Error::with_msg_no_trace(format!("time {ts} out of range")))
+}
+
+pub fn ts_string(ts: u64) -> String {
+    Utc.timestamp_nanos(ts as i64)
+        .to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+pub fn csv_field(s: &str) -> Cow<str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+fn print_events<W>(out: &mut W, format: &EventsFormat, series: u64, rows: &[EventRow]) -> Result<(), Error>
+where
+    W: Write,
+{
+    let opt = |x: Option<i16>| x.map_or(String::new(), |x| x.to_string());
+    for row in rows {
+        match format {
+            EventsFormat::Table => writeln!(
+                out,
+                "{:30}  {:12}  {:>4}  {:>4}  {}",
+                ts_string(row.ts),
+                row.pulse,
+                opt(row.status),
+                opt(row.severity),
+                row.value
+            )?,
+            EventsFormat::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                series,
+                ts_string(row.ts),
+                row.pulse,
+                opt(row.status),
+                opt(row.severity),
+                csv_field(&row.value.to_string())
+            )?,
+            EventsFormat::Json => {
+                let v = serde_json::json!({
+                    "series": series,
+                    "ts": row.ts,
+                    "pulse": row.pulse,
+                    "status": row.status,
+                    "severity": row.severity,
+                    "value": row.value,
+                });
+                writeln!(out, "{v}")?
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn series_table(sr: &SeriesRow) -> Result<String, Error> {
+    scywr::readevents::events_table(sr.scalar_type, &sr.shape_dims).map_err(|e| Error::from(e.to_string()))
+}
+
+// Reads the events of a series in [beg, end) partition by partition, in time order.
+// Returns the number of events.
+pub async fn read_series_events<F>(
+    scy: &ScySession,
+    table: &str,
+    series: u64,
+    beg: u64,
+    end: u64,
+    mut f: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(&[EventRow]) -> Result<(), Error>,
+{
+    let msps = scywr::readevents::ts_msp_range(scy, series, beg, end)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let mut count = 0;
+    for msp in msps {
+        let rows = scywr::readevents::read_partition(scy, table, series, msp, beg, end)
+            .await
+            .map_err(|e| Error::from(e.to_string()))?;
+        count += rows.len();
+        f(&rows)?;
+    }
+    Ok(count)
+}
+
+pub async fn fetch_events(k: FetchEvents) -> Result<(), Error> {
+    let conf = DbConf::from_args(&k.db).await?;
+    let backend = conf.backend(k.backend.as_ref())?;
+    let beg = ts_nanos(&k.beg)?;
+    let end = ts_nanos(&k.end.unwrap_or_else(Utc::now))?;
+    let pg = dbpg::conn::make_pg_client(conf.pg()?)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let series = dbpg::findseries::find_series(&backend, &k.channel, &pg)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    if series.len() == 0 {
+        return Err(Error::with_msg_no_trace(format!(
+            "no series found for {backend} {}",
+            k.channel
+        )));
+    }
+    let scy = scywr::session::create_session(conf.scylla()?)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let mut out = std::io::BufWriter::new(std::io::stdout());
+    if let EventsFormat::Csv = k.format {
+        writeln!(out, "series,ts,pulse,status,severity,value")?;
+    }
+    for sr in series {
+        let table = series_table(&sr)?;
+        info!("series {}  {table}  shape {:?}", sr.series, sr.shape_dims);
+        if let EventsFormat::Table = k.format {
+            writeln!(out, "# series {}  {table}", sr.series)?;
+        }
+        read_series_events(&scy, &table, sr.series, beg, end, |rows| {
+            print_events(&mut out, &k.format, sr.series, rows)
+        })
+        .await?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub async fn pulse_lookup(k: PulseLookup) -> Result<(), Error> {
+    let conf = DbConf::from_args(&k.db).await?;
+    let scy = scywr::session::create_session(conf.scylla()?)
+        .await
+        .map_err(|e| Error::from(e.to_string()))?;
+    let found = match (k.pulse, &k.time) {
+        (Some(pulse), _) => scywr::pulsemap::ts_by_pulse(&scy, pulse)
+            .await
+            .map_err(|e| Error::from(e.to_string()))?
+            .map(|ts| (pulse, ts)),
+        (None, Some(time)) => scywr::pulsemap::pulse_by_ts(&scy, ts_nanos(time)?)
+            .await
+            .map_err(|e| Error::from(e.to_string()))?,
+        (None, None) => return Err(Error::with_msg_no_trace("give --pulse or --time")),
+    };
+    match found {
+        Some((pulse, ts)) => println!("pulse {pulse}  ts {ts}  {}", ts_string(ts)),
+        None => return Err(Error::with_msg_no_trace("no pulse found")),
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_pg_url_parts() {
+    let db = parse_pg_url("postgresql://daqingest:pw@sf-db:5433/daq").unwrap();
+    assert_eq!(
+        (
+            db.user.as_str(),
+            db.pass.as_str(),
+            db.host.as_str(),
+            db.port,
+            db.name.as_str()
+        ),
+        ("daqingest", "pw", "sf-db", 5433, "daq")
+    );
+    assert_eq!(parse_pg_url("postgres://u@h/d").unwrap().port, 5432);
+    assert!(parse_pg_url("sf-db:5432").is_err());
+}
+
+#[test]
+fn csv_field_quoting() {
+    assert_eq!(csv_field("a"), "a");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+}
+
+#[test]
+fn ts_nanos_range() {
+    let ts = Utc.timestamp_opt(1, 5).unwrap();
+    assert_eq!(ts_nanos(&ts).unwrap(), 1_000_000_005);
+    let ts = Utc.timestamp_opt(-1, 0).unwrap();
+    assert!(ts_nanos(&ts).is_err());
+}
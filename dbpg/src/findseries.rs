@@ -0,0 +1,49 @@
+use crate::conn::PgClient;
+use err::thiserror;
+use err::ThisError;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    Postgres(#[from] tokio_postgres::Error),
+}
+
+#[derive(Clone, Debug)]
+pub struct SeriesRow {
+    pub series: u64,
+    pub channel: String,
+    pub scalar_type: i32,
+    pub shape_dims: Vec<i32>,
+}
+
+fn series_rows(rows: Vec<tokio_postgres::Row>) -> Vec<SeriesRow> {
+    rows.into_iter()
+        .map(|row| SeriesRow {
+            series: row.get::<_, i64>(0) as u64,
+            channel: row.get(1),
+            scalar_type: row.get(2),
+            shape_dims: row.get(3),
+        })
+        .collect()
+}
+
+// All series of a channel, a channel which changed type over time has more than one.
+pub async fn find_series(backend: &str, channel: &str, pg: &PgClient) -> Result<Vec<SeriesRow>, Error> {
+    let sql = concat!(
+        "select series, channel, scalar_type, shape_dims from series_by_channel",
+        " where facility = $1 and channel = $2 and agg_kind = 0",
+        " order by tscreate"
+    );
+    let rows = pg.query(sql, &[&backend, &channel]).await?;
+    Ok(series_rows(rows))
+}
+
+// Series of all channels whose name matches the postgres regular expression.
+pub async fn find_series_regex(backend: &str, pattern: &str, pg: &PgClient) -> Result<Vec<SeriesRow>, Error> {
+    let sql = concat!(
+        "select series, channel, scalar_type, shape_dims from series_by_channel",
+        " where facility = $1 and channel ~ $2 and agg_kind = 0",
+        " order by channel, tscreate"
+    );
+    let rows = pg.query(sql, &[&backend, &pattern]).await?;
+    Ok(series_rows(rows))
+}
@@ -1,6 +1,7 @@
 pub mod conn;
 pub mod err;
 pub mod findaddr;
+pub mod findseries;
 pub mod iocindex;
 pub mod pool;
 pub mod schema;
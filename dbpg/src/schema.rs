@@ -2,6 +2,7 @@ use crate::conn::PgClient;
 use err::thiserror;
 use err::ThisError;
 use log::*;
+use tokio_postgres::GenericClient;
 
 #[derive(Debug, ThisError)]
 pub enum Error {
@@ -18,60 +19,184 @@ impl Error {
     }
 }
 
-async fn has_column(table: &str, column: &str, pgc: &PgClient) -> Result<bool, Error> {
+struct Migration {
+    version: i32,
+    descr: &'static str,
+    sql: &'static [&'static str],
+}
+
+// Applied in order, each in its own transaction. Databases from before the version table
+// may contain any of these already, therefore every statement must be idempotent.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        descr: "create series_by_channel",
+        sql: &[concat!(
+            "create table if not exists series_by_channel (",
+            "series bigint not null primary key",
+            ", facility text not null",
+            ", channel text not null",
+            ", scalar_type int not null",
+            ", shape_dims int[] not null",
+            ", agg_kind int not null",
+            ", tscreate timestamptz not null default now()",
+            ")"
+        )],
+    },
+    Migration {
+        version: 2,
+        descr: "create ioc_by_channel_log",
+        sql: &[
+            concat!(
+                "create table if not exists ioc_by_channel_log (",
+                "facility text not null",
+                ", channel text not null",
+                ", tscreate timestamptz not null default now()",
+                ", tsmod timestamptz not null default now()",
+                ", archived int not null default 0",
+                ", responseaddr text",
+                ", addr text",
+                ")"
+            ),
+            "create index if not exists ioc_by_channel_log_channel on ioc_by_channel_log (facility, channel, tsmod)",
+        ],
+    },
+    Migration {
+        version: 3,
+        descr: "ioc_by_channel_log tscreate and archived",
+        sql: &[
+            "alter table ioc_by_channel_log add column if not exists tscreate timestamptz not null default now()",
+            "alter table ioc_by_channel_log add column if not exists archived int not null default 0",
+        ],
+    },
+    Migration {
+        version: 4,
+        descr: "series_by_channel unique channel and type",
+        sql: &[concat!(
+            "do $$ begin",
+            " if not exists (select 1 from pg_constraint where conname = 'series_by_channel_nondup') then",
+            " alter table series_by_channel add constraint series_by_channel_nondup",
+            " unique (facility, channel, scalar_type, shape_dims, agg_kind);",
+            " end if;",
+            " end $$"
+        )],
+    },
+];
+
+pub fn schema_version_latest() -> i32 {
+    MIGRATIONS.last().map_or(0, |x| x.version)
+}
+
+async fn create_version_table(pgc: &PgClient) -> Result<(), Error> {
+    let sql = concat!(
+        "create table if not exists schema_version (",
+        "version int not null primary key",
+        ", descr text not null",
+        ", tsapplied timestamptz not null default now()",
+        ")"
+    );
+    pgc.batch_execute(sql).await?;
+    Ok(())
+}
+
+async fn version_of<C>(pgc: &C) -> Result<i32, Error>
+where
+    C: GenericClient,
+{
+    let rows = pgc.query("select max(version) from schema_version", &[]).await?;
+    let v: Option<i32> = rows.get(0).and_then(|x| x.get(0));
+    Ok(v.unwrap_or(0))
+}
+
+pub async fn schema_version(pgc: &PgClient) -> Result<i32, Error> {
+    create_version_table(pgc).await?;
+    version_of(pgc).await
+}
+
+// Like `schema_version` but does not create the version table, a missing table is version 0.
+async fn schema_version_read_only(pgc: &PgClient) -> Result<i32, Error> {
     let rows = pgc
-        .query(
-            "select count(*) as c from information_schema.columns where table_name = $1 and column_name = $2 limit 10",
-            &[&table, &column],
-        )
+        .query("select to_regclass('schema_version') is not null", &[])
         .await?;
-    if rows.len() == 1 {
-        let c: i64 = rows[0].get(0);
-        if c == 0 {
-            Ok(false)
-        } else if c == 1 {
-            Ok(true)
-        } else {
-            Err(Error::from_logic_msg(format!("has_columns bad count {}", c)))
-        }
-    } else if rows.len() == 0 {
-        Ok(false)
+    let exists: bool = rows.get(0).map_or(false, |x| x.get(0));
+    if exists {
+        version_of(pgc).await
     } else {
-        Err(Error::from_logic_msg(format!(
-            "has_columns bad row count {}",
-            rows.len()
-        )))
+        Ok(0)
     }
 }
 
-async fn migrate_00(pgc: &PgClient) -> Result<(), Error> {
-    if !has_column("ioc_by_channel_log", "tscreate", pgc).await? {
-        pgc.execute(
-            "alter table ioc_by_channel_log add tscreate timestamptz not null default now()",
-            &[],
-        )
-        .await?;
+// Returns false if some other process has applied the migration meanwhile.
+async fn apply(m: &Migration, pgc: &mut PgClient) -> Result<bool, Error> {
+    let tx = pgc.transaction().await?;
+    tx.batch_execute("lock table schema_version in exclusive mode").await?;
+    if version_of(&tx).await? >= m.version {
+        return Ok(false);
     }
-    if !has_column("ioc_by_channel_log", "archived", pgc).await? {
-        pgc.execute(
-            "alter table ioc_by_channel_log add archived int not null default 0",
-            &[],
-        )
-        .await?;
+    for sql in m.sql {
+        tx.batch_execute(sql).await?;
     }
-    {
-        match pgc.execute("alter table series_by_channel add constraint series_by_channel_nondup unique (facility, channel, scalar_type, shape_dims, agg_kind)", &[]).await {
-            Ok(_) => {
-                info!("constraint added");
-            }
-            Err(_)=>{}
+    tx.execute(
+        "insert into schema_version (version, descr) values ($1, $2)",
+        &[&m.version, &m.descr],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Brings the schema to the latest version, creates all tables on an empty database.
+pub async fn schema_migrate(pgc: &mut PgClient) -> Result<(), Error> {
+    let have = schema_version(pgc).await?;
+    if have > schema_version_latest() {
+        return Err(Error::from_logic_msg(format!(
+            "schema version {have} is newer than the latest known {}",
+            schema_version_latest()
+        )));
+    }
+    for m in MIGRATIONS.iter().filter(|x| x.version > have) {
+        if apply(m, pgc).await? {
+            info!("schema migration {}  {}", m.version, m.descr);
         }
     }
     Ok(())
 }
 
-pub async fn schema_check(pgc: &PgClient) -> Result<(), Error> {
-    migrate_00(&pgc).await?;
+pub async fn schema_check(pgc: &mut PgClient) -> Result<(), Error> {
+    schema_migrate(pgc).await?;
     info!("schema_check done");
     Ok(())
 }
+
+// The migrations which are not yet applied, as version and description. Does not modify the database.
+pub async fn schema_pending(pgc: &PgClient) -> Result<Vec<(i32, String)>, Error> {
+    let have = schema_version_read_only(pgc).await?;
+    let ret = MIGRATIONS
+        .iter()
+        .filter(|x| x.version > have)
+        .map(|x| (x.version, x.descr.to_string()))
+        .collect();
+    Ok(ret)
+}
+
+// All migrations as sql.
+pub fn schema_dump() -> String {
+    let mut ret = String::new();
+    for m in MIGRATIONS {
+        ret.push_str(&format!("-- {}  {}\n", m.version, m.descr));
+        for sql in m.sql {
+            ret.push_str(sql);
+            ret.push_str(";\n");
+        }
+    }
+    ret
+}
+
+#[test]
+fn migrations_ordered() {
+    for (i, m) in MIGRATIONS.iter().enumerate() {
+        assert_eq!(m.version, i as i32 + 1);
+        assert!(m.sql.len() > 0);
+    }
+    assert_eq!(schema_version_latest(), MIGRATIONS.len() as i32);
+}
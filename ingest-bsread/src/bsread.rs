@@ -6,6 +6,9 @@ use netpod::AggKind;
 use netpod::ByteOrder;
 use netpod::ScalarType;
 use netpod::Shape;
+use scywr::iteminsertqueue::ArrayValue;
+use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::ScalarValue;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Value as JsVal;
@@ -52,6 +55,71 @@ pub enum CompressionKind {
     BitshuffleLz4,
 }
 
+// Larger payloads are rejected instead of growing the decompression buffer further.
+const DECOMPRESS_MAX: usize = 1024 * 1024 * 256;
+
+impl CompressionKind {
+    pub fn from_bsread_str(s: Option<&str>) -> Result<Option<Self>, Error> {
+        match s {
+            None | Some("none") => Ok(None),
+            Some("lz4") => Ok(Some(CompressionKind::Lz4)),
+            Some("bitshuffle_lz4") => Ok(Some(CompressionKind::BitshuffleLz4)),
+            Some(k) => Err(Error::with_msg_no_trace(format!(
+                "can not understand bsread compression kind: {k:?}"
+            ))),
+        }
+    }
+
+    // Decompresses into `out` which grows as needed, returns the decompressed byte count.
+    // lz4 payloads start with the size as u32 BE, bitshuffle_lz4 payloads with the size as u64 BE
+    // followed by the block size in bytes as u32 BE.
+    pub fn decompress(&self, inp: &[u8], elem_size: usize, out: &mut Vec<u8>) -> Result<usize, Error> {
+        let (nd, bs, body) = match self {
+            CompressionKind::Lz4 => {
+                if inp.len() < 4 {
+                    return Err(Error::with_public_msg("lz4 payload too short"));
+                }
+                let nd = u32::from_be_bytes(inp[0..4].try_into()?) as usize;
+                (nd, 0, &inp[4..])
+            }
+            CompressionKind::BitshuffleLz4 => {
+                if inp.len() < 12 {
+                    return Err(Error::with_public_msg("bitshuffle_lz4 payload too short"));
+                }
+                let nd = u64::from_be_bytes(inp[0..8].try_into()?) as usize;
+                let bs = u32::from_be_bytes(inp[8..12].try_into()?) as usize;
+                (nd, bs, &inp[12..])
+            }
+        };
+        if nd > DECOMPRESS_MAX {
+            return Err(Error::with_public_msg(format!("decompressed size too large {nd}")));
+        }
+        if out.len() < nd {
+            out.resize(nd.next_power_of_two(), 0);
+        }
+        match self {
+            CompressionKind::Lz4 => match bitshuffle::lz4_decompress(body, &mut out[..nd]) {
+                Ok(_) => {}
+                Err(e) => return Err(Error::with_public_msg(format!("lz4 error {e:?}"))),
+            },
+            CompressionKind::BitshuffleLz4 => {
+                let elem_size = elem_size.max(1);
+                if nd % elem_size != 0 {
+                    return Err(Error::with_public_msg(format!(
+                        "bitshuffle_lz4 size {nd} not a multiple of {elem_size}"
+                    )));
+                }
+                match bitshuffle::bitshuffle_decompress(body, &mut out[..nd], nd / elem_size, elem_size, bs / elem_size)
+                {
+                    Ok(_) => {}
+                    Err(e) => return Err(Error::with_public_msg(format!("bitshuffle_lz4 error {e:?}"))),
+                }
+            }
+        }
+        Ok(nd)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChannelDescDecoded {
     pub name: String,
@@ -70,19 +138,7 @@ impl TryFrom<&ChannelDesc> for ChannelDescDecoded {
             name: cd.name.clone(),
             scalar_type: ScalarType::from_bsread_str(&cd.ty)?,
             shape: Shape::from_bsread_jsval(&cd.shape)?,
-            compression: match &cd.compression {
-                None => None,
-                Some(k) => match k.as_str() {
-                    "none" => None,
-                    "lz4" => Some(CompressionKind::Lz4),
-                    "bitshuffle_lz4" => Some(CompressionKind::BitshuffleLz4),
-                    _ => {
-                        return Err(Error::with_msg_no_trace(format!(
-                            "can not understand bsread compression kind: {k:?}"
-                        )))
-                    }
-                },
-            },
+            compression: CompressionKind::from_bsread_str(cd.compression.as_deref())?,
             byte_order: match cd.encoding.as_str() {
                 "little" => ByteOrder::Little,
                 "big" => ByteOrder::Big,
@@ -94,6 +150,115 @@ impl TryFrom<&ChannelDesc> for ChannelDescDecoded {
     }
 }
 
+macro_rules! decode_nums {
+    ($data:expr, $ty:ty, $be:expr, $n:expr) => {
+        $data
+            .chunks_exact(std::mem::size_of::<$ty>())
+            .take($n)
+            .map(|x| {
+                let a = x.try_into().unwrap();
+                if $be {
+                    <$ty>::from_be_bytes(a)
+                } else {
+                    <$ty>::from_le_bytes(a)
+                }
+            })
+            .collect()
+    };
+}
+
+impl ChannelDescDecoded {
+    // Decompresses the channel frame into `buf` if the channel is compressed and decodes it.
+    pub fn decode_frame(&self, data: &[u8], array_truncate: usize, buf: &mut Vec<u8>) -> Result<DataValue, Error> {
+        match &self.compression {
+            Some(k) => {
+                let elem_size = match self.scalar_type {
+                    ScalarType::STRING => 1,
+                    _ => self.scalar_type.bytes() as usize,
+                };
+                let n = k.decompress(data, elem_size, buf)?;
+                self.decode(&buf[..n], array_truncate)
+            }
+            None => self.decode(data, array_truncate),
+        }
+    }
+
+    // Decodes an uncompressed channel frame, arrays are cut to `array_truncate` elements.
+    pub fn decode(&self, data: &[u8], array_truncate: usize) -> Result<DataValue, Error> {
+        if let ScalarType::STRING = self.scalar_type {
+            return match self.shape {
+                Shape::Scalar => Ok(DataValue::Scalar(ScalarValue::String(
+                    String::from_utf8_lossy(data).into(),
+                ))),
+                _ => Err(Error::with_msg_no_trace(format!("string array in {}", self.name))),
+            };
+        }
+        let be = matches!(self.byte_order, ByteOrder::Big);
+        let size = self.scalar_type.bytes() as usize;
+        let count = match self.shape {
+            Shape::Scalar => 1,
+            Shape::Wave(n) => n as usize,
+            Shape::Image(w, h) => w as usize * h as usize,
+        };
+        // The series is made for the shape in the data header, other element counts are rejected.
+        if data.len() != count * size {
+            return Err(Error::with_msg_no_trace(format!(
+                "{} {} bytes for {:?} of {:?}",
+                self.name,
+                data.len(),
+                self.shape,
+                self.scalar_type
+            )));
+        }
+        let n = match self.shape {
+            Shape::Scalar => 1,
+            _ => count.min(array_truncate),
+        };
+        let vals = match self.scalar_type {
+            ScalarType::U8 => ArrayValue::U8(decode_nums!(data, u8, be, n)),
+            ScalarType::U16 => ArrayValue::U16(decode_nums!(data, u16, be, n)),
+            ScalarType::U32 => ArrayValue::U32(decode_nums!(data, u32, be, n)),
+            ScalarType::U64 => ArrayValue::U64(decode_nums!(data, u64, be, n)),
+            ScalarType::I8 => ArrayValue::I8(decode_nums!(data, i8, be, n)),
+            ScalarType::I16 => ArrayValue::I16(decode_nums!(data, i16, be, n)),
+            ScalarType::I32 => ArrayValue::I32(decode_nums!(data, i32, be, n)),
+            ScalarType::I64 => ArrayValue::I64(decode_nums!(data, i64, be, n)),
+            ScalarType::F32 => ArrayValue::F32(decode_nums!(data, f32, be, n)),
+            ScalarType::F64 => ArrayValue::F64(decode_nums!(data, f64, be, n)),
+            ScalarType::BOOL => ArrayValue::Bool(data.iter().take(n).map(|&x| x != 0).collect()),
+            _ => {
+                return Err(Error::with_msg_no_trace(format!(
+                    "unsupported type {:?} in {}",
+                    self.scalar_type, self.name
+                )))
+            }
+        };
+        let ret = match self.shape {
+            Shape::Scalar => {
+                let v = match vals {
+                    ArrayValue::U8(x) => ScalarValue::U8(x[0]),
+                    ArrayValue::U16(x) => ScalarValue::U16(x[0]),
+                    ArrayValue::U32(x) => ScalarValue::U32(x[0]),
+                    ArrayValue::U64(x) => ScalarValue::U64(x[0]),
+                    ArrayValue::I8(x) => ScalarValue::I8(x[0]),
+                    ArrayValue::I16(x) => ScalarValue::I16(x[0]),
+                    ArrayValue::I32(x) => ScalarValue::I32(x[0]),
+                    ArrayValue::I64(x) => ScalarValue::I64(x[0]),
+                    ArrayValue::F32(x) => ScalarValue::F32(x[0]),
+                    ArrayValue::F64(x) => ScalarValue::F64(x[0]),
+                    ArrayValue::Bool(x) => ScalarValue::Bool(x[0]),
+                    ArrayValue::String(_) => {
+                        return Err(Error::with_msg_no_trace(format!("string array in {}", self.name)))
+                    }
+                };
+                DataValue::Scalar(v)
+            }
+            _ => DataValue::Array(vals),
+        };
+        Ok(ret)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HeadA {
     pub htype: String,
@@ -147,63 +312,17 @@ impl Parser {
             let h = hasher.finalize();
             hex::encode(&h)
         };
-        let dhdecompr = match &head_a.dh_compression {
-            Some(m) => match m.as_str() {
-                "none" => msg.frames()[1].data(),
-                "lz4" => {
-                    let inp = msg.frames()[1].data();
-                    let nd = u32::from_be_bytes(inp[0..4].try_into()?) as usize;
-                    loop {
-                        let g = self.tmp1.len();
-                        if g >= nd {
-                            break;
-                        }
-                        if g > 1024 * 512 {
-                            return Err(Error::with_public_msg("decomp buffer too large"));
-                        }
-                        let u = self.tmp1.len() * 2;
-                        info!("resize buffer to {u}");
-                        self.tmp1.resize(u, 0);
-                    }
-                    match bitshuffle::lz4_decompress(&inp[4..], &mut self.tmp1) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            // TODO throttle log output
-                            error!("lz4 error {e:?}");
-                            return Err(Error::with_public_msg(format!("lz4 error {e:?}")));
-                        }
-                    }
-                    &self.tmp1[..nd]
-                }
-                "bitshuffle_lz4" => {
-                    let inp = msg.frames()[1].data();
-                    let nd = u64::from_be_bytes(inp[0..8].try_into()?) as usize;
-                    let bs = u32::from_be_bytes(inp[8..12].try_into()?) as usize;
-                    loop {
-                        let g = self.tmp1.len();
-                        if g >= nd {
-                            break;
-                        }
-                        if g > 1024 * 512 {
-                            return Err(Error::with_public_msg("decomp buffer too large"));
-                        }
-                        let u = self.tmp1.len() * 2;
-                        info!("resize buffer to {u}");
-                        self.tmp1.resize(u, 0);
-                    }
-                    match bitshuffle::bitshuffle_decompress(&inp[12..], &mut self.tmp1, nd, 1, bs) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            // TODO throttle log output
-                            error!("bitshuffle_lz4 error {e:?}");
-                            return Err(Error::with_public_msg(format!("bitshuffle_lz4 error {e:?}")));
-                        }
-                    }
-                    &self.tmp1[..nd]
+        let dh = msg.frames()[1].data();
+        let dhdecompr = match CompressionKind::from_bsread_str(head_a.dh_compression.as_deref())? {
+            Some(k) => match k.decompress(dh, 1, &mut self.tmp1) {
+                Ok(n) => &self.tmp1[..n],
+                Err(e) => {
+                    // TODO throttle log output
+                    error!("data header {e}");
+                    return Err(e);
                 }
-                _ => msg.frames()[1].data(),
             },
-            None => msg.frames()[1].data(),
+            None => dh,
         };
         let head_b: HeadB = serde_json::from_slice(dhdecompr).map_err(|e| format!("data header parse error: {e:?}"))?;
         if false && msg.frames().len() == head_b.channels.len() + 3 {
@@ -235,3 +354,160 @@ impl Parser {
         Ok(ret)
     }
 }
+
+#[test]
+fn decode_channel_frames() {
+    let cd = ChannelDescDecoded {
+        name: "CH".into(),
+        scalar_type: ScalarType::U16,
+        shape: Shape::Wave(3),
+        byte_order: ByteOrder::Big,
+        compression: None,
+        agg_kind: AggKind::Plain,
+    };
+    let v = cd.decode(&[0, 1, 0, 2, 1, 0], usize::MAX).unwrap();
+    assert!(matches!(v, DataValue::Array(ArrayValue::U16(ref x)) if x == &[1, 2, 256]));
+    let v = cd.decode(&[0, 1, 0, 2, 1, 0], 2).unwrap();
+    assert!(matches!(v, DataValue::Array(ArrayValue::U16(ref x)) if x.len() == 2));
+    assert!(cd.decode(&[0, 1, 0], usize::MAX).is_err());
+    // The element count must match the shape.
+    assert!(cd.decode(&[0, 1, 0, 2], usize::MAX).is_err());
+    assert!(cd.decode(&[0, 1, 0, 2, 0, 3, 0, 4], usize::MAX).is_err());
+    let cd = ChannelDescDecoded {
+        scalar_type: ScalarType::F64,
+        shape: Shape::Scalar,
+        byte_order: ByteOrder::Little,
+        ..cd
+    };
+    let v = cd.decode(&1.5f64.to_le_bytes(), usize::MAX).unwrap();
+    assert!(matches!(v, DataValue::Scalar(ScalarValue::F64(x)) if x == 1.5));
+}
+
+#[test]
+fn decode_scalar_not_truncated() {
+    let cd = ChannelDescDecoded {
+        name: "CH".into(),
+        scalar_type: ScalarType::I32,
+        shape: Shape::Scalar,
+        byte_order: ByteOrder::Big,
+        compression: None,
+        agg_kind: AggKind::Plain,
+    };
+    let v = cd.decode(&[0, 0, 1, 2], 0).unwrap();
+    assert!(matches!(v, DataValue::Scalar(ScalarValue::I32(258))));
+    let cd = ChannelDescDecoded {
+        shape: Shape::Wave(2),
+        ..cd
+    };
+    let v = cd.decode(&[0, 0, 1, 2, 0, 0, 0, 3], 0).unwrap();
+    assert!(matches!(v, DataValue::Array(ArrayValue::I32(ref x)) if x.is_empty()));
+}
+
+#[cfg(test)]
+fn lz4_literals(inp: &[u8]) -> Vec<u8> {
+    // A valid lz4 block which consists of a single literal run.
+    let mut ret = Vec::new();
+    if inp.len() < 15 {
+        ret.push((inp.len() as u8) << 4);
+    } else {
+        ret.push(0xf0);
+        let mut n = inp.len() - 15;
+        while n >= 255 {
+            ret.push(255);
+            n -= 255;
+        }
+        ret.push(n as u8);
+    }
+    ret.extend_from_slice(inp);
+    ret
+}
+
+#[test]
+fn parse_lz4_data_header() {
+    use crate::zmtp::zmtpproto::ZmtpFrame;
+    let frame = |data: Vec<u8>| ZmtpFrame {
+        msglen: data.len(),
+        has_more: true,
+        is_command: false,
+        data,
+    };
+    let dh = br#"{"htype":"bsr_d-1.1","channels":[{"name":"CH1","type":"uint16","shape":[4],"compression":"bitshuffle_lz4"}]}"#;
+    let mut dhc = (dh.len() as u32).to_be_bytes().to_vec();
+    dhc.extend(lz4_literals(dh));
+    let ha = br#"{"htype":"bsr_m-1.1","hash":"h","pulse_id":100,"global_timestamp":{"sec":1,"ns":2},"dh_compression":"lz4"}"#;
+    let msg = ZmtpMessage {
+        frames: vec![frame(ha.to_vec()), frame(dhc)],
+    };
+    let mut parser = Parser::new();
+    let bm = parser.parse_zmtp_message(&msg).unwrap();
+    assert_eq!(bm.head_b.channels.len(), 1);
+    let cd: ChannelDescDecoded = (&bm.head_b.channels[0]).try_into().unwrap();
+    assert!(matches!(cd.compression, Some(CompressionKind::BitshuffleLz4)));
+}
+
+// Channel frame of a uint16 waveform of 90 elements with bitshuffle_lz4 compression and a block size
+// of 32 elements: two full blocks, a last block of 24 elements and the 2 remaining elements stored
+// uncompressed. Encoded after the layout of the bitshuffle C library, not with the crate under test.
+#[cfg(test)]
+const BSHUF_LZ4_U16_FRAME: &str = concat!(
+    "00000000000000b4000000400000003af72150adccde10db7307268736aae000303370664f0ffaf02abb56af4cc66460",
+    "dafe78e039fe801ff8010000f8ffffff070001005000000000000000003bf6228e20fa6368666b7b44407c417bd6ba30",
+    "7baadf9f2c2342f51ac969a6ac0dd892300e388e3ff0077e3f0000fec0ffff0100010050000000000000000032f021a8",
+    "868cf0a31680774cd579682affbcee558bb1cc723f69033f8e03c00ffcff0f0000f0ff00000000000000000000000006",
+    "0acc09",
+);
+
+#[cfg(test)]
+fn bshuf_lz4_u16_values() -> Vec<u16> {
+    (0..90)
+        .map(|i| (2048. + (600. * (i as f64 / 6.).sin()).round()) as u16)
+        .collect()
+}
+
+#[test]
+fn bitshuffle_lz4_channel_frame() {
+    use crate::zmtp::zmtpproto::ZmtpFrame;
+    let frame = |data: Vec<u8>| ZmtpFrame {
+        msglen: data.len(),
+        has_more: true,
+        is_command: false,
+        data,
+    };
+    let ha = br#"{"htype":"bsr_m-1.1","hash":"h","pulse_id":18000000001,"global_timestamp":{"sec":1700000000,"ns":5},"dh_compression":null}"#;
+    let dh = br#"{"htype":"bsr_d-1.1","channels":[{"name":"TEST:WAVE","type":"uint16","shape":[90],"encoding":"little","compression":"bitshuffle_lz4"}]}"#;
+    let data = hex::decode(BSHUF_LZ4_U16_FRAME).unwrap();
+    let msg = ZmtpMessage {
+        frames: vec![
+            frame(ha.to_vec()),
+            frame(dh.to_vec()),
+            frame(data.clone()),
+            frame(vec![0; 16]),
+        ],
+    };
+    let mut parser = Parser::new();
+    let bm = parser.parse_zmtp_message(&msg).unwrap();
+    let cd: ChannelDescDecoded = (&bm.head_b.channels[0]).try_into().unwrap();
+    assert_eq!(cd.shape, Shape::Wave(90));
+    let mut buf = Vec::new();
+    let v = cd.decode_frame(&data, usize::MAX, &mut buf).unwrap();
+    assert!(matches!(v, DataValue::Array(ArrayValue::U16(ref x)) if x == &bshuf_lz4_u16_values()));
+}
+
+#[test]
+fn lz4_channel_frame() {
+    let vals = bshuf_lz4_u16_values();
+    let inp: Vec<u8> = vals.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let cd = ChannelDescDecoded {
+        name: "CH".into(),
+        scalar_type: ScalarType::U16,
+        shape: Shape::Wave(vals.len() as u32),
+        byte_order: ByteOrder::Little,
+        compression: Some(CompressionKind::Lz4),
+        agg_kind: AggKind::Plain,
+    };
+    let mut data = (inp.len() as u32).to_be_bytes().to_vec();
+    data.extend(lz4_literals(&inp));
+    let mut buf = Vec::new();
+    let v = cd.decode_frame(&data, usize::MAX, &mut buf).unwrap();
+    assert!(matches!(v, DataValue::Array(ArrayValue::U16(ref x)) if x == &vals));
+}
@@ -1,39 +1,54 @@
-use crate::bsread::BsreadMessage;
 use crate::bsread::ChannelDescDecoded;
 use crate::bsread::HeadB;
 use crate::bsread::Parser;
 use crate::zmtp::zmtpproto;
 use crate::zmtp::zmtpproto::SocketType;
 use crate::zmtp::zmtpproto::Zmtp;
-use crate::zmtp::zmtpproto::ZmtpFrame;
 use crate::zmtp::zmtpproto::ZmtpMessage;
 use crate::zmtp::ZmtpClientOpts;
 use crate::zmtp::ZmtpEvent;
 use async_channel::Sender;
+use dbpg::seriesbychannel::CanSendChannelInfoResult;
 use dbpg::seriesbychannel::ChannelInfoQuery;
+use dbpg::seriesbychannel::ChannelInfoResult;
 use err::thiserror;
 use err::ThisError;
 use futures_util::StreamExt;
 use netpod::log::*;
 use netpod::timeunits::HOUR;
 use netpod::timeunits::SEC;
-use netpod::ScalarType;
-use netpod::Shape;
 use netpod::TS_MSP_GRID_SPACING;
 use netpod::TS_MSP_GRID_UNIT;
-use scywr::iteminsertqueue::ArrayValue;
-use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::ChannelStatus;
+use scywr::iteminsertqueue::ChannelStatusClosedReason;
+use scywr::iteminsertqueue::ChannelStatusItem;
 use scywr::iteminsertqueue::InsertItem;
+use scywr::iteminsertqueue::PulseMapItem;
 use scywr::iteminsertqueue::QueryItem;
-use scywr::session::ScySession;
 use series::SeriesId;
 use stats::CheckEvery;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
 use taskrun::tokio;
 
+// Sources which alternate between a few data headers keep their writers.
+const HEAD_CACHE_MAX: usize = 8;
+
+// Waiting longer for the series of a data header would stall the source.
+const SERIES_LOOKUP_TIMEOUT: Duration = Duration::from_millis(10000);
+// Channels of the current data header without a series are looked up again after this time.
+const SERIES_LOOKUP_RETRY: Duration = Duration::from_millis(30000);
+
+// About a minute of pulses at 100 Hz.
+const PULSE_MAP_DEDUP_MAX: usize = 8192;
+
 #[derive(Debug, ThisError)]
 pub enum Error {
     #[error("InsertQueueSenderMissing")]
@@ -63,19 +78,181 @@ impl From<err::Error> for Error {
     }
 }
 
+struct SeriesLookupSender {
+    tx: Sender<Result<ChannelInfoResult, dbpg::seriesbychannel::Error>>,
+}
+
+impl CanSendChannelInfoResult for SeriesLookupSender {
+    fn make_send(
+        &self,
+        item: Result<ChannelInfoResult, dbpg::seriesbychannel::Error>,
+    ) -> dbpg::seriesbychannel::BoxedSend {
+        let tx = self.tx.clone();
+        let fut = async move { tx.send(item).await.map_err(|_| ()) };
+        Box::pin(fut)
+    }
+}
+
+struct MspState {
+    ts_msp: u64,
+    inserted_in_ts_msp: u64,
+    ts_msp_grid_last: u32,
+}
+
+impl MspState {
+    fn new() -> Self {
+        Self {
+            ts_msp: 0,
+            inserted_in_ts_msp: u64::MAX,
+            ts_msp_grid_last: 0,
+        }
+    }
+
+    // Returns ts_msp, whether it changed and the grid if that changed.
+    fn next(&mut self, ts: u64) -> (u64, bool, Option<u32>) {
+        let (ts_msp, msp_bump) = if self.inserted_in_ts_msp >= 64000 || ts < self.ts_msp || self.ts_msp + HOUR <= ts {
+            let div = SEC * 10;
+            let ts_msp = ts / div * div;
+            self.inserted_in_ts_msp = 1;
+            if ts_msp == self.ts_msp {
+                (ts_msp, false)
+            } else {
+                self.ts_msp = ts_msp;
+                (ts_msp, true)
+            }
+        } else {
+            self.inserted_in_ts_msp += 1;
+            (self.ts_msp, false)
+        };
+        let ts_msp_grid = (ts / TS_MSP_GRID_UNIT / TS_MSP_GRID_SPACING * TS_MSP_GRID_SPACING) as u32;
+        let ts_msp_grid = if self.ts_msp_grid_last != ts_msp_grid {
+            self.ts_msp_grid_last = ts_msp_grid;
+            Some(ts_msp_grid)
+        } else {
+            None
+        };
+        (ts_msp, msp_bump, ts_msp_grid)
+    }
+}
+
+// Pulses which were recently written to the pulse map. Shared by the clients of a process
+// because several sources carry the same pulse.
+pub struct PulseMapDedup {
+    seen: Mutex<BTreeSet<u64>>,
+}
+
+impl PulseMapDedup {
+    pub fn new() -> Self {
+        Self {
+            seen: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    // Returns true for the first occurrence of the pulse.
+    pub fn first(&self, pulse: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.insert(pulse) {
+            return false;
+        }
+        if seen.len() > PULSE_MAP_DEDUP_MAX {
+            seen.pop_first();
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
+struct ChannelWriter {
+    cd: ChannelDescDecoded,
+    series: SeriesId,
+}
+
+struct CachedHeader {
+    used: Instant,
+    writers: Vec<Option<ChannelWriter>>,
+    // Some channels had no series when the writers were set up.
+    missing: bool,
+}
+
+fn series_key(cd: &ChannelDescDecoded) -> (String, i32, Vec<i32>) {
+    (
+        cd.name.clone(),
+        cd.scalar_type.to_scylla_i32(),
+        cd.shape.to_scylla_vec(),
+    )
+}
+
+// Difference between the channels of two data headers.
+struct HeaderDiff {
+    added: usize,
+    removed: usize,
+    changed: usize,
+    opened: Vec<SeriesId>,
+    closed: Vec<SeriesId>,
+}
+
+impl HeaderDiff {
+    fn new(old: &[Option<ChannelWriter>], new: &[Option<ChannelWriter>]) -> Self {
+        let series = |ws: &[Option<ChannelWriter>]| -> BTreeMap<String, SeriesId> {
+            ws.iter()
+                .filter_map(|w| w.as_ref())
+                .map(|w| (w.cd.name.clone(), w.series.clone()))
+                .collect()
+        };
+        let old = series(old);
+        let new = series(new);
+        let mut ret = Self {
+            added: 0,
+            removed: 0,
+            changed: 0,
+            opened: Vec::new(),
+            closed: Vec::new(),
+        };
+        for (name, s) in &old {
+            match new.get(name) {
+                Some(s2) if s2 == s => {}
+                Some(s2) => {
+                    ret.changed += 1;
+                    ret.closed.push(s.clone());
+                    ret.opened.push(s2.clone());
+                }
+                None => {
+                    ret.removed += 1;
+                    ret.closed.push(s.clone());
+                }
+            }
+        }
+        for (name, s) in &new {
+            if !old.contains_key(name) {
+                ret.added += 1;
+                ret.opened.push(s.clone());
+            }
+        }
+        ret
+    }
+}
+
 pub struct BsreadClient {
     opts: ZmtpClientOpts,
     source_addr: SocketAddr,
-    do_pulse_id: bool,
     rcvbuf: Option<usize>,
     print_stats: CheckEvery,
     parser: Parser,
     insqtx: Sender<QueryItem>,
-    tmp_evtset_series: Option<SeriesId>,
     channel_info_query_tx: Sender<ChannelInfoQuery>,
-    inserted_in_ts_msp_count: u32,
-    ts_msp_last: u64,
-    ts_msp_grid_last: u32,
+    series_by_channel: BTreeMap<(String, i32, Vec<i32>), SeriesId>,
+    // Same order as the channels in the data header, None for channels which are not stored.
+    writers: Vec<Option<ChannelWriter>>,
+    msp_states: BTreeMap<SeriesId, MspState>,
+    decode_error_count: u64,
+    decompress_buf: Vec<u8>,
+    // Writers of recently seen data headers by header hash.
+    head_cache: BTreeMap<String, CachedHeader>,
+    // Series with an opened status and no closed status yet.
+    open_series: BTreeSet<SeriesId>,
+    pulse_map_dedup: Arc<PulseMapDedup>,
+    // Set while channels of the current data header have no series.
+    lookup_retry: Option<Instant>,
 }
 
 impl BsreadClient {
@@ -83,131 +260,29 @@ impl BsreadClient {
         opts: ZmtpClientOpts,
         insqtx: Sender<QueryItem>,
         channel_info_query_tx: Sender<ChannelInfoQuery>,
+        pulse_map_dedup: Arc<PulseMapDedup>,
     ) -> Result<Self, Error> {
         let ret = Self {
             source_addr: opts.addr,
-            do_pulse_id: opts.do_pulse_id,
             rcvbuf: opts.rcvbuf,
             opts,
             print_stats: CheckEvery::new(Duration::from_millis(2000)),
             parser: Parser::new(),
             insqtx,
-            tmp_evtset_series: None,
             channel_info_query_tx,
-            inserted_in_ts_msp_count: 0,
-            ts_msp_last: 0,
-            ts_msp_grid_last: 0,
+            series_by_channel: BTreeMap::new(),
+            writers: Vec::new(),
+            msp_states: BTreeMap::new(),
+            decode_error_count: 0,
+            decompress_buf: Vec::new(),
+            head_cache: BTreeMap::new(),
+            open_series: BTreeSet::new(),
+            pulse_map_dedup,
+            lookup_retry: None,
         };
         Ok(ret)
     }
 
-    async fn test_evtset_extract(
-        &mut self,
-        msg: &ZmtpMessage,
-        bm: &BsreadMessage,
-        ts: u64,
-        pulse: u64,
-    ) -> Result<(), Error> {
-        let chname = "SAR-CVME-TIFALL5:EvtSet";
-        // Test the bool set write
-        let mut i3 = usize::MAX;
-        for (i, ch) in bm.head_b.channels.iter().enumerate() {
-            if ch.name == chname {
-                i3 = i;
-                break;
-            }
-        }
-        if i3 != usize::MAX {
-            if let Some(fr) = msg.frames.get(2 + 2 * i3) {
-                debug!("try to extract bools  {}  {}", fr.msglen, fr.data.len());
-                let setlen = fr.data.len();
-                debug!("flags {:?}", &fr.data[..setlen.min(16)]);
-                let evtset: Vec<_> = fr.data.iter().map(|&x| x != 0).collect();
-                let scalar_type = ScalarType::BOOL;
-                let shape = Shape::Wave(256);
-                if true {
-                    todo!("TODO bsreadclient try to fetch series id");
-                }
-                #[cfg(DISABLED)]
-                if self.tmp_evtset_series.is_none() {
-                    debug!("try to fetch series id");
-                    let (tx, rx) = async_channel::bounded(8);
-                    let item = ChannelInfoQuery {
-                        backend: self.opts.backend.clone(),
-                        channel: chname.into(),
-                        scalar_type: ScalarType::BOOL.to_scylla_i32(),
-                        shape_dims: Shape::Wave(setlen as _).to_scylla_vec(),
-                        tx,
-                    };
-                    self.channel_info_query_tx.send(item).await?;
-                    match rx.recv().await {
-                        Ok(res) => match res {
-                            Ok(res) => {
-                                debug!("got series id: {res:?}");
-                                self.tmp_evtset_series = Some(res.into_inner());
-                            }
-                            Err(e) => {
-                                error!("{e}");
-                            }
-                        },
-                        Err(e) => {
-                            error!("{e}");
-                        }
-                    }
-                }
-                if let Some(series) = self.tmp_evtset_series.clone() {
-                    let (ts_msp, ts_msp_changed) =
-                        if self.inserted_in_ts_msp_count >= 6400 || self.ts_msp_last + HOUR <= ts {
-                            let div = SEC * 10;
-                            let ts_msp = ts / div * div;
-                            if ts_msp == self.ts_msp_last {
-                                (ts_msp, false)
-                            } else {
-                                self.ts_msp_last = ts_msp;
-                                self.inserted_in_ts_msp_count = 1;
-                                (ts_msp, true)
-                            }
-                        } else {
-                            self.inserted_in_ts_msp_count += 1;
-                            (self.ts_msp_last, false)
-                        };
-                    let ts_lsp = ts - ts_msp;
-                    let ts_msp_grid = (ts / TS_MSP_GRID_UNIT / TS_MSP_GRID_SPACING * TS_MSP_GRID_SPACING) as u32;
-                    let ts_msp_grid = if self.ts_msp_grid_last != ts_msp_grid {
-                        self.ts_msp_grid_last = ts_msp_grid;
-                        Some(ts_msp_grid)
-                    } else {
-                        None
-                    };
-                    let item = InsertItem {
-                        series: series.into(),
-                        ts_msp,
-                        ts_lsp,
-                        msp_bump: ts_msp_changed,
-                        ts_msp_grid,
-                        pulse,
-                        scalar_type,
-                        shape,
-                        val: DataValue::Array(ArrayValue::Bool(evtset)),
-                    };
-                    let item = QueryItem::Insert(item);
-                    match self.insqtx.send(item).await {
-                        Ok(_) => {
-                            debug!("item send ok  pulse {}", pulse);
-                        }
-                        Err(e) => {
-                            error!("can not send item {:?}", e.0);
-                        }
-                    }
-                } else {
-                    error!("still no series id");
-                    tokio::time::sleep(Duration::from_millis(1000)).await;
-                }
-            }
-        }
-        Ok(())
-    }
-
     pub async fn run(&mut self) -> Result<(), Error> {
         let mut conn = tokio::net::TcpStream::connect(&self.source_addr).await?;
         if let Some(v) = self.rcvbuf {
@@ -259,53 +334,14 @@ impl BsreadClient {
                                         );
                                     }
                                 }
-                                {
-                                    if bm.head_b_md5 != dh_md5_last {
-                                        // TODO header changed, don't support this at the moment.
-                                        head_b = bm.head_b.clone();
-                                        if dh_md5_last.is_empty() {
-                                            debug!("data header hash {}", bm.head_b_md5);
-                                            dh_md5_last = bm.head_b_md5.clone();
-                                            // TODO must fetch series ids on-demand.
-                                            // For the time being, assume that channel list never changes, but WARN!
-                                            /*let scy = self.scy.clone();
-                                            for chn in &head_b.channels {
-                                                info!("Setup writer for {}", chn.name);
-                                                let cd: ChannelDescDecoded = chn.try_into()?;
-                                                match self.setup_channel_writers(&scy, &cd).await {
-                                                    Ok(_) => {}
-                                                    Err(e) => {
-                                                        warn!("can not set up writer for {}  {e:?}", chn.name);
-                                                    }
-                                                }
-                                            }*/
-                                        } else {
-                                            error!("TODO  changed data header hash {}", bm.head_b_md5);
-                                            dh_md5_last = bm.head_b_md5.clone();
-                                            // TODO
-                                            // Update only the changed channel writers.
-                                            // Flush buffers before creating new channel writer.
-                                        }
-                                    }
+                                if bm.head_b_md5 != dh_md5_last {
+                                    debug!("data header hash {}", bm.head_b_md5);
+                                    dh_md5_last = bm.head_b_md5.clone();
+                                    head_b = bm.head_b.clone();
+                                    self.switch_data_header(&bm.head_b_md5, &head_b).await?;
                                 }
-                                if self.do_pulse_id {
-                                    let nframes = msg.frames().len();
-                                    debug!("nframes {nframes}");
-                                    let mut i3 = u32::MAX;
-                                    for (i, ch) in head_b.channels.iter().enumerate() {
-                                        if ch.name == "SINEG01-RLLE-STA:MASTER-EVRPULSEID"
-                                            || ch.name == "SAR-CVME-TIFALL4:EvtSet"
-                                        {
-                                            i3 = i as u32;
-                                        }
-                                    }
-                                    // TODO need to know the facility!
-                                    if i3 < u32::MAX {
-                                        let i4 = 2 * i3 + 2;
-                                        if let Some(fr) = msg.frames.get(i4 as usize) {
-                                            self.insert_pulse_map(fr, &msg, &bm).await?;
-                                        }
-                                    }
+                                if self.lookup_retry.map_or(false, |x| x <= tsnow) {
+                                    self.retry_series_lookup(&bm.head_b_md5, &head_b).await?;
                                 }
                                 if msg.frames.len() < 2 + 2 * head_b.channels.len() {
                                     // TODO count always, throttle log.
@@ -315,6 +351,9 @@ impl BsreadClient {
                                 let ts = (gts.sec as u64) * SEC + gts.ns as u64;
                                 let pulse = bm.head_a.pulse_id.as_u64().unwrap_or(0);
                                 debug!("ts {ts:20}  pulse{pulse:20}");
+                                if let Some(pulse) = bm.head_a.pulse_id.as_u64() {
+                                    self.insert_pulse_map(pulse, ts).await?;
+                                }
                                 // TODO limit warn rate
                                 if pulse != 0 && (pulse < 14781000000 || pulse > 49000000000) {
                                     // TODO limit log rate
@@ -326,22 +365,11 @@ impl BsreadClient {
                                         ts, pulse, self.source_addr
                                     );
                                 }
-                                self.test_evtset_extract(&msg, &bm, ts, pulse).await?;
-                                let nch = head_b.channels.len();
-                                let nmax = self.opts.process_channel_count_limit.unwrap_or(4000);
-                                let nlim = if nch > nmax {
-                                    // TODO count this event
-                                    4000
-                                } else {
-                                    nch
-                                };
-                                for i1 in 0..nlim {
-                                    // TODO skip decoding if header unchanged.
-                                    let chn = &head_b.channels[i1];
-                                    let _chd: ChannelDescDecoded = chn.try_into()?;
-                                    let _fr = &msg.frames[2 + 2 * i1];
-                                    // TODO store the channel information together with series in struct.
-                                }
+                                let ts1 = Instant::now();
+                                let (n, bytes) = self.insert_channels(&msg, ts, pulse).await?;
+                                time_spent_inserting += ts1.elapsed();
+                                rows_inserted += n;
+                                bytes_payload += bytes;
                             }
                             Err(e) => {
                                 error!("{}", e);
@@ -387,45 +415,388 @@ impl BsreadClient {
         Ok(())
     }
 
-    #[allow(unused)]
-    async fn setup_channel_writers(&mut self, _scy: &ScySession, cd: &ChannelDescDecoded) -> Result<(), Error> {
-        let has_comp = cd.compression.is_some();
-        if has_comp {
-            warn!("Compression not yet supported  [{}]", cd.name);
+    // Switches to the writers of the given data header, a header seen recently is taken from the cache.
+    async fn switch_data_header(&mut self, head_b_md5: &str, head_b: &HeadB) -> Result<(), Error> {
+        let cached = self.head_cache.get(head_b_md5).map(|x| (x.writers.clone(), x.missing));
+        let mut evicted = Vec::new();
+        let (writers, missing) = match cached {
+            // Series which were found since the header was cached are taken up without a lookup.
+            Some((_, true)) => self.setup_channel_writers(head_b, false).await?,
+            Some(x) => x,
+            None => {
+                let ret = self.setup_channel_writers(head_b, true).await?;
+                if self.head_cache.len() >= HEAD_CACHE_MAX {
+                    let oldest = self.head_cache.iter().min_by_key(|x| x.1.used).map(|x| x.0.clone());
+                    if let Some(x) = oldest.and_then(|k| self.head_cache.remove(&k)) {
+                        evicted = x.writers.into_iter().flatten().map(|w| w.series).collect();
+                    }
+                }
+                ret
+            }
+        };
+        let entry = CachedHeader {
+            used: Instant::now(),
+            writers: writers.clone(),
+            missing,
+        };
+        self.head_cache.insert(head_b_md5.into(), entry);
+        let had_writers = self.writers.len() != 0;
+        let diff = self.set_writers(writers).await?;
+        if had_writers {
+            info!(
+                "data header changed {}  added {}  removed {}  changed {}",
+                self.source_addr, diff.added, diff.removed, diff.changed
+            );
+        }
+        self.close_unused(evicted).await?;
+        self.set_lookup_retry(missing);
+        Ok(())
+    }
+
+    // Looks up the series which were missing for the current data header, the channels which get a
+    // series are written from now on.
+    async fn retry_series_lookup(&mut self, head_b_md5: &str, head_b: &HeadB) -> Result<(), Error> {
+        let (writers, missing) = self.setup_channel_writers(head_b, true).await?;
+        if let Some(x) = self.head_cache.get_mut(head_b_md5) {
+            x.writers = writers.clone();
+            x.missing = missing;
+        }
+        let diff = self.set_writers(writers).await?;
+        if diff.added != 0 {
+            info!("found series for {} more channels of {}", diff.added, self.source_addr);
+        }
+        self.set_lookup_retry(missing);
+        Ok(())
+    }
+
+    fn set_lookup_retry(&mut self, missing: bool) {
+        self.lookup_retry = if missing {
+            Some(Instant::now() + SERIES_LOOKUP_RETRY)
+        } else {
+            None
+        };
+    }
+
+    // Makes `writers` current. The series of the other cached data headers stay open, so that a
+    // source which alternates between headers does not record a status change on every switch.
+    async fn set_writers(&mut self, writers: Vec<Option<ChannelWriter>>) -> Result<HeaderDiff, Error> {
+        let mut diff = HeaderDiff::new(&self.writers, &writers);
+        self.writers = writers;
+        for series in std::mem::take(&mut diff.opened) {
+            if self.open_series.insert(series.clone()) {
+                let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                    ts: SystemTime::now(),
+                    series,
+                    status: ChannelStatus::Opened,
+                });
+                self.insqtx.send(item).await?;
+            }
+        }
+        self.close_unused(std::mem::take(&mut diff.closed)).await?;
+        Ok(diff)
+    }
+
+    // Records the closed status of the series which neither the current nor a cached data header uses.
+    async fn close_unused(&mut self, series: Vec<SeriesId>) -> Result<(), Error> {
+        for series in series {
+            let used = self
+                .head_cache
+                .values()
+                .map(|x| &x.writers)
+                .chain(std::iter::once(&self.writers))
+                .flatten()
+                .flatten()
+                .any(|w| w.series == series);
+            if used || !self.open_series.remove(&series) {
+                continue;
+            }
+            self.msp_states.remove(&series);
+            let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                ts: SystemTime::now(),
+                series,
+                status: ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove),
+            });
+            self.insqtx.send(item).await?;
+        }
+        Ok(())
+    }
+
+    // Resolves the series of every channel in the data header, known series are taken from
+    // `series_by_channel` and the others are looked up if `lookup` is set. A changed type or shape
+    // gives a different key and therefore a new series. Also returns whether channels lack a series.
+    async fn setup_channel_writers(
+        &mut self,
+        head_b: &HeadB,
+        lookup: bool,
+    ) -> Result<(Vec<Option<ChannelWriter>>, bool), Error> {
+        let mut cds = Vec::new();
+        let mut keys = Vec::new();
+        for chn in &head_b.channels {
+            let cd: ChannelDescDecoded = match chn.try_into() {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("can not decode channel description of {}  {e}", chn.name);
+                    cds.push(None);
+                    continue;
+                }
+            };
+            let key = series_key(&cd);
+            if !self.series_by_channel.contains_key(&key) {
+                keys.push(key);
+            }
+            cds.push(Some(cd));
+        }
+        if lookup {
+            self.lookup_series(keys).await?;
+        }
+        let mut missing = false;
+        let ret = cds
+            .into_iter()
+            .map(|cd| {
+                let cd = cd?;
+                match self.series_by_channel.get(&series_key(&cd)) {
+                    Some(series) => Some(ChannelWriter {
+                        cd,
+                        series: series.clone(),
+                    }),
+                    None => {
+                        missing = true;
+                        None
+                    }
+                }
+            })
+            .collect();
+        Ok((ret, missing))
+    }
+
+    // Sends all queries before waiting for the answers, a data header can list thousands of channels.
+    // Channels without an answer in time stay without series until the next retry.
+    async fn lookup_series(&mut self, keys: Vec<(String, i32, Vec<i32>)>) -> Result<(), Error> {
+        if keys.is_empty() {
             return Ok(());
         }
-        let _shape_dims = cd.shape.to_scylla_vec();
+        let n = keys.len();
+        let (tx, rx) = async_channel::unbounded();
+        let mut by_name = BTreeMap::new();
+        for key in keys {
+            let item = ChannelInfoQuery {
+                backend: self.opts.backend.clone(),
+                channel: key.0.clone(),
+                scalar_type: key.1,
+                shape_dims: key.2.clone(),
+                tx: Box::pin(SeriesLookupSender { tx: tx.clone() }),
+            };
+            self.channel_info_query_tx.send(item).await?;
+            by_name.insert(key.0.clone(), key);
+        }
+        drop(tx);
+        let deadline = tokio::time::Instant::now() + SERIES_LOOKUP_TIMEOUT;
+        for _ in 0..n {
+            let res = match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(x) => x,
+                Err(_) => {
+                    warn!("series lookup timeout for {}", self.source_addr);
+                    break;
+                }
+            };
+            match res {
+                Ok(Ok(res)) => {
+                    if let Some(key) = by_name.remove(&res.channel) {
+                        let series = res.series.into_inner();
+                        self.series_by_channel.insert(key, series);
+                    }
+                }
+                Ok(Err(e)) => warn!("series lookup failed  {e}"),
+                Err(_) => break,
+            }
+        }
+        if by_name.len() != 0 {
+            warn!("no series for {} channels of {}", by_name.len(), self.source_addr);
+        }
         Ok(())
     }
 
-    async fn insert_pulse_map(&mut self, fr: &ZmtpFrame, msg: &ZmtpMessage, bm: &BsreadMessage) -> Result<(), Error> {
-        debug!("data len {}", fr.data.len());
-        // TODO take pulse-id also from main header and compare.
-        let pulse_f64 = f64::from_be_bytes(fr.data[..8].try_into().map_err(|_| Error::BadSlice)?);
-        debug!("pulse_f64 {pulse_f64}");
-        let pulse = pulse_f64 as u64;
-        if false {
-            let i4 = 3;
-            // TODO this next frame should be described somehow in the json header or?
-            debug!("next val len {}", msg.frames[i4 as usize + 1].data.len());
-            let ts_a = u64::from_be_bytes(
-                msg.frames[i4 as usize + 1].data[0..8]
-                    .try_into()
-                    .map_err(|_| Error::BadSlice)?,
-            );
-            let ts_b = u64::from_be_bytes(
-                msg.frames[i4 as usize + 1].data[8..16]
-                    .try_into()
-                    .map_err(|_| Error::BadSlice)?,
-            );
-            debug!("ts_a {ts_a}  ts_b {ts_b}");
+    // Returns the number of inserted values and their payload bytes.
+    async fn insert_channels(&mut self, msg: &ZmtpMessage, ts: u64, pulse: u64) -> Result<(u32, u64), Error> {
+        let nlim = self
+            .writers
+            .len()
+            .min(self.opts.process_channel_count_limit.unwrap_or(4000));
+        let array_truncate = self.opts.array_truncate.unwrap_or(usize::MAX);
+        let mut items = Vec::new();
+        let mut bytes = 0;
+        for (i, w) in self.writers[..nlim].iter().enumerate() {
+            let w = match w {
+                Some(x) => x,
+                None => continue,
+            };
+            let fr = match msg.frames.get(2 + 2 * i) {
+                Some(x) => x,
+                None => break,
+            };
+            // An empty frame means that there is no value for this channel in this message.
+            if fr.data.is_empty() {
+                continue;
+            }
+            let val = match w.cd.decode_frame(&fr.data, array_truncate, &mut self.decompress_buf) {
+                Ok(x) => x,
+                Err(e) => {
+                    self.decode_error_count += 1;
+                    if self.decode_error_count < 100 {
+                        warn!("can not decode {}  {e}", w.cd.name);
+                    }
+                    continue;
+                }
+            };
+            bytes += fr.data.len() as u64;
+            let st = self.msp_states.entry(w.series.clone()).or_insert_with(MspState::new);
+            let (ts_msp, msp_bump, ts_msp_grid) = st.next(ts);
+            let item = InsertItem {
+                series: w.series.clone(),
+                ts_msp,
+                ts_lsp: ts - ts_msp,
+                msp_bump,
+                ts_msp_grid,
+                pulse,
+                scalar_type: w.cd.scalar_type.clone(),
+                shape: w.cd.shape.clone(),
+                val,
+                status: 0,
+                severity: 0,
+            };
+            items.push(QueryItem::Insert(item));
+        }
+        let n = items.len() as u32;
+        for item in items {
+            self.insqtx.send(item).await?;
+        }
+        Ok((n, bytes))
+    }
+
+    async fn insert_pulse_map(&mut self, pulse: u64, ts: u64) -> Result<(), Error> {
+        trace!("ts {ts:20}  pulse {pulse:20}");
+        if self.pulse_map_dedup.first(pulse) {
+            let item = QueryItem::PulseMap(PulseMapItem { pulse, ts });
+            self.insqtx.send(item).await?;
         }
-        let ts = bm.head_a.global_timestamp.sec * SEC + bm.head_a.global_timestamp.ns;
-        /*let pulse_a = (pulse >> 14) as i64;
-        let pulse_b = (pulse & 0x3fff) as i32;
-        let ts_a = bm.head_a.global_timestamp.sec as i64;
-        let ts_b = bm.head_a.global_timestamp.ns as i32;*/
-        debug!("ts {ts:20}  pulse {pulse:20}");
         Ok(())
     }
 }
+
+#[test]
+fn data_header_diff() {
+    use netpod::AggKind;
+    use netpod::ByteOrder;
+    use netpod::ScalarType;
+    use netpod::Shape;
+    let w = |name: &str, series: u64| {
+        Some(ChannelWriter {
+            cd: ChannelDescDecoded {
+                name: name.into(),
+                scalar_type: ScalarType::F64,
+                shape: Shape::Scalar,
+                byte_order: ByteOrder::Little,
+                compression: None,
+                agg_kind: AggKind::Plain,
+            },
+            series: SeriesId::new(series),
+        })
+    };
+    let old = vec![w("A", 1), None, w("B", 2), w("C", 3)];
+    let new = vec![w("A", 1), w("C", 4), w("D", 5)];
+    let diff = HeaderDiff::new(&old, &new);
+    assert_eq!((diff.added, diff.removed, diff.changed), (1, 1, 1));
+    assert_eq!(diff.closed, vec![SeriesId::new(2), SeriesId::new(3)]);
+    assert_eq!(diff.opened, vec![SeriesId::new(4), SeriesId::new(5)]);
+}
+
+#[test]
+fn pulse_map_dedup() {
+    let dedup = PulseMapDedup::new();
+    assert!(dedup.first(10));
+    assert!(!dedup.first(10));
+    for i in 0..PULSE_MAP_DEDUP_MAX as u64 {
+        dedup.first(100 + i);
+    }
+    assert!(dedup.first(10));
+    assert!(!dedup.first(100 + PULSE_MAP_DEDUP_MAX as u64 - 1));
+}
+
+#[test]
+fn data_header_switch_keeps_cached_series_open() {
+    use crate::bsread::ChannelDesc;
+    let fut = async {
+        let (insqtx, insqrx) = async_channel::bounded(1024);
+        let (query_tx, _query_rx) = async_channel::bounded(1);
+        let opts = ZmtpClientOpts {
+            backend: "testbackend".into(),
+            addr: "127.0.0.1:9999".parse().unwrap(),
+            rcvbuf: None,
+            array_truncate: None,
+            process_channel_count_limit: None,
+        };
+        let mut client = BsreadClient::new(opts, insqtx, query_tx, Arc::new(PulseMapDedup::new())).await?;
+        let head = |names: &[&str]| HeadB {
+            htype: "bsr_d-1.1".into(),
+            channels: names
+                .iter()
+                .map(|name| ChannelDesc {
+                    name: name.to_string(),
+                    ty: "float64".into(),
+                    shape: serde_json::Value::Array(Vec::new()),
+                    encoding: "little".into(),
+                    compression: None,
+                })
+                .collect(),
+        };
+        // All series are known, no lookup is needed.
+        let mut heads = vec![head(&["X", "Y"]), head(&["X", "Z"])];
+        for i in 0..HEAD_CACHE_MAX {
+            heads.push(head(&[&format!("W{i}")]));
+        }
+        for h in &heads {
+            for chn in &h.channels {
+                let cd: ChannelDescDecoded = chn.try_into()?;
+                let id = 100 + client.series_by_channel.len() as u64;
+                client
+                    .series_by_channel
+                    .entry(series_key(&cd))
+                    .or_insert(SeriesId::new(id));
+            }
+        }
+        let id = |name: &str| {
+            let key = (name.to_string(), netpod::ScalarType::F64.to_scylla_i32(), Vec::new());
+            client.series_by_channel[&key].id()
+        };
+        let (x, y, z) = (id("X"), id("Y"), id("Z"));
+        let w: Vec<_> = (0..HEAD_CACHE_MAX).map(|i| id(&format!("W{i}"))).collect();
+        let statuses = || {
+            let mut ret = Vec::new();
+            while let Ok(item) = insqrx.try_recv() {
+                if let QueryItem::ChannelStatus(item) = item {
+                    let opened = matches!(item.status, ChannelStatus::Opened);
+                    ret.push((opened, item.series.id()));
+                }
+            }
+            ret
+        };
+        for (md5, i) in [("a", 0), ("b", 1), ("a", 0)] {
+            client.switch_data_header(md5, &heads[i]).await?;
+        }
+        assert_eq!(statuses(), vec![(true, x), (true, y), (true, z)]);
+        // Each new header evicts the least recently used one, "b" before "a".
+        for i in 0..HEAD_CACHE_MAX - 2 {
+            client.switch_data_header(&format!("w{i}"), &heads[2 + i]).await?;
+        }
+        let exp: Vec<_> = w[..HEAD_CACHE_MAX - 2].iter().map(|x| (true, *x)).collect();
+        assert_eq!(statuses(), exp);
+        let i = HEAD_CACHE_MAX - 2;
+        client.switch_data_header(&format!("w{i}"), &heads[2 + i]).await?;
+        assert_eq!(statuses(), vec![(true, w[i]), (false, z)]);
+        let i = HEAD_CACHE_MAX - 1;
+        client.switch_data_header(&format!("w{i}"), &heads[2 + i]).await?;
+        assert_eq!(statuses(), vec![(true, w[i]), (false, x), (false, y)]);
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
@@ -6,10 +6,13 @@ use self::zmtpproto::ZmtpMessage;
 use crate::bsread::ChannelDescDecoded;
 use crate::bsreadclient;
 use crate::bsreadclient::BsreadClient;
+use crate::bsreadclient::PulseMapDedup;
 use crate::zmtp::zmtpproto::SocketType;
 use crate::zmtp::zmtpproto::Zmtp;
+use async_channel::Sender;
 #[allow(unused)]
 use bytes::BufMut;
+use dbpg::seriesbychannel::ChannelInfoQuery;
 use err::thiserror;
 use err::ThisError;
 use futures_util::Future;
@@ -17,10 +20,12 @@ use futures_util::FutureExt;
 use futures_util::StreamExt;
 use futures_util::TryFutureExt;
 use log::*;
+use scywr::iteminsertqueue::QueryItem;
 use scywr::session::ScySession;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 use taskrun::tokio;
@@ -77,7 +82,6 @@ pub async fn get_series_id(_scy: &ScySession, _chn: &ChannelDescDecoded) -> Resu
 pub struct ZmtpClientOpts {
     pub backend: String,
     pub addr: SocketAddr,
-    pub do_pulse_id: bool,
     pub rcvbuf: Option<usize>,
     pub array_truncate: Option<usize>,
     pub process_channel_count_limit: Option<usize>,
@@ -114,19 +118,14 @@ pub enum ZmtpEvent {
     ZmtpMessage(ZmtpMessage),
 }
 
-pub async fn zmtp_client(opts: ZmtpClientOpts) -> Result<(), Error> {
-    let client = BsreadClient::new(opts.clone(), err::todoval(), err::todoval()).await?;
-    let fut = {
-        async move {
-            let mut client = client;
-            client.run().await?;
-            Ok::<_, Error>(())
-        }
-    };
-    let jh = tokio::spawn(fut);
-    //let mut jhs = Vec::new();
-    //jhs.push(jh);
-    //futures_util::future::join_all(jhs).await;
-    jh.await.map_err(|_| Error::TaskJoin)??;
+pub async fn zmtp_client(
+    opts: ZmtpClientOpts,
+    insqtx: Sender<QueryItem>,
+    channel_info_query_tx: Sender<ChannelInfoQuery>,
+    pulse_map_dedup: Arc<PulseMapDedup>,
+) -> Result<(), Error> {
+    // Runs in the task of the caller, so that aborting it also drops the insert queue sender.
+    let mut client = BsreadClient::new(opts, insqtx, channel_info_query_tx, pulse_map_dedup).await?;
+    client.run().await?;
     Ok(())
 }
@@ -1,4 +1,5 @@
 use std::ffi::CStr;
+use std::net::Ipv4Addr;
 use taskrun::tokio;
 use thiserror::Error;
 use tokio::net::TcpStream;
@@ -8,6 +9,7 @@ use tokio::net::TcpStream;
 pub enum Error {
     SocketOptionSet,
     SocketOptionGet,
+    InterfaceList,
 }
 
 pub fn local_hostname() -> String {
@@ -28,6 +30,40 @@ fn test_get_local_hostname() {
     assert_ne!(local_hostname().len(), 0);
 }
 
+// Broadcast addresses of the ipv4 interfaces which are up, without loopback.
+pub fn local_broadcast_addrs() -> Result<Vec<Ipv4Addr>, Error> {
+    let mut ret = Vec::new();
+    unsafe {
+        let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return Err(Error::InterfaceList);
+        }
+        let mut p = ifap;
+        while !p.is_null() {
+            let ifa = &*p;
+            p = ifa.ifa_next;
+            let flags = ifa.ifa_flags as libc::c_int;
+            if flags & libc::IFF_UP == 0 || flags & libc::IFF_LOOPBACK != 0 || flags & libc::IFF_BROADCAST == 0 {
+                continue;
+            }
+            let addr = ifa.ifa_ifu;
+            if ifa.ifa_addr.is_null() || addr.is_null() {
+                continue;
+            }
+            if (*ifa.ifa_addr).sa_family as libc::c_int != libc::AF_INET {
+                continue;
+            }
+            let addr = &*(addr as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(addr.sin_addr.s_addr.to_ne_bytes());
+            if !ret.contains(&ip) {
+                ret.push(ip);
+            }
+        }
+        libc::freeifaddrs(ifap);
+    }
+    Ok(ret)
+}
+
 pub fn set_rcv_sock_opts(conn: &mut TcpStream, rcvbuf: u32) -> Result<(), Error> {
     use std::mem::size_of;
     use std::os::unix::prelude::AsRawFd;
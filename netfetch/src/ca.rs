@@ -1,7 +1,10 @@
+pub mod beacon;
+pub mod caenv;
 pub mod conn;
 pub mod connset;
 pub mod finder;
 pub mod findioc;
+pub mod policy;
 pub mod proto;
 pub mod search;
 pub mod statemap;
@@ -13,7 +16,6 @@ use futures_util::FutureExt;
 use log::*;
 use netpod::Database;
 use scywr::insertworker::InsertWorkerOpts;
-use scywr::store::DataStore;
 use stats::CaConnStatsAgg;
 use std::pin::Pin;
 use std::sync::atomic::AtomicU32;
@@ -36,7 +38,6 @@ pub struct IngestCommons {
     pub pgconf: Arc<Database>,
     pub backend: String,
     pub local_epics_hostname: String,
-    pub data_store: Arc<DataStore>,
     pub insert_ivl_min: Arc<AtomicU64>,
     pub extra_inserts_conf: TokMx<ExtraInsertsConf>,
     pub insert_frac: Arc<AtomicU64>,
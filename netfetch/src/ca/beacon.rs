@@ -0,0 +1,241 @@
+use crate::ca::caenv::CA_SERVER_PORT_DEFAULT;
+use crate::ca::connset::CaConnSetEvent;
+use crate::ca::connset::ConnSetCmd;
+use crate::ca::proto::HeadInfo;
+use async_channel::Sender;
+use err::Error;
+use log::*;
+use slidebuf::SlideBuf;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::os::fd::FromRawFd;
+use std::time::Duration;
+use std::time::Instant;
+use taskrun::tokio;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+pub const CA_REPEATER_PORT_DEFAULT: u16 = 5065;
+const CMD_RSRV_IS_UP: u16 = 13;
+// Servers which we hear for the first time shortly after our start are not new, we just did not know them yet.
+const STARTUP_GRACE: Duration = Duration::from_millis(40000);
+// An IOC beacons fast during startup, report the restart only once.
+const ANOMALY_HOLDOFF: Duration = Duration::from_millis(10000);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BeaconAnomalyKind {
+    NewServer,
+    SequenceReset,
+    FastBeacon,
+}
+
+#[derive(Clone, Debug)]
+pub struct BeaconAnomaly {
+    // Address of the CA server, as used for the TCP connection.
+    pub addr: SocketAddrV4,
+    pub kind: BeaconAnomalyKind,
+}
+
+struct ServerBeacons {
+    id_last: u32,
+    ts_last: Instant,
+    // Smoothed beacon period in seconds.
+    period: Option<f32>,
+    anomaly_last: Option<Instant>,
+}
+
+// Tracks the beacon sequence and period of every server to detect restarts.
+pub struct BeaconTracker {
+    servers: BTreeMap<SocketAddrV4, ServerBeacons>,
+    ts_start: Instant,
+}
+
+impl BeaconTracker {
+    pub fn new(ts_start: Instant) -> Self {
+        Self {
+            servers: BTreeMap::new(),
+            ts_start,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn beacon(&mut self, addr: SocketAddrV4, id: u32, tsnow: Instant) -> Option<BeaconAnomaly> {
+        let kind = match self.servers.get_mut(&addr) {
+            None => {
+                let e = ServerBeacons {
+                    id_last: id,
+                    ts_last: tsnow,
+                    period: None,
+                    anomaly_last: None,
+                };
+                self.servers.insert(addr, e);
+                if tsnow.saturating_duration_since(self.ts_start) > STARTUP_GRACE {
+                    Some(BeaconAnomalyKind::NewServer)
+                } else {
+                    None
+                }
+            }
+            Some(e) => {
+                if id == e.id_last {
+                    // The same beacon once more, e.g. received over a second interface.
+                    return None;
+                }
+                let dt = tsnow.saturating_duration_since(e.ts_last).as_secs_f32();
+                // Lost beacons leave a gap, a restarted server counts from zero again.
+                let kind = if (id.wrapping_sub(e.id_last) as i32) < 0 {
+                    Some(BeaconAnomalyKind::SequenceReset)
+                } else if e.period.map_or(false, |p| dt < p / 3.) {
+                    Some(BeaconAnomalyKind::FastBeacon)
+                } else {
+                    None
+                };
+                e.period = Some(match e.period {
+                    Some(p) => p + 0.25 * (dt - p),
+                    None => dt,
+                });
+                e.id_last = id;
+                e.ts_last = tsnow;
+                match kind {
+                    Some(_) if e.anomaly_last.map_or(false, |x| tsnow < x + ANOMALY_HOLDOFF) => None,
+                    Some(k) => {
+                        e.anomaly_last = Some(tsnow);
+                        Some(k)
+                    }
+                    None => None,
+                }
+            }
+        };
+        kind.map(|kind| BeaconAnomaly { addr, kind })
+    }
+}
+
+// Binds with SO_REUSEADDR so that we receive the beacon broadcasts next to a running CA repeater,
+// broadcasts reach every socket on the port. Unicast datagrams to the port reach only one socket,
+// which may be ours and not the one of the repeater. SO_REUSEPORT is not set: on Linux it
+// balances unicast datagrams over the sockets, and the repeater would lose even more of them.
+fn bind_reuse(port: u16) -> Result<std::net::UdpSocket, Error> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd == -1 {
+            return Err("can not create socket".into());
+        }
+        let sock = std::net::UdpSocket::from_raw_fd(fd);
+        let opt: libc::c_int = 1;
+        let ec = libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            &opt as *const _ as _,
+            std::mem::size_of::<libc::c_int>() as _,
+        );
+        if ec == -1 {
+            return Err("can not set address reuse".into());
+        }
+        let addr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as u16,
+            sin_port: port.to_be(),
+            sin_addr: libc::in_addr { s_addr: 0 },
+            sin_zero: [0; 8],
+        };
+        let addr_len = std::mem::size_of::<libc::sockaddr_in>();
+        let ec = libc::bind(fd, &addr as *const _ as _, addr_len as _);
+        if ec == -1 {
+            return Err(format!("can not bind beacon socket to port {port}").into());
+        }
+        sock.set_nonblocking(true)?;
+        Ok(sock)
+    }
+}
+
+fn parse_beacons(buf: &[u8], src: SocketAddrV4) -> Vec<(SocketAddrV4, u32)> {
+    let mut ret = Vec::new();
+    let mut nb = SlideBuf::new(buf.len());
+    if nb.put_slice(buf).is_err() {
+        return ret;
+    }
+    while nb.len() >= 16 {
+        let hi = match HeadInfo::from_netbuf(&mut nb) {
+            Ok(x) => x,
+            Err(_) => break,
+        };
+        if nb.read_bytes(hi.payload()).is_err() {
+            break;
+        }
+        if hi.cmdid() != CMD_RSRV_IS_UP {
+            continue;
+        }
+        // Older servers leave the address and port empty.
+        let ip = match hi.param2() {
+            0 => *src.ip(),
+            x => Ipv4Addr::from(x),
+        };
+        let port = match hi.data_count() {
+            0 => CA_SERVER_PORT_DEFAULT,
+            x => x as u16,
+        };
+        ret.push((SocketAddrV4::new(ip, port), hi.param1()));
+    }
+    ret
+}
+
+async fn beacon_listener(tx: Sender<CaConnSetEvent>, port: u16) -> Result<(), Error> {
+    let sock = UdpSocket::from_std(bind_reuse(port)?)?;
+    debug!("listen for beacons on port {port}");
+    let mut tracker = BeaconTracker::new(Instant::now());
+    let mut buf = vec![0; 1024 * 4];
+    loop {
+        let (n, src) = sock.recv_from(&mut buf).await?;
+        let src = match src {
+            SocketAddr::V4(x) => x,
+            SocketAddr::V6(_) => continue,
+        };
+        let tsnow = Instant::now();
+        for (addr, id) in parse_beacons(&buf[..n], src) {
+            if let Some(anomaly) = tracker.beacon(addr, id, tsnow) {
+                debug!("beacon anomaly {anomaly:?}  servers {}", tracker.len());
+                let cmd = ConnSetCmd::BeaconAnomaly(anomaly);
+                if tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+pub fn start_beacon_listener(tx: Sender<CaConnSetEvent>, port: u16) -> JoinHandle<()> {
+    taskrun::spawn(async move {
+        if let Err(e) = beacon_listener(tx, port).await {
+            warn!("beacon listener stopped  {e}");
+        }
+    })
+}
+
+#[test]
+fn beacon_tracker_detects_restart() {
+    let t0 = Instant::now();
+    let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5064);
+    let mut tr = BeaconTracker::new(t0);
+    let mut ts = t0;
+    for id in 100..104 {
+        assert!(tr.beacon(addr, id, ts).is_none());
+        ts += Duration::from_secs(15);
+    }
+    // A lost beacon is not an anomaly.
+    assert!(tr.beacon(addr, 105, ts).is_none());
+    // Neither is the same beacon over a second interface.
+    assert!(tr.beacon(addr, 105, ts + Duration::from_millis(1)).is_none());
+    ts += Duration::from_secs(15);
+    let a = tr.beacon(addr, 0, ts).unwrap();
+    assert_eq!(a.kind, BeaconAnomalyKind::SequenceReset);
+    // The fast startup beacons which follow are held off.
+    ts += Duration::from_millis(20);
+    assert!(tr.beacon(addr, 1, ts).is_none());
+    let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5064);
+    let a = tr.beacon(other, 7, ts).unwrap();
+    assert_eq!(a.kind, BeaconAnomalyKind::NewServer);
+}
@@ -0,0 +1,86 @@
+use err::Error;
+use ingest_linux::net::local_broadcast_addrs;
+use log::*;
+
+pub const CA_SERVER_PORT_DEFAULT: u16 = 5064;
+
+// Channel Access client settings from the standard EPICS environment variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EpicsCaEnv {
+    pub addr_list: Vec<String>,
+    pub auto_addr_list: bool,
+    pub server_port: u16,
+    pub name_servers: Vec<String>,
+}
+
+impl EpicsCaEnv {
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_lookup(|k| std::env::var(k).ok())
+    }
+
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let list = |k: &str| -> Vec<String> {
+            lookup(k)
+                .map(|v| v.split_whitespace().map(String::from).collect())
+                .unwrap_or_default()
+        };
+        let auto_addr_list = match lookup("EPICS_CA_AUTO_ADDR_LIST") {
+            Some(v) => !v.trim().eq_ignore_ascii_case("no"),
+            None => true,
+        };
+        let server_port = match lookup("EPICS_CA_SERVER_PORT") {
+            Some(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse()
+                .map_err(|e| Error::with_msg_no_trace(format!("bad EPICS_CA_SERVER_PORT {v:?} {e}")))?,
+            _ => CA_SERVER_PORT_DEFAULT,
+        };
+        let ret = Self {
+            addr_list: list("EPICS_CA_ADDR_LIST"),
+            auto_addr_list,
+            server_port,
+            name_servers: list("EPICS_CA_NAME_SERVERS"),
+        };
+        Ok(ret)
+    }
+
+    // The explicit address list plus, if enabled, the broadcast address of every local interface.
+    pub fn search_addrs(&self) -> Result<Vec<String>, Error> {
+        let mut ret = self.addr_list.clone();
+        if self.auto_addr_list {
+            let addrs = local_broadcast_addrs()
+                .map_err(|e| Error::with_msg_no_trace(format!("can not list network interfaces {e:?}")))?;
+            debug!("auto addr list {addrs:?}");
+            for a in addrs {
+                let a = a.to_string();
+                if !ret.contains(&a) {
+                    ret.push(a);
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[test]
+fn parse_epics_ca_env() {
+    let vars = [
+        ("EPICS_CA_ADDR_LIST", " 10.0.0.255  gw.example.com:5066 "),
+        ("EPICS_CA_AUTO_ADDR_LIST", "NO"),
+        ("EPICS_CA_SERVER_PORT", "5070"),
+    ];
+    let lookup = |k: &str| vars.iter().find(|x| x.0 == k).map(|x| x.1.to_string());
+    let env = EpicsCaEnv::from_lookup(lookup).unwrap();
+    assert_eq!(env.addr_list, vec!["10.0.0.255", "gw.example.com:5066"]);
+    assert_eq!(env.auto_addr_list, false);
+    assert_eq!(env.server_port, 5070);
+    assert_eq!(env.name_servers.len(), 0);
+    assert_eq!(env.search_addrs().unwrap(), env.addr_list);
+    let env = EpicsCaEnv::from_lookup(|_| None).unwrap();
+    assert_eq!(env.auto_addr_list, true);
+    assert_eq!(env.server_port, CA_SERVER_PORT_DEFAULT);
+    assert!(EpicsCaEnv::from_lookup(|k| (k == "EPICS_CA_SERVER_PORT").then(|| "x".into())).is_err());
+}
@@ -4,8 +4,13 @@ use super::proto::CaMsg;
 use super::proto::CaMsgTy;
 use super::proto::CaProto;
 use super::ExtraInsertsConf;
+use crate::ca::policy::ArchivingPolicy;
+use crate::ca::policy::PolicyState;
+use crate::ca::proto::ClearChannel;
 use crate::ca::proto::CreateChan;
 use crate::ca::proto::EventAdd;
+use crate::ca::proto::EventCancel;
+use crate::ca::proto::ReadNotify;
 use crate::senderpolling::SenderPolling;
 use crate::timebin::ConnTimeBin;
 use async_channel::Sender;
@@ -25,6 +30,7 @@ use netpod::TS_MSP_GRID_SPACING;
 use netpod::TS_MSP_GRID_UNIT;
 use scywr::iteminsertqueue as scywriiq;
 use scywriiq::ChannelInfoItem;
+use scywriiq::ChannelMetaItem;
 use scywriiq::ChannelStatus;
 use scywriiq::ChannelStatusClosedReason;
 use scywriiq::ChannelStatusItem;
@@ -57,6 +63,9 @@ use std::time::SystemTime;
 use taskrun::tokio;
 use tokio::net::TcpStream;
 
+// How long a connection without channels may take to write the cancel and clear messages.
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_millis(2000);
+
 #[allow(unused)]
 macro_rules! trace3 {
     ($($arg:tt)*) => {
@@ -158,7 +167,7 @@ struct CreatedState {
     #[allow(unused)]
     sid: u32,
     data_type: u16,
-    data_count: u16,
+    data_count: u32,
     scalar_type: ScalarType,
     shape: Shape,
     #[allow(unused)]
@@ -174,6 +183,10 @@ struct CreatedState {
     insert_next_earliest: Instant,
     muted_before: u32,
     info_store_msp_last: u32,
+    meta_last: Option<proto::CaChannelMeta>,
+    policy: PolicyState,
+    subid: Option<u32>,
+    subid_meta: Option<u32>,
 }
 
 #[allow(unused)]
@@ -261,6 +274,8 @@ enum CaConnState {
     Listen,
     PeerReady,
     Wait(Pin<Box<dyn Future<Output = ()> + Send>>),
+    // Last channel removed, writes the pending cancel and clear messages before shutdown.
+    Closing(Pin<Box<tokio::time::Sleep>>),
     Shutdown,
     EndOfStream,
 }
@@ -310,8 +325,12 @@ fn info_store_msp_from_time(ts: SystemTime) -> u32 {
 #[derive(Debug)]
 pub enum ConnCommandKind {
     SeriesLookupResult(Result<ChannelInfoResult, dbpg::seriesbychannel::Error>),
-    ChannelAdd(String, ChannelStatusSeriesId),
+    ChannelAdd(String, ChannelStatusSeriesId, ArchivingPolicy),
     ChannelRemove(String),
+    FindChannel(String, Sender<(SocketAddrV4, Vec<String>)>),
+    ChannelState(String, Sender<Option<ChannelStateInfo>>),
+    ChannelStatesAll(Sender<Vec<ChannelStateInfo>>),
+    BeaconAnomaly,
     CheckHealth,
     Shutdown,
 }
@@ -330,10 +349,10 @@ impl ConnCommand {
         }
     }
 
-    pub fn channel_add(name: String, cssid: ChannelStatusSeriesId) -> Self {
+    pub fn channel_add(name: String, cssid: ChannelStatusSeriesId, policy: ArchivingPolicy) -> Self {
         Self {
             id: Self::make_id(),
-            kind: ConnCommandKind::ChannelAdd(name, cssid),
+            kind: ConnCommandKind::ChannelAdd(name, cssid, policy),
         }
     }
 
@@ -344,6 +363,34 @@ impl ConnCommand {
         }
     }
 
+    pub fn find_channel(pattern: String, tx: Sender<(SocketAddrV4, Vec<String>)>) -> Self {
+        Self {
+            id: Self::make_id(),
+            kind: ConnCommandKind::FindChannel(pattern, tx),
+        }
+    }
+
+    pub fn channel_state(name: String, tx: Sender<Option<ChannelStateInfo>>) -> Self {
+        Self {
+            id: Self::make_id(),
+            kind: ConnCommandKind::ChannelState(name, tx),
+        }
+    }
+
+    pub fn channel_states_all(tx: Sender<Vec<ChannelStateInfo>>) -> Self {
+        Self {
+            id: Self::make_id(),
+            kind: ConnCommandKind::ChannelStatesAll(tx),
+        }
+    }
+
+    pub fn beacon_anomaly() -> Self {
+        Self {
+            id: Self::make_id(),
+            kind: ConnCommandKind::BeaconAnomaly,
+        }
+    }
+
     pub fn check_health() -> Self {
         Self {
             id: Self::make_id(),
@@ -453,6 +500,9 @@ pub struct CaConn {
     cid_by_name: BTreeMap<String, Cid>,
     cid_by_subid: BTreeMap<u32, Cid>,
     name_by_cid: BTreeMap<Cid, String>,
+    policy_by_cid: BTreeMap<Cid, ArchivingPolicy>,
+    ioid_store: SubidStore,
+    cid_by_ioid: BTreeMap<u32, Cid>,
     insert_item_queue: VecDeque<QueryItem>,
     remote_addr_dbg: SocketAddrV4,
     local_epics_hostname: String,
@@ -501,6 +551,9 @@ impl CaConn {
             cid_by_name: BTreeMap::new(),
             cid_by_subid: BTreeMap::new(),
             name_by_cid: BTreeMap::new(),
+            policy_by_cid: BTreeMap::new(),
+            ioid_store: SubidStore::new(),
+            cid_by_ioid: BTreeMap::new(),
             insert_item_queue: VecDeque::new(),
             remote_addr_dbg,
             local_epics_hostname,
@@ -538,9 +591,18 @@ impl CaConn {
         }
     }
 
+    fn trigger_close(&mut self) {
+        if self.proto.is_some() {
+            self.state = CaConnState::Closing(Box::pin(tokio::time::sleep(CLOSE_FLUSH_TIMEOUT)));
+        } else {
+            self.trigger_shutdown(ChannelStatusClosedReason::ChannelRemove);
+        }
+    }
+
     fn trigger_shutdown(&mut self, channel_reason: ChannelStatusClosedReason) {
         self.state = CaConnState::Shutdown;
         self.proto = None;
+        self.cid_by_ioid.clear();
         self.channel_state_on_shutdown(channel_reason);
     }
 
@@ -561,7 +623,7 @@ impl CaConn {
         //self.stats.caconn_command_can_not_reply_inc();
     }
 
-    fn cmd_find_channel(&self, pattern: &str) {
+    fn cmd_find_channel(&self, pattern: &str, tx: Sender<(SocketAddrV4, Vec<String>)>) {
         let res = if let Ok(re) = regex::Regex::new(&pattern) {
             self.name_by_cid
                 .values()
@@ -571,10 +633,13 @@ impl CaConn {
         } else {
             Vec::new()
         };
-        // TODO return the result
+        let msg = (self.remote_addr_dbg.clone(), res);
+        if tx.try_send(msg).is_err() {
+            self.stats.caconn_command_can_not_reply_inc();
+        }
     }
 
-    fn cmd_channel_state(&self, name: String) {
+    fn cmd_channel_state(&self, name: String, tx: Sender<Option<ChannelStateInfo>>) {
         let res = match self.cid_by_name.get(&name) {
             Some(cid) => match self.channels.get(cid) {
                 Some(state) => Some(state.to_info(name, self.remote_addr_dbg.clone())),
@@ -582,14 +647,12 @@ impl CaConn {
             },
             None => None,
         };
-        let msg = (self.remote_addr_dbg.clone(), res);
-        if msg.1.is_some() {
-            info!("Sending back {msg:?}");
+        if tx.try_send(res).is_err() {
+            self.stats.caconn_command_can_not_reply_inc();
         }
-        // TODO return the result
     }
 
-    fn cmd_channel_states_all(&self) {
+    fn cmd_channel_states_all(&self, tx: Sender<Vec<ChannelStateInfo>>) {
         let res: Vec<_> = self
             .channels
             .iter()
@@ -601,12 +664,13 @@ impl CaConn {
                 state.to_info(name, self.remote_addr_dbg.clone())
             })
             .collect();
-        let msg = (self.remote_addr_dbg.clone(), res);
-        // TODO return the result
+        if tx.try_send(res).is_err() {
+            self.stats.caconn_command_can_not_reply_inc();
+        }
     }
 
-    fn cmd_channel_add(&mut self, name: String, cssid: ChannelStatusSeriesId) {
-        self.channel_add(name, cssid);
+    fn cmd_channel_add(&mut self, name: String, cssid: ChannelStatusSeriesId, policy: ArchivingPolicy) {
+        self.channel_add(name, cssid, policy);
         // TODO return the result
         //self.stats.caconn_command_can_not_reply_inc();
     }
@@ -617,6 +681,27 @@ impl CaConn {
         //self.stats.caconn_command_can_not_reply_inc();
     }
 
+    // The IOC has probably restarted. Reconnect without waiting for the backoff, and if we
+    // believe to be connected, find out now with an echo instead of at the next regular one.
+    fn cmd_beacon_anomaly(&mut self) {
+        debug!("cmd_beacon_anomaly {}", self.remote_addr_dbg);
+        self.backoff_reset();
+        match self.state {
+            CaConnState::Wait(_) => {
+                self.state = CaConnState::Unconnected;
+            }
+            CaConnState::PeerReady => {
+                if self.ioc_ping_start.is_none() {
+                    if let Some(proto) = &mut self.proto {
+                        self.ioc_ping_start = Some(Instant::now());
+                        proto.push_out(CaMsg { ty: CaMsgTy::Echo });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn cmd_shutdown(&mut self) {
         debug!("cmd_shutdown {}", self.remote_addr_dbg);
         self.trigger_shutdown(ChannelStatusClosedReason::ShutdownCommand);
@@ -684,14 +769,30 @@ impl CaConn {
             Ready(Some(a)) => {
                 trace!("handle_conn_command received a command  {}", self.remote_addr_dbg);
                 match a.kind {
-                    ConnCommandKind::ChannelAdd(name, cssid) => {
-                        self.cmd_channel_add(name, cssid);
+                    ConnCommandKind::ChannelAdd(name, cssid, policy) => {
+                        self.cmd_channel_add(name, cssid, policy);
                         Ready(Some(Ok(())))
                     }
                     ConnCommandKind::ChannelRemove(name) => {
                         self.cmd_channel_remove(name);
                         Ready(Some(Ok(())))
                     }
+                    ConnCommandKind::FindChannel(pattern, tx) => {
+                        self.cmd_find_channel(&pattern, tx);
+                        Ready(Some(Ok(())))
+                    }
+                    ConnCommandKind::ChannelState(name, tx) => {
+                        self.cmd_channel_state(name, tx);
+                        Ready(Some(Ok(())))
+                    }
+                    ConnCommandKind::ChannelStatesAll(tx) => {
+                        self.cmd_channel_states_all(tx);
+                        Ready(Some(Ok(())))
+                    }
+                    ConnCommandKind::BeaconAnomaly => {
+                        self.cmd_beacon_anomaly();
+                        Ready(Some(Ok(())))
+                    }
                     ConnCommandKind::CheckHealth => {
                         self.cmd_check_health();
                         Ready(Some(Ok(())))
@@ -740,16 +841,19 @@ impl CaConn {
         }
     }
 
-    pub fn channel_add(&mut self, channel: String, cssid: ChannelStatusSeriesId) {
+    pub fn channel_add(&mut self, channel: String, cssid: ChannelStatusSeriesId, policy: ArchivingPolicy) {
         Self::channel_add_expl(
-            channel,
+            channel.clone(),
             cssid,
             &mut self.channels,
             &mut self.cid_by_name,
             &mut self.name_by_cid,
             &mut self.cid_store,
             &mut self.init_state_count,
-        )
+        );
+        if let Some(cid) = self.cid_by_name.get(&channel) {
+            self.policy_by_cid.insert(*cid, policy);
+        }
     }
 
     fn channel_remove_expl(
@@ -761,9 +865,6 @@ impl CaConn {
         time_binners: &mut BTreeMap<Cid, ConnTimeBin>,
     ) {
         let cid = Self::cid_by_name_expl(&channel, cid_by_name, name_by_cid, cid_store);
-        if channels.contains_key(&cid) {
-            warn!("TODO actually cause the channel to get closed and removed  {}", channel);
-        }
         {
             let a: Vec<_> = cid_by_name
                 .iter()
@@ -776,11 +877,14 @@ impl CaConn {
         }
         channels.remove(&cid);
         name_by_cid.remove(&cid);
-        // TODO emit in-progress before drop?
         time_binners.remove(&cid);
     }
 
     pub fn channel_remove(&mut self, channel: String) {
+        if let Some(&cid) = self.cid_by_name.get(&channel) {
+            self.policy_by_cid.remove(&cid);
+            self.channel_close(cid);
+        }
         Self::channel_remove_expl(
             channel,
             &mut self.channels,
@@ -788,7 +892,70 @@ impl CaConn {
             &mut self.name_by_cid,
             &mut self.cid_store,
             &mut self.time_binners,
-        )
+        );
+        if self.channels.is_empty() && !self.is_shutdown() {
+            if let CaConnState::Closing(_) = self.state {
+            } else {
+                debug!("no channels left, close {}", self.remote_addr_dbg);
+                self.trigger_close();
+            }
+        }
+    }
+
+    // Cancels the subscriptions and clears the channel on the IOC, emits the bin in progress
+    // and records the channel as closed.
+    fn channel_close(&mut self, cid: Cid) {
+        self.cid_by_subid.retain(|_, x| *x != cid);
+        self.cid_by_ioid.retain(|_, x| *x != cid);
+        if let Some(tb) = self.time_binners.get_mut(&cid) {
+            if let Err(e) = tb.flush(&mut self.insert_item_queue) {
+                warn!("can not flush time binner on channel remove  {e}");
+            }
+        }
+        let st = match self.channels.get(&cid) {
+            Some(ChannelState::Created(series, st)) => {
+                let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                    ts: SystemTime::now(),
+                    series: series.clone(),
+                    status: ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove),
+                });
+                self.insert_item_queue.push_back(item);
+                st
+            }
+            Some(ChannelState::FetchingSeriesId(st)) => st,
+            _ => return,
+        };
+        if let Some(proto) = self.proto.as_mut() {
+            if let Some(subid) = st.subid {
+                let msg = CaMsg {
+                    ty: CaMsgTy::EventCancel(EventCancel {
+                        data_type: st.data_type + 14,
+                        data_count: st.data_count,
+                        sid: st.sid,
+                        subid,
+                    }),
+                };
+                proto.push_out(msg);
+            }
+            if let Some(subid) = st.subid_meta {
+                let msg = CaMsg {
+                    ty: CaMsgTy::EventCancel(EventCancel {
+                        data_type: st.data_type + 28,
+                        data_count: 1,
+                        sid: st.sid,
+                        subid,
+                    }),
+                };
+                proto.push_out(msg);
+            }
+            let msg = CaMsg {
+                ty: CaMsgTy::ClearChannel(ClearChannel {
+                    sid: st.sid,
+                    cid: cid.0,
+                }),
+            };
+            proto.push_out(msg);
+        }
     }
 
     fn cid_by_name_expl(
@@ -854,27 +1021,25 @@ impl CaConn {
     fn check_channels_alive(&mut self) -> Result<(), Error> {
         let tsnow = Instant::now();
         trace!("check_channels_alive  {addr:?}", addr = &self.remote_addr_dbg);
-        if self.ioc_ping_last.elapsed() > Duration::from_millis(20000) {
-            if let Some(started) = self.ioc_ping_start {
-                if started.elapsed() > Duration::from_millis(4000) {
-                    warn!("pong timeout {addr:?}", addr = self.remote_addr_dbg);
-                    let item = CaConnEvent {
-                        ts: Instant::now(),
-                        value: CaConnEventValue::EchoTimeout,
-                    };
-                    self.ca_conn_event_out_queue.push_back(item);
-                    self.trigger_shutdown(ChannelStatusClosedReason::IocTimeout);
-                }
+        if let Some(started) = self.ioc_ping_start {
+            if started.elapsed() > Duration::from_millis(4000) {
+                warn!("pong timeout {addr:?}", addr = self.remote_addr_dbg);
+                let item = CaConnEvent {
+                    ts: Instant::now(),
+                    value: CaConnEventValue::EchoTimeout,
+                };
+                self.ca_conn_event_out_queue.push_back(item);
+                self.trigger_shutdown(ChannelStatusClosedReason::IocTimeout);
+            }
+        } else if self.ioc_ping_last.elapsed() > Duration::from_millis(20000) {
+            self.ioc_ping_start = Some(Instant::now());
+            if let Some(proto) = &mut self.proto {
+                debug!("ping to {}", self.remote_addr_dbg);
+                let msg = CaMsg { ty: CaMsgTy::Echo };
+                proto.push_out(msg);
             } else {
-                self.ioc_ping_start = Some(Instant::now());
-                if let Some(proto) = &mut self.proto {
-                    debug!("ping to {}", self.remote_addr_dbg);
-                    let msg = CaMsg { ty: CaMsgTy::Echo };
-                    proto.push_out(msg);
-                } else {
-                    warn!("can not ping {}  no proto", self.remote_addr_dbg);
-                    self.trigger_shutdown(ChannelStatusClosedReason::NoProtocol);
-                }
+                warn!("can not ping {}  no proto", self.remote_addr_dbg);
+                self.trigger_shutdown(ChannelStatusClosedReason::NoProtocol);
             }
         }
         let mut alive_count = 0;
@@ -950,11 +1115,12 @@ impl CaConn {
         cid: Cid,
         sid: u32,
         data_type: u16,
-        data_count: u16,
+        data_count: u32,
         series: SeriesId,
     ) -> Result<(), Error> {
         let tsnow = Instant::now();
         self.stats.get_series_id_ok_inc();
+        let policy = self.policy_by_cid.get(&cid).cloned().unwrap_or_default();
         if series.id() == 0 {
             warn!("Weird series id: {series:?}");
         }
@@ -963,24 +1129,44 @@ impl CaConn {
         }
         // TODO handle error better! Transition channel to Error state?
         let scalar_type = ScalarType::from_ca_id(data_type)?;
-        let shape = Shape::from_ca_count(data_count)?;
+        let shape = proto::shape_from_ca_count(data_count).map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
         let mut tb = ConnTimeBin::empty();
         tb.setup_for(series.clone(), &scalar_type, &shape)?;
         self.time_binners.insert(cid, tb);
-        let subid = self.subid_store.next();
-        self.cid_by_subid.insert(subid, cid);
-        let name = self.name_by_cid(cid).unwrap().to_string();
-        // TODO convert first to CaDbrType, set to `Time`, then convert to ix:
-        let data_type_asked = data_type + 14;
+        let proto = self.proto.as_mut().unwrap();
+        // Scanned channels get their values through ReadNotify instead.
+        let subid = if policy.is_scan() {
+            None
+        } else {
+            let subid = self.subid_store.next();
+            self.cid_by_subid.insert(subid, cid);
+            // TODO convert first to CaDbrType, set to `Time`, then convert to ix:
+            let data_type_asked = data_type + 14;
+            let msg = CaMsg {
+                ty: CaMsgTy::EventAdd(EventAdd {
+                    sid,
+                    data_type: data_type_asked,
+                    data_count,
+                    subid,
+                    mask: proto::DBE_VALUE | proto::DBE_LOG | proto::DBE_ALARM,
+                }),
+            };
+            proto.push_out(msg);
+            Some(subid)
+        };
+        // Subscribe to property changes with the DBR_CTRL type to learn units, limits and enum strings.
+        // We only need the metadata, therefore ask for a single element.
+        let subid_meta = self.subid_store.next();
+        self.cid_by_subid.insert(subid_meta, cid);
         let msg = CaMsg {
             ty: CaMsgTy::EventAdd(EventAdd {
                 sid,
-                data_type: data_type_asked,
-                data_count,
-                subid,
+                data_type: data_type + 28,
+                data_count: 1,
+                subid: subid_meta,
+                mask: proto::DBE_PROPERTY,
             }),
         };
-        let proto = self.proto.as_mut().unwrap();
         proto.push_out(msg);
         // TODO handle not-found error:
         let ch_s = self.channels.get_mut(&cid).unwrap();
@@ -1012,6 +1198,10 @@ impl CaConn {
             insert_next_earliest: tsnow,
             muted_before: 0,
             info_store_msp_last: info_store_msp_from_time(SystemTime::now()),
+            meta_last: None,
+            policy: PolicyState::new(policy),
+            subid,
+            subid_meta: Some(subid_meta),
         };
         *ch_s = ChannelState::Created(series, created_state);
         Ok(())
@@ -1056,6 +1246,8 @@ impl CaConn {
             shape,
             val: ev.value.data.into(),
             ts_msp_grid,
+            status: ev.value.status.map_or(0, |x| x.get()),
+            severity: ev.value.severity.map_or(0, |x| x.get()),
         };
         item_queue.push_back(QueryItem::Insert(item));
         stats.insert_item_create_inc();
@@ -1127,8 +1319,36 @@ impl CaConn {
     }
 
     fn handle_event_add_res(&mut self, ev: proto::EventAddRes, tsnow: Instant) -> Result<(), Error> {
-        // TODO handle subid-not-found which can also be peer error:
-        let cid = *self.cid_by_subid.get(&ev.subid).unwrap();
+        let cid = match self.cid_by_subid.get(&ev.subid) {
+            Some(x) => *x,
+            None => {
+                // Can still arrive for a subscription which we have just cancelled.
+                debug!("EventAddRes for unknown subid {}", ev.subid);
+                return Ok(());
+            }
+        };
+        self.handle_channel_value(cid, ev, tsnow)
+    }
+
+    fn handle_read_notify_res(&mut self, res: proto::ReadNotifyRes, tsnow: Instant) -> Result<(), Error> {
+        let cid = match self.cid_by_ioid.remove(&res.ioid) {
+            Some(x) => x,
+            None => {
+                warn!("ReadNotifyRes for unknown ioid {}", res.ioid);
+                return Ok(());
+            }
+        };
+        let ev = proto::EventAddRes {
+            data_type: res.data_type,
+            data_count: res.data_count,
+            status: res.sid,
+            subid: 0,
+            value: res.value,
+        };
+        self.handle_channel_value(cid, ev, tsnow)
+    }
+
+    fn handle_channel_value(&mut self, cid: Cid, ev: proto::EventAddRes, tsnow: Instant) -> Result<(), Error> {
         if false {
             let name = self.name_by_cid(cid);
             info!("event {name:?} {ev:?}");
@@ -1223,20 +1443,24 @@ impl CaConn {
                         },
                         _ => {}
                     }
-                    Self::do_event_insert(
-                        st,
-                        series,
-                        scalar_type,
-                        shape,
-                        ts,
-                        ev,
-                        tsnow,
-                        item_queue,
-                        self.insert_ivl_min_mus,
-                        self.stats.clone(),
-                        inserts_counter,
-                        extra_inserts_conf,
-                    )?;
+                    if st.policy.accept(&ev.value, tsnow) {
+                        Self::do_event_insert(
+                            st,
+                            series,
+                            scalar_type,
+                            shape,
+                            ts,
+                            ev,
+                            tsnow,
+                            item_queue,
+                            self.insert_ivl_min_mus,
+                            self.stats.clone(),
+                            inserts_counter,
+                            extra_inserts_conf,
+                        )?;
+                    } else {
+                        self.stats.channel_policy_drop_inc();
+                    }
                 } else {
                     self.stats.channel_fast_item_drop_inc();
                     if tsnow.duration_since(st.insert_recv_ivl_last) >= Duration::from_millis(10000) {
@@ -1271,6 +1495,48 @@ impl CaConn {
         Ok(())
     }
 
+    fn handle_event_add_res_meta(&mut self, ev: proto::EventAddResMeta, tsnow: Instant) -> Result<(), Error> {
+        let cid = match self.cid_by_subid.get(&ev.subid) {
+            Some(x) => *x,
+            None => {
+                warn!("EventAddResMeta for unknown subid {}", ev.subid);
+                return Ok(());
+            }
+        };
+        match self.channels.get_mut(&cid) {
+            Some(ChannelState::Created(series, st)) => {
+                st.ts_alive_last = tsnow;
+                if st.meta_last.as_ref() != Some(&ev.meta) {
+                    let m = ev.meta;
+                    let item = ChannelMetaItem {
+                        ts: SystemTime::now(),
+                        series: series.clone(),
+                        units: m.units.clone(),
+                        precision: m.precision,
+                        upper_disp_limit: m.upper_disp_limit,
+                        lower_disp_limit: m.lower_disp_limit,
+                        upper_alarm_limit: m.upper_alarm_limit,
+                        upper_warning_limit: m.upper_warning_limit,
+                        lower_warning_limit: m.lower_warning_limit,
+                        lower_alarm_limit: m.lower_alarm_limit,
+                        upper_ctrl_limit: m.upper_ctrl_limit,
+                        lower_ctrl_limit: m.lower_ctrl_limit,
+                        enum_strs: m.enum_strs.clone(),
+                    };
+                    self.insert_item_queue.push_back(QueryItem::ChannelMeta(item));
+                    st.meta_last = Some(m);
+                }
+            }
+            Some(ch_s) => {
+                warn!("unexpected state: EventAddResMeta while having {ch_s:?}");
+            }
+            None => {
+                warn!("EventAddResMeta for unknown cid {cid:?}");
+            }
+        }
+        Ok(())
+    }
+
     /*
     Acts more like a stream? Can be:
     Pending
@@ -1408,7 +1674,10 @@ impl CaConn {
                                     error!("CreateChanRes with unexpected data_type {}", k.data_type);
                                 }
                                 let scalar_type = ScalarType::from_ca_id(k.data_type)?;
-                                let shape = Shape::from_ca_count(k.data_count)?;
+                                let shape = match proto::shape_from_ca_count(k.data_count) {
+                                    Ok(x) => x,
+                                    Err(e) => return Ready(Some(Err(Error::with_msg_no_trace(e.to_string())))),
+                                };
                                 // TODO handle not-found error:
                                 let ch_s = self.channels.get_mut(&cid).unwrap();
                                 let cssid = match ch_s {
@@ -1438,6 +1707,10 @@ impl CaConn {
                                     insert_next_earliest: tsnow,
                                     muted_before: 0,
                                     info_store_msp_last: info_store_msp_from_time(SystemTime::now()),
+                                    meta_last: None,
+                                    policy: PolicyState::new(self.policy_by_cid.get(&cid).cloned().unwrap_or_default()),
+                                    subid: None,
+                                    subid_meta: None,
                                 };
                                 *ch_s = ChannelState::FetchingSeriesId(created_state);
                                 // TODO handle error in different way. Should most likely not abort.
@@ -1466,6 +1739,21 @@ impl CaConn {
                                 let _ = ts1;
                                 res?
                             }
+                            CaMsgTy::EventAddResMeta(k) => {
+                                trace!("got EventAddResMeta: {k:?}");
+                                Self::handle_event_add_res_meta(self, k, tsnow)?
+                            }
+                            CaMsgTy::ReadNotifyRes(k) => {
+                                trace!("got ReadNotifyRes: {k:?}");
+                                self.stats.caconn_recv_data_inc();
+                                Self::handle_read_notify_res(self, k, tsnow)?
+                            }
+                            CaMsgTy::EventCancelRes(k) => {
+                                trace!("got EventCancelRes: {k:?}");
+                            }
+                            CaMsgTy::ClearChannelRes(k) => {
+                                trace!("got ClearChannelRes: {k:?}");
+                            }
                             CaMsgTy::Error(e) => {
                                 warn!("channel access error message {e:?}");
                             }
@@ -1630,6 +1918,29 @@ impl CaConn {
                 }
                 Pending => Ok(Some(Pending)),
             },
+            CaConnState::Closing(timeout) => {
+                let timed_out = timeout.poll_unpin(cx).is_ready();
+                let flushed = match self.proto.as_mut() {
+                    Some(proto) => match Pin::new(proto).poll_flush_out(cx) {
+                        Ready(Ok(())) => true,
+                        Ready(Err(e)) => {
+                            debug!("flush on close {}  {e}", self.remote_addr_dbg);
+                            true
+                        }
+                        Pending => false,
+                    },
+                    None => true,
+                };
+                if timed_out && !flushed {
+                    warn!("close {}  could not write cancel and clear in time", self.remote_addr_dbg);
+                }
+                if flushed || timed_out {
+                    self.trigger_shutdown(ChannelStatusClosedReason::ChannelRemove);
+                    Ok(None)
+                } else {
+                    Ok(Some(Pending))
+                }
+            }
             CaConnState::Shutdown => Ok(None),
             CaConnState::EndOfStream => Ok(None),
         }
@@ -1724,9 +2035,35 @@ impl CaConn {
             let iiq = &mut this.insert_item_queue;
             tb.tick(iiq)?;
         }
+        this.emit_scan_reads(Instant::now());
         Ok(())
     }
 
+    fn emit_scan_reads(&mut self, tsnow: Instant) {
+        let proto = match self.proto.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        for (cid, chst) in self.channels.iter_mut() {
+            if let ChannelState::Created(_, st) = chst {
+                if st.policy.scan_due(tsnow) {
+                    let ioid = self.ioid_store.next();
+                    self.cid_by_ioid.insert(ioid, *cid);
+                    let msg = CaMsg {
+                        ty: CaMsgTy::ReadNotify(ReadNotify {
+                            data_type: st.data_type + 14,
+                            data_count: st.data_count,
+                            sid: st.sid,
+                            ioid,
+                        }),
+                    };
+                    proto.push_out(msg);
+                    self.stats.caconn_scan_read_inc();
+                }
+            }
+        }
+    }
+
     fn queues_async_out_flushed(&self) -> bool {
         self.channel_info_query_queue.is_empty() && self.channel_info_query_sending.is_idle()
     }
@@ -1,10 +1,15 @@
+use super::beacon::start_beacon_listener;
+use super::beacon::BeaconAnomaly;
+use super::finder::FinderSource;
 use super::findioc::FindIocRes;
+use super::policy::ArchivingPolicy;
 use super::statemap;
 use super::statemap::ChannelState;
 use crate::ca::conn::CaConn;
 use crate::ca::conn::CaConnEvent;
 use crate::ca::conn::CaConnEventValue;
 use crate::ca::conn::CaConnOpts;
+use crate::ca::conn::ChannelStateInfo;
 use crate::ca::conn::ConnCommand;
 use crate::ca::statemap::CaConnState;
 use crate::ca::statemap::ConnectionState;
@@ -25,7 +30,6 @@ use err::Error;
 use futures_util::FutureExt;
 use futures_util::StreamExt;
 use log::*;
-use netpod::Database;
 use netpod::Shape;
 use scywr::iteminsertqueue::ChannelStatusItem;
 use scywr::iteminsertqueue::QueryItem;
@@ -60,6 +64,7 @@ const UNKNOWN_ADDRESS_STAY: Duration = Duration::from_millis(2000);
 const NO_ADDRESS_STAY: Duration = Duration::from_millis(20000);
 const SEARCH_PENDING_TIMEOUT: Duration = Duration::from_millis(30000);
 const SEARCH_PENDING_TIMEOUT_WARN: Duration = Duration::from_millis(8000);
+const CA_CONN_REPLY_TIMEOUT: Duration = Duration::from_millis(2000);
 
 // TODO put all these into metrics
 static SEARCH_REQ_MARK_COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -107,6 +112,7 @@ pub struct ChannelAdd {
     backend: String,
     name: String,
     local_epics_hostname: String,
+    policy: ArchivingPolicy,
 }
 
 #[derive(Debug, Clone)]
@@ -122,6 +128,11 @@ pub enum ConnSetCmd {
     ChannelAddWithAddr(ChannelAddWithAddr),
     ChannelRemove(ChannelRemove),
     IocAddrQueryResult(VecDeque<FindIocRes>),
+    FindChannel(String, Sender<Vec<(String, Vec<String>)>>),
+    ChannelState(String, Sender<Option<ChannelStateInfo>>),
+    ChannelStatesAll(Sender<Vec<ChannelStateInfo>>),
+    ChannelPoliciesAll(Sender<BTreeMap<String, ArchivingPolicy>>),
+    BeaconAnomaly(BeaconAnomaly),
     CheckHealth,
     Shutdown,
 }
@@ -148,11 +159,18 @@ impl CaConnSetCtrl {
         self.rx.clone()
     }
 
-    pub async fn add_channel(&self, backend: String, name: String, local_epics_hostname: String) -> Result<(), Error> {
+    pub async fn add_channel(
+        &self,
+        backend: String,
+        name: String,
+        local_epics_hostname: String,
+        policy: ArchivingPolicy,
+    ) -> Result<(), Error> {
         let cmd = ChannelAdd {
             backend,
             name,
             local_epics_hostname,
+            policy,
         };
         let cmd = ConnSetCmd::ChannelAdd(cmd);
         self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
@@ -166,6 +184,30 @@ impl CaConnSetCtrl {
         Ok(())
     }
 
+    pub async fn find_channel(&self, pattern: String, tx: Sender<Vec<(String, Vec<String>)>>) -> Result<(), Error> {
+        let cmd = ConnSetCmd::FindChannel(pattern, tx);
+        self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
+        Ok(())
+    }
+
+    pub async fn channel_state(&self, name: String, tx: Sender<Option<ChannelStateInfo>>) -> Result<(), Error> {
+        let cmd = ConnSetCmd::ChannelState(name, tx);
+        self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
+        Ok(())
+    }
+
+    pub async fn channel_states_all(&self, tx: Sender<Vec<ChannelStateInfo>>) -> Result<(), Error> {
+        let cmd = ConnSetCmd::ChannelStatesAll(tx);
+        self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
+        Ok(())
+    }
+
+    pub async fn channel_policies_all(&self, tx: Sender<BTreeMap<String, ArchivingPolicy>>) -> Result<(), Error> {
+        let cmd = ConnSetCmd::ChannelPoliciesAll(tx);
+        self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
+        Ok(())
+    }
+
     pub async fn shutdown(&self) -> Result<(), Error> {
         let cmd = ConnSetCmd::Shutdown;
         self.tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await?;
@@ -211,6 +253,7 @@ pub struct CaConnSet {
     search_tx: Sender<IocAddrQuery>,
     ca_conn_ress: BTreeMap<SocketAddr, CaConnRes>,
     channel_states: ChannelStateMap,
+    channel_policies: BTreeMap<Channel, ArchivingPolicy>,
     connset_tx: Sender<CaConnSetEvent>,
     connset_rx: Receiver<CaConnSetEvent>,
     channel_info_query_tx: Sender<ChannelInfoQuery>,
@@ -221,6 +264,7 @@ pub struct CaConnSet {
     stats: CaConnSetStats,
     connset_out_tx: Sender<CaConnSetItem>,
     ioc_finder_jh: JoinHandle<Result<(), Error>>,
+    beacon_jh: Option<JoinHandle<()>>,
 }
 
 impl CaConnSet {
@@ -229,17 +273,21 @@ impl CaConnSet {
         local_epics_hostname: String,
         storage_insert_tx: Sender<QueryItem>,
         channel_info_query_tx: Sender<ChannelInfoQuery>,
-        pgconf: Database,
+        finder_source: FinderSource,
+        beacon_port: Option<u16>,
     ) -> CaConnSetCtrl {
         let (connset_out_tx, connset_out_rx) = async_channel::bounded(256);
         let (connset_tx, connset_rx) = async_channel::bounded(10000);
-        let (search_tx, ioc_finder_jh) = super::finder::start_finder(connset_tx.clone(), backend.clone(), pgconf);
+        let (search_tx, ioc_finder_jh) =
+            super::finder::start_finder(connset_tx.clone(), backend.clone(), finder_source);
+        let beacon_jh = beacon_port.map(|port| start_beacon_listener(connset_tx.clone(), port));
         let connset = Self {
             backend,
             local_epics_hostname,
             search_tx,
             ca_conn_ress: BTreeMap::new(),
             channel_states: ChannelStateMap::new(),
+            channel_policies: BTreeMap::new(),
             connset_tx: connset_tx.clone(),
             connset_rx,
             channel_info_query_tx,
@@ -250,6 +298,7 @@ impl CaConnSet {
             stats: CaConnSetStats::new(),
             connset_out_tx,
             ioc_finder_jh,
+            beacon_jh,
         };
         // TODO await on jh
         let jh = tokio::spawn(CaConnSet::run(connset));
@@ -287,6 +336,9 @@ impl CaConnSet {
             .await
             .map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
         debug!("joined ioc_finder_jh");
+        if let Some(jh) = this.beacon_jh.take() {
+            jh.abort();
+        }
         this.connset_out_tx.close();
         this.connset_rx.close();
         this.shutdown_done = true;
@@ -302,6 +354,11 @@ impl CaConnSet {
                 ConnSetCmd::ChannelRemove(x) => self.handle_remove_channel(x).await,
                 ConnSetCmd::IocAddrQueryResult(x) => self.handle_ioc_query_result(x).await,
                 ConnSetCmd::SeriesLookupResult(x) => self.handle_series_lookup_result(x).await,
+                ConnSetCmd::FindChannel(pattern, tx) => self.handle_find_channel(pattern, tx).await,
+                ConnSetCmd::ChannelState(name, tx) => self.handle_channel_state(name, tx).await,
+                ConnSetCmd::ChannelStatesAll(tx) => self.handle_channel_states_all(tx).await,
+                ConnSetCmd::ChannelPoliciesAll(tx) => self.handle_channel_policies_all(tx).await,
+                ConnSetCmd::BeaconAnomaly(x) => self.handle_beacon_anomaly(x).await,
                 ConnSetCmd::CheckHealth => self.handle_check_health().await,
                 ConnSetCmd::Shutdown => self.handle_shutdown().await,
             },
@@ -349,11 +406,18 @@ impl CaConnSet {
         }
         // TODO should I add the transition through ActiveChannelState::Init as well?
         let ch = Channel::new(add.name.clone());
-        let _st = self.channel_states.inner().entry(ch).or_insert_with(|| ChannelState {
+        self.channel_policies.insert(ch.clone(), add.policy);
+        let st = self.channel_states.inner().entry(ch).or_insert_with(|| ChannelState {
             value: ChannelStateValue::Active(ActiveChannelState::WaitForStatusSeriesId {
                 since: SystemTime::now(),
             }),
         });
+        // Added again after a remove, e.g. on a policy change during a reload.
+        if let ChannelStateValue::ToRemove { .. } = st.value {
+            st.value = ChannelStateValue::Active(ActiveChannelState::WaitForStatusSeriesId {
+                since: SystemTime::now(),
+            });
+        }
         let item = ChannelInfoQuery {
             backend: add.backend,
             channel: add.name,
@@ -409,13 +473,19 @@ impl CaConnSet {
             self.ca_conn_ress.insert(add.addr, c);
         }
         let conn_ress = self.ca_conn_ress.get_mut(&add.addr).unwrap();
-        let cmd = ConnCommand::channel_add(add.name, add.cssid);
+        let policy = self
+            .channel_policies
+            .get(&Channel::new(add.name.clone()))
+            .cloned()
+            .unwrap_or_default();
+        let cmd = ConnCommand::channel_add(add.name, add.cssid, policy);
         conn_ress.sender.send(cmd).await?;
         Ok(())
     }
 
     async fn handle_remove_channel(&mut self, add: ChannelRemove) -> Result<(), Error> {
         let ch = Channel::new(add.name);
+        self.channel_policies.remove(&ch);
         if let Some(k) = self.channel_states.inner().get_mut(&ch) {
             match &k.value {
                 ChannelStateValue::Active(j) => match j {
@@ -436,6 +506,10 @@ impl CaConnSet {
                             k.value = ChannelStateValue::ToRemove { addr: None };
                         }
                         WithStatusSeriesIdStateInner::WithAddress { addr, state: _ } => {
+                            if let Some(res) = self.ca_conn_ress.get(&SocketAddr::V4(*addr)) {
+                                let cmd = ConnCommand::channel_remove(ch.id().into());
+                                res.sender.send(cmd).await?;
+                            }
                             k.value = ChannelStateValue::ToRemove {
                                 addr: Some(addr.clone()),
                             };
@@ -496,6 +570,134 @@ impl CaConnSet {
         Ok(())
     }
 
+    async fn handle_find_channel(
+        &mut self,
+        pattern: String,
+        tx: Sender<Vec<(String, Vec<String>)>>,
+    ) -> Result<(), Error> {
+        let n = self.ca_conn_ress.len();
+        let (tx2, rx2) = async_channel::bounded(n.max(1));
+        let mut sent = 0;
+        for (_, res) in self.ca_conn_ress.iter() {
+            let cmd = ConnCommand::find_channel(pattern.clone(), tx2.clone());
+            if self.try_send_conn_cmd(res, cmd) {
+                sent += 1;
+            }
+        }
+        tokio::spawn(async move {
+            let res = Self::collect_replies(rx2, sent)
+                .await
+                .into_iter()
+                .filter(|x| x.1.len() != 0)
+                .map(|(addr, names)| (addr.to_string(), names))
+                .collect();
+            tx.send(res).await.ok();
+        });
+        Ok(())
+    }
+
+    async fn handle_channel_state(&mut self, name: String, tx: Sender<Option<ChannelStateInfo>>) -> Result<(), Error> {
+        let ch = Channel::new(name.clone());
+        let addr = match self.channel_states.inner().get(&ch).map(|x| &x.value) {
+            Some(ChannelStateValue::Active(ActiveChannelState::WithStatusSeriesId { state, .. })) => {
+                match &state.inner {
+                    WithStatusSeriesIdStateInner::WithAddress { addr, .. } => Some(SocketAddr::V4(*addr)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match addr.as_ref().and_then(|addr| self.ca_conn_ress.get(addr)) {
+            Some(res) => {
+                let cmd = ConnCommand::channel_state(name, tx.clone());
+                if !self.try_send_conn_cmd(res, cmd) {
+                    tx.send(None).await.ok();
+                }
+            }
+            None => {
+                tx.send(None).await.ok();
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_channel_states_all(&mut self, tx: Sender<Vec<ChannelStateInfo>>) -> Result<(), Error> {
+        let n = self.ca_conn_ress.len();
+        let (tx2, rx2) = async_channel::bounded(n.max(1));
+        let mut sent = 0;
+        for (_, res) in self.ca_conn_ress.iter() {
+            let cmd = ConnCommand::channel_states_all(tx2.clone());
+            if self.try_send_conn_cmd(res, cmd) {
+                sent += 1;
+            }
+        }
+        tokio::spawn(async move {
+            let res = Self::collect_replies(rx2, sent).await.into_iter().flatten().collect();
+            tx.send(res).await.ok();
+        });
+        Ok(())
+    }
+
+    async fn handle_channel_policies_all(
+        &mut self,
+        tx: Sender<BTreeMap<String, ArchivingPolicy>>,
+    ) -> Result<(), Error> {
+        let res = self
+            .channel_policies
+            .iter()
+            .map(|(ch, policy)| (ch.id().to_string(), policy.clone()))
+            .collect();
+        tx.send(res).await.ok();
+        Ok(())
+    }
+
+    // A restarted or new IOC may serve channels which we could not find so far.
+    async fn handle_beacon_anomaly(&mut self, anomaly: BeaconAnomaly) -> Result<(), Error> {
+        debug!("handle_beacon_anomaly {anomaly:?}");
+        self.stats.beacon_anomaly_inc();
+        let tsnow = SystemTime::now();
+        for (_, st) in self.channel_states.inner().iter_mut() {
+            if let ChannelStateValue::Active(ActiveChannelState::WithStatusSeriesId { state, .. }) = &mut st.value {
+                if let WithStatusSeriesIdStateInner::NoAddress { .. } = state.inner {
+                    state.inner = WithStatusSeriesIdStateInner::UnknownAddress { since: tsnow };
+                    self.stats.beacon_anomaly_research_inc();
+                }
+            }
+        }
+        if let Some(res) = self.ca_conn_ress.get(&SocketAddr::V4(anomaly.addr)) {
+            self.try_send_conn_cmd(res, ConnCommand::beacon_anomaly());
+        }
+        Ok(())
+    }
+
+    // For commands which may get lost: a CaConn with a full command queue must not stall the set.
+    fn try_send_conn_cmd(&self, res: &CaConnRes, cmd: ConnCommand) -> bool {
+        match res.sender.try_send(cmd) {
+            Ok(()) => true,
+            Err(e) => {
+                self.stats.ca_conn_cmd_drop_inc();
+                debug!("command to CaConn dropped {e}");
+                false
+            }
+        }
+    }
+
+    async fn collect_replies<T>(rx: Receiver<T>, n: usize) -> Vec<T> {
+        let deadline = tokio::time::Instant::now() + CA_CONN_REPLY_TIMEOUT;
+        let mut ret = Vec::with_capacity(n);
+        while ret.len() < n {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Ok(x)) => ret.push(x),
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    warn!("collect_replies  timeout  got {} of {}", ret.len(), n);
+                    break;
+                }
+            }
+        }
+        ret
+    }
+
     async fn handle_check_health(&mut self) -> Result<(), Error> {
         debug!("TODO handle_check_health");
         let item = CaConnSetItem::Healthy;
@@ -4,7 +4,6 @@ use super::connset::CURRENT_SEARCH_PENDING_MAX;
 use super::connset::SEARCH_BATCH_MAX;
 use crate::ca::findioc::FindIocRes;
 use crate::ca::findioc::FindIocStream;
-use crate::daemon_common::DaemonEvent;
 use async_channel::Receiver;
 use async_channel::Sender;
 use dbpg::conn::make_pg_client;
@@ -214,14 +213,31 @@ async fn finder_worker(
     Ok(())
 }
 
+// Where the addresses of the IOCs are looked up.
+#[derive(Debug, Clone)]
+pub enum FinderSource {
+    // The ioc_by_channel_log table, filled by the search process.
+    Database(Database),
+    // Channel Access search over UDP to the given targets and over TCP to the name servers.
+    Search {
+        udp: Vec<SocketAddrV4>,
+        name_servers: Vec<SocketAddrV4>,
+    },
+}
+
 pub fn start_finder(
     tx: Sender<CaConnSetEvent>,
     backend: String,
-    db: Database,
+    source: FinderSource,
 ) -> (Sender<IocAddrQuery>, JoinHandle<Result<(), Error>>) {
-    let (qtx, qrx) = async_channel::bounded(CURRENT_SEARCH_PENDING_MAX);
-    let jh = taskrun::spawn(finder_worker(qrx, tx, backend, db));
-    (qtx, jh)
+    match source {
+        FinderSource::Database(db) => {
+            let (qtx, qrx) = async_channel::bounded(CURRENT_SEARCH_PENDING_MAX);
+            let jh = taskrun::spawn(finder_worker(qrx, tx, backend, db));
+            (qtx, jh)
+        }
+        FinderSource::Search { udp, name_servers } => start_finder_ca(tx, udp, name_servers),
+    }
 }
 
 struct OptFut<F> {
@@ -256,37 +272,40 @@ where
     }
 }
 
-#[allow(unused)]
-fn start_finder_ca(tx: Sender<DaemonEvent>, tgts: Vec<SocketAddrV4>) -> (Sender<String>, JoinHandle<()>) {
-    let (qtx, qrx) = async_channel::bounded(32);
-    let (atx, arx) = async_channel::bounded(32);
+fn start_finder_ca(
+    tx: Sender<CaConnSetEvent>,
+    tgts: Vec<SocketAddrV4>,
+    name_servers: Vec<SocketAddrV4>,
+) -> (Sender<IocAddrQuery>, JoinHandle<Result<(), Error>>) {
+    let (qtx, qrx) = async_channel::bounded(CURRENT_SEARCH_PENDING_MAX);
     let ioc_finder_fut = async move {
         let mut finder = FindIocStream::new(tgts, FINDER_TIMEOUT, FINDER_IN_FLIGHT_MAX, FINDER_BATCH_SIZE);
+        finder.set_name_servers(name_servers);
         let fut_tick_dur = Duration::from_millis(100);
         let mut finder_more = true;
         let mut finder_fut = OptFut::new(finder.next());
         let mut qrx_fut = OptFut::new(qrx.recv());
-        let mut qrx_more = true;
         let mut fut_tick = Box::pin(tokio::time::sleep(fut_tick_dur));
-        let mut asend = OptFut::empty();
         loop {
             tokio::select! {
-                _ = &mut asend, if asend.is_enabled() => {
-                    asend = OptFut::empty();
-                }
                 r1 = &mut finder_fut, if finder_fut.is_enabled() => {
                     finder_fut = OptFut::empty();
                     match r1 {
-                        Some(item) => {
-                            asend = OptFut::new(atx.send(item));
+                        Some(Ok(items)) => {
+                            let cmd = crate::ca::connset::ConnSetCmd::IocAddrQueryResult(items);
+                            if tx.send(CaConnSetEvent::ConnSetCmd(cmd)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Err(e)) => {
+                            error!("finder error {e}");
                         }
                         None => {
-                            // TODO finder has stopped, do no longer poll on it
                             warn!("Finder has stopped");
                             finder_more = false;
                         }
                     }
-                    if qrx_more && finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
+                    if finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
                         qrx_fut = OptFut::new(qrx.recv());
                     }
                     if finder_more {
@@ -298,15 +317,14 @@ fn start_finder_ca(tx: Sender<DaemonEvent>, tgts: Vec<SocketAddrV4>) -> (Sender<
                     qrx_fut = OptFut::empty();
                     match r2 {
                         Ok(item) => {
-                            finder.push(item);
+                            finder.push(item.name);
                         }
-                        Err(e) => {
-                            // TODO input is done... ignore from here on.
-                            error!("Finder input channel error {e}");
-                            qrx_more = false;
+                        Err(_) => {
+                            // Input closed on shutdown.
+                            break;
                         }
                     }
-                    if qrx_more && finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
+                    if finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
                         qrx_fut = OptFut::new(qrx.recv());
                     }
                     if finder_more {
@@ -317,7 +335,7 @@ fn start_finder_ca(tx: Sender<DaemonEvent>, tgts: Vec<SocketAddrV4>) -> (Sender<
                     fut_tick = Box::pin(tokio::time::sleep(fut_tick_dur));
                 }
                 _ = &mut fut_tick => {
-                    if qrx_more && finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
+                    if finder.job_queue_len() < FINDER_JOB_QUEUE_LEN_MAX {
                         qrx_fut = OptFut::new(qrx.recv());
                     }
                     if finder_more {
@@ -333,15 +351,8 @@ fn start_finder_ca(tx: Sender<DaemonEvent>, tgts: Vec<SocketAddrV4>) -> (Sender<
                 }
             };
         }
+        Ok(())
     };
     let ioc_finder_jh = taskrun::spawn(ioc_finder_fut);
-    taskrun::spawn({
-        async move {
-            while let Ok(item) = arx.recv().await {
-                todo!("send the result item");
-            }
-            warn!("search res fwd inp closed");
-        }
-    });
     (qtx, ioc_finder_jh)
 }
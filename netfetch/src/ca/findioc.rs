@@ -1,12 +1,16 @@
 use crate::ca::proto::CaMsg;
 use crate::ca::proto::CaMsgTy;
 use crate::ca::proto::HeadInfo;
+use async_channel::Receiver;
+use async_channel::Sender;
 use err::Error;
 use futures_util::Future;
 use futures_util::FutureExt;
 use futures_util::Stream;
+use futures_util::StreamExt;
 use libc::c_int;
 use log::*;
+use slidebuf::SlideBuf;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::net::Ipv4Addr;
@@ -20,6 +24,13 @@ use std::time::Duration;
 use std::time::Instant;
 use taskrun::tokio;
 use tokio::io::unix::AsyncFd;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+const NAME_SERVER_CONNECT_TIMEOUT: Duration = Duration::from_millis(2000);
+const NAME_SERVER_RECONNECT_DELAY: Duration = Duration::from_millis(2000);
 
 struct SockBox(c_int);
 
@@ -52,6 +63,98 @@ struct SearchBatch {
     done: Vec<bool>,
 }
 
+type SearchResults = (SocketAddrV4, Vec<(SearchId, SocketAddrV4)>);
+
+// Persistent TCP connection to a name server, for example a CA gateway,
+// which gets the same search batches as the UDP targets.
+struct NameServerConn {
+    tx: Sender<Vec<u8>>,
+    jh: JoinHandle<()>,
+}
+
+impl NameServerConn {
+    fn start(addr: SocketAddrV4, restx: Sender<SearchResults>) -> Self {
+        let (tx, rx) = async_channel::bounded(64);
+        let jh = taskrun::spawn(Self::run(addr, rx, restx));
+        Self { tx, jh }
+    }
+
+    async fn run(addr: SocketAddrV4, rx: Receiver<Vec<u8>>, restx: Sender<SearchResults>) {
+        loop {
+            match Self::run_conn(addr, &rx, &restx).await {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!("name server {addr}  {e}");
+                    tokio::time::sleep(NAME_SERVER_RECONNECT_DELAY).await;
+                    // Batches sent meanwhile have timed out already.
+                    while rx.try_recv().is_ok() {}
+                }
+            }
+        }
+    }
+
+    async fn run_conn(addr: SocketAddrV4, rx: &Receiver<Vec<u8>>, restx: &Sender<SearchResults>) -> Result<(), Error> {
+        let mut stream = tokio::time::timeout(NAME_SERVER_CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connect timeout"))??;
+        stream.set_nodelay(true)?;
+        debug!("connected to name server {addr}");
+        let mut nb = SlideBuf::new(1024 * 64);
+        let mut rbuf = vec![0; 1024 * 16];
+        loop {
+            tokio::select! {
+                buf = rx.recv() => match buf {
+                    Ok(buf) => stream.write_all(&buf).await?,
+                    Err(_) => return Ok(()),
+                },
+                n = stream.read(&mut rbuf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Err(Error::with_msg_no_trace("connection closed"));
+                    }
+                    nb.put_slice(&rbuf[..n]).map_err(|e| e.to_string())?;
+                    let mut res = Vec::new();
+                    let mut out = Vec::new();
+                    while nb.len() >= 16 {
+                        let hi = HeadInfo::from_netbuf(&mut nb).map_err(|e| e.to_string())?;
+                        if nb.len() < hi.payload() {
+                            nb.rewind_rp(16).map_err(|e| e.to_string())?;
+                            break;
+                        }
+                        let msg = CaMsg::from_proto_infos(&hi, nb.data(), 32).map_err(|e| e.to_string())?;
+                        nb.adv(hi.payload()).map_err(|e| e.to_string())?;
+                        match msg.ty {
+                            CaMsgTy::SearchRes(k) => {
+                                // All ones asks us to use the address of the name server itself.
+                                let ip = match k.addr {
+                                    0 | u32::MAX => *addr.ip(),
+                                    x => Ipv4Addr::from(x),
+                                };
+                                res.push((SearchId(k.id), SocketAddrV4::new(ip, k.tcp_port)));
+                            }
+                            CaMsgTy::VersionRes(_) => {}
+                            CaMsgTy::Echo => out.extend(CaMsg { ty: CaMsgTy::Echo }.to_vec()),
+                            ty => debug!("name server {addr} sent {ty:?}"),
+                        }
+                    }
+                    if !out.is_empty() {
+                        stream.write_all(&out).await?;
+                    }
+                    if !res.is_empty() && restx.send((addr, res)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for NameServerConn {
+    fn drop(&mut self) {
+        self.jh.abort();
+    }
+}
+
 #[derive(Debug)]
 pub struct FindIocRes {
     pub channel: String,
@@ -81,12 +184,16 @@ pub struct FindIocStream {
     result_for_done_sid_count: u64,
     sleeper: Pin<Box<dyn Future<Output = ()> + Send>>,
     stop_on_empty_queue: bool,
+    name_servers: Vec<NameServerConn>,
+    tcp_res_tx: Sender<SearchResults>,
+    tcp_res_rx: Receiver<SearchResults>,
 }
 
 impl FindIocStream {
     pub fn new(tgts: Vec<SocketAddrV4>, batch_run_max: Duration, in_flight_max: usize, batch_size: usize) -> Self {
         let sock = unsafe { Self::create_socket() }.unwrap();
         let afd = AsyncFd::new(sock.0).unwrap();
+        let (tcp_res_tx, tcp_res_rx) = async_channel::bounded(64);
         Self {
             tgts,
             channels_input: VecDeque::new(),
@@ -108,6 +215,9 @@ impl FindIocStream {
             batch_run_max,
             sleeper: Box::pin(tokio::time::sleep(Duration::from_millis(500))),
             stop_on_empty_queue: false,
+            name_servers: Vec::new(),
+            tcp_res_tx,
+            tcp_res_rx,
         }
     }
 
@@ -115,6 +225,14 @@ impl FindIocStream {
         self.stop_on_empty_queue = true;
     }
 
+    // Also search over TCP at these name servers. The first answer from any target wins.
+    pub fn set_name_servers(&mut self, name_servers: Vec<SocketAddrV4>) {
+        self.name_servers = name_servers
+            .into_iter()
+            .map(|addr| NameServerConn::start(addr, self.tcp_res_tx.clone()))
+            .collect();
+    }
+
     pub fn quick_state(&self) -> String {
         format!(
             "channels_input {}  in_flight {}  bid_by_sid {}  out_queue {}  result_for_done_sid_count {}  bids_timed_out {}",
@@ -383,6 +501,14 @@ impl FindIocStream {
             sids,
             done: vec![false; n],
         };
+        if !self.name_servers.is_empty() {
+            let mut buf = Vec::new();
+            Self::serialize_batch(&mut buf, &batch);
+            for ns in &self.name_servers {
+                // If the name server does not keep up, the batch simply times out there.
+                let _ = ns.tx.try_send(buf.clone());
+            }
+        }
         self.in_flight.insert(bid.clone(), batch);
         self.batch_send_queue.push_back(bid);
     }
@@ -580,6 +706,10 @@ impl Stream for FindIocStream {
                 self.create_in_flight();
                 loop_again = true;
             }
+            while let Ready(Some((src, res))) = self.tcp_res_rx.poll_next_unpin(cx) {
+                self.handle_result(src, res);
+                loop_again = true;
+            }
             break match self.afd.poll_read_ready(cx) {
                 Ready(Ok(mut g)) => match unsafe { Self::try_read(self.sock.0) } {
                     Ready(Ok((src, res))) => {
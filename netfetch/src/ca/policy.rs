@@ -0,0 +1,253 @@
+use crate::ca::proto::CaDataScalarValue;
+use crate::ca::proto::CaDataValue;
+use crate::ca::proto::CaEventValue;
+use err::Error;
+use std::time::Duration;
+use std::time::Instant;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Deadband {
+    Abs(f64),
+    // Fraction of the last archived value.
+    Rel(f64),
+}
+
+// How the values of a channel get archived.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArchivingPolicy {
+    // Archive every monitor update, at most one per `ivl_min`.
+    Monitor {
+        ivl_min: Option<Duration>,
+    },
+    // Do not subscribe, read the value with ReadNotify every `period`.
+    Scan {
+        period: Duration,
+    },
+    // Archive monitor updates only if the value moved out of the deadband around the last archived one.
+    Deadband {
+        ivl_min: Option<Duration>,
+        deadband: Deadband,
+    },
+}
+
+impl Default for ArchivingPolicy {
+    fn default() -> Self {
+        ArchivingPolicy::Monitor { ivl_min: None }
+    }
+}
+
+impl ArchivingPolicy {
+    pub fn from_parts(
+        monitor: Option<Duration>,
+        scan: Option<Duration>,
+        deadband_abs: Option<f64>,
+        deadband_rel: Option<f64>,
+    ) -> Result<Self, Error> {
+        let deadband = match (deadband_abs, deadband_rel) {
+            (Some(_), Some(_)) => {
+                return Err(Error::with_msg_no_trace(
+                    "policy can not have absolute and relative deadband",
+                ));
+            }
+            (Some(x), None) => Some(Deadband::Abs(x)),
+            (None, Some(x)) => Some(Deadband::Rel(x)),
+            (None, None) => None,
+        };
+        if scan.is_some() && (monitor.is_some() || deadband.is_some()) {
+            return Err(Error::with_msg_no_trace(
+                "policy can not combine scan with monitor or deadband",
+            ));
+        }
+        let ret = if let Some(period) = scan {
+            if period.is_zero() {
+                return Err(Error::with_msg_no_trace("scan period must not be zero"));
+            }
+            ArchivingPolicy::Scan { period }
+        } else if let Some(deadband) = deadband {
+            ArchivingPolicy::Deadband {
+                ivl_min: monitor,
+                deadband,
+            }
+        } else {
+            ArchivingPolicy::Monitor { ivl_min: monitor }
+        };
+        Ok(ret)
+    }
+
+    // Parses the `key=value` tokens which may follow the channel name in the channel list.
+    pub fn from_tokens<'a, I>(tokens: I) -> Result<Option<Self>, Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut monitor = None;
+        let mut scan = None;
+        let mut deadband_abs = None;
+        let mut deadband_rel = None;
+        let mut any = false;
+        for tok in tokens {
+            let (k, v) = tok
+                .split_once('=')
+                .ok_or_else(|| Error::with_msg_no_trace(format!("bad policy token {tok:?}")))?;
+            match k {
+                "monitor" => monitor = Some(Self::parse_dur(v)?),
+                "scan" => scan = Some(Self::parse_dur(v)?),
+                "deadband_abs" => deadband_abs = Some(Self::parse_f64(v)?),
+                "deadband_rel" => deadband_rel = Some(Self::parse_f64(v)?),
+                _ => return Err(Error::with_msg_no_trace(format!("unknown policy key {k:?}"))),
+            }
+            any = true;
+        }
+        if any {
+            Ok(Some(Self::from_parts(monitor, scan, deadband_abs, deadband_rel)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_dur(s: &str) -> Result<Duration, Error> {
+        humantime::parse_duration(s).map_err(|e| Error::with_msg_no_trace(format!("bad duration {s:?} {e}")))
+    }
+
+    fn parse_f64(s: &str) -> Result<f64, Error> {
+        s.parse()
+            .map_err(|e| Error::with_msg_no_trace(format!("bad number {s:?} {e}")))
+    }
+
+    pub fn is_scan(&self) -> bool {
+        matches!(self, ArchivingPolicy::Scan { .. })
+    }
+
+    fn ivl_min(&self) -> Option<Duration> {
+        match self {
+            ArchivingPolicy::Monitor { ivl_min } => *ivl_min,
+            ArchivingPolicy::Scan { .. } => None,
+            ArchivingPolicy::Deadband { ivl_min, .. } => *ivl_min,
+        }
+    }
+}
+
+fn scalar_as_f64(v: &CaDataValue) -> Option<f64> {
+    match v {
+        CaDataValue::Scalar(x) => match x {
+            CaDataScalarValue::I8(x) => Some(*x as f64),
+            CaDataScalarValue::I16(x) => Some(*x as f64),
+            CaDataScalarValue::I32(x) => Some(*x as f64),
+            CaDataScalarValue::F32(x) => Some(*x as f64),
+            CaDataScalarValue::F64(x) => Some(*x),
+            CaDataScalarValue::Enum(x) => Some(*x as f64),
+            CaDataScalarValue::String(_) => None,
+            CaDataScalarValue::Bool(x) => Some(*x as u8 as f64),
+        },
+        CaDataValue::Array(_) => None,
+    }
+}
+
+// Per channel state to decide whether an update gets archived.
+#[derive(Clone, Debug)]
+pub struct PolicyState {
+    policy: ArchivingPolicy,
+    ts_last: Option<Instant>,
+    // Last archived value and alarm, for the deadband.
+    last: Option<(f64, Option<u16>, Option<u16>)>,
+    scan_next: Option<Instant>,
+}
+
+impl PolicyState {
+    pub fn new(policy: ArchivingPolicy) -> Self {
+        Self {
+            policy,
+            ts_last: None,
+            last: None,
+            scan_next: None,
+        }
+    }
+
+    pub fn policy(&self) -> &ArchivingPolicy {
+        &self.policy
+    }
+
+    // Decides whether this update gets archived and remembers it if so.
+    pub fn accept(&mut self, value: &CaEventValue, tsnow: Instant) -> bool {
+        if let (Some(ivl_min), Some(ts_last)) = (self.policy.ivl_min(), self.ts_last) {
+            if tsnow.saturating_duration_since(ts_last) < ivl_min {
+                return false;
+            }
+        }
+        let v = scalar_as_f64(&value.data);
+        let status = value.status.map(|x| x.get());
+        let severity = value.severity.map(|x| x.get());
+        if let ArchivingPolicy::Deadband { deadband, .. } = &self.policy {
+            // Non-numeric values and alarm changes are always archived.
+            if let (Some(v), Some((last, st, sev))) = (v, self.last) {
+                if st == status && sev == severity {
+                    let band = match deadband {
+                        Deadband::Abs(x) => *x,
+                        Deadband::Rel(x) => x * last.abs(),
+                    };
+                    if (v - last).abs() <= band {
+                        return false;
+                    }
+                }
+            }
+        }
+        self.ts_last = Some(tsnow);
+        self.last = v.map(|v| (v, status, severity));
+        true
+    }
+
+    // Returns true if a scan read is due, and schedules the next one.
+    pub fn scan_due(&mut self, tsnow: Instant) -> bool {
+        if let ArchivingPolicy::Scan { period } = &self.policy {
+            match self.scan_next {
+                Some(next) if tsnow < next => false,
+                _ => {
+                    self.scan_next = Some(tsnow + *period);
+                    true
+                }
+            }
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn policy_from_tokens() {
+    let p = ArchivingPolicy::from_tokens("scan=2s".split_whitespace()).unwrap();
+    assert_eq!(
+        p,
+        Some(ArchivingPolicy::Scan {
+            period: Duration::from_secs(2)
+        })
+    );
+    let p = ArchivingPolicy::from_tokens("monitor=100ms deadband_rel=0.01".split_whitespace()).unwrap();
+    assert_eq!(
+        p,
+        Some(ArchivingPolicy::Deadband {
+            ivl_min: Some(Duration::from_millis(100)),
+            deadband: Deadband::Rel(0.01),
+        })
+    );
+    assert_eq!(ArchivingPolicy::from_tokens("".split_whitespace()).unwrap(), None);
+    assert!(ArchivingPolicy::from_tokens("scan=1s deadband_abs=1".split_whitespace()).is_err());
+    assert!(ArchivingPolicy::from_tokens("bogus".split_whitespace()).is_err());
+}
+
+#[test]
+fn policy_deadband_abs() {
+    use std::num::NonZeroU16;
+    let policy = ArchivingPolicy::from_parts(None, None, Some(0.5), None).unwrap();
+    let mut st = PolicyState::new(policy);
+    let ev = |v: f64, severity: u16| CaEventValue {
+        ts: None,
+        status: None,
+        severity: NonZeroU16::new(severity),
+        data: CaDataValue::Scalar(CaDataScalarValue::F64(v)),
+    };
+    let tsnow = Instant::now();
+    assert_eq!(st.accept(&ev(1.0, 0), tsnow), true);
+    assert_eq!(st.accept(&ev(1.3, 0), tsnow), false);
+    assert_eq!(st.accept(&ev(1.6, 0), tsnow), true);
+    assert_eq!(st.accept(&ev(1.7, 2), tsnow), true);
+    assert_eq!(st.accept(&ev(1.2, 2), tsnow), false);
+}
@@ -39,12 +39,25 @@ pub enum Error {
     NotEnoughPayloadTimeMetadata(usize),
     MismatchDbrTimeType,
     BadCaCount,
+    PayloadTooLarge(u32),
     CaCommandNotSupported(u16),
     ParseAttemptInDoneState,
 }
 
 const CA_PROTO_VERSION: u16 = 13;
 const EPICS_EPOCH_OFFSET: u64 = 631152000;
+const MAX_STRING_SIZE: usize = 40;
+// Upper limit for a single message payload, the input buffer grows up to this size.
+const PAYLOAD_MAX: usize = 1024 * 1024 * 64;
+const INPBUF_CAP_INIT: usize = 1024 * 128;
+const MAX_UNITS_SIZE: usize = 8;
+const MAX_ENUM_STRING_SIZE: usize = 26;
+const MAX_ENUM_STATES: usize = 16;
+
+pub const DBE_VALUE: u16 = 0x01;
+pub const DBE_LOG: u16 = 0x02;
+pub const DBE_ALARM: u16 = 0x04;
+pub const DBE_PROPERTY: u16 = 0x08;
 
 #[derive(Debug)]
 pub struct Search {
@@ -81,7 +94,7 @@ pub struct CreateChan {
 #[derive(Debug)]
 pub struct CreateChanRes {
     pub data_type: u16,
-    pub data_count: u16,
+    pub data_count: u32,
     pub cid: u32,
     pub sid: u32,
 }
@@ -100,25 +113,64 @@ pub struct AccessRightsRes {
 #[derive(Debug)]
 pub struct EventAdd {
     pub data_type: u16,
-    pub data_count: u16,
+    pub data_count: u32,
     pub sid: u32,
     pub subid: u32,
+    pub mask: u16,
 }
 
 // TODO Clone is only used for testing purposes and should get removed later.
 #[derive(Debug, Clone)]
 pub struct EventAddRes {
     pub data_type: u16,
-    pub data_count: u16,
+    pub data_count: u32,
     pub status: u32,
     pub subid: u32,
     pub value: CaEventValue,
 }
 
+#[derive(Debug)]
+pub struct EventCancel {
+    pub data_type: u16,
+    pub data_count: u32,
+    pub sid: u32,
+    pub subid: u32,
+}
+
+// The server confirms the cancel with a last EventAdd response without payload.
+#[derive(Debug)]
+pub struct EventCancelRes {
+    pub data_type: u16,
+    pub data_count: u32,
+    pub subid: u32,
+}
+
+#[derive(Debug)]
+pub struct ClearChannel {
+    pub sid: u32,
+    pub cid: u32,
+}
+
+#[derive(Debug)]
+pub struct ClearChannelRes {
+    pub sid: u32,
+    pub cid: u32,
+}
+
+// Response to a subscription with a DBR_GR_* or DBR_CTRL_* data type.
+#[derive(Debug, Clone)]
+pub struct EventAddResMeta {
+    pub data_type: u16,
+    pub data_count: u32,
+    pub status: u32,
+    pub subid: u32,
+    pub meta: CaChannelMeta,
+}
+
 #[derive(Debug)]
 pub struct ReadNotify {
     pub data_type: u16,
-    pub data_count: u16,
+    pub data_count: u32,
     pub sid: u32,
     pub ioid: u32,
 }
@@ -126,9 +178,10 @@ pub struct ReadNotify {
 #[derive(Debug)]
 pub struct ReadNotifyRes {
     pub data_type: u16,
-    pub data_count: u16,
+    pub data_count: u32,
     pub sid: u32,
     pub ioid: u32,
+    pub value: CaEventValue,
 }
 
 #[derive(Debug)]
@@ -147,6 +200,8 @@ enum CaDbrMetaType {
     Plain,
     Status,
     Time,
+    Graphic,
+    Ctrl,
 }
 
 #[derive(Debug)]
@@ -157,10 +212,14 @@ pub struct CaDbrType {
 
 impl CaDbrType {
     pub fn from_ca_u16(k: u16) -> Result<Self, Error> {
-        if k > 20 {
+        if k > 34 {
             return Err(Error::BadCaDbrTypeId(k));
         }
-        let (meta, k) = if k >= 14 {
+        let (meta, k) = if k >= 28 {
+            (CaDbrMetaType::Ctrl, k - 28)
+        } else if k >= 21 {
+            (CaDbrMetaType::Graphic, k - 21)
+        } else if k >= 14 {
             (CaDbrMetaType::Time, k - 14)
         } else if k >= 7 {
             (CaDbrMetaType::Status, k - 7)
@@ -303,6 +362,7 @@ pub enum CaDataArrayValue {
     I32(Vec<i32>),
     F32(Vec<f32>),
     F64(Vec<f64>),
+    String(Vec<String>),
     // TODO remove, CA has no bool, make new enum for other use cases.
     Bool(Vec<bool>),
 }
@@ -316,6 +376,7 @@ impl From<CaDataArrayValue> for scywr::iteminsertqueue::ArrayValue {
             CaDataArrayValue::I32(x) => ArrayValue::I32(x),
             CaDataArrayValue::F32(x) => ArrayValue::F32(x),
             CaDataArrayValue::F64(x) => ArrayValue::F64(x),
+            CaDataArrayValue::String(x) => ArrayValue::String(x),
             CaDataArrayValue::Bool(x) => ArrayValue::Bool(x),
         }
     }
@@ -337,6 +398,21 @@ impl From<CaDataValue> for scywr::iteminsertqueue::DataValue {
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CaChannelMeta {
+    pub units: String,
+    pub precision: i16,
+    pub upper_disp_limit: f64,
+    pub lower_disp_limit: f64,
+    pub upper_alarm_limit: f64,
+    pub upper_warning_limit: f64,
+    pub lower_warning_limit: f64,
+    pub lower_alarm_limit: f64,
+    pub upper_ctrl_limit: f64,
+    pub lower_ctrl_limit: f64,
+    pub enum_strs: Vec<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct CaEventValue {
     pub ts: Option<NonZeroU64>,
@@ -345,6 +421,66 @@ pub struct CaEventValue {
     pub data: CaDataValue,
 }
 
+// Encoding is only needed by the mock IOC.
+#[cfg(test)]
+impl CaEventValue {
+    // Layout of the dbr_time_* structs as in db_access.h, padded to 8 bytes.
+    fn time_payload(&self) -> Vec<u8> {
+        fn put_str(buf: &mut Vec<u8>, s: &str) {
+            let b = s.as_bytes();
+            let n = b.len().min(MAX_STRING_SIZE - 1);
+            buf.extend_from_slice(&b[..n]);
+            buf.resize(buf.len() + MAX_STRING_SIZE - n, 0);
+        }
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.status.map_or(0, |x| x.get()).to_be_bytes());
+        buf.extend_from_slice(&self.severity.map_or(0, |x| x.get()).to_be_bytes());
+        let ts = self.ts.map_or(0, |x| x.get());
+        let secs = (ts / SEC).saturating_sub(EPICS_EPOCH_OFFSET) as u32;
+        let nanos = (ts % SEC) as u32;
+        buf.extend_from_slice(&secs.to_be_bytes());
+        buf.extend_from_slice(&nanos.to_be_bytes());
+        let pad = match &self.data {
+            CaDataValue::Scalar(x) => match x {
+                CaDataScalarValue::I8(_) | CaDataScalarValue::Bool(_) => 3,
+                CaDataScalarValue::I16(_) | CaDataScalarValue::Enum(_) => 2,
+                CaDataScalarValue::F64(_) => 4,
+                _ => 0,
+            },
+            CaDataValue::Array(x) => match x {
+                CaDataArrayValue::I8(_) | CaDataArrayValue::Bool(_) => 3,
+                CaDataArrayValue::I16(_) => 2,
+                CaDataArrayValue::F64(_) => 4,
+                _ => 0,
+            },
+        };
+        buf.resize(buf.len() + pad, 0);
+        match &self.data {
+            CaDataValue::Scalar(x) => match x {
+                CaDataScalarValue::I8(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::I16(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::I32(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::F32(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::F64(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::Enum(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                CaDataScalarValue::String(v) => put_str(&mut buf, v),
+                CaDataScalarValue::Bool(v) => buf.push(*v as u8),
+            },
+            CaDataValue::Array(x) => match x {
+                CaDataArrayValue::I8(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+                CaDataArrayValue::I16(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+                CaDataArrayValue::I32(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+                CaDataArrayValue::F32(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+                CaDataArrayValue::F64(v) => v.iter().for_each(|x| buf.extend_from_slice(&x.to_be_bytes())),
+                CaDataArrayValue::String(v) => v.iter().for_each(|x| put_str(&mut buf, x)),
+                CaDataArrayValue::Bool(v) => v.iter().for_each(|x| buf.push(*x as u8)),
+            },
+        }
+        buf.resize((buf.len() + 7) / 8 * 8, 0);
+        buf
+    }
+}
+
 #[derive(Debug)]
 pub enum CaMsgTy {
     Version,
@@ -361,6 +497,11 @@ pub enum CaMsgTy {
     AccessRightsRes(AccessRightsRes),
     EventAdd(EventAdd),
     EventAddRes(EventAddRes),
+    EventAddResMeta(EventAddResMeta),
+    EventCancel(EventCancel),
+    EventCancelRes(EventCancelRes),
+    ClearChannel(ClearChannel),
+    ClearChannelRes(ClearChannelRes),
     ReadNotify(ReadNotify),
     ReadNotifyRes(ReadNotifyRes),
     Echo,
@@ -384,6 +525,11 @@ impl CaMsgTy {
             AccessRightsRes(_) => 0x16,
             EventAdd(_) => 0x01,
             EventAddRes(_) => 0x01,
+            EventAddResMeta(_) => 0x01,
+            EventCancel(_) => 0x02,
+            EventCancelRes(_) => 0x01,
+            ClearChannel(_) => 0x0c,
+            ClearChannelRes(_) => 0x0c,
             ReadNotify(_) => 0x0f,
             ReadNotifyRes(_) => 0x0f,
             Echo => 0x17,
@@ -391,7 +537,15 @@ impl CaMsgTy {
     }
 
     fn len(&self) -> usize {
-        16 + self.payload_len()
+        if self.is_ext() {
+            24 + self.payload_len()
+        } else {
+            16 + self.payload_len()
+        }
+    }
+
+    fn is_ext(&self) -> bool {
+        self.payload_len() >= 0xffff || self.data_count() >= 0xffff
     }
 
     fn payload_len(&self) -> usize {
@@ -410,11 +564,25 @@ impl CaMsgTy {
             CreateChanFail(_) => 0,
             AccessRightsRes(_) => 0,
             EventAdd(_) => 16,
+            #[cfg(test)]
+            EventAddRes(x) => x.value.time_payload().len(),
+            #[cfg(not(test))]
             EventAddRes(_) => {
                 error!("should not attempt to serialize the response again");
                 panic!();
             }
+            EventAddResMeta(_) => {
+                error!("should not attempt to serialize the response again");
+                panic!();
+            }
+            EventCancel(_) => 0,
+            EventCancelRes(_) => 0,
+            ClearChannel(_) => 0,
+            ClearChannelRes(_) => 0,
             ReadNotify(_) => 0,
+            #[cfg(test)]
+            ReadNotifyRes(x) => x.value.time_payload().len(),
+            #[cfg(not(test))]
             ReadNotifyRes(_) => {
                 error!("should not attempt to serialize the response again");
                 panic!();
@@ -443,22 +611,27 @@ impl CaMsgTy {
             AccessRightsRes(_) => 0,
             EventAdd(x) => x.data_type,
             EventAddRes(x) => x.data_type,
+            EventAddResMeta(x) => x.data_type,
+            EventCancel(x) => x.data_type,
+            EventCancelRes(x) => x.data_type,
+            ClearChannel(_) => 0,
+            ClearChannelRes(_) => 0,
             ReadNotify(x) => x.data_type,
             ReadNotifyRes(x) => x.data_type,
             Echo => 0,
         }
     }
 
-    fn data_count(&self) -> u16 {
+    fn data_count(&self) -> u32 {
         use CaMsgTy::*;
         match self {
-            Version => CA_PROTO_VERSION,
+            Version => CA_PROTO_VERSION as _,
             VersionRes(_) => 0,
             Error(_) => 0,
             ClientName => 0,
             ClientNameRes(_) => 0,
             HostName(_) => 0,
-            Search(_) => CA_PROTO_VERSION,
+            Search(_) => CA_PROTO_VERSION as _,
             SearchRes(_) => 0,
             CreateChan(_) => 0,
             CreateChanRes(x) => x.data_count,
@@ -466,6 +639,11 @@ impl CaMsgTy {
             AccessRightsRes(_) => 0,
             EventAdd(x) => x.data_count,
             EventAddRes(x) => x.data_count,
+            EventAddResMeta(x) => x.data_count,
+            EventCancel(x) => x.data_count,
+            EventCancelRes(x) => x.data_count,
+            ClearChannel(_) => 0,
+            ClearChannelRes(_) => 0,
             ReadNotify(x) => x.data_count,
             ReadNotifyRes(x) => x.data_count,
             Echo => 0,
@@ -489,6 +667,11 @@ impl CaMsgTy {
             AccessRightsRes(x) => x.cid,
             EventAdd(x) => x.sid,
             EventAddRes(x) => x.status,
+            EventAddResMeta(x) => x.status,
+            EventCancel(x) => x.sid,
+            EventCancelRes(_) => 0,
+            ClearChannel(x) => x.sid,
+            ClearChannelRes(x) => x.sid,
             ReadNotify(x) => x.sid,
             ReadNotifyRes(x) => x.sid,
             Echo => 0,
@@ -512,6 +695,11 @@ impl CaMsgTy {
             AccessRightsRes(x) => x.rights,
             EventAdd(x) => x.subid,
             EventAddRes(x) => x.subid,
+            EventAddResMeta(x) => x.subid,
+            EventCancel(x) => x.subid,
+            EventCancelRes(x) => x.subid,
+            ClearChannel(x) => x.cid,
+            ClearChannelRes(x) => x.cid,
             ReadNotify(x) => x.ioid,
             ReadNotifyRes(x) => x.ioid,
             Echo => 0,
@@ -553,6 +741,13 @@ impl CaMsgTy {
                 }
                 buf[0..d.len()].copy_from_slice(&d[0..d.len()]);
             }
+            // Responses only get written by the mock IOC in tests.
+            #[cfg(test)]
+            SearchRes(x) => {
+                buf.fill(0);
+                buf[0..2].copy_from_slice(&x.proto_version.to_be_bytes());
+            }
+            #[cfg(not(test))]
             SearchRes(_) => {
                 error!("should not attempt to write SearchRes");
                 panic!();
@@ -571,12 +766,29 @@ impl CaMsgTy {
             CreateChanRes(_) => {}
             CreateChanFail(_) => {}
             AccessRightsRes(_) => {}
-            EventAdd(_) => {
-                // TODO allow to customize the mask. Test if it works.
-                buf.copy_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0e, 0, 0]);
+            EventAdd(x) => {
+                buf.fill(0);
+                buf[12..14].copy_from_slice(&x.mask.to_be_bytes());
+            }
+            #[cfg(test)]
+            EventAddRes(x) => {
+                let b = x.value.time_payload();
+                buf[..b.len()].copy_from_slice(&b);
             }
+            #[cfg(not(test))]
             EventAddRes(_) => {}
+            EventAddResMeta(_) => {}
+            EventCancel(_) => {}
+            EventCancelRes(_) => {}
+            ClearChannel(_) => {}
+            ClearChannelRes(_) => {}
             ReadNotify(_) => {}
+            #[cfg(test)]
+            ReadNotifyRes(x) => {
+                let b = x.value.time_payload();
+                buf[..b.len()].copy_from_slice(&b);
+            }
+            #[cfg(not(test))]
             ReadNotifyRes(_) => {}
             Echo => {}
         }
@@ -612,6 +824,79 @@ macro_rules! convert_wave_value {
     }};
 }
 
+struct MetaReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> MetaReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(Error::NotEnoughPayload);
+        }
+        let (a, b) = self.buf.split_at(n);
+        self.buf = b;
+        Ok(a)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_be_bytes(
+            self.take(2)?.try_into().map_err(|_| Error::BadSlice)?,
+        ))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(
+            self.take(4)?.try_into().map_err(|_| Error::BadSlice)?,
+        ))
+    }
+
+    fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_be_bytes(
+            self.take(4)?.try_into().map_err(|_| Error::BadSlice)?,
+        ))
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_be_bytes(
+            self.take(8)?.try_into().map_err(|_| Error::BadSlice)?,
+        ))
+    }
+
+    fn string(&mut self, n: usize) -> Result<String, Error> {
+        let buf = self.take(n)?;
+        Ok(CaMsg::ca_string_from_buf(buf))
+    }
+
+    fn limits<F>(&mut self, meta: &mut CaChannelMeta, with_ctrl: bool, f: F) -> Result<(), Error>
+    where
+        F: Fn(&mut Self) -> Result<f64, Error>,
+    {
+        meta.upper_disp_limit = f(self)?;
+        meta.lower_disp_limit = f(self)?;
+        meta.upper_alarm_limit = f(self)?;
+        meta.upper_warning_limit = f(self)?;
+        meta.lower_warning_limit = f(self)?;
+        meta.lower_alarm_limit = f(self)?;
+        if with_ctrl {
+            meta.upper_ctrl_limit = f(self)?;
+            meta.lower_ctrl_limit = f(self)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct CaMsg {
     pub ty: CaMsgTy,
@@ -622,38 +907,104 @@ impl CaMsg {
         self.ty.len()
     }
 
+    // Used for the echo to name servers. Responses encode only in tests, for the mock IOC.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.len()];
+        self.place_into(&mut buf);
+        buf
+    }
+
     fn place_into(&self, buf: &mut [u8]) {
         //info!("place_into  given {} bytes buffer", buf.len());
-        if self.ty.payload_len() > 0x4000 - 16 {
-            error!("TODO emit for larger payloads");
-            panic!();
+        let t = self.ty.cmdid().to_be_bytes();
+        buf[0] = t[0];
+        buf[1] = t[1];
+        let t = self.ty.data_type().to_be_bytes();
+        buf[4] = t[0];
+        buf[5] = t[1];
+        let t = self.ty.param1().to_be_bytes();
+        buf[8] = t[0];
+        buf[9] = t[1];
+        buf[10] = t[2];
+        buf[11] = t[3];
+        let t = self.ty.param2().to_be_bytes();
+        buf[12] = t[0];
+        buf[13] = t[1];
+        buf[14] = t[2];
+        buf[15] = t[3];
+        if self.ty.is_ext() {
+            // Extended header: marker in the standard fields, actual sizes follow as u32.
+            buf[2..4].copy_from_slice(&0xffffu16.to_be_bytes());
+            buf[6..8].copy_from_slice(&0u16.to_be_bytes());
+            buf[16..20].copy_from_slice(&(self.ty.payload_len() as u32).to_be_bytes());
+            buf[20..24].copy_from_slice(&self.ty.data_count().to_be_bytes());
+            self.ty.place_payload_into(&mut buf[24..]);
         } else {
-            let t = self.ty.cmdid().to_be_bytes();
-            buf[0] = t[0];
-            buf[1] = t[1];
             let t = (self.ty.payload_len() as u16).to_be_bytes();
             buf[2] = t[0];
             buf[3] = t[1];
-            let t = self.ty.data_type().to_be_bytes();
-            buf[4] = t[0];
-            buf[5] = t[1];
-            let t = self.ty.data_count().to_be_bytes();
+            let t = (self.ty.data_count() as u16).to_be_bytes();
             buf[6] = t[0];
             buf[7] = t[1];
-            let t = self.ty.param1().to_be_bytes();
-            buf[8] = t[0];
-            buf[9] = t[1];
-            buf[10] = t[2];
-            buf[11] = t[3];
-            let t = self.ty.param2().to_be_bytes();
-            buf[12] = t[0];
-            buf[13] = t[1];
-            buf[14] = t[2];
-            buf[15] = t[3];
             self.ty.place_payload_into(&mut buf[16..]);
         }
     }
 
+    // CA strings are fixed size and null-terminated if shorter than the maximum size.
+    fn ca_string_from_buf(buf: &[u8]) -> String {
+        let ixn = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..ixn]).into()
+    }
+
+    // Layout of the dbr_gr_* and dbr_ctrl_* structs as in db_access.h. The value itself is ignored.
+    fn ca_channel_meta(ty: &CaDbrType, payload: &[u8]) -> Result<CaChannelMeta, Error> {
+        let with_ctrl = match ty.meta {
+            CaDbrMetaType::Ctrl => true,
+            _ => false,
+        };
+        let mut rd = MetaReader::new(payload);
+        // status and severity
+        rd.skip(4)?;
+        let mut ret = CaChannelMeta::default();
+        match ty.scalar_type {
+            CaScalarType::String => {}
+            CaScalarType::Enum => {
+                let n = rd.i16()?.max(0) as usize;
+                for i in 0..MAX_ENUM_STATES {
+                    let s = rd.string(MAX_ENUM_STRING_SIZE)?;
+                    if i < n {
+                        ret.enum_strs.push(s);
+                    }
+                }
+            }
+            CaScalarType::I8 => {
+                ret.units = rd.string(MAX_UNITS_SIZE)?;
+                rd.limits(&mut ret, with_ctrl, |rd| Ok(rd.u8()? as f64))?;
+            }
+            CaScalarType::I16 => {
+                ret.units = rd.string(MAX_UNITS_SIZE)?;
+                rd.limits(&mut ret, with_ctrl, |rd| Ok(rd.i16()? as f64))?;
+            }
+            CaScalarType::I32 => {
+                ret.units = rd.string(MAX_UNITS_SIZE)?;
+                rd.limits(&mut ret, with_ctrl, |rd| Ok(rd.i32()? as f64))?;
+            }
+            CaScalarType::F32 => {
+                ret.precision = rd.i16()?;
+                rd.skip(2)?;
+                ret.units = rd.string(MAX_UNITS_SIZE)?;
+                rd.limits(&mut ret, with_ctrl, |rd| Ok(rd.f32()? as f64))?;
+            }
+            CaScalarType::F64 => {
+                ret.precision = rd.i16()?;
+                rd.skip(2)?;
+                ret.units = rd.string(MAX_UNITS_SIZE)?;
+                rd.limits(&mut ret, with_ctrl, |rd| rd.f64())?;
+            }
+        }
+        Ok(ret)
+    }
+
     fn ca_scalar_value(scalar_type: &CaScalarType, buf: &[u8]) -> Result<CaDataValue, Error> {
         let val = match scalar_type {
             CaScalarType::I8 => convert_scalar_value!(i8, I8, buf),
@@ -663,17 +1014,8 @@ impl CaMsg {
             CaScalarType::F64 => convert_scalar_value!(f64, F64, buf),
             CaScalarType::Enum => convert_scalar_value!(i16, I16, buf),
             CaScalarType::String => {
-                // TODO constrain string length to the CA `data_count`.
-                let mut ixn = buf.len();
-                for (i, &c) in buf.iter().enumerate() {
-                    if c == 0 {
-                        ixn = i;
-                        break;
-                    }
-                }
-                //info!("try to read string from payload len {} ixn {}", buf.len(), ixn);
-                let v = String::from_utf8_lossy(&buf[..ixn]);
-                CaDataValue::Scalar(CaDataScalarValue::String(v.into()))
+                let v = Self::ca_string_from_buf(&buf[..buf.len().min(MAX_STRING_SIZE)]);
+                CaDataValue::Scalar(CaDataScalarValue::String(v))
             }
         };
         Ok(val)
@@ -686,7 +1028,14 @@ impl CaMsg {
             CaScalarType::I32 => convert_wave_value!(i32, I32, n, buf),
             CaScalarType::F32 => convert_wave_value!(f32, F32, n, buf),
             CaScalarType::F64 => convert_wave_value!(f64, F64, n, buf),
-            CaScalarType::String => CaDataValue::Scalar(CaDataScalarValue::String("todo-array-string".into())),
+            CaScalarType::String => {
+                let nn = n.min(buf.len() / MAX_STRING_SIZE);
+                let mut a = Vec::with_capacity(nn);
+                for bb in buf.chunks_exact(MAX_STRING_SIZE).take(nn) {
+                    a.push(Self::ca_string_from_buf(bb));
+                }
+                CaDataValue::Array(CaDataArrayValue::String(a))
+            }
             _ => {
                 warn!("TODO conversion array {scalar_type:?}");
                 return Err(Error::TodoConversionArray);
@@ -695,10 +1044,67 @@ impl CaMsg {
         Ok(val)
     }
 
+    // Status, severity, timestamp and value of a DBR_TIME_* payload.
+    fn ca_time_value(
+        ca_dbr_ty: &CaDbrType,
+        data_count: u32,
+        payload: &[u8],
+        array_truncate: usize,
+    ) -> Result<CaEventValue, Error> {
+        use netpod::Shape;
+        if payload.len() < 12 {
+            return Err(Error::NotEnoughPayloadTimeMetadata(payload.len()));
+        }
+        let ca_status = u16::from_be_bytes(payload[0..2].try_into().map_err(|_| Error::BadSlice)?);
+        let ca_severity = u16::from_be_bytes(payload[2..4].try_into().map_err(|_| Error::BadSlice)?);
+        let ca_secs = u32::from_be_bytes(payload[4..8].try_into().map_err(|_| Error::BadSlice)?);
+        let ca_nanos = u32::from_be_bytes(payload[8..12].try_into().map_err(|_| Error::BadSlice)?);
+        let ca_sh = shape_from_ca_count(data_count)?;
+        let meta_padding = match ca_dbr_ty.meta {
+            CaDbrMetaType::Plain => 0,
+            CaDbrMetaType::Status => match ca_dbr_ty.scalar_type {
+                CaScalarType::I8 => 1,
+                CaScalarType::I16 => 0,
+                CaScalarType::I32 => 0,
+                CaScalarType::F32 => 0,
+                CaScalarType::F64 => 4,
+                CaScalarType::Enum => 0,
+                CaScalarType::String => 0,
+            },
+            CaDbrMetaType::Time => match ca_dbr_ty.scalar_type {
+                CaScalarType::I8 => 3,
+                CaScalarType::I16 => 2,
+                CaScalarType::I32 => 0,
+                CaScalarType::F32 => 0,
+                CaScalarType::F64 => 4,
+                CaScalarType::Enum => 2,
+                CaScalarType::String => 0,
+            },
+            CaDbrMetaType::Graphic | CaDbrMetaType::Ctrl => 0,
+        };
+        let valbuf = &payload[12 + meta_padding..];
+        let value = match ca_sh {
+            Shape::Scalar => Self::ca_scalar_value(&ca_dbr_ty.scalar_type, valbuf)?,
+            Shape::Wave(n) => Self::ca_wave_value(&ca_dbr_ty.scalar_type, (n as usize).min(array_truncate), valbuf)?,
+            Shape::Image(_, _) => {
+                error!("Can not handle image from channel access");
+                err::todoval()
+            }
+        };
+        let ts = SEC * (ca_secs as u64 + EPICS_EPOCH_OFFSET) + ca_nanos as u64;
+        let ret = CaEventValue {
+            ts: NonZeroU64::new(ts),
+            status: NonZeroU16::new(ca_status),
+            severity: NonZeroU16::new(ca_severity),
+            data: value,
+        };
+        Ok(ret)
+    }
+
     pub fn from_proto_infos(hi: &HeadInfo, payload: &[u8], array_truncate: usize) -> Result<Self, Error> {
         let msg = match hi.cmdid {
             0x00 => CaMsg {
-                ty: CaMsgTy::VersionRes(hi.data_count),
+                ty: CaMsgTy::VersionRes(hi.data_count as u16),
             },
             0x0b => {
                 let mut s = String::new();
@@ -772,60 +1178,33 @@ impl CaMsg {
                     ty: CaMsgTy::CreateChanFail(CreateChanFail { cid: hi.param1 }),
                 }
             }
+            1 if payload.is_empty() => CaMsg {
+                ty: CaMsgTy::EventCancelRes(EventCancelRes {
+                    data_type: hi.data_type,
+                    data_count: hi.data_count,
+                    subid: hi.param2,
+                }),
+            },
             1 => {
-                use netpod::Shape;
                 let ca_dbr_ty = CaDbrType::from_ca_u16(hi.data_type)?;
-                if let CaDbrMetaType::Time = ca_dbr_ty.meta {
-                } else {
-                    return Err(Error::MismatchDbrTimeType);
-                }
-                if payload.len() < 12 {
-                    return Err(Error::NotEnoughPayloadTimeMetadata(payload.len()));
-                }
-                let ca_status = u16::from_be_bytes(payload[0..2].try_into().map_err(|_| Error::BadSlice)?);
-                let ca_severity = u16::from_be_bytes(payload[2..4].try_into().map_err(|_| Error::BadSlice)?);
-                let ca_secs = u32::from_be_bytes(payload[4..8].try_into().map_err(|_| Error::BadSlice)?);
-                let ca_nanos = u32::from_be_bytes(payload[8..12].try_into().map_err(|_| Error::BadSlice)?);
-                let ca_sh = Shape::from_ca_count(hi.data_count).map_err(|_| Error::BadCaCount)?;
-                let meta_padding = match ca_dbr_ty.meta {
-                    CaDbrMetaType::Plain => 0,
-                    CaDbrMetaType::Status => match ca_dbr_ty.scalar_type {
-                        CaScalarType::I8 => 1,
-                        CaScalarType::I16 => 0,
-                        CaScalarType::I32 => 0,
-                        CaScalarType::F32 => 0,
-                        CaScalarType::F64 => 4,
-                        CaScalarType::Enum => 0,
-                        CaScalarType::String => 0,
-                    },
-                    CaDbrMetaType::Time => match ca_dbr_ty.scalar_type {
-                        CaScalarType::I8 => 3,
-                        CaScalarType::I16 => 2,
-                        CaScalarType::I32 => 0,
-                        CaScalarType::F32 => 0,
-                        CaScalarType::F64 => 4,
-                        CaScalarType::Enum => 2,
-                        CaScalarType::String => 0,
-                    },
-                };
-                let valbuf = &payload[12 + meta_padding..];
-                let value = match ca_sh {
-                    Shape::Scalar => Self::ca_scalar_value(&ca_dbr_ty.scalar_type, valbuf)?,
-                    Shape::Wave(n) => {
-                        Self::ca_wave_value(&ca_dbr_ty.scalar_type, (n as usize).min(array_truncate), valbuf)?
+                match ca_dbr_ty.meta {
+                    CaDbrMetaType::Time => {}
+                    CaDbrMetaType::Graphic | CaDbrMetaType::Ctrl => {
+                        let meta = Self::ca_channel_meta(&ca_dbr_ty, payload)?;
+                        let d = EventAddResMeta {
+                            data_type: hi.data_type,
+                            data_count: hi.data_count,
+                            status: hi.param1,
+                            subid: hi.param2,
+                            meta,
+                        };
+                        return Ok(CaMsg {
+                            ty: CaMsgTy::EventAddResMeta(d),
+                        });
                     }
-                    Shape::Image(_, _) => {
-                        error!("Can not handle image from channel access");
-                        err::todoval()
-                    }
-                };
-                let ts = SEC * (ca_secs as u64 + EPICS_EPOCH_OFFSET) + ca_nanos as u64;
-                let value = CaEventValue {
-                    ts: NonZeroU64::new(ts),
-                    status: NonZeroU16::new(ca_status),
-                    severity: NonZeroU16::new(ca_severity),
-                    data: value,
-                };
+                    _ => return Err(Error::MismatchDbrTimeType),
+                }
+                let value = Self::ca_time_value(&ca_dbr_ty, hi.data_count, payload, array_truncate)?;
                 let d = EventAddRes {
                     data_type: hi.data_type,
                     data_count: hi.data_count,
@@ -838,30 +1217,27 @@ impl CaMsg {
                 }
             }
             15 => {
-                if payload.len() == 8 {
-                    let v = u64::from_be_bytes(payload.try_into().map_err(|_| Error::BadSlice)?);
-                    info!("Payload as u64: {v}");
-                    let v = i64::from_be_bytes(payload.try_into().map_err(|_| Error::BadSlice)?);
-                    info!("Payload as i64: {v}");
-                    let v = f64::from_be_bytes(payload.try_into().map_err(|_| Error::BadSlice)?);
-                    info!("Payload as f64: {v}");
-                } else {
-                    info!(
-                        "payload string  {:?}  payload {:?}",
-                        String::from_utf8_lossy(&payload[..payload.len().min(12)]),
-                        &payload[..payload.len().min(12)],
-                    );
+                let ca_dbr_ty = CaDbrType::from_ca_u16(hi.data_type)?;
+                if !matches!(ca_dbr_ty.meta, CaDbrMetaType::Time) {
+                    return Err(Error::MismatchDbrTimeType);
                 }
+                let value = Self::ca_time_value(&ca_dbr_ty, hi.data_count, payload, array_truncate)?;
                 CaMsg {
-                    // TODO use different structs for request and response:
                     ty: CaMsgTy::ReadNotifyRes(ReadNotifyRes {
                         data_type: hi.data_type,
                         data_count: hi.data_count,
                         sid: hi.param1,
                         ioid: hi.param2,
+                        value,
                     }),
                 }
             }
+            0x0c => CaMsg {
+                ty: CaMsgTy::ClearChannelRes(ClearChannelRes {
+                    sid: hi.param1,
+                    cid: hi.param2,
+                }),
+            },
             0x17 => CaMsg { ty: CaMsgTy::Echo },
             x => return Err(Error::CaCommandNotSupported(x)),
         };
@@ -881,12 +1257,23 @@ impl CaItem {
     }
 }
 
+pub fn shape_from_ca_count(k: u32) -> Result<netpod::Shape, Error> {
+    use netpod::Shape;
+    if k == 0 {
+        Err(Error::BadCaCount)
+    } else if k == 1 {
+        Ok(Shape::Scalar)
+    } else {
+        Ok(Shape::Wave(k))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HeadInfo {
     cmdid: u16,
-    payload_size: u16,
+    payload_size: u32,
     data_type: u16,
-    data_count: u16,
+    data_count: u32,
     param1: u32,
     param2: u32,
 }
@@ -894,9 +1281,9 @@ pub struct HeadInfo {
 impl HeadInfo {
     pub fn from_netbuf(buf: &mut SlideBuf) -> Result<Self, Error> {
         let command = buf.read_u16_be()?;
-        let payload_size = buf.read_u16_be()?;
+        let payload_size = buf.read_u16_be()? as u32;
         let data_type = buf.read_u16_be()?;
-        let data_count = buf.read_u16_be()?;
+        let data_count = buf.read_u16_be()? as u32;
         let param1 = buf.read_u32_be()?;
         let param2 = buf.read_u32_be()?;
         let hi = HeadInfo {
@@ -917,6 +1304,22 @@ impl HeadInfo {
     pub fn payload(&self) -> usize {
         self.payload_size as _
     }
+
+    pub fn data_type(&self) -> u16 {
+        self.data_type
+    }
+
+    pub fn data_count(&self) -> u32 {
+        self.data_count
+    }
+
+    pub fn param1(&self) -> u32 {
+        self.param1
+    }
+
+    pub fn param2(&self) -> u32 {
+        self.param2
+    }
 }
 
 #[derive(Debug)]
@@ -956,7 +1359,7 @@ impl CaProto {
             tcp,
             remote_addr_dbg,
             state: CaState::StdHead,
-            buf: SlideBuf::new(1024 * 128),
+            buf: SlideBuf::new(INPBUF_CAP_INIT),
             outbuf: SlideBuf::new(1024 * 128),
             out: VecDeque::new(),
             array_truncate,
@@ -980,6 +1383,9 @@ impl CaProto {
 
     fn out_msg_buf(&mut self) -> Option<(&CaMsg, &mut [u8])> {
         if let Some(item) = self.out.front() {
+            if item.len() > self.outbuf.cap() {
+                self.outbuf.grow(item.len());
+            }
             match self.outbuf.available_writable_area(item.len()) {
                 Ok(buf) => Some((item, buf)),
                 Err(_) => {
@@ -1014,6 +1420,36 @@ impl CaProto {
         }
     }
 
+    // Writes all queued messages without reading input, used before the connection gets dropped.
+    pub fn poll_flush_out(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        use Poll::*;
+        loop {
+            while let Some((msg, buf)) = self.out_msg_buf() {
+                let msglen = msg.len();
+                if msglen > buf.len() {
+                    break;
+                }
+                msg.place_into(&mut buf[..msglen]);
+                if let Err(e) = self.outbuf.wadv(msglen) {
+                    return Ready(Err(e.into()));
+                }
+                self.out.pop_front();
+            }
+            if self.outbuf.len() == 0 {
+                if self.out.len() == 0 {
+                    break Ready(Ok(()));
+                } else {
+                    break Ready(Err(Error::BufferTooSmallForNeedMin(self.outbuf.cap(), self.out[0].len())));
+                }
+            }
+            match Self::attempt_output(self.as_mut(), cx) {
+                Ready(Ok(())) => {}
+                Ready(Err(e)) => break Ready(Err(e)),
+                Pending => break Pending,
+            }
+        }
+    }
+
     fn loop_body(mut self: Pin<&mut Self>, cx: &mut Context) -> Result<Option<Poll<CaItem>>, Error> {
         use Poll::*;
         let output_res_1: Option<Poll<()>> = 'll1: loop {
@@ -1054,10 +1490,19 @@ impl CaProto {
             }
         };
         let need_min = self.state.need_min();
+        if self.buf.cap() < need_min && need_min <= PAYLOAD_MAX {
+            debug!(
+                "grow input buffer  {} -> {}  {:?}",
+                self.buf.cap(),
+                need_min,
+                self.remote_addr_dbg
+            );
+            self.buf.grow(need_min);
+        }
         let read_res = {
             if self.buf.cap() < need_min {
                 self.state = CaState::Done;
-                let e = Error::BufferTooSmallForNeedMin(self.buf.cap(), self.state.need_min());
+                let e = Error::BufferTooSmallForNeedMin(self.buf.cap(), need_min);
                 Err(e)
             } else if self.buf.len() < need_min {
                 let (w, mut rbuf) = self.inpbuf_conn(need_min)?;
@@ -1152,24 +1597,25 @@ impl CaProto {
                     }
                 }
                 CaState::ExtHead(hi) => {
+                    let mut hi = hi.clone();
                     let payload_size = self.buf.read_u32_be()?;
                     let data_count = self.buf.read_u32_be()?;
-                    if payload_size > 1024 * 256 {
-                        warn!(
-                            "ExtHead  data_type {}  payload_size {payload_size}  data_count {data_count}",
-                            hi.data_type
-                        );
+                    trace!(
+                        "ExtHead  data_type {}  payload_size {payload_size}  data_count {data_count}",
+                        hi.data_type
+                    );
+                    if payload_size as usize > PAYLOAD_MAX {
+                        self.state = CaState::Done;
+                        return Err(Error::PayloadTooLarge(payload_size));
                     }
-                    if payload_size <= 16368 {
-                        warn!(
-                            "ExtHead  data_type {}  payload_size {payload_size}  data_count {data_count}",
-                            hi.data_type
-                        );
-                        let msg = CaMsg::from_proto_infos(hi, &[], self.array_truncate)?;
+                    hi.payload_size = payload_size;
+                    hi.data_count = data_count;
+                    if payload_size == 0 {
+                        let msg = CaMsg::from_proto_infos(&hi, &[], self.array_truncate)?;
                         self.state = CaState::StdHead;
                         Ok(Some(CaItem::Msg(msg)))
                     } else {
-                        self.state = CaState::Payload(hi.clone());
+                        self.state = CaState::Payload(hi);
                         Ok(None)
                     }
                 }
@@ -1206,3 +1652,154 @@ impl Stream for CaProto {
         }
     }
 }
+
+#[test]
+fn parse_ctrl_double_meta() {
+    let mut payload = Vec::new();
+    // status, severity, precision, padding
+    payload.extend_from_slice(&[0, 0, 0, 0, 0, 3, 0, 0]);
+    let mut units = [0u8; MAX_UNITS_SIZE];
+    units[..2].copy_from_slice(b"mA");
+    payload.extend_from_slice(&units);
+    for v in [10., -10., 9., 8., -8., -9., 5., -5.] {
+        payload.extend_from_slice(&f64::to_be_bytes(v));
+    }
+    // The value itself.
+    payload.extend_from_slice(&f64::to_be_bytes(1.5));
+    let hi = HeadInfo {
+        cmdid: 1,
+        payload_size: payload.len() as u32,
+        data_type: 28 + 6,
+        data_count: 1,
+        param1: 1,
+        param2: 7,
+    };
+    let msg = CaMsg::from_proto_infos(&hi, &payload, 1024).unwrap();
+    let meta = match msg.ty {
+        CaMsgTy::EventAddResMeta(x) => {
+            assert_eq!(x.subid, 7);
+            x.meta
+        }
+        x => panic!("unexpected {x:?}"),
+    };
+    let exp = CaChannelMeta {
+        units: "mA".into(),
+        precision: 3,
+        upper_disp_limit: 10.,
+        lower_disp_limit: -10.,
+        upper_alarm_limit: 9.,
+        upper_warning_limit: 8.,
+        lower_warning_limit: -8.,
+        lower_alarm_limit: -9.,
+        upper_ctrl_limit: 5.,
+        lower_ctrl_limit: -5.,
+        enum_strs: Vec::new(),
+    };
+    assert_eq!(meta, exp);
+}
+
+#[test]
+fn parse_ctrl_enum_meta() {
+    let mut payload = Vec::new();
+    // status, severity, number of states
+    payload.extend_from_slice(&[0, 0, 0, 0, 0, 2]);
+    for s in ["Off", "On"] {
+        let mut b = [0u8; MAX_ENUM_STRING_SIZE];
+        b[..s.len()].copy_from_slice(s.as_bytes());
+        payload.extend_from_slice(&b);
+    }
+    payload.resize(6 + MAX_ENUM_STATES * MAX_ENUM_STRING_SIZE, 0);
+    payload.extend_from_slice(&[0, 1]);
+    let hi = HeadInfo {
+        cmdid: 1,
+        payload_size: payload.len() as u32,
+        data_type: 28 + 3,
+        data_count: 1,
+        param1: 1,
+        param2: 7,
+    };
+    let msg = CaMsg::from_proto_infos(&hi, &payload, 1024).unwrap();
+    match msg.ty {
+        CaMsgTy::EventAddResMeta(x) => {
+            assert_eq!(x.meta.enum_strs, vec!["Off".to_string(), "On".to_string()]);
+            assert_eq!(x.meta.units, "");
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+}
+
+#[test]
+fn parse_ctrl_meta_short_payload() {
+    let hi = HeadInfo {
+        cmdid: 1,
+        payload_size: 12,
+        data_type: 28 + 6,
+        data_count: 1,
+        param1: 1,
+        param2: 7,
+    };
+    let res = CaMsg::from_proto_infos(&hi, &[0; 12], 1024);
+    assert!(matches!(res, Err(Error::NotEnoughPayload)));
+}
+
+#[test]
+fn parse_ext_header_large_payload() {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+    // More than 0xffff bytes and larger than the initial input buffer.
+    let n = 40000;
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&1u16.to_be_bytes());
+    msg.extend_from_slice(&0xffffu16.to_be_bytes());
+    msg.extend_from_slice(&20u16.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&1u32.to_be_bytes());
+    msg.extend_from_slice(&7u32.to_be_bytes());
+    msg.extend_from_slice(&(16 + 8 * n as u32).to_be_bytes());
+    msg.extend_from_slice(&(n as u32).to_be_bytes());
+    // status, severity, secs, nanos, padding
+    msg.extend_from_slice(&[0; 16]);
+    for i in 0..n {
+        msg.extend_from_slice(&(i as f64).to_be_bytes());
+    }
+    let fut = async move {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = match listener.local_addr()? {
+            std::net::SocketAddr::V4(x) => x,
+            _ => panic!(),
+        };
+        let jh = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            stream.write_all(&msg).await?;
+            Ok::<_, io::Error>(stream)
+        });
+        let tcp = TcpStream::connect(addr).await?;
+        let mut proto = CaProto::new(tcp, addr, n);
+        let ev = loop {
+            match proto.next().await {
+                Some(Ok(CaItem::Msg(x))) => break x,
+                Some(Ok(CaItem::Empty)) => {}
+                Some(Err(e)) => panic!("{e}"),
+                None => panic!("no message"),
+            }
+        };
+        assert!(proto.buf.cap() >= 16 + 8 * n);
+        match ev.ty {
+            CaMsgTy::EventAddRes(x) => {
+                assert_eq!(x.subid, 7);
+                assert_eq!(x.data_count, n as u32);
+                match x.value.data {
+                    CaDataValue::Array(CaDataArrayValue::F64(v)) => {
+                        assert_eq!(v.len(), n);
+                        assert_eq!(v[n - 1], (n - 1) as f64);
+                    }
+                    x => panic!("unexpected {x:?}"),
+                }
+            }
+            x => panic!("unexpected {x:?}"),
+        }
+        let _stream = jh.await.unwrap()?;
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
@@ -10,6 +10,7 @@ use futures_util::StreamExt;
 use log::*;
 use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::net::SocketAddrV4;
 use std::time::Duration;
 use std::time::Instant;
 use taskrun::tokio;
@@ -17,14 +18,13 @@ use tokio::task::JoinHandle;
 
 const DB_WORKER_COUNT: usize = 4;
 
-async fn resolve_address(addr_str: &str) -> Result<SocketAddr, Error> {
-    const PORT_DEFAULT: u16 = 5064;
+async fn resolve_address(addr_str: &str, port_default: u16) -> Result<SocketAddr, Error> {
     let ac = match addr_str.parse::<SocketAddr>() {
         Ok(k) => k,
         Err(_) => {
             trace!("can not parse {addr_str} as SocketAddr");
             match addr_str.parse::<IpAddr>() {
-                Ok(k) => SocketAddr::new(k, PORT_DEFAULT),
+                Ok(k) => SocketAddr::new(k, port_default),
                 Err(_e) => {
                     trace!("can not parse {addr_str} as IpAddr");
                     let (hostname, port) = if addr_str.contains(":") {
@@ -34,7 +34,7 @@ async fn resolve_address(addr_str: &str) -> Result<SocketAddr, Error> {
                             it.next().unwrap().parse::<u16>().unwrap(),
                         )
                     } else {
-                        (addr_str.to_string(), PORT_DEFAULT)
+                        (addr_str.to_string(), port_default)
                     };
                     let host = format!("{}:{}", hostname.clone(), port);
                     match tokio::net::lookup_host(host.clone()).await {
@@ -54,6 +54,25 @@ async fn resolve_address(addr_str: &str) -> Result<SocketAddr, Error> {
     Ok(ac)
 }
 
+pub(crate) async fn resolve_addresses(addrs: &[String], port_default: u16) -> Vec<SocketAddrV4> {
+    let mut ret = Vec::new();
+    for s in addrs {
+        match resolve_address(s, port_default).await {
+            Ok(SocketAddr::V4(addr)) => {
+                trace!("resolved {s} as {addr}");
+                ret.push(addr);
+            }
+            Ok(SocketAddr::V6(_)) => {
+                error!("TODO check ipv6 support for IOCs");
+            }
+            Err(e) => {
+                error!("can not resolve {s} {e}");
+            }
+        }
+    }
+    ret
+}
+
 struct DbUpdateWorker {
     jh: JoinHandle<()>,
 }
@@ -70,28 +89,22 @@ impl DbUpdateWorker {
 
 pub async fn ca_search(opts: CaIngestOpts, channels: &Vec<String>) -> Result<(), Error> {
     info!("ca_search begin");
-    let pg = dbpg::conn::make_pg_client(opts.postgresql_config())
+    let mut pg = dbpg::conn::make_pg_client(opts.postgresql_config())
         .await
         .map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
-    dbpg::schema::schema_check(&pg)
+    dbpg::schema::schema_check(&mut pg)
         .await
         .map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
-    let mut addrs = Vec::new();
-    for s in opts.search() {
-        match resolve_address(s).await {
-            Ok(addr) => {
-                trace!("resolved {s} as {addr}");
-                addrs.push(addr);
-            }
-            Err(e) => {
-                error!("can not resolve {s} {e}");
-            }
-        }
+    let search_addrs = opts.ca_search_addrs()?;
+    if search_addrs.search.is_empty() && search_addrs.name_servers.is_empty() {
+        return Err(Error::with_msg_no_trace("no search addresses configured"));
     }
+    let addrs = resolve_addresses(&search_addrs.search, search_addrs.port_default).await;
+    let name_servers = resolve_addresses(&search_addrs.name_servers, search_addrs.port_default).await;
     let gw_addrs = {
         let mut gw_addrs = Vec::new();
         for s in opts.search_blacklist() {
-            match resolve_address(s).await {
+            match resolve_address(s, search_addrs.port_default).await {
                 Ok(addr) => {
                     trace!("resolved {s} as {addr}");
                     gw_addrs.push(addr);
@@ -103,17 +116,8 @@ pub async fn ca_search(opts: CaIngestOpts, channels: &Vec<String>) -> Result<(),
         }
         gw_addrs
     };
-    let addrs = addrs
-        .into_iter()
-        .filter_map(|x| match x {
-            SocketAddr::V4(x) => Some(x),
-            SocketAddr::V6(_) => {
-                error!("TODO check ipv6 support for IOCs");
-                None
-            }
-        })
-        .collect();
     let mut finder = FindIocStream::new(addrs, Duration::from_millis(800), 20, 4);
+    finder.set_name_servers(name_servers);
     finder.set_stop_on_empty_queue();
     for ch in channels.iter() {
         finder.push(ch.into());
@@ -1,10 +1,19 @@
+use crate::ca::beacon::CA_REPEATER_PORT_DEFAULT;
+use crate::ca::caenv::EpicsCaEnv;
+use crate::ca::caenv::CA_SERVER_PORT_DEFAULT;
+use crate::ca::policy::ArchivingPolicy;
+use crate::pva::pvaenv::EpicsPvaEnv;
+use crate::pva::PVA_BROADCAST_PORT_DEFAULT;
 use err::Error;
 use ingest_linux::net::local_hostname;
 use netpod::log::*;
 use netpod::Database;
-use netpod::ScyllaConfig;
+use scywr::config::ScyllaConfig;
+use scywr::insertworker::Ttls;
+use scywr::sink::SinkOpts;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::time::Duration;
 use taskrun::tokio;
@@ -15,17 +24,28 @@ use tokio::io::AsyncReadExt;
 pub struct CaIngestOpts {
     backend: String,
     channels: PathBuf,
+    channels_reload_on_change: Option<bool>,
     api_bind: Option<String>,
+    #[serde(default)]
     search: Vec<String>,
     #[serde(default)]
     search_blacklist: Vec<String>,
+    // Also take the search addresses from EPICS_CA_ADDR_LIST and friends, default if `search` is empty.
+    search_epics_env: Option<bool>,
+    #[serde(default)]
+    name_servers: Vec<String>,
+    // Search addresses for pvAccess channels, EPICS_PVA_ADDR_LIST and friends if empty.
+    #[serde(default)]
+    pva_search: Vec<String>,
+    beacon_listen: Option<bool>,
+    beacon_port: Option<u16>,
     whitelist: Option<String>,
     blacklist: Option<String>,
     max_simul: Option<usize>,
     #[serde(with = "humantime_serde")]
     timeout: Option<Duration>,
     postgresql: Database,
-    scylla: ScyllaConfig,
+    scylla: Option<ScyllaConfig>,
     array_truncate: Option<usize>,
     insert_worker_count: Option<usize>,
     insert_scylla_sessions: Option<usize>,
@@ -43,7 +63,20 @@ pub struct CaIngestOpts {
     ttl_d1: Option<Duration>,
     #[serde(with = "humantime_serde")]
     ttl_binned: Option<Duration>,
+    // Used for data imported from other archivers, which is typically kept much longer.
+    #[serde(with = "humantime_serde")]
+    ttl_archive_index: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    ttl_archive_d0: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    ttl_archive_d1: Option<Duration>,
     pub test_bsread_addr: Option<String>,
+    spool_dir: Option<PathBuf>,
+    spool_segment_max: Option<u64>,
+    spool_max: Option<u64>,
+    sink_file: Option<PathBuf>,
+    #[serde(default)]
+    policies: Vec<ChannelPolicyRule>,
 }
 
 impl CaIngestOpts {
@@ -51,6 +84,14 @@ impl CaIngestOpts {
         &self.backend
     }
 
+    pub fn channels_file(&self) -> &PathBuf {
+        &self.channels
+    }
+
+    pub fn channels_reload_on_change(&self) -> bool {
+        self.channels_reload_on_change.unwrap_or(false)
+    }
+
     pub fn api_bind(&self) -> String {
         self.api_bind.clone().unwrap_or_else(|| "0.0.0.0:3011".into())
     }
@@ -59,8 +100,8 @@ impl CaIngestOpts {
         &self.postgresql
     }
 
-    pub fn scylla_config(&self) -> &ScyllaConfig {
-        &self.scylla
+    pub fn scylla_config(&self) -> Option<&ScyllaConfig> {
+        self.scylla.as_ref()
     }
 
     pub fn search(&self) -> &Vec<String> {
@@ -71,6 +112,61 @@ impl CaIngestOpts {
         &self.search_blacklist
     }
 
+    pub fn search_epics_env(&self) -> bool {
+        self.search_epics_env.unwrap_or(self.search.is_empty())
+    }
+
+    pub fn ca_search_addrs(&self) -> Result<CaSearchAddrs, Error> {
+        let mut ret = CaSearchAddrs {
+            search: self.search.clone(),
+            name_servers: self.name_servers.clone(),
+            port_default: CA_SERVER_PORT_DEFAULT,
+        };
+        if self.search_epics_env() {
+            let env = EpicsCaEnv::from_env()?;
+            debug!("{env:?}");
+            for a in env.search_addrs()? {
+                if !ret.search.contains(&a) {
+                    ret.search.push(a);
+                }
+            }
+            for a in env.name_servers {
+                if !ret.name_servers.contains(&a) {
+                    ret.name_servers.push(a);
+                }
+            }
+            ret.port_default = env.server_port;
+        }
+        Ok(ret)
+    }
+
+    pub fn pva_search_addrs(&self) -> Result<PvaSearchAddrs, Error> {
+        if self.pva_search.is_empty() {
+            let env = EpicsPvaEnv::from_env()?;
+            debug!("{env:?}");
+            let ret = PvaSearchAddrs {
+                search: env.search_addrs()?,
+                port_default: env.broadcast_port,
+            };
+            Ok(ret)
+        } else {
+            let ret = PvaSearchAddrs {
+                search: self.pva_search.clone(),
+                port_default: PVA_BROADCAST_PORT_DEFAULT,
+            };
+            Ok(ret)
+        }
+    }
+
+    // Port to listen for CA beacons, None if disabled. Opt-in, see `bind_reuse`.
+    pub fn beacon_port(&self) -> Option<u16> {
+        if self.beacon_listen.unwrap_or(false) {
+            Some(self.beacon_port.unwrap_or(CA_REPEATER_PORT_DEFAULT))
+        } else {
+            None
+        }
+    }
+
     pub fn timeout(&self) -> Duration {
         Duration::from_millis(1200)
     }
@@ -132,6 +228,105 @@ impl CaIngestOpts {
             .clone()
             .unwrap_or_else(|| Duration::from_secs(60 * 60 * 24 * 40))
     }
+
+    pub fn archive_ttls(&self) -> Ttls {
+        let ttl_archive = Duration::from_secs(60 * 60 * 24 * 365 * 10);
+        Ttls {
+            index: self.ttl_archive_index.unwrap_or(ttl_archive),
+            d0: self.ttl_archive_d0.unwrap_or(ttl_archive),
+            d1: self.ttl_archive_d1.unwrap_or(ttl_archive),
+            binned: self.ttl_binned(),
+        }
+    }
+
+    pub fn spool_dir(&self) -> Option<PathBuf> {
+        self.spool_dir.clone()
+    }
+
+    pub fn spool_segment_max(&self) -> u64 {
+        self.spool_segment_max.unwrap_or(1024 * 1024 * 64)
+    }
+
+    pub fn spool_max(&self) -> u64 {
+        self.spool_max.unwrap_or(1024 * 1024 * 1024 * 8)
+    }
+
+    pub fn sink_file(&self) -> Option<PathBuf> {
+        self.sink_file.clone()
+    }
+
+    // The file sink, if configured, replaces scylla as destination.
+    pub fn sink_opts(&self) -> Result<SinkOpts, Error> {
+        if let Some(path) = self.sink_file() {
+            Ok(SinkOpts::File(path))
+        } else if let Some(scyconf) = &self.scylla {
+            let ret = SinkOpts::Scylla {
+                scyconf: scyconf.clone(),
+                sessions: self.insert_scylla_sessions(),
+            };
+            Ok(ret)
+        } else {
+            Err(Error::with_msg_no_trace("config needs either scylla or sink_file"))
+        }
+    }
+}
+
+// Archiving policy for all channels which match the regex.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChannelPolicyRule {
+    channels: String,
+    #[serde(default, with = "humantime_serde")]
+    monitor: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    scan: Option<Duration>,
+    deadband_abs: Option<f64>,
+    deadband_rel: Option<f64>,
+    protocol: Option<ChannelProtocol>,
+}
+
+// Where to search for channels, from the config and the EPICS environment.
+#[derive(Clone, Debug)]
+pub struct CaSearchAddrs {
+    pub search: Vec<String>,
+    pub name_servers: Vec<String>,
+    // Port for entries which do not specify one.
+    pub port_default: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct PvaSearchAddrs {
+    pub search: Vec<String>,
+    pub port_default: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelProtocol {
+    Ca,
+    Pva,
+}
+
+impl Default for ChannelProtocol {
+    fn default() -> Self {
+        ChannelProtocol::Ca
+    }
+}
+
+impl ChannelProtocol {
+    fn from_token(s: &str) -> Result<Self, Error> {
+        match s {
+            "ca" => Ok(ChannelProtocol::Ca),
+            "pva" => Ok(ChannelProtocol::Pva),
+            _ => Err(Error::with_msg_no_trace(format!("unknown protocol {s:?}"))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelConfig {
+    pub name: String,
+    pub policy: ArchivingPolicy,
+    pub protocol: ChannelProtocol,
 }
 
 #[test]
@@ -163,11 +358,37 @@ scylla:
     assert_eq!(conf.channels, PathBuf::from("/some/path/file.txt"));
     assert_eq!(conf.api_bind, Some("0.0.0.0:3011".to_string()));
     assert_eq!(conf.search.get(0), Some(&"172.26.0.255".to_string()));
-    assert_eq!(conf.scylla.hosts.get(1), Some(&"sf-nube-12:19042".to_string()));
+    assert_eq!(
+        conf.scylla.as_ref().unwrap().hosts.get(1),
+        Some(&"sf-nube-12:19042".to_string())
+    );
     assert_eq!(conf.ttl_d1, Some(Duration::from_millis(1000 * (60 * 10 + 3) + 45)));
     assert_eq!(conf.ttl_binned, Some(Duration::from_secs(60 * 60 * 70)));
 }
 
+#[test]
+fn parse_config_file_sink() {
+    let conf = r###"
+backend: lab
+channels: /some/path/file.txt
+search:
+  - 127.0.0.255
+postgresql:
+  host: localhost
+  port: 5432
+  user: USER
+  pass: PASS
+  name: NAME
+sink_file: /tmp/daqingest/items.jsonl
+"###;
+    let conf: CaIngestOpts = serde_yaml::from_slice(conf.as_bytes()).unwrap();
+    assert!(conf.scylla_config().is_none());
+    match conf.sink_opts().unwrap() {
+        SinkOpts::File(path) => assert_eq!(path, PathBuf::from("/tmp/daqingest/items.jsonl")),
+        _ => panic!("expect file sink"),
+    }
+}
+
 #[test]
 fn test_duration_parse() {
     #[derive(Serialize, Deserialize)]
@@ -189,35 +410,177 @@ fn test_duration_parse() {
     assert_eq!(a.dur, Duration::from_millis(3170));
 }
 
-pub async fn parse_config(config: PathBuf) -> Result<(CaIngestOpts, Vec<String>), Error> {
+// Only the config file itself, without the channel list.
+pub async fn parse_config_opts(config: PathBuf) -> Result<CaIngestOpts, Error> {
     let mut file = OpenOptions::new().read(true).open(config).await?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).await?;
     let conf: CaIngestOpts = serde_yaml::from_slice(&buf).map_err(|e| Error::with_msg_no_trace(format!("{:?}", e)))?;
-    drop(file);
-    let re_p = regex::Regex::new(&conf.whitelist.clone().unwrap_or("--nothing-whitelisted--".into()))?;
-    let re_n = regex::Regex::new(&conf.blacklist.clone().unwrap_or("--nothing-blacklisted--".into()))?;
+    Ok(conf)
+}
+
+pub async fn parse_config(config: PathBuf) -> Result<(CaIngestOpts, Vec<ChannelConfig>), Error> {
+    let conf = parse_config_opts(config).await?;
     let mut file = OpenOptions::new().read(true).open(&conf.channels).await?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).await?;
+    let channels = parse_channel_list(&conf, &buf)?;
+    info!("Parsed {} channels", channels.len());
+    Ok((conf, channels))
+}
+
+// Each line holds a channel name, optionally followed by policy tokens like `scan=10s` and `protocol=pva`.
+// A policy or protocol given on the line takes precedence over the first matching rule from the config.
+fn parse_channel_list(conf: &CaIngestOpts, buf: &[u8]) -> Result<Vec<ChannelConfig>, Error> {
+    let re_p = regex::Regex::new(&conf.whitelist.clone().unwrap_or("--nothing-whitelisted--".into()))?;
+    let re_n = regex::Regex::new(&conf.blacklist.clone().unwrap_or("--nothing-blacklisted--".into()))?;
+    let mut rules = Vec::new();
+    for rule in &conf.policies {
+        let re = regex::Regex::new(&rule.channels)?;
+        let policy = ArchivingPolicy::from_parts(rule.monitor, rule.scan, rule.deadband_abs, rule.deadband_rel)?;
+        rules.push((re, policy, rule.protocol));
+    }
     let lines = buf.split(|&x| x == 0x0a);
     let mut channels = Vec::new();
     for line in lines {
         let line = String::from_utf8_lossy(line);
-        let line = line.trim();
-        let use_line = if line.is_empty() {
-            false
-        } else if let Some(_cs) = re_p.captures(&line) {
+        let mut toks = line.split_whitespace();
+        let name = match toks.next() {
+            Some(x) => x,
+            None => continue,
+        };
+        let use_line = if let Some(_cs) = re_p.captures(name) {
             true
-        } else if re_n.is_match(&line) {
+        } else if re_n.is_match(name) {
             false
         } else {
             true
         };
         if use_line {
-            channels.push(line.into());
+            let mut protocol = None;
+            let mut policy_toks = Vec::new();
+            for tok in toks {
+                match tok.strip_prefix("protocol=") {
+                    Some(x) => {
+                        let x = ChannelProtocol::from_token(x)
+                            .map_err(|e| Error::with_msg_no_trace(format!("channel {name}  {e}")))?;
+                        protocol = Some(x);
+                    }
+                    None => policy_toks.push(tok),
+                }
+            }
+            let rule = rules.iter().find(|(re, ..)| re.is_match(name));
+            let policy = match ArchivingPolicy::from_tokens(policy_toks)
+                .map_err(|e| Error::with_msg_no_trace(format!("channel {name}  {e}")))?
+            {
+                Some(x) => x,
+                None => rule.map_or_else(ArchivingPolicy::default, |(_, p, _)| p.clone()),
+            };
+            let protocol = protocol.or_else(|| rule.and_then(|x| x.2)).unwrap_or_default();
+            let ch = ChannelConfig {
+                name: name.into(),
+                policy,
+                protocol,
+            };
+            channels.push(ch);
         }
     }
-    info!("Parsed {} channels", channels.len());
-    Ok((conf, channels))
+    Ok(channels)
+}
+
+// Returns the channels to add and the channels to remove so that the active set matches the configured one.
+// A channel with a changed policy gets removed and added again.
+pub fn channel_set_diff(
+    active: &BTreeMap<String, ArchivingPolicy>,
+    configured: &[ChannelConfig],
+) -> (Vec<ChannelConfig>, Vec<String>) {
+    let configured: BTreeMap<_, _> = configured.iter().map(|x| (&x.name, x)).collect();
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    for (name, policy) in active {
+        match configured.get(name) {
+            Some(&x) if &x.policy == policy => {}
+            _ => remove.push(name.clone()),
+        }
+    }
+    for (&name, &x) in &configured {
+        match active.get(name) {
+            Some(policy) if policy == &x.policy => {}
+            _ => add.push(x.clone()),
+        }
+    }
+    (add, remove)
+}
+
+#[test]
+fn channel_set_diff_changes_only() {
+    let ch = |name: &str, policy: ArchivingPolicy| ChannelConfig {
+        name: name.into(),
+        policy,
+        protocol: ChannelProtocol::Ca,
+    };
+    let scan = ArchivingPolicy::Scan {
+        period: Duration::from_secs(5),
+    };
+    let mut active = BTreeMap::new();
+    active.insert("A".to_string(), ArchivingPolicy::default());
+    active.insert("B".to_string(), ArchivingPolicy::default());
+    active.insert("C".to_string(), ArchivingPolicy::default());
+    let configured = vec![
+        ch("A", ArchivingPolicy::default()),
+        ch("C", scan.clone()),
+        ch("D", ArchivingPolicy::default()),
+    ];
+    let (add, remove) = channel_set_diff(&active, &configured);
+    assert_eq!(remove, vec!["B".to_string(), "C".to_string()]);
+    assert_eq!(add, vec![ch("C", scan), ch("D", ArchivingPolicy::default())]);
+}
+
+#[test]
+fn parse_channel_list_policies() {
+    let conf = r###"
+backend: lab
+channels: /some/path/file.txt
+search: []
+blacklist: "^BAD:"
+postgresql:
+  host: localhost
+  port: 5432
+  user: USER
+  pass: PASS
+  name: NAME
+sink_file: /tmp/daqingest/items.jsonl
+policies:
+  - channels: "^SLOW:"
+    scan: 10s
+  - channels: "^PV:"
+    protocol: pva
+"###;
+    let conf: CaIngestOpts = serde_yaml::from_slice(conf.as_bytes()).unwrap();
+    let list = "CH:A\n  \nSLOW:B\nSLOW:C monitor=1s\nBAD:D\nPV:E\nCH:F protocol=pva scan=2s\n";
+    let chs = parse_channel_list(&conf, list.as_bytes()).unwrap();
+    assert_eq!(chs.len(), 5);
+    assert_eq!(chs[3].protocol, ChannelProtocol::Pva);
+    assert_eq!(
+        chs[4].policy,
+        ArchivingPolicy::Scan {
+            period: Duration::from_secs(2)
+        }
+    );
+    assert_eq!(chs[4].protocol, ChannelProtocol::Pva);
+    assert!(parse_channel_list(&conf, b"CH:G protocol=xyz").is_err());
+    assert_eq!(chs[0].name, "CH:A");
+    assert_eq!(chs[0].policy, ArchivingPolicy::default());
+    assert_eq!(
+        chs[1].policy,
+        ArchivingPolicy::Scan {
+            period: Duration::from_secs(10)
+        }
+    );
+    assert_eq!(
+        chs[2].policy,
+        ArchivingPolicy::Monitor {
+            ivl_min: Some(Duration::from_secs(1))
+        }
+    );
 }
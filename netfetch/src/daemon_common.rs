@@ -1,6 +1,9 @@
+use crate::ca::conn::ChannelStateInfo;
 use crate::ca::connset::CaConnSetItem;
+use crate::ca::policy::ArchivingPolicy;
 use async_channel::Sender;
 use serde::Serialize;
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, Serialize, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Channel {
@@ -20,8 +23,12 @@ impl Channel {
 #[derive(Debug, Clone)]
 pub enum DaemonEvent {
     TimerTick(u32, Sender<u32>),
-    ChannelAdd(Channel),
+    ChannelAdd(Channel, ArchivingPolicy),
     ChannelRemove(Channel),
+    FindChannel(String, Sender<Vec<(String, Vec<String>)>>),
+    ChannelState(Channel, Sender<Option<ChannelStateInfo>>),
+    ChannelStatesAll(Sender<Vec<ChannelStateInfo>>),
+    ChannelPoliciesAll(Sender<BTreeMap<String, ArchivingPolicy>>),
     CaConnSetItem(CaConnSetItem),
     Shutdown,
 }
@@ -31,8 +38,12 @@ impl DaemonEvent {
         use DaemonEvent::*;
         match self {
             TimerTick(_, _) => format!("TimerTick"),
-            ChannelAdd(x) => format!("ChannelAdd {x:?}"),
+            ChannelAdd(x, policy) => format!("ChannelAdd {x:?} {policy:?}"),
             ChannelRemove(x) => format!("ChannelRemove {x:?}"),
+            FindChannel(x, _) => format!("FindChannel {x:?}"),
+            ChannelState(x, _) => format!("ChannelState {x:?}"),
+            ChannelStatesAll(_) => format!("ChannelStatesAll"),
+            ChannelPoliciesAll(_) => format!("ChannelPoliciesAll"),
             CaConnSetItem(_) => format!("CaConnSetItem"),
             Shutdown => format!("Shutdown"),
         }
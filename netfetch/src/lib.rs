@@ -7,6 +7,7 @@ pub mod linuxhelper;
 pub mod metrics;
 pub mod netbuf;
 pub mod patchcollect;
+pub mod pva;
 pub mod rt;
 pub mod senderpolling;
 #[cfg(test)]
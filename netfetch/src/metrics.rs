@@ -1,6 +1,11 @@
+use crate::ca::conn::ChannelStateInfo;
+use crate::ca::policy::ArchivingPolicy;
 use crate::ca::IngestCommons;
 use crate::ca::METRICS;
+use crate::daemon_common::Channel;
 use crate::daemon_common::DaemonEvent;
+use crate::errconv::ErrConv;
+use async_channel::Receiver;
 use async_channel::Sender;
 use axum::extract::Query;
 use err::Error;
@@ -13,19 +18,21 @@ use stats::CaConnStatsAgg;
 use stats::CaConnStatsAggDiff;
 use stats::DaemonStats;
 use std::collections::HashMap;
-use std::net::SocketAddrV4;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use taskrun::tokio;
 
+const DAEMON_REPLY_TIMEOUT: Duration = Duration::from_millis(4000);
+
 pub struct StatsSet {
     daemon: Arc<DaemonStats>,
+    insert_store: Arc<CaConnStats>,
 }
 
 impl StatsSet {
-    pub fn new(daemon: Arc<DaemonStats>) -> Self {
-        Self { daemon }
+    pub fn new(daemon: Arc<DaemonStats>, insert_store: Arc<CaConnStats>) -> Self {
+        Self { daemon, insert_store }
     }
 }
 
@@ -45,16 +52,23 @@ async fn find_channel(
     dcom: Arc<DaemonComm>,
 ) -> axum::Json<Vec<(String, Vec<String>)>> {
     let pattern = params.get("pattern").map_or(String::new(), |x| x.clone()).to_string();
-    // TODO ask Daemon for that information.
-    error!("TODO find_channel");
-    let res = Vec::new();
-    axum::Json(res)
+    match dcom.find_channel(pattern).await {
+        Ok(res) => axum::Json(res),
+        Err(e) => {
+            error!("find_channel {e}");
+            axum::Json(Vec::new())
+        }
+    }
 }
 
 async fn channel_add_inner(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> Result<(), Error> {
-    if let (Some(backend), Some(name)) = (params.get("backend"), params.get("name")) {
-        error!("TODO channel_add_inner");
-        Err(Error::with_msg_no_trace(format!("TODO channel_add_inner")))
+    if let (Some(_backend), Some(name)) = (params.get("backend"), params.get("name")) {
+        let toks: Vec<_> = ["monitor", "scan", "deadband_abs", "deadband_rel"]
+            .into_iter()
+            .filter_map(|k| params.get(k).map(|v| format!("{k}={v}")))
+            .collect();
+        let policy = ArchivingPolicy::from_tokens(toks.iter().map(String::as_str))?.unwrap_or_default();
+        dcom.channel_add(name.into(), policy).await
     } else {
         Err(Error::with_msg_no_trace(format!("wrong parameters given")))
     }
@@ -63,7 +77,10 @@ async fn channel_add_inner(params: HashMap<String, String>, dcom: Arc<DaemonComm
 async fn channel_add(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> axum::Json<bool> {
     let ret = match channel_add_inner(params, dcom).await {
         Ok(_) => true,
-        Err(_) => false,
+        Err(e) => {
+            error!("channel_add {e}");
+            false
+        }
     };
     axum::Json(ret)
 }
@@ -71,15 +88,6 @@ async fn channel_add(params: HashMap<String, String>, dcom: Arc<DaemonComm>) ->
 async fn channel_remove(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> axum::Json<serde_json::Value> {
     use axum::Json;
     use serde_json::Value;
-    let addr = if let Some(x) = params.get("addr") {
-        if let Ok(addr) = x.parse::<SocketAddrV4>() {
-            addr
-        } else {
-            return Json(Value::Bool(false));
-        }
-    } else {
-        return Json(Value::Bool(false));
-    };
     let _backend = if let Some(x) = params.get("backend") {
         x
     } else {
@@ -90,23 +98,39 @@ async fn channel_remove(params: HashMap<String, String>, dcom: Arc<DaemonComm>)
     } else {
         return Json(Value::Bool(false));
     };
-    error!("TODO channel_remove");
-    Json(Value::Bool(false))
+    match dcom.channel_remove(name.into()).await {
+        Ok(()) => Json(Value::Bool(true)),
+        Err(e) => {
+            error!("channel_remove {e}");
+            Json(Value::Bool(false))
+        }
+    }
 }
 
-async fn channel_state(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> axum::Json<bool> {
+async fn channel_state(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> axum::Json<Option<ChannelStateInfo>> {
     let name = params.get("name").map_or(String::new(), |x| x.clone()).to_string();
-    error!("TODO channel_state");
-    axum::Json(false)
+    match dcom.channel_state(name).await {
+        Ok(res) => axum::Json(res),
+        Err(e) => {
+            error!("channel_state {e}");
+            axum::Json(None)
+        }
+    }
 }
 
-async fn channel_states(
-    params: HashMap<String, String>,
-    dcom: Arc<DaemonComm>,
-) -> axum::Json<Vec<crate::ca::conn::ChannelStateInfo>> {
+async fn channel_states(params: HashMap<String, String>, dcom: Arc<DaemonComm>) -> axum::Json<Vec<ChannelStateInfo>> {
     let limit = params.get("limit").map(|x| x.parse()).unwrap_or(Ok(40)).unwrap_or(40);
-    error!("TODO channel_state");
-    axum::Json(Vec::new())
+    match dcom.channel_states_all().await {
+        Ok(mut res) => {
+            res.sort_by(|a, b| a.name.cmp(&b.name));
+            res.truncate(limit);
+            axum::Json(res)
+        }
+        Err(e) => {
+            error!("channel_states {e}");
+            axum::Json(Vec::new())
+        }
+    }
 }
 
 async fn extra_inserts_conf_set(v: ExtraInsertsConf, dcom: Arc<DaemonComm>) -> axum::Json<bool> {
@@ -132,6 +156,43 @@ impl DaemonComm {
     pub fn new(tx: Sender<DaemonEvent>) -> Self {
         Self { tx }
     }
+
+    pub async fn channel_add(&self, name: String, policy: ArchivingPolicy) -> Result<(), Error> {
+        self.tx
+            .send(DaemonEvent::ChannelAdd(Channel::new(name), policy))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn channel_remove(&self, name: String) -> Result<(), Error> {
+        self.tx.send(DaemonEvent::ChannelRemove(Channel::new(name))).await?;
+        Ok(())
+    }
+
+    pub async fn find_channel(&self, pattern: String) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.tx.send(DaemonEvent::FindChannel(pattern, tx)).await?;
+        Self::recv_reply(rx).await
+    }
+
+    pub async fn channel_state(&self, name: String) -> Result<Option<ChannelStateInfo>, Error> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.tx.send(DaemonEvent::ChannelState(Channel::new(name), tx)).await?;
+        Self::recv_reply(rx).await
+    }
+
+    pub async fn channel_states_all(&self) -> Result<Vec<ChannelStateInfo>, Error> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.tx.send(DaemonEvent::ChannelStatesAll(tx)).await?;
+        Self::recv_reply(rx).await
+    }
+
+    async fn recv_reply<T>(rx: Receiver<T>) -> Result<T, Error> {
+        match tokio::time::timeout(DAEMON_REPLY_TIMEOUT, rx.recv()).await {
+            Ok(x) => x.err_conv(),
+            Err(_) => Err(Error::with_msg_no_trace("timeout waiting for daemon reply")),
+        }
+    }
 }
 
 fn make_routes(dcom: Arc<DaemonComm>, stats_set: StatsSet) -> axum::Router {
@@ -162,7 +223,8 @@ fn make_routes(dcom: Arc<DaemonComm>, stats_set: StatsSet) -> axum::Router {
                 || async move {
                     info!("metrics");
                     let s1 = stats_set.daemon.prometheus();
-                    s1
+                    let s2 = stats_set.insert_store.prometheus();
+                    [s1, s2].join("")
                 }
             }),
         )
@@ -0,0 +1,10 @@
+pub mod conn;
+pub mod connset;
+pub mod nt;
+pub mod proto;
+pub mod pvaenv;
+pub mod pvdata;
+pub mod search;
+
+pub const PVA_SERVER_PORT_DEFAULT: u16 = 5075;
+pub const PVA_BROADCAST_PORT_DEFAULT: u16 = 5076;
@@ -0,0 +1,693 @@
+use super::connset::PvaConnSetEvent;
+use super::nt::nt_sample;
+use super::nt::NtSample;
+use super::proto::parse_msgs;
+use super::proto::MonitorReq;
+use super::proto::MonitorRes;
+use super::proto::PvaMsg;
+use super::proto::Status;
+use super::pvdata::FieldDesc;
+use super::pvdata::PvReader;
+use super::pvdata::PvValue;
+use super::pvdata::TypeCache;
+use async_channel::Receiver;
+use async_channel::Sender;
+use dbpg::seriesbychannel::CanSendChannelInfoResult;
+use dbpg::seriesbychannel::ChannelInfoQuery;
+use dbpg::seriesbychannel::ChannelInfoResult;
+use err::Error;
+use log::*;
+use netpod::timeunits::*;
+use netpod::ScalarType;
+use netpod::Shape;
+use netpod::TS_MSP_GRID_SPACING;
+use netpod::TS_MSP_GRID_UNIT;
+use scywr::iteminsertqueue::ChannelStatus;
+use scywr::iteminsertqueue::ChannelStatusClosedReason;
+use scywr::iteminsertqueue::ChannelStatusItem;
+use scywr::iteminsertqueue::InsertItem;
+use scywr::iteminsertqueue::QueryItem;
+use series::SeriesId;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use taskrun::tokio;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(4000);
+const ECHO_IVL: Duration = Duration::from_millis(10000);
+// Without anything received for this long, the server is considered gone.
+const RECV_TIMEOUT: Duration = Duration::from_millis(30000);
+const RECV_BUFFER_SIZE: u32 = 1024 * 64;
+// Number of updates the server may send before it has to wait for an ack.
+pub const MONITOR_QUEUE_SIZE: u32 = 16;
+// Updates kept while the series lookup is in flight.
+const PENDING_MAX: usize = 64;
+
+#[derive(Debug)]
+pub enum ConnCommand {
+    ChannelAdd(String),
+    ChannelRemove(String),
+    Shutdown,
+}
+
+#[derive(Debug)]
+pub enum PvaConnEvent {
+    // The server does not serve the channel (any longer), it should be searched again.
+    ChannelNotFound(String),
+    // The connection is gone, with the channels which were still assigned to it.
+    Ended(Vec<String>),
+}
+
+type LookupRes = (u32, u32, Result<ChannelInfoResult, dbpg::seriesbychannel::Error>);
+
+struct SeriesLookupSender {
+    cid: u32,
+    seq: u32,
+    tx: Sender<LookupRes>,
+}
+
+impl CanSendChannelInfoResult for SeriesLookupSender {
+    fn make_send(
+        &self,
+        item: Result<ChannelInfoResult, dbpg::seriesbychannel::Error>,
+    ) -> dbpg::seriesbychannel::BoxedSend {
+        let tx = self.tx.clone();
+        let (cid, seq) = (self.cid, self.seq);
+        let fut = async move { tx.send((cid, seq, item)).await.map_err(|_| ()) };
+        Box::pin(fut)
+    }
+}
+
+struct MspState {
+    ts_msp: u64,
+    inserted_in_ts_msp: u64,
+    ts_msp_grid_last: u32,
+}
+
+struct Monitoring {
+    sid: u32,
+    ioid: u32,
+    desc: FieldDesc,
+    val: PvValue,
+    unacked: u32,
+    series: Option<SeriesId>,
+    // Type and shape of the current series. An array series has the largest length seen so far,
+    // a longer update or a change of type starts a new series.
+    kind: Option<(ScalarType, Shape)>,
+    // Identifies the latest series lookup, results of earlier ones are dropped.
+    lookup_seq: u32,
+    pending: VecDeque<NtSample>,
+    msp: MspState,
+}
+
+// The type and shape for a new series if the sample does not fit the current one.
+fn kind_change(kind: &Option<(ScalarType, Shape)>, sample: &NtSample) -> Option<(ScalarType, Shape)> {
+    let new = (sample.scalar_type.clone(), sample.shape.clone());
+    let (scalar_type, shape) = match kind {
+        Some(x) => x,
+        None => return Some(new),
+    };
+    if *scalar_type != sample.scalar_type {
+        return Some(new);
+    }
+    match (shape, &sample.shape) {
+        (Shape::Scalar, Shape::Scalar) => None,
+        (Shape::Wave(n), Shape::Wave(m)) if m <= n => None,
+        _ => Some(new),
+    }
+}
+
+impl Monitoring {
+    fn update(&mut self, body: &[u8], big_endian: bool, cache: &mut TypeCache) -> Result<NtSample, Error> {
+        let rd = &mut PvReader::new(body, big_endian);
+        let changed = rd.bitset().map_err(Error::from_string)?;
+        rd.changed(&self.desc, &mut self.val, &changed, cache)
+            .map_err(Error::from_string)?;
+        let _overrun = rd.bitset().map_err(Error::from_string)?;
+        match &self.desc {
+            FieldDesc::Struct(sd) => nt_sample(sd, &self.val),
+            _ => Err(Error::with_msg_no_trace("no structure")),
+        }
+    }
+
+    fn insert_item(&mut self, series: SeriesId, sample: NtSample) -> InsertItem {
+        let ts = match sample.ts {
+            Some(x) => x,
+            None => {
+                let dt = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO);
+                SEC * dt.as_secs() + dt.subsec_nanos() as u64
+            }
+        };
+        let st = &mut self.msp;
+        // Timestamps come from the server, a step back starts a new partition.
+        let (ts_msp, msp_bump) = if st.inserted_in_ts_msp >= 64000 || ts < st.ts_msp || st.ts_msp + HOUR <= ts {
+            let div = SEC * 10;
+            let ts_msp = ts / div * div;
+            st.inserted_in_ts_msp = 1;
+            if ts_msp == st.ts_msp {
+                (ts_msp, false)
+            } else {
+                st.ts_msp = ts_msp;
+                (ts_msp, true)
+            }
+        } else {
+            st.inserted_in_ts_msp += 1;
+            (st.ts_msp, false)
+        };
+        let ts_msp_grid = (ts / TS_MSP_GRID_UNIT / TS_MSP_GRID_SPACING * TS_MSP_GRID_SPACING) as u32;
+        let ts_msp_grid = if st.ts_msp_grid_last != ts_msp_grid {
+            st.ts_msp_grid_last = ts_msp_grid;
+            Some(ts_msp_grid)
+        } else {
+            None
+        };
+        let (scalar_type, shape) = match &self.kind {
+            Some(x) => x.clone(),
+            None => (sample.scalar_type, sample.shape),
+        };
+        InsertItem {
+            series,
+            ts_msp,
+            ts_lsp: ts - ts_msp,
+            msp_bump,
+            ts_msp_grid,
+            pulse: 0,
+            scalar_type,
+            shape,
+            val: sample.value,
+            status: sample.status,
+            severity: sample.severity,
+        }
+    }
+}
+
+enum ChannelState {
+    // Waiting for the connection to be validated.
+    Init,
+    Creating,
+    Subscribing { sid: u32, ioid: u32 },
+    Monitoring(Box<Monitoring>),
+    // The monitor was refused or delivers something we can not store, kept until removed.
+    Failed { sid: u32 },
+}
+
+struct ChannelSt {
+    name: String,
+    state: ChannelState,
+}
+
+// A connection to one pvAccess server, monitors all channels assigned to that server.
+pub struct PvaConn {
+    addr: SocketAddrV4,
+    backend: String,
+    channels: BTreeMap<u32, ChannelSt>,
+    cid_by_name: BTreeMap<String, u32>,
+    cid_by_ioid: BTreeMap<u32, u32>,
+    cid_next: u32,
+    ioid_next: u32,
+    validated: bool,
+    cache: TypeCache,
+    out: Vec<u8>,
+    last_recv: Instant,
+    item_tx: Sender<QueryItem>,
+    lookup_tx: Sender<ChannelInfoQuery>,
+    lookup_res_tx: Sender<LookupRes>,
+    lookup_res_rx: Receiver<LookupRes>,
+    cmd_rx: Receiver<ConnCommand>,
+    event_tx: Sender<PvaConnSetEvent>,
+}
+
+impl PvaConn {
+    pub fn new(
+        addr: SocketAddrV4,
+        backend: String,
+        item_tx: Sender<QueryItem>,
+        lookup_tx: Sender<ChannelInfoQuery>,
+        cmd_rx: Receiver<ConnCommand>,
+        event_tx: Sender<PvaConnSetEvent>,
+    ) -> Self {
+        let (lookup_res_tx, lookup_res_rx) = async_channel::bounded(64);
+        Self {
+            addr,
+            backend,
+            channels: BTreeMap::new(),
+            cid_by_name: BTreeMap::new(),
+            cid_by_ioid: BTreeMap::new(),
+            cid_next: 1,
+            ioid_next: 1,
+            validated: false,
+            cache: TypeCache::new(),
+            out: Vec::new(),
+            last_recv: Instant::now(),
+            item_tx,
+            lookup_tx,
+            lookup_res_tx,
+            lookup_res_rx,
+            cmd_rx,
+            event_tx,
+        }
+    }
+
+    pub async fn run(mut self) {
+        let reason = match self.run_inner().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("pva conn {}  {e}", self.addr);
+                ChannelStatusClosedReason::ProtocolError
+            }
+        };
+        debug!("pva conn {} done {reason:?}", self.addr);
+        let mut names = Vec::new();
+        for ch in std::mem::take(&mut self.channels).into_values() {
+            if let ChannelState::Monitoring(st) = &ch.state {
+                if let Some(series) = &st.series {
+                    let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                        ts: SystemTime::now(),
+                        series: series.clone(),
+                        status: ChannelStatus::Closed(reason.clone()),
+                    });
+                    if self.item_tx.send(item).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            names.push(ch.name);
+        }
+        if !matches!(reason, ChannelStatusClosedReason::ShutdownCommand) {
+            let ev = PvaConnSetEvent::Conn(self.addr, PvaConnEvent::Ended(names));
+            self.event_tx.send(ev).await.ok();
+        }
+    }
+
+    async fn run_inner(&mut self) -> Result<ChannelStatusClosedReason, Error> {
+        let mut stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(self.addr)).await {
+            Ok(x) => x?,
+            Err(_) => return Err(Error::with_msg_no_trace("connect timeout")),
+        };
+        stream.set_nodelay(true)?;
+        self.last_recv = Instant::now();
+        let cmd_rx = self.cmd_rx.clone();
+        let lookup_res_rx = self.lookup_res_rx.clone();
+        let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + ECHO_IVL, ECHO_IVL);
+        let mut inbuf = Vec::new();
+        let mut rbuf = vec![0; RECV_BUFFER_SIZE as usize];
+        loop {
+            tokio::select! {
+                x = stream.read(&mut rbuf) => {
+                    let n = x?;
+                    if n == 0 {
+                        return Ok(ChannelStatusClosedReason::ProtocolDone);
+                    }
+                    self.last_recv = Instant::now();
+                    inbuf.extend_from_slice(&rbuf[..n]);
+                    let (msgs, n) = parse_msgs(&inbuf, &mut self.cache).map_err(Error::from_string)?;
+                    inbuf.drain(..n);
+                    for (_, msg) in msgs {
+                        self.handle_msg(msg).await?;
+                    }
+                }
+                x = cmd_rx.recv() => match x {
+                    Ok(ConnCommand::ChannelAdd(name)) => self.channel_add(name),
+                    Ok(ConnCommand::ChannelRemove(name)) => self.channel_remove(&name).await?,
+                    Ok(ConnCommand::Shutdown) | Err(_) => return Ok(ChannelStatusClosedReason::ShutdownCommand),
+                },
+                x = lookup_res_rx.recv() => {
+                    if let Ok((cid, seq, res)) = x {
+                        self.series_lookup_done(cid, seq, res).await?;
+                    }
+                }
+                _ = tick.tick() => {
+                    if self.last_recv.elapsed() > RECV_TIMEOUT {
+                        return Ok(ChannelStatusClosedReason::IocTimeout);
+                    }
+                    self.push_msg(&PvaMsg::Echo(Vec::new()));
+                }
+            }
+            if !self.out.is_empty() {
+                stream.write_all(&self.out).await?;
+                self.out.clear();
+            }
+        }
+    }
+
+    fn push_msg(&mut self, msg: &PvaMsg) {
+        self.out.extend_from_slice(&msg.to_vec(false));
+    }
+
+    async fn emit(&self, item: QueryItem) -> Result<(), Error> {
+        self.item_tx
+            .send(item)
+            .await
+            .map_err(|_| Error::with_msg_no_trace("insert queue closed"))
+    }
+
+    async fn handle_msg(&mut self, msg: PvaMsg) -> Result<(), Error> {
+        match msg {
+            PvaMsg::ValidationReq { auth, .. } => {
+                let auth = if auth.iter().any(|x| x == "anonymous") {
+                    "anonymous"
+                } else {
+                    ""
+                };
+                self.push_msg(&PvaMsg::ValidationRes {
+                    buffer_size: RECV_BUFFER_SIZE,
+                    registry_size: 0x7fff,
+                    qos: 0,
+                    auth: auth.into(),
+                });
+            }
+            PvaMsg::Validated(status) => {
+                if !status.is_ok() {
+                    return Err(Error::with_msg_no_trace(format!("validation failed {status:?}")));
+                }
+                self.validated = true;
+                let mut create = Vec::new();
+                for (cid, ch) in self.channels.iter_mut() {
+                    if let ChannelState::Init = ch.state {
+                        ch.state = ChannelState::Creating;
+                        create.push((*cid, ch.name.clone()));
+                    }
+                }
+                if !create.is_empty() {
+                    self.push_msg(&PvaMsg::CreateChannel(create));
+                }
+            }
+            PvaMsg::CreateChannelRes { cid, sid, status } => self.channel_created(cid, sid, status).await,
+            PvaMsg::MonitorRes(MonitorRes::Init { ioid, status, desc }) => self.monitor_init(ioid, status, desc),
+            PvaMsg::MonitorRes(MonitorRes::Data { ioid, body, big_endian }) => {
+                self.monitor_data(ioid, &body, big_endian).await?
+            }
+            PvaMsg::MonitorRes(MonitorRes::End { ioid }) => {
+                if let Some(cid) = self.cid_by_ioid.get(&ioid).cloned() {
+                    self.channel_lost(cid).await?;
+                }
+            }
+            PvaMsg::DestroyChannel { cid, .. } => self.channel_lost(cid).await?,
+            PvaMsg::Message { ioid, text } => info!("pva message from {}  ioid {ioid}  {text}", self.addr),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn channel_add(&mut self, name: String) {
+        if self.cid_by_name.contains_key(&name) {
+            return;
+        }
+        let cid = self.cid_next;
+        self.cid_next += 1;
+        let state = if self.validated {
+            self.push_msg(&PvaMsg::CreateChannel(vec![(cid, name.clone())]));
+            ChannelState::Creating
+        } else {
+            ChannelState::Init
+        };
+        self.cid_by_name.insert(name.clone(), cid);
+        self.channels.insert(cid, ChannelSt { name, state });
+    }
+
+    async fn channel_remove(&mut self, name: &str) -> Result<(), Error> {
+        let cid = match self.cid_by_name.remove(name) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let ch = match self.channels.remove(&cid) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let (sid, ioid, series) = match ch.state {
+            // A pending create is destroyed when the response arrives.
+            ChannelState::Init | ChannelState::Creating => return Ok(()),
+            ChannelState::Subscribing { sid, ioid } => (sid, Some(ioid), None),
+            ChannelState::Monitoring(st) => (st.sid, Some(st.ioid), st.series),
+            ChannelState::Failed { sid } => (sid, None, None),
+        };
+        if let Some(ioid) = ioid {
+            self.cid_by_ioid.remove(&ioid);
+            self.push_msg(&PvaMsg::DestroyRequest { sid, ioid });
+        }
+        self.push_msg(&PvaMsg::DestroyChannel { sid, cid });
+        if let Some(series) = series {
+            self.emit(QueryItem::ChannelStatus(ChannelStatusItem {
+                ts: SystemTime::now(),
+                series,
+                status: ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove),
+            }))
+            .await?;
+        }
+        Ok(())
+    }
+
+    // The server dropped the channel or ended its monitor.
+    async fn channel_lost(&mut self, cid: u32) -> Result<(), Error> {
+        let ch = match self.channels.remove(&cid) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        self.cid_by_name.remove(&ch.name);
+        debug!("pva channel {} lost on {}", ch.name, self.addr);
+        match ch.state {
+            ChannelState::Subscribing { ioid, .. } => {
+                self.cid_by_ioid.remove(&ioid);
+            }
+            ChannelState::Monitoring(st) => {
+                self.cid_by_ioid.remove(&st.ioid);
+                if let Some(series) = st.series {
+                    self.emit(QueryItem::ChannelStatus(ChannelStatusItem {
+                        ts: SystemTime::now(),
+                        series,
+                        status: ChannelStatus::Closed(ChannelStatusClosedReason::ProtocolDone),
+                    }))
+                    .await?;
+                }
+            }
+            _ => {}
+        }
+        let ev = PvaConnSetEvent::Conn(self.addr, PvaConnEvent::ChannelNotFound(ch.name));
+        self.event_tx.send(ev).await.ok();
+        Ok(())
+    }
+
+    async fn channel_created(&mut self, cid: u32, sid: u32, status: Status) {
+        let ch = match self.channels.get_mut(&cid) {
+            Some(x) => x,
+            None => {
+                if status.is_ok() {
+                    self.push_msg(&PvaMsg::DestroyChannel { sid, cid });
+                }
+                return;
+            }
+        };
+        if status.is_ok() {
+            let ioid = self.ioid_next;
+            self.ioid_next += 1;
+            ch.state = ChannelState::Subscribing { sid, ioid };
+            self.cid_by_ioid.insert(ioid, cid);
+            self.push_msg(&PvaMsg::MonitorReq(MonitorReq::Init {
+                sid,
+                ioid,
+                queue_size: MONITOR_QUEUE_SIZE,
+            }));
+        } else {
+            debug!("pva create channel {} on {} failed {status:?}", ch.name, self.addr);
+            let name = ch.name.clone();
+            self.channels.remove(&cid);
+            self.cid_by_name.remove(&name);
+            let ev = PvaConnSetEvent::Conn(self.addr, PvaConnEvent::ChannelNotFound(name));
+            self.event_tx.send(ev).await.ok();
+        }
+    }
+
+    fn monitor_init(&mut self, ioid: u32, status: Status, desc: Option<FieldDesc>) {
+        let ch = match self.cid_by_ioid.get(&ioid).and_then(|cid| self.channels.get_mut(cid)) {
+            Some(x) => x,
+            None => return,
+        };
+        let sid = match ch.state {
+            ChannelState::Subscribing { sid, .. } => sid,
+            _ => return,
+        };
+        match desc {
+            Some(desc @ FieldDesc::Struct(_)) if status.is_ok() => {
+                let st = Monitoring {
+                    sid,
+                    ioid,
+                    val: PvValue::default_for(&desc),
+                    desc,
+                    unacked: 0,
+                    series: None,
+                    kind: None,
+                    lookup_seq: 0,
+                    pending: VecDeque::new(),
+                    msp: MspState {
+                        ts_msp: 0,
+                        inserted_in_ts_msp: u64::MAX,
+                        ts_msp_grid_last: 0,
+                    },
+                };
+                ch.state = ChannelState::Monitoring(Box::new(st));
+                self.push_msg(&PvaMsg::MonitorReq(MonitorReq::Start { sid, ioid }));
+            }
+            _ => {
+                warn!("pva monitor {} on {} failed {status:?}", ch.name, self.addr);
+                ch.state = ChannelState::Failed { sid };
+                self.cid_by_ioid.remove(&ioid);
+            }
+        }
+    }
+
+    async fn monitor_data(&mut self, ioid: u32, body: &[u8], big_endian: bool) -> Result<(), Error> {
+        let cid = match self.cid_by_ioid.get(&ioid) {
+            Some(x) => *x,
+            None => return Ok(()),
+        };
+        let ch = match self.channels.get_mut(&cid) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let st = match &mut ch.state {
+            ChannelState::Monitoring(x) => x,
+            _ => return Ok(()),
+        };
+        // A bad update only fails its channel, the others on the connection go on.
+        let sample = st.update(body, big_endian, &mut self.cache);
+        st.unacked += 1;
+        let ack = if st.unacked >= MONITOR_QUEUE_SIZE / 2 {
+            let nfree = st.unacked;
+            st.unacked = 0;
+            Some(MonitorReq::Ack {
+                sid: st.sid,
+                ioid,
+                nfree,
+            })
+        } else {
+            None
+        };
+        let sample = match sample {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("pva channel {} on {}  {e}", ch.name, self.addr);
+                let (sid, series) = (st.sid, st.series.take());
+                ch.state = ChannelState::Failed { sid };
+                self.cid_by_ioid.remove(&ioid);
+                self.push_msg(&PvaMsg::DestroyRequest { sid, ioid });
+                if let Some(series) = series {
+                    self.emit(QueryItem::ChannelStatus(ChannelStatusItem {
+                        ts: SystemTime::now(),
+                        series,
+                        status: ChannelStatus::Closed(ChannelStatusClosedReason::ProtocolError),
+                    }))
+                    .await?;
+                }
+                return Ok(());
+            }
+        };
+        if let Some(kind) = kind_change(&st.kind, &sample) {
+            if let Some(series) = st.series.take() {
+                debug!("pva channel {} on {} changes to {kind:?}", ch.name, self.addr);
+                let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                    ts: SystemTime::now(),
+                    series,
+                    status: ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove),
+                });
+                self.item_tx
+                    .send(item)
+                    .await
+                    .map_err(|_| Error::with_msg_no_trace("insert queue closed"))?;
+            }
+            // Shorter arrays of the same type still fit the new series, anything else is dropped.
+            let grows = matches!(
+                (&st.kind, &kind),
+                (Some((a, Shape::Wave(_))), (b, Shape::Wave(_))) if a == b
+            );
+            if !grows {
+                st.pending.clear();
+            }
+            st.kind = Some(kind.clone());
+            st.lookup_seq += 1;
+            let item = ChannelInfoQuery {
+                backend: self.backend.clone(),
+                channel: ch.name.clone(),
+                scalar_type: kind.0.to_scylla_i32(),
+                shape_dims: kind.1.to_scylla_vec(),
+                tx: Box::pin(SeriesLookupSender {
+                    cid,
+                    seq: st.lookup_seq,
+                    tx: self.lookup_res_tx.clone(),
+                }),
+            };
+            self.lookup_tx
+                .send(item)
+                .await
+                .map_err(|_| Error::with_msg_no_trace("series lookup closed"))?;
+        }
+        match st.series.clone() {
+            Some(series) => {
+                let item = st.insert_item(series, sample);
+                self.item_tx
+                    .send(QueryItem::Insert(item))
+                    .await
+                    .map_err(|_| Error::with_msg_no_trace("insert queue closed"))?;
+            }
+            None => {
+                if st.pending.len() >= PENDING_MAX {
+                    st.pending.pop_front();
+                }
+                st.pending.push_back(sample);
+            }
+        }
+        if let Some(ack) = ack {
+            self.push_msg(&PvaMsg::MonitorReq(ack));
+        }
+        Ok(())
+    }
+
+    async fn series_lookup_done(
+        &mut self,
+        cid: u32,
+        seq: u32,
+        res: Result<ChannelInfoResult, dbpg::seriesbychannel::Error>,
+    ) -> Result<(), Error> {
+        let ch = match self.channels.get_mut(&cid) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let st = match &mut ch.state {
+            ChannelState::Monitoring(x) if x.lookup_seq == seq => x,
+            _ => return Ok(()),
+        };
+        match res {
+            Ok(res) => {
+                let series = res.series.into_inner();
+                st.series = Some(series.clone());
+                let item = QueryItem::ChannelStatus(ChannelStatusItem {
+                    ts: SystemTime::now(),
+                    series: series.clone(),
+                    status: ChannelStatus::Opened,
+                });
+                let mut items = vec![item];
+                for sample in std::mem::take(&mut st.pending) {
+                    items.push(QueryItem::Insert(st.insert_item(series.clone(), sample)));
+                }
+                for item in items {
+                    self.emit(item).await?;
+                }
+            }
+            Err(e) => {
+                warn!("pva series lookup for {} failed  {e}", ch.name);
+                let (sid, ioid) = (st.sid, st.ioid);
+                ch.state = ChannelState::Failed { sid };
+                self.cid_by_ioid.remove(&ioid);
+                self.push_msg(&PvaMsg::DestroyRequest { sid, ioid });
+            }
+        }
+        Ok(())
+    }
+}
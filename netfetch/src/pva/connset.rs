@@ -0,0 +1,268 @@
+use super::conn::ConnCommand;
+use super::conn::PvaConn;
+use super::conn::PvaConnEvent;
+use super::search::pva_search;
+use async_channel::Receiver;
+use async_channel::Sender;
+use dbpg::seriesbychannel::ChannelInfoQuery;
+use err::Error;
+use log::*;
+use scywr::iteminsertqueue::QueryItem;
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use std::time::Instant;
+use taskrun::tokio;
+use tokio::task::JoinHandle;
+
+const SEARCH_TIMEOUT: Duration = Duration::from_millis(1500);
+const SEARCH_BACKOFF_MIN: Duration = Duration::from_millis(2000);
+const SEARCH_BACKOFF_MAX: Duration = Duration::from_millis(60000);
+const SEARCH_COUNT_MAX: usize = 512;
+const CHECK_IVL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum PvaConnSetEvent {
+    ChannelAdd(String),
+    ChannelRemove(String),
+    Shutdown,
+    SearchDone(Vec<(String, Option<SocketAddrV4>)>),
+    Conn(SocketAddrV4, PvaConnEvent),
+}
+
+#[derive(Debug)]
+enum Assignment {
+    Unassigned { search_next: Instant, backoff: Duration },
+    Searching { backoff: Duration },
+    Assigned(SocketAddrV4),
+}
+
+impl Assignment {
+    fn search_now() -> Self {
+        Assignment::Unassigned {
+            search_next: Instant::now(),
+            backoff: SEARCH_BACKOFF_MIN,
+        }
+    }
+}
+
+struct ConnRes {
+    tx: Sender<ConnCommand>,
+    jh: JoinHandle<()>,
+}
+
+pub struct PvaConnSetCtrl {
+    tx: Sender<PvaConnSetEvent>,
+    jh: JoinHandle<Result<(), Error>>,
+}
+
+impl PvaConnSetCtrl {
+    pub async fn add_channel(&self, name: String) -> Result<(), Error> {
+        self.send(PvaConnSetEvent::ChannelAdd(name)).await
+    }
+
+    pub async fn remove_channel(&self, name: String) -> Result<(), Error> {
+        self.send(PvaConnSetEvent::ChannelRemove(name)).await
+    }
+
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.send(PvaConnSetEvent::Shutdown).await
+    }
+
+    pub async fn join(self) -> Result<(), Error> {
+        self.jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
+        Ok(())
+    }
+
+    async fn send(&self, item: PvaConnSetEvent) -> Result<(), Error> {
+        self.tx
+            .send(item)
+            .await
+            .map_err(|_| Error::with_msg_no_trace("pva connset closed"))
+    }
+}
+
+// Finds the server of each pvAccess channel and keeps one connection per server.
+pub struct PvaConnSet {
+    backend: String,
+    search_addrs: Vec<SocketAddrV4>,
+    channels: BTreeMap<String, Assignment>,
+    conns: BTreeMap<SocketAddrV4, ConnRes>,
+    search_pending: bool,
+    tx: Sender<PvaConnSetEvent>,
+    rx: Receiver<PvaConnSetEvent>,
+    storage_insert_tx: Sender<QueryItem>,
+    channel_info_query_tx: Sender<ChannelInfoQuery>,
+}
+
+impl PvaConnSet {
+    pub fn start(
+        backend: String,
+        storage_insert_tx: Sender<QueryItem>,
+        channel_info_query_tx: Sender<ChannelInfoQuery>,
+        search_addrs: Vec<SocketAddrV4>,
+    ) -> PvaConnSetCtrl {
+        // Unbounded, the connections report to it also while it waits for them to finish.
+        let (tx, rx) = async_channel::unbounded();
+        let connset = Self {
+            backend,
+            search_addrs,
+            channels: BTreeMap::new(),
+            conns: BTreeMap::new(),
+            search_pending: false,
+            tx: tx.clone(),
+            rx,
+            storage_insert_tx,
+            channel_info_query_tx,
+        };
+        let jh = tokio::spawn(connset.run());
+        PvaConnSetCtrl { tx, jh }
+    }
+
+    async fn run(mut self) -> Result<(), Error> {
+        let rx = self.rx.clone();
+        let mut tick = tokio::time::interval(CHECK_IVL);
+        loop {
+            tokio::select! {
+                x = rx.recv() => match x {
+                    Ok(PvaConnSetEvent::Shutdown) | Err(_) => break,
+                    Ok(ev) => self.handle_event(ev).await,
+                },
+                _ = tick.tick() => self.check_search(),
+            }
+        }
+        debug!("pva connset shutdown");
+        for (_, conn) in &self.conns {
+            conn.tx.send(ConnCommand::Shutdown).await.ok();
+        }
+        for (_, conn) in std::mem::take(&mut self.conns) {
+            conn.jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, ev: PvaConnSetEvent) {
+        match ev {
+            PvaConnSetEvent::ChannelAdd(name) => {
+                if !self.channels.contains_key(&name) {
+                    self.channels.insert(name, Assignment::search_now());
+                }
+            }
+            PvaConnSetEvent::ChannelRemove(name) => {
+                if let Some(Assignment::Assigned(addr)) = self.channels.remove(&name) {
+                    if let Some(conn) = self.conns.get(&addr) {
+                        conn.tx.send(ConnCommand::ChannelRemove(name)).await.ok();
+                    }
+                }
+            }
+            PvaConnSetEvent::Shutdown => {}
+            PvaConnSetEvent::SearchDone(res) => {
+                self.search_pending = false;
+                for (name, addr) in res {
+                    let backoff = match self.channels.get(&name) {
+                        Some(Assignment::Searching { backoff }) => *backoff,
+                        _ => continue,
+                    };
+                    match addr {
+                        Some(addr) => self.assign(name, addr).await,
+                        None => {
+                            trace!("pva channel {name} not found");
+                            self.channels.insert(
+                                name,
+                                Assignment::Unassigned {
+                                    search_next: Instant::now() + backoff,
+                                    backoff: (backoff * 2).min(SEARCH_BACKOFF_MAX),
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+            PvaConnSetEvent::Conn(addr, PvaConnEvent::ChannelNotFound(name)) => {
+                if let Some(st) = self.channels.get_mut(&name) {
+                    if let Assignment::Assigned(a) = st {
+                        if *a == addr {
+                            *st = Assignment::Unassigned {
+                                search_next: Instant::now() + SEARCH_BACKOFF_MIN,
+                                backoff: SEARCH_BACKOFF_MIN,
+                            };
+                        }
+                    }
+                }
+            }
+            PvaConnSetEvent::Conn(addr, PvaConnEvent::Ended(_)) => {
+                debug!("pva conn {addr} ended");
+                self.conns.remove(&addr);
+                // Also the channels which were assigned while the connection was going down.
+                for st in self.channels.values_mut() {
+                    if let Assignment::Assigned(a) = st {
+                        if *a == addr {
+                            *st = Assignment::Unassigned {
+                                search_next: Instant::now() + SEARCH_BACKOFF_MIN,
+                                backoff: SEARCH_BACKOFF_MIN,
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn assign(&mut self, name: String, addr: SocketAddrV4) {
+        let conn = self.conns.entry(addr).or_insert_with(|| {
+            debug!("pva new conn {addr}");
+            let (tx, rx) = async_channel::bounded(64);
+            let conn = PvaConn::new(
+                addr,
+                self.backend.clone(),
+                self.storage_insert_tx.clone(),
+                self.channel_info_query_tx.clone(),
+                rx,
+                self.tx.clone(),
+            );
+            let jh = tokio::spawn(conn.run());
+            ConnRes { tx, jh }
+        });
+        if conn.tx.send(ConnCommand::ChannelAdd(name.clone())).await.is_ok() {
+            self.channels.insert(name, Assignment::Assigned(addr));
+        } else {
+            // The connection is already gone, its end event is on the way.
+            self.channels.insert(name, Assignment::search_now());
+        }
+    }
+
+    fn check_search(&mut self) {
+        if self.search_pending {
+            return;
+        }
+        let now = Instant::now();
+        let mut names = Vec::new();
+        for (name, st) in self.channels.iter_mut() {
+            if names.len() >= SEARCH_COUNT_MAX {
+                break;
+            }
+            if let Assignment::Unassigned { search_next, backoff } = st {
+                if *search_next <= now {
+                    names.push(name.clone());
+                    *st = Assignment::Searching { backoff: *backoff };
+                }
+            }
+        }
+        if names.is_empty() {
+            return;
+        }
+        self.search_pending = true;
+        let addrs = self.search_addrs.clone();
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let res = match pva_search(&addrs, &names, SEARCH_TIMEOUT).await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("pva search  {e}");
+                    names.into_iter().map(|x| (x, None)).collect()
+                }
+            };
+            tx.send(PvaConnSetEvent::SearchDone(res)).await.ok();
+        });
+    }
+}
@@ -0,0 +1,202 @@
+// Values of the normative types NTScalar, NTScalarArray and NTEnum as insert items.
+// Types with a Channel Access equivalent map to the same scalar type so that series match,
+// unsigned types are stored as the unsigned types, as bsread does.
+
+use super::pvdata::field_at;
+use super::pvdata::FieldDesc;
+use super::pvdata::PvArray;
+use super::pvdata::PvScalar;
+use super::pvdata::PvValue;
+use super::pvdata::StructDesc;
+use err::Error;
+use netpod::timeunits::SEC;
+use netpod::ScalarType;
+use netpod::Shape;
+use scywr::iteminsertqueue::ArrayValue;
+use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::ScalarValue;
+
+const CA_STRING: u16 = 0;
+const CA_SHORT: u16 = 1;
+const CA_FLOAT: u16 = 2;
+const CA_ENUM: u16 = 3;
+const CA_CHAR: u16 = 4;
+const CA_LONG: u16 = 5;
+const CA_DOUBLE: u16 = 6;
+
+#[derive(Debug)]
+pub struct NtSample {
+    pub scalar_type: ScalarType,
+    pub shape: Shape,
+    pub value: DataValue,
+    // From the timeStamp field, if present and set.
+    pub ts: Option<u64>,
+    pub status: u16,
+    pub severity: u16,
+}
+
+fn scalar_value(x: &PvScalar) -> Result<(ScalarType, ScalarValue), Error> {
+    use PvScalar as P;
+    use ScalarValue as V;
+    let ret = match x {
+        P::Bool(x) => (ScalarType::BOOL, V::Bool(*x)),
+        P::I8(x) => (ScalarType::from_ca_id(CA_CHAR)?, V::I8(*x)),
+        P::U8(x) => (ScalarType::U8, V::U8(*x)),
+        P::I16(x) => (ScalarType::from_ca_id(CA_SHORT)?, V::I16(*x)),
+        P::U16(x) => (ScalarType::U16, V::U16(*x)),
+        P::I32(x) => (ScalarType::from_ca_id(CA_LONG)?, V::I32(*x)),
+        P::U32(x) => (ScalarType::U32, V::U32(*x)),
+        P::I64(x) => (ScalarType::I64, V::I64(*x)),
+        P::U64(x) => (ScalarType::U64, V::U64(*x)),
+        P::F32(x) => (ScalarType::from_ca_id(CA_FLOAT)?, V::F32(*x)),
+        P::F64(x) => (ScalarType::from_ca_id(CA_DOUBLE)?, V::F64(*x)),
+        P::String(x) => (ScalarType::from_ca_id(CA_STRING)?, V::String(x.clone())),
+    };
+    Ok(ret)
+}
+
+fn array_value(x: &PvArray) -> Result<(ScalarType, ArrayValue), Error> {
+    use ArrayValue as V;
+    use PvArray as P;
+    let ret = match x {
+        P::Bool(x) => (ScalarType::BOOL, V::Bool(x.clone())),
+        P::I8(x) => (ScalarType::from_ca_id(CA_CHAR)?, V::I8(x.clone())),
+        P::U8(x) => (ScalarType::U8, V::U8(x.clone())),
+        P::I16(x) => (ScalarType::from_ca_id(CA_SHORT)?, V::I16(x.clone())),
+        P::U16(x) => (ScalarType::U16, V::U16(x.clone())),
+        P::I32(x) => (ScalarType::from_ca_id(CA_LONG)?, V::I32(x.clone())),
+        P::U32(x) => (ScalarType::U32, V::U32(x.clone())),
+        P::I64(x) => (ScalarType::I64, V::I64(x.clone())),
+        P::U64(x) => (ScalarType::U64, V::U64(x.clone())),
+        P::F32(x) => (ScalarType::from_ca_id(CA_FLOAT)?, V::F32(x.clone())),
+        P::F64(x) => (ScalarType::from_ca_id(CA_DOUBLE)?, V::F64(x.clone())),
+        P::String(x) => (ScalarType::from_ca_id(CA_STRING)?, V::String(x.clone())),
+    };
+    Ok(ret)
+}
+
+fn as_u64(v: &PvValue) -> Option<u64> {
+    match v {
+        PvValue::Scalar(x) => match x {
+            PvScalar::I8(x) => Some(*x as u64),
+            PvScalar::I16(x) => Some(*x as u64),
+            PvScalar::I32(x) => Some(*x as u64),
+            PvScalar::I64(x) => Some(*x as u64),
+            PvScalar::U8(x) => Some(*x as u64),
+            PvScalar::U16(x) => Some(*x as u64),
+            PvScalar::U32(x) => Some(*x as u64),
+            PvScalar::U64(x) => Some(*x),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The shape of an array is the length of this update, variable size arrays are the common case.
+// The connection picks the shape of the series from it.
+pub fn nt_sample(desc: &StructDesc, val: &PvValue) -> Result<NtSample, Error> {
+    let (fd, v) = field_at(desc, val, "value").ok_or_else(|| Error::with_msg_no_trace("no value field"))?;
+    let (scalar_type, shape, value) = match (fd, v) {
+        (FieldDesc::Scalar(_), PvValue::Scalar(x)) => {
+            let (st, v) = scalar_value(x)?;
+            (st, Shape::Scalar, DataValue::Scalar(v))
+        }
+        (FieldDesc::Array(_), PvValue::Array(x)) => {
+            let (st, v) = array_value(x)?;
+            (st, Shape::Wave(x.len() as u32), DataValue::Array(v))
+        }
+        (FieldDesc::Struct(sd), PvValue::Struct(_)) if sd.id.starts_with("enum_t") => {
+            let index = field_at(desc, val, "value.index")
+                .and_then(|x| as_u64(x.1))
+                .ok_or_else(|| Error::with_msg_no_trace("enum without index"))?;
+            (
+                ScalarType::from_ca_id(CA_ENUM)?,
+                Shape::Scalar,
+                DataValue::Scalar(ScalarValue::Enum(index as i16)),
+            )
+        }
+        _ => {
+            return Err(Error::with_msg_no_trace(format!(
+                "unsupported value field in {:?}",
+                desc.id
+            )))
+        }
+    };
+    let get = |path: &str| field_at(desc, val, path).and_then(|x| as_u64(x.1));
+    let ts = match (get("timeStamp.secondsPastEpoch"), get("timeStamp.nanoseconds")) {
+        (Some(sec), Some(ns)) if sec != 0 => Some(SEC * sec + ns),
+        _ => None,
+    };
+    let ret = NtSample {
+        scalar_type,
+        shape,
+        value,
+        ts,
+        status: get("alarm.status").unwrap_or(0) as u16,
+        severity: get("alarm.severity").unwrap_or(0) as u16,
+    };
+    Ok(ret)
+}
+
+#[test]
+fn nt_enum_sample() {
+    use super::pvdata::ScalarKind;
+    let enum_t = StructDesc::new(
+        "enum_t",
+        vec![
+            ("index", FieldDesc::Scalar(ScalarKind::I32)),
+            ("choices", FieldDesc::Array(ScalarKind::String)),
+        ],
+    );
+    let alarm_t = StructDesc::new(
+        "alarm_t",
+        vec![
+            ("severity", FieldDesc::Scalar(ScalarKind::I32)),
+            ("status", FieldDesc::Scalar(ScalarKind::I32)),
+            ("message", FieldDesc::Scalar(ScalarKind::String)),
+        ],
+    );
+    let desc = StructDesc::new(
+        "epics:nt/NTEnum:1.0",
+        vec![
+            ("value", FieldDesc::Struct(enum_t)),
+            ("alarm", FieldDesc::Struct(alarm_t)),
+        ],
+    );
+    let val = PvValue::Struct(vec![
+        PvValue::Struct(vec![
+            PvValue::Scalar(PvScalar::I32(2)),
+            PvValue::Array(PvArray::String(vec!["a".into(), "b".into(), "c".into()])),
+        ]),
+        PvValue::Struct(vec![
+            PvValue::Scalar(PvScalar::I32(1)),
+            PvValue::Scalar(PvScalar::I32(3)),
+            PvValue::Scalar(PvScalar::String("HIGH".into())),
+        ]),
+    ]);
+    let s = nt_sample(&desc, &val).unwrap();
+    assert!(matches!(s.value, DataValue::Scalar(ScalarValue::Enum(2))));
+    assert_eq!((s.severity, s.status), (1, 3));
+    assert_eq!(s.ts, None);
+}
+
+#[test]
+fn nt_unsigned_sample() {
+    use super::pvdata::ScalarKind;
+    let desc = StructDesc::new(
+        "epics:nt/NTScalar:1.0",
+        vec![("value", FieldDesc::Scalar(ScalarKind::U64))],
+    );
+    let val = PvValue::Struct(vec![PvValue::Scalar(PvScalar::U64(u64::MAX))]);
+    let s = nt_sample(&desc, &val).unwrap();
+    assert_eq!(s.scalar_type, ScalarType::U64);
+    assert!(matches!(s.value, DataValue::Scalar(ScalarValue::U64(u64::MAX))));
+    let desc = StructDesc::new(
+        "epics:nt/NTScalarArray:1.0",
+        vec![("value", FieldDesc::Array(ScalarKind::U16))],
+    );
+    let val = PvValue::Struct(vec![PvValue::Array(PvArray::U16(vec![1, 65535]))]);
+    let s = nt_sample(&desc, &val).unwrap();
+    assert_eq!((s.scalar_type, s.shape), (ScalarType::U16, Shape::Wave(2)));
+    assert!(matches!(s.value, DataValue::Array(ArrayValue::U16(ref x)) if x == &[1, 65535]));
+}
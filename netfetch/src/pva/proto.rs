@@ -0,0 +1,808 @@
+use super::pvdata;
+use super::pvdata::BitSet;
+use super::pvdata::FieldDesc;
+use super::pvdata::PvReader;
+use super::pvdata::PvValue;
+use super::pvdata::PvWriter;
+use super::pvdata::TypeCache;
+use err::thiserror;
+use err::ThisError;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    PvData(#[from] pvdata::Error),
+    BadMagic(u8),
+    PayloadTooLarge(u32),
+    SegmentedMessage,
+    BadMonitorSubcmd(u8),
+}
+
+const PVA_MAGIC: u8 = 0xca;
+const PVA_VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 8;
+// Upper limit for a single message payload.
+const PAYLOAD_MAX: u32 = 1024 * 1024 * 64;
+
+const FLAG_CONTROL: u8 = 0x01;
+// Segment bits: first 0x10, middle 0x30, last 0x20.
+const FLAG_SEGMENTED: u8 = 0x30;
+const FLAG_SEGMENT_FIRST: u8 = 0x10;
+const FLAG_SEGMENT_LAST: u8 = 0x20;
+const FLAG_FROM_SERVER: u8 = 0x40;
+const FLAG_BIG_ENDIAN: u8 = 0x80;
+
+const CMD_VALIDATION: u8 = 0x01;
+const CMD_ECHO: u8 = 0x02;
+const CMD_SEARCH: u8 = 0x03;
+const CMD_SEARCH_RES: u8 = 0x04;
+const CMD_CREATE_CHANNEL: u8 = 0x07;
+const CMD_DESTROY_CHANNEL: u8 = 0x08;
+const CMD_VALIDATED: u8 = 0x09;
+const CMD_MONITOR: u8 = 0x0d;
+const CMD_DESTROY_REQUEST: u8 = 0x0f;
+const CMD_MESSAGE: u8 = 0x12;
+const CTRL_SET_BYTE_ORDER: u8 = 0x02;
+
+const SUBCMD_INIT: u8 = 0x08;
+const SUBCMD_DESTROY: u8 = 0x10;
+const SUBCMD_PIPELINE: u8 = 0x80;
+const SUBCMD_START: u8 = 0x44;
+const SUBCMD_STOP: u8 = 0x04;
+
+#[derive(Debug)]
+pub struct Header {
+    pub flags: u8,
+    pub cmd: u8,
+    pub size: u32,
+}
+
+impl Header {
+    pub fn parse(buf: &[u8; HEADER_LEN]) -> Result<Self, Error> {
+        if buf[0] != PVA_MAGIC {
+            return Err(Error::BadMagic(buf[0]));
+        }
+        let flags = buf[2];
+        let size = [buf[4], buf[5], buf[6], buf[7]];
+        let size = if flags & FLAG_BIG_ENDIAN != 0 {
+            u32::from_be_bytes(size)
+        } else {
+            u32::from_le_bytes(size)
+        };
+        let ret = Self {
+            flags,
+            cmd: buf[3],
+            size,
+        };
+        // For control messages the size field carries a value instead of a payload length.
+        if !ret.is_control() {
+            if size > PAYLOAD_MAX {
+                return Err(Error::PayloadTooLarge(size));
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.flags & FLAG_CONTROL != 0
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.flags & FLAG_BIG_ENDIAN != 0
+    }
+
+    pub fn from_server(&self) -> bool {
+        self.flags & FLAG_FROM_SERVER != 0
+    }
+
+    fn segment(&self) -> u8 {
+        if self.is_control() {
+            0
+        } else {
+            self.flags & FLAG_SEGMENTED
+        }
+    }
+
+    pub fn payload_len(&self) -> usize {
+        if self.is_control() {
+            0
+        } else {
+            self.size as usize
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Ok,
+    Warning(String),
+    Error(String),
+    Fatal(String),
+}
+
+impl Status {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Status::Ok | Status::Warning(_))
+    }
+
+    fn read(rd: &mut PvReader) -> Result<Self, Error> {
+        let ty = rd.u8()?;
+        if ty == 0xff {
+            return Ok(Status::Ok);
+        }
+        let msg = rd.string()?;
+        let _stack = rd.string()?;
+        let ret = match ty {
+            0 => Status::Ok,
+            1 => Status::Warning(msg),
+            2 => Status::Error(msg),
+            _ => Status::Fatal(msg),
+        };
+        Ok(ret)
+    }
+
+    fn write(&self, w: &mut PvWriter) {
+        let (ty, msg) = match self {
+            Status::Ok => return w.u8(0xff),
+            Status::Warning(x) => (1, x),
+            Status::Error(x) => (2, x),
+            Status::Fatal(x) => (3, x),
+        };
+        w.u8(ty);
+        w.string(msg);
+        w.string("");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    pub seq: u32,
+    // Where the responses should go, unspecified for the sender of the request.
+    pub response_addr: SocketAddrV4,
+    pub channels: Vec<(u32, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRes {
+    pub guid: [u8; 12],
+    pub seq: u32,
+    // Unspecified for the sender of the response.
+    pub server_addr: SocketAddrV4,
+    pub found: bool,
+    pub ids: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub enum MonitorReq {
+    Init { sid: u32, ioid: u32, queue_size: u32 },
+    Start { sid: u32, ioid: u32 },
+    Stop { sid: u32, ioid: u32 },
+    Ack { sid: u32, ioid: u32, nfree: u32 },
+    Destroy { sid: u32, ioid: u32 },
+}
+
+#[derive(Debug)]
+pub enum MonitorRes {
+    Init {
+        ioid: u32,
+        status: Status,
+        desc: Option<FieldDesc>,
+    },
+    // Changed bit set, the changed fields and the overrun bit set, decoded with the type of the request.
+    Data {
+        ioid: u32,
+        body: Vec<u8>,
+        big_endian: bool,
+    },
+    End {
+        ioid: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum PvaMsg {
+    SetByteOrder,
+    ValidationReq {
+        buffer_size: u32,
+        registry_size: u16,
+        auth: Vec<String>,
+    },
+    ValidationRes {
+        buffer_size: u32,
+        registry_size: u16,
+        qos: u16,
+        auth: String,
+    },
+    Validated(Status),
+    Echo(Vec<u8>),
+    Search(Search),
+    SearchRes(SearchRes),
+    CreateChannel(Vec<(u32, String)>),
+    CreateChannelRes {
+        cid: u32,
+        sid: u32,
+        status: Status,
+    },
+    DestroyChannel {
+        sid: u32,
+        cid: u32,
+    },
+    MonitorReq(MonitorReq),
+    MonitorRes(MonitorRes),
+    DestroyRequest {
+        sid: u32,
+        ioid: u32,
+    },
+    Message {
+        ioid: u32,
+        text: String,
+    },
+    Ignored(u8),
+}
+
+fn read_addr(rd: &mut PvReader) -> Result<SocketAddrV4, Error> {
+    let b = rd.bytes(16)?;
+    let ip = Ipv4Addr::new(b[12], b[13], b[14], b[15]);
+    // The port is always in network byte order.
+    let p = rd.bytes(2)?;
+    Ok(SocketAddrV4::new(ip, u16::from_be_bytes([p[0], p[1]])))
+}
+
+fn write_addr(w: &mut PvWriter, addr: &SocketAddrV4) {
+    // IPv4 mapped into IPv6, all zero for the unspecified address.
+    if addr.ip().is_unspecified() {
+        w.bytes(&[0; 16]);
+    } else {
+        w.bytes(&[0; 10]);
+        w.bytes(&[0xff, 0xff]);
+        w.bytes(&addr.ip().octets());
+    }
+    w.u16(addr.port());
+}
+
+// The pvRequest for a monitor of the whole structure, with pipelined flow control.
+fn monitor_request(queue_size: u32) -> (FieldDesc, PvValue) {
+    use pvdata::PvScalar;
+    use pvdata::ScalarKind;
+    use pvdata::StructDesc;
+    let options = StructDesc::new(
+        "",
+        vec![
+            ("pipeline", FieldDesc::Scalar(ScalarKind::String)),
+            ("queueSize", FieldDesc::Scalar(ScalarKind::String)),
+        ],
+    );
+    let record = StructDesc::new("", vec![("_options", FieldDesc::Struct(options))]);
+    let desc = FieldDesc::Struct(StructDesc::new(
+        "",
+        vec![
+            ("field", FieldDesc::Struct(StructDesc::new("", Vec::new()))),
+            ("record", FieldDesc::Struct(record)),
+        ],
+    ));
+    let val = PvValue::Struct(vec![
+        PvValue::Struct(Vec::new()),
+        PvValue::Struct(vec![PvValue::Struct(vec![
+            PvValue::Scalar(PvScalar::String("true".into())),
+            PvValue::Scalar(PvScalar::String(queue_size.to_string())),
+        ])]),
+    ]);
+    (desc, val)
+}
+
+impl PvaMsg {
+    pub fn parse(hi: &Header, payload: &[u8], cache: &mut TypeCache) -> Result<Self, Error> {
+        if hi.is_control() {
+            let ret = match hi.cmd {
+                CTRL_SET_BYTE_ORDER => PvaMsg::SetByteOrder,
+                k => PvaMsg::Ignored(k),
+            };
+            return Ok(ret);
+        }
+        let rd = &mut PvReader::new(payload, hi.is_big_endian());
+        let from_server = hi.from_server();
+        let ret = match hi.cmd {
+            CMD_VALIDATION if from_server => {
+                let buffer_size = rd.u32()?;
+                let registry_size = rd.u16()?;
+                let n = rd.size()?.unwrap_or(0);
+                let mut auth = Vec::new();
+                for _ in 0..n {
+                    auth.push(rd.string()?);
+                }
+                PvaMsg::ValidationReq {
+                    buffer_size,
+                    registry_size,
+                    auth,
+                }
+            }
+            CMD_VALIDATION => PvaMsg::ValidationRes {
+                buffer_size: rd.u32()?,
+                registry_size: rd.u16()?,
+                qos: rd.u16()?,
+                auth: rd.string()?,
+            },
+            CMD_VALIDATED => PvaMsg::Validated(Status::read(rd)?),
+            CMD_ECHO => PvaMsg::Echo(payload.to_vec()),
+            CMD_SEARCH => {
+                let seq = rd.u32()?;
+                let _flags = rd.u8()?;
+                rd.bytes(3)?;
+                let response_addr = read_addr(rd)?;
+                let n = rd.size()?.unwrap_or(0);
+                for _ in 0..n {
+                    rd.string()?;
+                }
+                let n = rd.u16()?;
+                let mut channels = Vec::new();
+                for _ in 0..n {
+                    let id = rd.u32()?;
+                    channels.push((id, rd.string()?));
+                }
+                PvaMsg::Search(Search {
+                    seq,
+                    response_addr,
+                    channels,
+                })
+            }
+            CMD_SEARCH_RES => {
+                let mut guid = [0; 12];
+                guid.copy_from_slice(rd.bytes(12)?);
+                let seq = rd.u32()?;
+                let server_addr = read_addr(rd)?;
+                let _protocol = rd.string()?;
+                let found = rd.u8()? != 0;
+                let n = rd.u16()?;
+                let mut ids = Vec::new();
+                for _ in 0..n {
+                    ids.push(rd.u32()?);
+                }
+                PvaMsg::SearchRes(SearchRes {
+                    guid,
+                    seq,
+                    server_addr,
+                    found,
+                    ids,
+                })
+            }
+            CMD_CREATE_CHANNEL if from_server => PvaMsg::CreateChannelRes {
+                cid: rd.u32()?,
+                sid: rd.u32()?,
+                status: Status::read(rd)?,
+            },
+            CMD_CREATE_CHANNEL => {
+                let n = rd.u16()?;
+                let mut channels = Vec::new();
+                for _ in 0..n {
+                    let cid = rd.u32()?;
+                    channels.push((cid, rd.string()?));
+                }
+                PvaMsg::CreateChannel(channels)
+            }
+            CMD_DESTROY_CHANNEL => PvaMsg::DestroyChannel {
+                sid: rd.u32()?,
+                cid: rd.u32()?,
+            },
+            CMD_MONITOR if from_server => {
+                let ioid = rd.u32()?;
+                let subcmd = rd.u8()?;
+                let res = if subcmd & SUBCMD_INIT != 0 {
+                    let status = Status::read(rd)?;
+                    let desc = if status.is_ok() { rd.desc_cached(cache)? } else { None };
+                    MonitorRes::Init { ioid, status, desc }
+                } else if subcmd & SUBCMD_DESTROY != 0 {
+                    MonitorRes::End { ioid }
+                } else {
+                    MonitorRes::Data {
+                        ioid,
+                        body: rd.bytes(rd.remaining())?.to_vec(),
+                        big_endian: hi.is_big_endian(),
+                    }
+                };
+                PvaMsg::MonitorRes(res)
+            }
+            CMD_MONITOR => {
+                let sid = rd.u32()?;
+                let ioid = rd.u32()?;
+                let subcmd = rd.u8()?;
+                let req = if subcmd & SUBCMD_INIT != 0 {
+                    if let Some(desc) = rd.desc_cached(cache)? {
+                        rd.value(&desc, cache)?;
+                    }
+                    let queue_size = if subcmd & SUBCMD_PIPELINE != 0 { rd.u32()? } else { 0 };
+                    MonitorReq::Init { sid, ioid, queue_size }
+                } else if subcmd & SUBCMD_DESTROY != 0 {
+                    MonitorReq::Destroy { sid, ioid }
+                } else if subcmd == SUBCMD_PIPELINE {
+                    MonitorReq::Ack {
+                        sid,
+                        ioid,
+                        nfree: rd.u32()?,
+                    }
+                } else if subcmd == SUBCMD_START {
+                    MonitorReq::Start { sid, ioid }
+                } else if subcmd == SUBCMD_STOP {
+                    MonitorReq::Stop { sid, ioid }
+                } else {
+                    return Err(Error::BadMonitorSubcmd(subcmd));
+                };
+                PvaMsg::MonitorReq(req)
+            }
+            CMD_DESTROY_REQUEST => PvaMsg::DestroyRequest {
+                sid: rd.u32()?,
+                ioid: rd.u32()?,
+            },
+            CMD_MESSAGE => {
+                let ioid = rd.u32()?;
+                let _ty = rd.u8()?;
+                PvaMsg::Message {
+                    ioid,
+                    text: rd.string()?,
+                }
+            }
+            k => PvaMsg::Ignored(k),
+        };
+        Ok(ret)
+    }
+
+    // The complete message with header, in big endian.
+    pub fn to_vec(&self, from_server: bool) -> Vec<u8> {
+        let mut w = PvWriter::new();
+        let cmd = match self {
+            PvaMsg::SetByteOrder => {
+                let mut ret = vec![
+                    PVA_MAGIC,
+                    PVA_VERSION,
+                    FLAG_BIG_ENDIAN | FLAG_FROM_SERVER | FLAG_CONTROL,
+                ];
+                ret.extend_from_slice(&[CTRL_SET_BYTE_ORDER, 0, 0, 0, 0]);
+                return ret;
+            }
+            PvaMsg::ValidationReq {
+                buffer_size,
+                registry_size,
+                auth,
+            } => {
+                w.u32(*buffer_size);
+                w.u16(*registry_size);
+                w.size(Some(auth.len()));
+                auth.iter().for_each(|x| w.string(x));
+                CMD_VALIDATION
+            }
+            PvaMsg::ValidationRes {
+                buffer_size,
+                registry_size,
+                qos,
+                auth,
+            } => {
+                w.u32(*buffer_size);
+                w.u16(*registry_size);
+                w.u16(*qos);
+                w.string(auth);
+                // No authentication data.
+                w.u8(0xff);
+                CMD_VALIDATION
+            }
+            PvaMsg::Validated(status) => {
+                status.write(&mut w);
+                CMD_VALIDATED
+            }
+            PvaMsg::Echo(x) => {
+                w.bytes(x);
+                CMD_ECHO
+            }
+            PvaMsg::Search(x) => {
+                w.u32(x.seq);
+                w.u8(0);
+                w.bytes(&[0; 3]);
+                write_addr(&mut w, &x.response_addr);
+                w.size(Some(1));
+                w.string("tcp");
+                w.u16(x.channels.len() as u16);
+                for (id, name) in &x.channels {
+                    w.u32(*id);
+                    w.string(name);
+                }
+                CMD_SEARCH
+            }
+            PvaMsg::SearchRes(x) => {
+                w.bytes(&x.guid);
+                w.u32(x.seq);
+                write_addr(&mut w, &x.server_addr);
+                w.string("tcp");
+                w.u8(x.found as u8);
+                w.u16(x.ids.len() as u16);
+                x.ids.iter().for_each(|&id| w.u32(id));
+                CMD_SEARCH_RES
+            }
+            PvaMsg::CreateChannel(x) => {
+                w.u16(x.len() as u16);
+                for (cid, name) in x {
+                    w.u32(*cid);
+                    w.string(name);
+                }
+                CMD_CREATE_CHANNEL
+            }
+            PvaMsg::CreateChannelRes { cid, sid, status } => {
+                w.u32(*cid);
+                w.u32(*sid);
+                status.write(&mut w);
+                CMD_CREATE_CHANNEL
+            }
+            PvaMsg::DestroyChannel { sid, cid } => {
+                w.u32(*sid);
+                w.u32(*cid);
+                CMD_DESTROY_CHANNEL
+            }
+            PvaMsg::MonitorReq(x) => {
+                match x {
+                    MonitorReq::Init { sid, ioid, queue_size } => {
+                        w.u32(*sid);
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_INIT | SUBCMD_PIPELINE);
+                        let (desc, val) = monitor_request(*queue_size);
+                        w.desc(&desc);
+                        w.value(&val);
+                        w.u32(*queue_size);
+                    }
+                    MonitorReq::Start { sid, ioid } => {
+                        w.u32(*sid);
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_START);
+                    }
+                    MonitorReq::Stop { sid, ioid } => {
+                        w.u32(*sid);
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_STOP);
+                    }
+                    MonitorReq::Ack { sid, ioid, nfree } => {
+                        w.u32(*sid);
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_PIPELINE);
+                        w.u32(*nfree);
+                    }
+                    MonitorReq::Destroy { sid, ioid } => {
+                        w.u32(*sid);
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_DESTROY);
+                    }
+                }
+                CMD_MONITOR
+            }
+            PvaMsg::MonitorRes(x) => {
+                match x {
+                    MonitorRes::Init { ioid, status, desc } => {
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_INIT | SUBCMD_PIPELINE);
+                        status.write(&mut w);
+                        if let Some(desc) = desc {
+                            w.desc(desc);
+                        }
+                    }
+                    MonitorRes::Data { ioid, body, .. } => {
+                        w.u32(*ioid);
+                        w.u8(0);
+                        w.bytes(body);
+                    }
+                    MonitorRes::End { ioid } => {
+                        w.u32(*ioid);
+                        w.u8(SUBCMD_DESTROY);
+                        Status::Ok.write(&mut w);
+                    }
+                }
+                CMD_MONITOR
+            }
+            PvaMsg::DestroyRequest { sid, ioid } => {
+                w.u32(*sid);
+                w.u32(*ioid);
+                CMD_DESTROY_REQUEST
+            }
+            PvaMsg::Message { ioid, text } => {
+                w.u32(*ioid);
+                w.u8(0);
+                w.string(text);
+                CMD_MESSAGE
+            }
+            PvaMsg::Ignored(k) => *k,
+        };
+        let payload = w.into_inner();
+        let mut flags = FLAG_BIG_ENDIAN;
+        if from_server {
+            flags |= FLAG_FROM_SERVER;
+        }
+        let mut ret = vec![PVA_MAGIC, PVA_VERSION, flags, cmd];
+        ret.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        ret.extend_from_slice(&payload);
+        ret
+    }
+}
+
+// Body of a monitor update: changed bit set, the changed fields and the overrun bit set.
+pub fn monitor_data_body(val: &PvValue, changed: &BitSet) -> Vec<u8> {
+    let mut w = PvWriter::new();
+    w.bitset(changed);
+    w.changed(val, changed);
+    w.bitset(&BitSet::new());
+    w.into_inner()
+}
+
+// Joins the segments of the message which starts at `pos`, control messages in between are kept.
+// Returns the header and payload of the whole message, the control headers and the end of the last
+// segment, or None if the last segment is not yet in the buffer.
+fn join_segments(buf: &[u8], pos: usize) -> Result<Option<(Header, Vec<u8>, Vec<Header>, usize)>, Error> {
+    let mut controls = Vec::new();
+    let mut payload = Vec::new();
+    let mut first: Option<Header> = None;
+    let mut pos = pos;
+    while buf.len() - pos >= HEADER_LEN {
+        let hb: &[u8; HEADER_LEN] = buf[pos..pos + HEADER_LEN].try_into().unwrap();
+        let hi = Header::parse(hb)?;
+        let end = pos + HEADER_LEN + hi.payload_len();
+        if buf.len() < end {
+            break;
+        }
+        if hi.is_control() {
+            controls.push(hi);
+            pos = end;
+            continue;
+        }
+        let seg = hi.segment();
+        match &first {
+            None if seg == FLAG_SEGMENT_FIRST => {}
+            Some(h) if seg != 0 && seg != FLAG_SEGMENT_FIRST && h.cmd == hi.cmd => {}
+            _ => return Err(Error::SegmentedMessage),
+        }
+        payload.extend_from_slice(&buf[pos + HEADER_LEN..end]);
+        if payload.len() > PAYLOAD_MAX as usize {
+            return Err(Error::PayloadTooLarge(payload.len() as u32));
+        }
+        pos = end;
+        if seg == FLAG_SEGMENT_LAST {
+            let h = first.unwrap_or(hi);
+            let hi = Header {
+                flags: h.flags & !FLAG_SEGMENTED,
+                cmd: h.cmd,
+                size: payload.len() as u32,
+            };
+            return Ok(Some((hi, payload, controls, pos)));
+        }
+        if first.is_none() {
+            first = Some(hi);
+        }
+    }
+    Ok(None)
+}
+
+// Splits a buffer into complete messages, returns them and the number of bytes consumed.
+// A segmented message is returned as one once all its segments are in the buffer.
+pub fn parse_msgs(buf: &[u8], cache: &mut TypeCache) -> Result<(Vec<(Header, PvaMsg)>, usize), Error> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    while buf.len() - pos >= HEADER_LEN {
+        let hb: &[u8; HEADER_LEN] = buf[pos..pos + HEADER_LEN].try_into().unwrap();
+        let hi = Header::parse(hb)?;
+        let end = pos + HEADER_LEN + hi.payload_len();
+        if buf.len() < end {
+            break;
+        }
+        if hi.segment() != 0 {
+            let (hi, payload, controls, end) = match join_segments(buf, pos)? {
+                Some(x) => x,
+                None => break,
+            };
+            for hc in controls {
+                let msg = PvaMsg::parse(&hc, &[], cache)?;
+                ret.push((hc, msg));
+            }
+            let msg = PvaMsg::parse(&hi, &payload, cache)?;
+            ret.push((hi, msg));
+            pos = end;
+            continue;
+        }
+        let msg = PvaMsg::parse(&hi, &buf[pos + HEADER_LEN..end], cache)?;
+        ret.push((hi, msg));
+        pos = end;
+    }
+    Ok((ret, pos))
+}
+
+#[test]
+fn search_roundtrip() {
+    let msg = PvaMsg::Search(Search {
+        seq: 7,
+        response_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 40123),
+        channels: vec![(1, "PV:A".into()), (2, "PV:B".into())],
+    });
+    let buf = msg.to_vec(false);
+    let mut cache = TypeCache::new();
+    let (msgs, n) = parse_msgs(&buf, &mut cache).unwrap();
+    assert_eq!(n, buf.len());
+    match &msgs[0].1 {
+        PvaMsg::Search(x) => {
+            assert_eq!(x.seq, 7);
+            assert_eq!(x.response_addr.port(), 40123);
+            assert_eq!(x.channels[1], (2, "PV:B".into()));
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    let msg = PvaMsg::SearchRes(SearchRes {
+        guid: [3; 12],
+        seq: 7,
+        server_addr: SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), 5075),
+        found: true,
+        ids: vec![2],
+    });
+    let buf = msg.to_vec(true);
+    // An incomplete message stays in the buffer.
+    let (msgs, n) = parse_msgs(&buf[..buf.len() - 1], &mut cache).unwrap();
+    assert_eq!((msgs.len(), n), (0, 0));
+    let (msgs, _) = parse_msgs(&buf, &mut cache).unwrap();
+    match &msgs[0].1 {
+        PvaMsg::SearchRes(x) => {
+            assert_eq!(x.server_addr, SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), 5075));
+            assert_eq!(x.ids, vec![2]);
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+}
+
+#[test]
+fn segmented_monitor_data() {
+    let body: Vec<u8> = (0..200).map(|x| x as u8).collect();
+    let msg = PvaMsg::MonitorRes(MonitorRes::Data {
+        ioid: 5,
+        body: body.clone(),
+        big_endian: true,
+    });
+    let whole = msg.to_vec(true);
+    let payload = &whole[HEADER_LEN..];
+    let flags = whole[2];
+    let mut buf = Vec::new();
+    let parts = [&payload[..50], &payload[50..120], &payload[120..]];
+    for (i, part) in parts.iter().enumerate() {
+        let seg = match i {
+            0 => FLAG_SEGMENT_FIRST,
+            2 => FLAG_SEGMENT_LAST,
+            _ => FLAG_SEGMENTED,
+        };
+        buf.extend_from_slice(&[PVA_MAGIC, PVA_VERSION, flags | seg, CMD_MONITOR]);
+        buf.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        buf.extend_from_slice(part);
+        if i == 0 {
+            // A control message may come between the segments.
+            buf.extend_from_slice(&[
+                PVA_MAGIC,
+                PVA_VERSION,
+                flags | FLAG_CONTROL,
+                CTRL_SET_BYTE_ORDER,
+                0,
+                0,
+                0,
+                0,
+            ]);
+        }
+    }
+    buf.extend(PvaMsg::Echo(vec![1, 2]).to_vec(true));
+    let mut cache = TypeCache::new();
+    // Nothing is consumed until the last segment is in.
+    let cut = buf.len() - 20;
+    let (msgs, n) = parse_msgs(&buf[..cut], &mut cache).unwrap();
+    assert_eq!((msgs.len(), n), (0, 0));
+    let (msgs, n) = parse_msgs(&buf, &mut cache).unwrap();
+    assert_eq!(n, buf.len());
+    assert_eq!(msgs.len(), 3);
+    assert!(matches!(msgs[0].1, PvaMsg::SetByteOrder));
+    match &msgs[1].1 {
+        PvaMsg::MonitorRes(MonitorRes::Data { ioid, body: b, .. }) => {
+            assert_eq!(*ioid, 5);
+            assert_eq!(b, &body);
+        }
+        x => panic!("unexpected {x:?}"),
+    }
+    assert!(matches!(&msgs[2].1, PvaMsg::Echo(x) if x == &vec![1, 2]));
+    // A middle segment without a first one is an error.
+    let mut bad = whole.clone();
+    bad[2] |= FLAG_SEGMENTED;
+    assert!(matches!(parse_msgs(&bad, &mut cache), Err(Error::SegmentedMessage)));
+}
@@ -0,0 +1,76 @@
+use super::PVA_BROADCAST_PORT_DEFAULT;
+use err::Error;
+use ingest_linux::net::local_broadcast_addrs;
+use log::*;
+
+// pvAccess client settings from the standard EPICS environment variables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EpicsPvaEnv {
+    pub addr_list: Vec<String>,
+    pub auto_addr_list: bool,
+    pub broadcast_port: u16,
+}
+
+impl EpicsPvaEnv {
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_lookup(|k| std::env::var(k).ok())
+    }
+
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let addr_list = lookup("EPICS_PVA_ADDR_LIST")
+            .map(|v| v.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let auto_addr_list = match lookup("EPICS_PVA_AUTO_ADDR_LIST") {
+            Some(v) => !v.trim().eq_ignore_ascii_case("no"),
+            None => true,
+        };
+        let broadcast_port = match lookup("EPICS_PVA_BROADCAST_PORT") {
+            Some(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse()
+                .map_err(|e| Error::with_msg_no_trace(format!("bad EPICS_PVA_BROADCAST_PORT {v:?} {e}")))?,
+            _ => PVA_BROADCAST_PORT_DEFAULT,
+        };
+        let ret = Self {
+            addr_list,
+            auto_addr_list,
+            broadcast_port,
+        };
+        Ok(ret)
+    }
+
+    pub fn search_addrs(&self) -> Result<Vec<String>, Error> {
+        let mut ret = self.addr_list.clone();
+        if self.auto_addr_list {
+            let addrs = local_broadcast_addrs()
+                .map_err(|e| Error::with_msg_no_trace(format!("can not list network interfaces {e:?}")))?;
+            debug!("auto addr list {addrs:?}");
+            for a in addrs {
+                let a = a.to_string();
+                if !ret.contains(&a) {
+                    ret.push(a);
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[test]
+fn parse_epics_pva_env() {
+    let vars = [
+        ("EPICS_PVA_ADDR_LIST", "10.0.0.255 pvagw:5078"),
+        ("EPICS_PVA_AUTO_ADDR_LIST", "no"),
+        ("EPICS_PVA_BROADCAST_PORT", "5086"),
+    ];
+    let lookup = |k: &str| vars.iter().find(|x| x.0 == k).map(|x| x.1.to_string());
+    let env = EpicsPvaEnv::from_lookup(lookup).unwrap();
+    assert_eq!(env.addr_list, vec!["10.0.0.255", "pvagw:5078"]);
+    assert_eq!(env.auto_addr_list, false);
+    assert_eq!(env.broadcast_port, 5086);
+    let env = EpicsPvaEnv::from_lookup(|_| None).unwrap();
+    assert_eq!(env.broadcast_port, PVA_BROADCAST_PORT_DEFAULT);
+}
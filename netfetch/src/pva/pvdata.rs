@@ -0,0 +1,833 @@
+// PVData introspection and values as carried by pvAccess.
+// Only variable size arrays are supported, bounded and fixed size arrays are rare and rejected.
+
+use err::thiserror;
+use err::ThisError;
+use std::collections::BTreeMap;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    ShortBuffer,
+    BadSize(i32),
+    BadString,
+    UnsupportedTypeCode(u8),
+    UnknownTypeId(u16),
+    BadUnionSelector(usize),
+}
+
+// Field descriptions received with an id, valid for the lifetime of the connection.
+pub type TypeCache = BTreeMap<u16, FieldDesc>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScalarKind {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    String,
+}
+
+impl ScalarKind {
+    fn from_code(code: u8) -> Result<Self, Error> {
+        use ScalarKind::*;
+        let ret = match code {
+            0x00 => Bool,
+            0x20 => I8,
+            0x21 => I16,
+            0x22 => I32,
+            0x23 => I64,
+            0x24 => U8,
+            0x25 => U16,
+            0x26 => U32,
+            0x27 => U64,
+            0x42 => F32,
+            0x43 => F64,
+            0x60 => String,
+            _ => return Err(Error::UnsupportedTypeCode(code)),
+        };
+        Ok(ret)
+    }
+
+    fn code(&self) -> u8 {
+        use ScalarKind::*;
+        match self {
+            Bool => 0x00,
+            I8 => 0x20,
+            I16 => 0x21,
+            I32 => 0x22,
+            I64 => 0x23,
+            U8 => 0x24,
+            U16 => 0x25,
+            U32 => 0x26,
+            U64 => 0x27,
+            F32 => 0x42,
+            F64 => 0x43,
+            String => 0x60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructDesc {
+    pub id: String,
+    pub fields: Vec<(String, FieldDesc)>,
+}
+
+impl StructDesc {
+    pub fn new(id: &str, fields: Vec<(&str, FieldDesc)>) -> Self {
+        Self {
+            id: id.into(),
+            fields: fields.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<(usize, &FieldDesc)> {
+        self.fields
+            .iter()
+            .enumerate()
+            .find(|(_, x)| x.0 == name)
+            .map(|(i, x)| (i, &x.1))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldDesc {
+    Scalar(ScalarKind),
+    Array(ScalarKind),
+    Struct(StructDesc),
+    Union(StructDesc),
+    Any,
+    StructArray(StructDesc),
+    UnionArray(StructDesc),
+    AnyArray,
+}
+
+impl FieldDesc {
+    // Number of bit set offsets the field occupies, a structure counts itself plus all members.
+    pub fn offset_count(&self) -> usize {
+        match self {
+            FieldDesc::Struct(sd) => 1 + sd.fields.iter().map(|x| x.1.offset_count()).sum::<usize>(),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PvScalar {
+    Bool(bool),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PvArray {
+    Bool(Vec<bool>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+    U64(Vec<u64>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    String(Vec<String>),
+}
+
+impl PvArray {
+    pub fn len(&self) -> usize {
+        use PvArray::*;
+        match self {
+            Bool(x) => x.len(),
+            I8(x) => x.len(),
+            I16(x) => x.len(),
+            I32(x) => x.len(),
+            I64(x) => x.len(),
+            U8(x) => x.len(),
+            U16(x) => x.len(),
+            U32(x) => x.len(),
+            U64(x) => x.len(),
+            F32(x) => x.len(),
+            F64(x) => x.len(),
+            String(x) => x.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PvValue {
+    Scalar(PvScalar),
+    Array(PvArray),
+    Struct(Vec<PvValue>),
+    // Selected member and its value.
+    Union(Option<(usize, Box<PvValue>)>),
+    Any(Option<Box<(FieldDesc, PvValue)>>),
+    // Elements of structure, union and any arrays, which may be null.
+    Elements(Vec<Option<PvValue>>),
+}
+
+impl PvValue {
+    pub fn default_for(desc: &FieldDesc) -> Self {
+        use ScalarKind as K;
+        match desc {
+            FieldDesc::Scalar(k) => PvValue::Scalar(match k {
+                K::Bool => PvScalar::Bool(false),
+                K::I8 => PvScalar::I8(0),
+                K::I16 => PvScalar::I16(0),
+                K::I32 => PvScalar::I32(0),
+                K::I64 => PvScalar::I64(0),
+                K::U8 => PvScalar::U8(0),
+                K::U16 => PvScalar::U16(0),
+                K::U32 => PvScalar::U32(0),
+                K::U64 => PvScalar::U64(0),
+                K::F32 => PvScalar::F32(0.),
+                K::F64 => PvScalar::F64(0.),
+                K::String => PvScalar::String(String::new()),
+            }),
+            FieldDesc::Array(k) => PvValue::Array(match k {
+                K::Bool => PvArray::Bool(Vec::new()),
+                K::I8 => PvArray::I8(Vec::new()),
+                K::I16 => PvArray::I16(Vec::new()),
+                K::I32 => PvArray::I32(Vec::new()),
+                K::I64 => PvArray::I64(Vec::new()),
+                K::U8 => PvArray::U8(Vec::new()),
+                K::U16 => PvArray::U16(Vec::new()),
+                K::U32 => PvArray::U32(Vec::new()),
+                K::U64 => PvArray::U64(Vec::new()),
+                K::F32 => PvArray::F32(Vec::new()),
+                K::F64 => PvArray::F64(Vec::new()),
+                K::String => PvArray::String(Vec::new()),
+            }),
+            FieldDesc::Struct(sd) => PvValue::Struct(sd.fields.iter().map(|x| PvValue::default_for(&x.1)).collect()),
+            FieldDesc::Union(_) => PvValue::Union(None),
+            FieldDesc::Any => PvValue::Any(None),
+            FieldDesc::StructArray(_) | FieldDesc::UnionArray(_) | FieldDesc::AnyArray => PvValue::Elements(Vec::new()),
+        }
+    }
+
+    fn offset_count(&self) -> usize {
+        match self {
+            PvValue::Struct(x) => 1 + x.iter().map(|x| x.offset_count()).sum::<usize>(),
+            _ => 1,
+        }
+    }
+}
+
+// Field and value at a dot separated path like `timeStamp.nanoseconds`.
+pub fn field_at<'a>(desc: &'a StructDesc, val: &'a PvValue, path: &str) -> Option<(&'a FieldDesc, &'a PvValue)> {
+    let mut it = path.split('.');
+    let (i, mut fd) = desc.field(it.next()?)?;
+    let mut v = match val {
+        PvValue::Struct(x) => x.get(i)?,
+        _ => return None,
+    };
+    for name in it {
+        match (fd, v) {
+            (FieldDesc::Struct(sd), PvValue::Struct(x)) => {
+                let (i, fd2) = sd.field(name)?;
+                fd = fd2;
+                v = x.get(i)?;
+            }
+            _ => return None,
+        }
+    }
+    Some((fd, v))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, i: usize) {
+        let w = i / 64;
+        if self.words.len() <= w {
+            self.words.resize(w + 1, 0);
+        }
+        self.words[w] |= 1 << (i % 64);
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words.get(i / 64).map_or(false, |w| w & (1 << (i % 64)) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&x| x == 0)
+    }
+
+    // Bytes needed up to the highest set bit.
+    fn byte_len(&self) -> usize {
+        for (i, w) in self.words.iter().enumerate().rev() {
+            if *w != 0 {
+                return i * 8 + 8 - w.leading_zeros() as usize / 8;
+            }
+        }
+        0
+    }
+}
+
+macro_rules! read_num {
+    ($name:ident, $ty:ty) => {
+        pub fn $name(&mut self) -> Result<$ty, Error> {
+            let b = self.bytes(std::mem::size_of::<$ty>())?;
+            let a = b.try_into().map_err(|_| Error::ShortBuffer)?;
+            if self.be {
+                Ok(<$ty>::from_be_bytes(a))
+            } else {
+                Ok(<$ty>::from_le_bytes(a))
+            }
+        }
+    };
+}
+
+pub struct PvReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    be: bool,
+}
+
+impl<'a> PvReader<'a> {
+    pub fn new(buf: &'a [u8], be: bool) -> Self {
+        Self { buf, pos: 0, be }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < n {
+            return Err(Error::ShortBuffer);
+        }
+        let ret = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    read_num!(u16, u16);
+    read_num!(u32, u32);
+    read_num!(i16, i16);
+    read_num!(i32, i32);
+    read_num!(i64, i64);
+    read_num!(u64, u64);
+    read_num!(f32, f32);
+    read_num!(f64, f64);
+
+    // None for the null size.
+    pub fn size(&mut self) -> Result<Option<usize>, Error> {
+        match self.u8()? {
+            0xff => Ok(None),
+            0xfe => {
+                let n = self.i32()?;
+                if n < 0 {
+                    Err(Error::BadSize(n))
+                } else {
+                    Ok(Some(n as usize))
+                }
+            }
+            n => Ok(Some(n as usize)),
+        }
+    }
+
+    pub fn string(&mut self) -> Result<String, Error> {
+        let n = self.size()?.unwrap_or(0);
+        let b = self.bytes(n)?;
+        String::from_utf8(b.to_vec()).map_err(|_| Error::BadString)
+    }
+
+    pub fn bitset(&mut self) -> Result<BitSet, Error> {
+        let n = self.size()?.unwrap_or(0);
+        let mut words = Vec::new();
+        for _ in 0..n / 8 {
+            words.push(self.u64()?);
+        }
+        if n % 8 != 0 {
+            let mut w = 0;
+            for (i, &b) in self.bytes(n % 8)?.iter().enumerate() {
+                w |= (b as u64) << (8 * i);
+            }
+            words.push(w);
+        }
+        Ok(BitSet { words })
+    }
+
+    // Element count of an array, guarded against counts the remaining payload can not hold.
+    fn count(&mut self) -> Result<usize, Error> {
+        let n = self.size()?.unwrap_or(0);
+        if n > self.remaining() {
+            Err(Error::ShortBuffer)
+        } else {
+            Ok(n)
+        }
+    }
+
+    // A field description which may be given as reference into the cache of the connection.
+    pub fn desc_cached(&mut self, cache: &mut TypeCache) -> Result<Option<FieldDesc>, Error> {
+        match self.u8()? {
+            0xff => Ok(None),
+            0xfe => {
+                let id = self.u16()?;
+                cache.get(&id).cloned().map(Some).ok_or(Error::UnknownTypeId(id))
+            }
+            0xfd => {
+                let id = self.u16()?;
+                let code = self.u8()?;
+                let desc = self.desc_code(code, cache)?;
+                cache.insert(id, desc.clone());
+                Ok(Some(desc))
+            }
+            code => Ok(Some(self.desc_code(code, cache)?)),
+        }
+    }
+
+    pub fn desc(&mut self, cache: &mut TypeCache) -> Result<FieldDesc, Error> {
+        let code = self.u8()?;
+        self.desc_code(code, cache)
+    }
+
+    fn desc_code(&mut self, code: u8, cache: &mut TypeCache) -> Result<FieldDesc, Error> {
+        let ret = match code {
+            0x80 => FieldDesc::Struct(self.struct_desc(cache)?),
+            0x81 => FieldDesc::Union(self.struct_desc(cache)?),
+            0x82 => FieldDesc::Any,
+            // Bounded string, the bound does not matter for reading.
+            0x83 => {
+                self.size()?;
+                FieldDesc::Scalar(ScalarKind::String)
+            }
+            0x88 => match self.desc_cached(cache)? {
+                Some(FieldDesc::Struct(sd)) => FieldDesc::StructArray(sd),
+                _ => return Err(Error::UnsupportedTypeCode(code)),
+            },
+            0x89 => match self.desc_cached(cache)? {
+                Some(FieldDesc::Union(sd)) => FieldDesc::UnionArray(sd),
+                _ => return Err(Error::UnsupportedTypeCode(code)),
+            },
+            0x8a => FieldDesc::AnyArray,
+            _ => match code & 0x18 {
+                0x00 => FieldDesc::Scalar(ScalarKind::from_code(code)?),
+                0x08 => FieldDesc::Array(ScalarKind::from_code(code & !0x18)?),
+                _ => return Err(Error::UnsupportedTypeCode(code)),
+            },
+        };
+        Ok(ret)
+    }
+
+    fn struct_desc(&mut self, cache: &mut TypeCache) -> Result<StructDesc, Error> {
+        let id = self.string()?;
+        let n = self.count()?;
+        let mut fields = Vec::with_capacity(n);
+        for _ in 0..n {
+            let name = self.string()?;
+            let desc = self.desc(cache)?;
+            fields.push((name, desc));
+        }
+        Ok(StructDesc { id, fields })
+    }
+
+    fn scalar(&mut self, kind: ScalarKind) -> Result<PvScalar, Error> {
+        use ScalarKind as K;
+        let ret = match kind {
+            K::Bool => PvScalar::Bool(self.u8()? != 0),
+            K::I8 => PvScalar::I8(self.u8()? as i8),
+            K::I16 => PvScalar::I16(self.i16()?),
+            K::I32 => PvScalar::I32(self.i32()?),
+            K::I64 => PvScalar::I64(self.i64()?),
+            K::U8 => PvScalar::U8(self.u8()?),
+            K::U16 => PvScalar::U16(self.u16()?),
+            K::U32 => PvScalar::U32(self.u32()?),
+            K::U64 => PvScalar::U64(self.u64()?),
+            K::F32 => PvScalar::F32(self.f32()?),
+            K::F64 => PvScalar::F64(self.f64()?),
+            K::String => PvScalar::String(self.string()?),
+        };
+        Ok(ret)
+    }
+
+    fn array(&mut self, kind: ScalarKind) -> Result<PvArray, Error> {
+        use ScalarKind as K;
+        let n = self.count()?;
+        macro_rules! arr {
+            ($var:ident, $f:ident) => {{
+                let mut a = Vec::with_capacity(n);
+                for _ in 0..n {
+                    a.push(self.$f()?);
+                }
+                PvArray::$var(a)
+            }};
+        }
+        let ret = match kind {
+            K::Bool => PvArray::Bool(self.bytes(n)?.iter().map(|&x| x != 0).collect()),
+            K::I8 => PvArray::I8(self.bytes(n)?.iter().map(|&x| x as i8).collect()),
+            K::U8 => PvArray::U8(self.bytes(n)?.to_vec()),
+            K::I16 => arr!(I16, i16),
+            K::I32 => arr!(I32, i32),
+            K::I64 => arr!(I64, i64),
+            K::U16 => arr!(U16, u16),
+            K::U32 => arr!(U32, u32),
+            K::U64 => arr!(U64, u64),
+            K::F32 => arr!(F32, f32),
+            K::F64 => arr!(F64, f64),
+            K::String => arr!(String, string),
+        };
+        Ok(ret)
+    }
+
+    fn union(&mut self, sd: &StructDesc, cache: &mut TypeCache) -> Result<PvValue, Error> {
+        match self.size()? {
+            None => Ok(PvValue::Union(None)),
+            Some(i) => {
+                let fd = &sd.fields.get(i).ok_or(Error::BadUnionSelector(i))?.1;
+                let v = self.value(fd, cache)?;
+                Ok(PvValue::Union(Some((i, Box::new(v)))))
+            }
+        }
+    }
+
+    fn any(&mut self, cache: &mut TypeCache) -> Result<PvValue, Error> {
+        match self.desc_cached(cache)? {
+            None => Ok(PvValue::Any(None)),
+            Some(fd) => {
+                let v = self.value(&fd, cache)?;
+                Ok(PvValue::Any(Some(Box::new((fd, v)))))
+            }
+        }
+    }
+
+    fn elements<F>(&mut self, mut f: F) -> Result<PvValue, Error>
+    where
+        F: FnMut(&mut Self) -> Result<PvValue, Error>,
+    {
+        let n = self.count()?;
+        let mut ret = Vec::with_capacity(n);
+        for _ in 0..n {
+            if self.u8()? == 0 {
+                ret.push(None);
+            } else {
+                ret.push(Some(f(self)?));
+            }
+        }
+        Ok(PvValue::Elements(ret))
+    }
+
+    pub fn value(&mut self, desc: &FieldDesc, cache: &mut TypeCache) -> Result<PvValue, Error> {
+        let ret = match desc {
+            FieldDesc::Scalar(k) => PvValue::Scalar(self.scalar(*k)?),
+            FieldDesc::Array(k) => PvValue::Array(self.array(*k)?),
+            FieldDesc::Struct(sd) => {
+                let mut vals = Vec::with_capacity(sd.fields.len());
+                for (_, fd) in &sd.fields {
+                    vals.push(self.value(fd, cache)?);
+                }
+                PvValue::Struct(vals)
+            }
+            FieldDesc::Union(sd) => self.union(sd, cache)?,
+            FieldDesc::Any => self.any(cache)?,
+            FieldDesc::StructArray(sd) => {
+                let fd = FieldDesc::Struct(sd.clone());
+                self.elements(|rd| rd.value(&fd, cache))?
+            }
+            FieldDesc::UnionArray(sd) => self.elements(|rd| rd.union(sd, cache))?,
+            FieldDesc::AnyArray => self.elements(|rd| rd.any(cache))?,
+        };
+        Ok(ret)
+    }
+
+    // Reads only the fields flagged in `changed` into `val`, in depth first order of their offsets.
+    pub fn changed(
+        &mut self,
+        desc: &FieldDesc,
+        val: &mut PvValue,
+        changed: &BitSet,
+        cache: &mut TypeCache,
+    ) -> Result<(), Error> {
+        self.changed_at(desc, val, 0, changed, cache)
+    }
+
+    fn changed_at(
+        &mut self,
+        desc: &FieldDesc,
+        val: &mut PvValue,
+        offset: usize,
+        changed: &BitSet,
+        cache: &mut TypeCache,
+    ) -> Result<(), Error> {
+        if changed.get(offset) {
+            *val = self.value(desc, cache)?;
+        } else if let (FieldDesc::Struct(sd), PvValue::Struct(vals)) = (desc, val) {
+            let mut off = offset + 1;
+            for ((_, fd), v) in sd.fields.iter().zip(vals.iter_mut()) {
+                self.changed_at(fd, v, off, changed, cache)?;
+                off += fd.offset_count();
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes in big endian, the byte order is announced per message.
+pub struct PvWriter {
+    buf: Vec<u8>,
+}
+
+impl PvWriter {
+    pub fn new() -> Self {
+        Self { buf: Vec::new() }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn bytes(&mut self, x: &[u8]) {
+        self.buf.extend_from_slice(x);
+    }
+
+    pub fn u8(&mut self, x: u8) {
+        self.buf.push(x);
+    }
+
+    pub fn u16(&mut self, x: u16) {
+        self.bytes(&x.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, x: u32) {
+        self.bytes(&x.to_be_bytes());
+    }
+
+    pub fn size(&mut self, n: Option<usize>) {
+        match n {
+            None => self.u8(0xff),
+            Some(n) if n < 0xfe => self.u8(n as u8),
+            Some(n) => {
+                self.u8(0xfe);
+                self.u32(n as u32);
+            }
+        }
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.size(Some(s.len()));
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn bitset(&mut self, x: &BitSet) {
+        let n = x.byte_len();
+        self.size(Some(n));
+        for i in 0..n / 8 {
+            self.bytes(&x.words[i].to_be_bytes());
+        }
+        if n % 8 != 0 {
+            let w = x.words[n / 8];
+            for i in 0..n % 8 {
+                self.u8((w >> (8 * i)) as u8);
+            }
+        }
+    }
+
+    // Full description without use of the type cache.
+    pub fn desc(&mut self, desc: &FieldDesc) {
+        match desc {
+            FieldDesc::Scalar(k) => self.u8(k.code()),
+            FieldDesc::Array(k) => self.u8(k.code() | 0x08),
+            FieldDesc::Struct(sd) => {
+                self.u8(0x80);
+                self.struct_desc(sd);
+            }
+            FieldDesc::Union(sd) => {
+                self.u8(0x81);
+                self.struct_desc(sd);
+            }
+            FieldDesc::Any => self.u8(0x82),
+            FieldDesc::StructArray(sd) => {
+                self.u8(0x88);
+                self.u8(0x80);
+                self.struct_desc(sd);
+            }
+            FieldDesc::UnionArray(sd) => {
+                self.u8(0x89);
+                self.u8(0x81);
+                self.struct_desc(sd);
+            }
+            FieldDesc::AnyArray => self.u8(0x8a),
+        }
+    }
+
+    fn struct_desc(&mut self, sd: &StructDesc) {
+        self.string(&sd.id);
+        self.size(Some(sd.fields.len()));
+        for (name, fd) in &sd.fields {
+            self.string(name);
+            self.desc(fd);
+        }
+    }
+
+    pub fn value(&mut self, val: &PvValue) {
+        match val {
+            PvValue::Scalar(x) => match x {
+                PvScalar::Bool(x) => self.u8(*x as u8),
+                PvScalar::I8(x) => self.u8(*x as u8),
+                PvScalar::I16(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::I32(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::I64(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::U8(x) => self.u8(*x),
+                PvScalar::U16(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::U32(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::U64(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::F32(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::F64(x) => self.bytes(&x.to_be_bytes()),
+                PvScalar::String(x) => self.string(x),
+            },
+            PvValue::Array(x) => {
+                self.size(Some(x.len()));
+                macro_rules! arr {
+                    ($a:expr) => {
+                        for v in $a {
+                            self.bytes(&v.to_be_bytes());
+                        }
+                    };
+                }
+                match x {
+                    PvArray::Bool(a) => a.iter().for_each(|&v| self.u8(v as u8)),
+                    PvArray::I8(a) => a.iter().for_each(|&v| self.u8(v as u8)),
+                    PvArray::U8(a) => self.bytes(a),
+                    PvArray::I16(a) => arr!(a),
+                    PvArray::I32(a) => arr!(a),
+                    PvArray::I64(a) => arr!(a),
+                    PvArray::U16(a) => arr!(a),
+                    PvArray::U32(a) => arr!(a),
+                    PvArray::U64(a) => arr!(a),
+                    PvArray::F32(a) => arr!(a),
+                    PvArray::F64(a) => arr!(a),
+                    PvArray::String(a) => a.iter().for_each(|v| self.string(v)),
+                }
+            }
+            PvValue::Struct(x) => x.iter().for_each(|v| self.value(v)),
+            PvValue::Union(x) => match x {
+                None => self.size(None),
+                Some((i, v)) => {
+                    self.size(Some(*i));
+                    self.value(v);
+                }
+            },
+            PvValue::Any(x) => match x {
+                None => self.u8(0xff),
+                Some(x) => {
+                    self.desc(&x.0);
+                    self.value(&x.1);
+                }
+            },
+            PvValue::Elements(x) => {
+                self.size(Some(x.len()));
+                for v in x {
+                    match v {
+                        None => self.u8(0),
+                        Some(v) => {
+                            self.u8(1);
+                            self.value(v);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Counterpart of `PvReader::changed`.
+    pub fn changed(&mut self, val: &PvValue, changed: &BitSet) {
+        self.changed_at(val, 0, changed);
+    }
+
+    fn changed_at(&mut self, val: &PvValue, offset: usize, changed: &BitSet) {
+        if changed.get(offset) {
+            self.value(val);
+        } else if let PvValue::Struct(vals) = val {
+            let mut off = offset + 1;
+            for v in vals {
+                self.changed_at(v, off, changed);
+                off += v.offset_count();
+            }
+        }
+    }
+}
+
+#[test]
+fn pvdata_partial_roundtrip() {
+    let time_t = StructDesc::new(
+        "time_t",
+        vec![
+            ("secondsPastEpoch", FieldDesc::Scalar(ScalarKind::I64)),
+            ("nanoseconds", FieldDesc::Scalar(ScalarKind::I32)),
+        ],
+    );
+    let sd = StructDesc::new(
+        "epics:nt/NTScalarArray:1.0",
+        vec![
+            ("value", FieldDesc::Array(ScalarKind::F64)),
+            ("timeStamp", FieldDesc::Struct(time_t)),
+            ("descr", FieldDesc::Scalar(ScalarKind::String)),
+        ],
+    );
+    let desc = FieldDesc::Struct(sd.clone());
+    let mut w = PvWriter::new();
+    w.desc(&desc);
+    let mut cache = TypeCache::new();
+    let buf = w.into_inner();
+    let desc2 = PvReader::new(&buf, true).desc(&mut cache).unwrap();
+    assert_eq!(desc2, desc);
+    assert_eq!(desc.offset_count(), 6);
+
+    let full = PvValue::Struct(vec![
+        PvValue::Array(PvArray::F64(vec![1.5, -2.])),
+        PvValue::Struct(vec![
+            PvValue::Scalar(PvScalar::I64(1700000000)),
+            PvValue::Scalar(PvScalar::I32(17)),
+        ]),
+        PvValue::Scalar(PvScalar::String("d".into())),
+    ]);
+    // Only the value and the nanoseconds change.
+    let mut changed = BitSet::new();
+    changed.set(1);
+    changed.set(4);
+    let mut w = PvWriter::new();
+    w.bitset(&changed);
+    w.changed(&full, &changed);
+    let buf = w.into_inner();
+    let mut rd = PvReader::new(&buf, true);
+    let changed2 = rd.bitset().unwrap();
+    assert_eq!(changed2, changed);
+    let mut val = PvValue::default_for(&desc);
+    rd.changed(&desc, &mut val, &changed2, &mut cache).unwrap();
+    assert_eq!(rd.remaining(), 0);
+    let (_, v) = field_at(&sd, &val, "timeStamp.nanoseconds").unwrap();
+    assert_eq!(v, &PvValue::Scalar(PvScalar::I32(17)));
+    let (_, v) = field_at(&sd, &val, "timeStamp.secondsPastEpoch").unwrap();
+    assert_eq!(v, &PvValue::Scalar(PvScalar::I64(0)));
+    let (_, v) = field_at(&sd, &val, "value").unwrap();
+    assert_eq!(v, &PvValue::Array(PvArray::F64(vec![1.5, -2.])));
+}
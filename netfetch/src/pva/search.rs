@@ -0,0 +1,130 @@
+use super::proto::parse_msgs;
+use super::proto::PvaMsg;
+use super::proto::Search;
+use super::pvdata::TypeCache;
+use crate::ca::search::resolve_addresses;
+use crate::conf::CaIngestOpts;
+use err::Error;
+use log::*;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use taskrun::tokio;
+use tokio::net::UdpSocket;
+
+// Keeps a search request well below the usual MTU.
+const SEARCH_BATCH_MAX: usize = 32;
+
+static SEARCH_SEQ: AtomicU32 = AtomicU32::new(1);
+
+pub async fn search_addrs(opts: &CaIngestOpts) -> Result<Vec<SocketAddrV4>, Error> {
+    let addrs = opts.pva_search_addrs()?;
+    let ret = resolve_addresses(&addrs.search, addrs.port_default).await;
+    if ret.is_empty() {
+        return Err(Error::with_msg_no_trace("no pva search address"));
+    }
+    Ok(ret)
+}
+
+// Servers answer to the port in the response address, the unspecified ip stands for the sender.
+fn search_datagrams(seq: u32, response_port: u16, channels: &[(u32, &String)]) -> Vec<Vec<u8>> {
+    channels
+        .chunks(SEARCH_BATCH_MAX)
+        .map(|chunk| {
+            let msg = PvaMsg::Search(Search {
+                seq,
+                response_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, response_port),
+                channels: chunk.iter().map(|(id, name)| (*id, (*name).clone())).collect(),
+            });
+            msg.to_vec(false)
+        })
+        .collect()
+}
+
+// Searches the channels on all addresses and returns for each channel the server address of the
+// first response, or None if no server answered within the timeout.
+// The request is repeated once for the channels which are still missing halfway through.
+pub async fn pva_search(
+    addrs: &[SocketAddrV4],
+    channels: &[String],
+    timeout: Duration,
+) -> Result<Vec<(String, Option<SocketAddrV4>)>, Error> {
+    let sock = UdpSocket::bind("0.0.0.0:0").await?;
+    sock.set_broadcast(true)?;
+    let response_port = sock.local_addr()?.port();
+    let seq = SEARCH_SEQ.fetch_add(1, Ordering::AcqRel);
+    let mut found: BTreeMap<u32, SocketAddrV4> = BTreeMap::new();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut resend = Some(tokio::time::Instant::now() + timeout / 2);
+    let mut send = true;
+    let mut buf = vec![0; 1024 * 16];
+    let mut cache = TypeCache::new();
+    while found.len() < channels.len() {
+        if send {
+            send = false;
+            let missing: Vec<_> = channels
+                .iter()
+                .enumerate()
+                .map(|(i, x)| (i as u32, x))
+                .filter(|x| !found.contains_key(&x.0))
+                .collect();
+            for dgram in search_datagrams(seq, response_port, &missing) {
+                for addr in addrs {
+                    if let Err(e) = sock.send_to(&dgram, addr).await {
+                        warn!("pva search send to {addr}  {e}");
+                    }
+                }
+            }
+        }
+        let until = resend.map_or(deadline, |x| x.min(deadline));
+        let (n, src) = match tokio::time::timeout_at(until, sock.recv_from(&mut buf)).await {
+            Ok(x) => x?,
+            Err(_) => {
+                if resend.take().is_some() {
+                    send = true;
+                    continue;
+                } else {
+                    break;
+                }
+            }
+        };
+        let src = match src {
+            SocketAddr::V4(x) => x,
+            SocketAddr::V6(_) => continue,
+        };
+        let msgs = match parse_msgs(&buf[..n], &mut cache) {
+            Ok(x) => x.0,
+            Err(e) => {
+                debug!("pva search bad response from {src}  {e}");
+                continue;
+            }
+        };
+        for (_, msg) in msgs {
+            if let PvaMsg::SearchRes(res) = msg {
+                if res.seq != seq || !res.found {
+                    continue;
+                }
+                let server = if res.server_addr.ip().is_unspecified() {
+                    SocketAddrV4::new(*src.ip(), res.server_addr.port())
+                } else {
+                    res.server_addr
+                };
+                for id in res.ids {
+                    if (id as usize) < channels.len() {
+                        found.entry(id).or_insert(server);
+                    }
+                }
+            }
+        }
+    }
+    let ret = channels
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.clone(), found.get(&(i as u32)).cloned()))
+        .collect();
+    Ok(ret)
+}
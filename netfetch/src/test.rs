@@ -1 +1,772 @@
-// TODO
+// Mock Channel Access server to run the search, CaConn and CaConnSet code paths
+// against a real socket without an IOC.
+
+mod pva;
+
+use crate::ca::connset::CaConnSet;
+use crate::ca::finder::FinderSource;
+use crate::ca::findioc::FindIocStream;
+use crate::ca::policy::ArchivingPolicy;
+use crate::ca::proto::AccessRightsRes;
+use crate::ca::proto::CaDataArrayValue;
+use crate::ca::proto::CaDataScalarValue;
+use crate::ca::proto::CaDataValue;
+use crate::ca::proto::CaEventValue;
+use crate::ca::proto::CaMsg;
+use crate::ca::proto::CaMsgTy;
+use crate::ca::proto::ClearChannelRes;
+use crate::ca::proto::CreateChanFail;
+use crate::ca::proto::CreateChanRes;
+use crate::ca::proto::EventAddRes;
+use crate::ca::proto::EventCancelRes;
+use crate::ca::proto::HeadInfo;
+use crate::ca::proto::ReadNotifyRes;
+use crate::ca::proto::SearchRes;
+use async_channel::Receiver;
+use dbpg::seriesbychannel::ChannelInfoQuery;
+use dbpg::seriesbychannel::ChannelInfoResult;
+use err::Error;
+use futures_util::StreamExt;
+use log::*;
+use netpod::timeunits::*;
+use scywr::iteminsertqueue::ChannelStatus;
+use scywr::iteminsertqueue::ChannelStatusClosedReason;
+use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::QueryItem;
+use scywr::iteminsertqueue::ScalarValue;
+use series::series::Existence;
+use series::SeriesId;
+use slidebuf::SlideBuf;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use taskrun::tokio;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+const CA_PROTO_VERSION: u16 = 13;
+
+pub type ValueGen = Arc<dyn Fn(u64) -> CaDataValue + Send + Sync>;
+
+#[derive(Clone)]
+pub struct MockChannel {
+    pub name: String,
+    pub gen: ValueGen,
+}
+
+impl MockChannel {
+    pub fn new<F>(name: &str, gen: F) -> Self
+    where
+        F: Fn(u64) -> CaDataValue + Send + Sync + 'static,
+    {
+        Self {
+            name: name.into(),
+            gen: Arc::new(gen),
+        }
+    }
+}
+
+struct Subscription {
+    channel: usize,
+    sid: u32,
+    subid: u32,
+    data_type: u16,
+    data_count: u32,
+    // Number of values emitted, the value generator gets this as input.
+    count: u64,
+}
+
+// Answers searches over UDP for the configured channels, accepts Channel Access
+// connections on TCP and emits a new value for every subscription each period.
+// On TCP it also answers searches like a name server.
+pub struct MockIoc {
+    udp_addr: SocketAddrV4,
+    tcp_addr: SocketAddrV4,
+    conns: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    // Command ids of all messages received over TCP.
+    received: Arc<Mutex<Vec<u16>>>,
+    jhs: Vec<JoinHandle<()>>,
+}
+
+impl MockIoc {
+    pub async fn start(channels: Vec<MockChannel>, period: Duration) -> Result<Self, Error> {
+        let udp = UdpSocket::bind("127.0.0.1:0").await?;
+        let tcp = TcpListener::bind("127.0.0.1:0").await?;
+        let udp_addr = Self::addr_v4(udp.local_addr()?)?;
+        let tcp_addr = Self::addr_v4(tcp.local_addr()?)?;
+        let channels = Arc::new(channels);
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let jh1 = tokio::spawn(Self::run_udp(udp, tcp_addr.port(), channels.clone()));
+        let jh2 = tokio::spawn(Self::run_tcp(tcp, channels, period, conns.clone(), received.clone()));
+        let ret = Self {
+            udp_addr,
+            tcp_addr,
+            conns,
+            received,
+            jhs: vec![jh1, jh2],
+        };
+        Ok(ret)
+    }
+
+    pub fn udp_addr(&self) -> SocketAddrV4 {
+        self.udp_addr
+    }
+
+    pub fn tcp_addr(&self) -> SocketAddrV4 {
+        self.tcp_addr
+    }
+
+    pub fn received_cmds(&self) -> Vec<u16> {
+        self.received.lock().unwrap().clone()
+    }
+
+    // Drops all client connections as if the IOC went away, keeps accepting new ones.
+    pub fn disconnect_all(&self) {
+        for jh in self.conns.lock().unwrap().drain(..) {
+            jh.abort();
+        }
+    }
+
+    fn addr_v4(addr: std::net::SocketAddr) -> Result<SocketAddrV4, Error> {
+        match addr {
+            std::net::SocketAddr::V4(x) => Ok(x),
+            _ => Err(Error::with_msg_no_trace("expect ipv4")),
+        }
+    }
+
+    async fn run_udp(udp: UdpSocket, tcp_port: u16, channels: Arc<Vec<MockChannel>>) {
+        let mut buf = vec![0; 1024 * 16];
+        loop {
+            let (n, src) = match udp.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("mock ioc udp recv {e}");
+                    break;
+                }
+            };
+            let mut out = Vec::new();
+            for (hi, payload) in Self::parse_msgs(&buf[..n]) {
+                if hi.cmdid() != 6 {
+                    continue;
+                }
+                let name = Self::ca_string(&payload);
+                if channels.iter().any(|x| x.name == name) {
+                    if out.is_empty() {
+                        out.extend(
+                            CaMsg {
+                                ty: CaMsgTy::VersionRes(CA_PROTO_VERSION),
+                            }
+                            .to_vec(),
+                        );
+                    }
+                    let res = SearchRes {
+                        addr: 0,
+                        tcp_port,
+                        id: hi.param2(),
+                        proto_version: CA_PROTO_VERSION,
+                    };
+                    out.extend(
+                        CaMsg {
+                            ty: CaMsgTy::SearchRes(res),
+                        }
+                        .to_vec(),
+                    );
+                }
+            }
+            if !out.is_empty() {
+                if let Err(e) = udp.send_to(&out, src).await {
+                    error!("mock ioc udp send {e}");
+                }
+            }
+        }
+    }
+
+    async fn run_tcp(
+        tcp: TcpListener,
+        channels: Arc<Vec<MockChannel>>,
+        period: Duration,
+        conns: Arc<Mutex<Vec<JoinHandle<()>>>>,
+        received: Arc<Mutex<Vec<u16>>>,
+    ) {
+        loop {
+            match tcp.accept().await {
+                Ok((stream, _)) => {
+                    let fut = Self::run_conn(stream, channels.clone(), period, received.clone());
+                    let jh = tokio::spawn(async move {
+                        if let Err(e) = fut.await {
+                            debug!("mock ioc conn done {e}");
+                        }
+                    });
+                    conns.lock().unwrap().push(jh);
+                }
+                Err(e) => {
+                    error!("mock ioc accept {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn run_conn(
+        mut stream: TcpStream,
+        channels: Arc<Vec<MockChannel>>,
+        period: Duration,
+        received: Arc<Mutex<Vec<u16>>>,
+    ) -> Result<(), Error> {
+        let mut nb = SlideBuf::new(1024 * 64);
+        let mut rbuf = vec![0; 1024 * 16];
+        let mut sids = BTreeMap::new();
+        let mut subs: BTreeMap<u32, Subscription> = BTreeMap::new();
+        let mut tick = tokio::time::interval(period);
+        let tcp_port = stream.local_addr()?.port();
+        loop {
+            tokio::select! {
+                n = stream.read(&mut rbuf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    nb.put_slice(&rbuf[..n]).map_err(|e| e.to_string())?;
+                    let mut out = Vec::new();
+                    while nb.len() >= 16 {
+                        let hi = HeadInfo::from_netbuf(&mut nb).map_err(|e| e.to_string())?;
+                        if nb.len() < hi.payload() {
+                            nb.rewind_rp(16).map_err(|e| e.to_string())?;
+                            break;
+                        }
+                        let payload = nb.read_bytes(hi.payload()).map_err(|e| e.to_string())?.to_vec();
+                        received.lock().unwrap().push(hi.cmdid());
+                        Self::handle_msg(&hi, &payload, tcp_port, &channels, &mut sids, &mut subs, &mut out);
+                    }
+                    stream.write_all(&out).await?;
+                }
+                _ = tick.tick() => {
+                    let mut out = Vec::new();
+                    for sub in subs.values_mut() {
+                        let value = CaEventValue {
+                            ts: NonZeroU64::new(Self::now_ns()),
+                            status: None,
+                            severity: None,
+                            data: (channels[sub.channel].gen)(sub.count),
+                        };
+                        sub.count += 1;
+                        let res = EventAddRes {
+                            data_type: sub.data_type,
+                            data_count: sub.data_count,
+                            status: 1,
+                            subid: sub.subid,
+                            value,
+                        };
+                        out.extend(CaMsg { ty: CaMsgTy::EventAddRes(res) }.to_vec());
+                    }
+                    stream.write_all(&out).await?;
+                }
+            }
+        }
+    }
+
+    fn handle_msg(
+        hi: &HeadInfo,
+        payload: &[u8],
+        tcp_port: u16,
+        channels: &[MockChannel],
+        sids: &mut BTreeMap<u32, usize>,
+        subs: &mut BTreeMap<u32, Subscription>,
+        out: &mut Vec<u8>,
+    ) {
+        match hi.cmdid() {
+            0 => {
+                out.extend(
+                    CaMsg {
+                        ty: CaMsgTy::VersionRes(CA_PROTO_VERSION),
+                    }
+                    .to_vec(),
+                );
+            }
+            // Client name and host name.
+            20 | 21 => {}
+            6 => {
+                let name = Self::ca_string(payload);
+                if channels.iter().any(|x| x.name == name) {
+                    let res = SearchRes {
+                        addr: u32::MAX,
+                        tcp_port,
+                        id: hi.param2(),
+                        proto_version: CA_PROTO_VERSION,
+                    };
+                    out.extend(
+                        CaMsg {
+                            ty: CaMsgTy::SearchRes(res),
+                        }
+                        .to_vec(),
+                    );
+                }
+            }
+            18 => {
+                let cid = hi.param1();
+                let name = Self::ca_string(payload);
+                match channels.iter().position(|x| x.name == name) {
+                    Some(i) => {
+                        let sid = sids.len() as u32 + 1;
+                        sids.insert(sid, i);
+                        let (data_type, data_count) = Self::native_type(&(channels[i].gen)(0));
+                        let res = AccessRightsRes { cid, rights: 3 };
+                        out.extend(
+                            CaMsg {
+                                ty: CaMsgTy::AccessRightsRes(res),
+                            }
+                            .to_vec(),
+                        );
+                        let res = CreateChanRes {
+                            data_type,
+                            data_count,
+                            cid,
+                            sid,
+                        };
+                        out.extend(
+                            CaMsg {
+                                ty: CaMsgTy::CreateChanRes(res),
+                            }
+                            .to_vec(),
+                        );
+                    }
+                    None => {
+                        let res = CreateChanFail { cid };
+                        out.extend(
+                            CaMsg {
+                                ty: CaMsgTy::CreateChanFail(res),
+                            }
+                            .to_vec(),
+                        );
+                    }
+                }
+            }
+            1 => {
+                let sid = hi.param1();
+                let subid = hi.param2();
+                if let Some(&i) = sids.get(&sid) {
+                    let (native, data_count) = Self::native_type(&(channels[i].gen)(0));
+                    // Only the time subscription gets events, the control one stays silent.
+                    if hi.data_type() == native + 14 {
+                        let sub = Subscription {
+                            channel: i,
+                            sid,
+                            subid,
+                            data_type: hi.data_type(),
+                            data_count,
+                            count: 0,
+                        };
+                        subs.insert(subid, sub);
+                    }
+                }
+            }
+            2 => {
+                if subs.remove(&hi.param2()).is_some() {
+                    let res = EventCancelRes {
+                        data_type: hi.data_type(),
+                        data_count: hi.data_count(),
+                        subid: hi.param2(),
+                    };
+                    out.extend(
+                        CaMsg {
+                            ty: CaMsgTy::EventCancelRes(res),
+                        }
+                        .to_vec(),
+                    );
+                }
+            }
+            12 => {
+                let sid = hi.param1();
+                subs.retain(|_, x| x.sid != sid);
+                sids.remove(&sid);
+                let res = ClearChannelRes { sid, cid: hi.param2() };
+                out.extend(
+                    CaMsg {
+                        ty: CaMsgTy::ClearChannelRes(res),
+                    }
+                    .to_vec(),
+                );
+            }
+            15 => {
+                let sid = hi.param1();
+                if let Some(&i) = sids.get(&sid) {
+                    let value = CaEventValue {
+                        ts: NonZeroU64::new(Self::now_ns()),
+                        status: None,
+                        severity: None,
+                        data: (channels[i].gen)(0),
+                    };
+                    let res = ReadNotifyRes {
+                        data_type: hi.data_type(),
+                        data_count: hi.data_count(),
+                        sid: 1,
+                        ioid: hi.param2(),
+                        value,
+                    };
+                    out.extend(
+                        CaMsg {
+                            ty: CaMsgTy::ReadNotifyRes(res),
+                        }
+                        .to_vec(),
+                    );
+                }
+            }
+            23 => {
+                out.extend(CaMsg { ty: CaMsgTy::Echo }.to_vec());
+            }
+            k => {
+                debug!("mock ioc ignore cmdid {k}");
+            }
+        }
+    }
+
+    fn parse_msgs(buf: &[u8]) -> Vec<(HeadInfo, Vec<u8>)> {
+        let mut ret = Vec::new();
+        let mut nb = SlideBuf::new(buf.len());
+        if nb.put_slice(buf).is_err() {
+            return ret;
+        }
+        while nb.len() >= 16 {
+            let hi = match HeadInfo::from_netbuf(&mut nb) {
+                Ok(x) => x,
+                Err(_) => break,
+            };
+            match nb.read_bytes(hi.payload()) {
+                Ok(x) => {
+                    let payload = x.to_vec();
+                    ret.push((hi, payload));
+                }
+                Err(_) => break,
+            }
+        }
+        ret
+    }
+
+    fn ca_string(buf: &[u8]) -> String {
+        let n = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+        String::from_utf8_lossy(&buf[..n]).into()
+    }
+
+    // DBR type id and element count of the plain value.
+    fn native_type(v: &CaDataValue) -> (u16, u32) {
+        match v {
+            CaDataValue::Scalar(x) => {
+                let t = match x {
+                    CaDataScalarValue::String(_) => 0,
+                    CaDataScalarValue::I16(_) => 1,
+                    CaDataScalarValue::F32(_) => 2,
+                    CaDataScalarValue::Enum(_) => 3,
+                    CaDataScalarValue::I8(_) | CaDataScalarValue::Bool(_) => 4,
+                    CaDataScalarValue::I32(_) => 5,
+                    CaDataScalarValue::F64(_) => 6,
+                };
+                (t, 1)
+            }
+            CaDataValue::Array(x) => match x {
+                CaDataArrayValue::String(v) => (0, v.len() as u32),
+                CaDataArrayValue::I16(v) => (1, v.len() as u32),
+                CaDataArrayValue::F32(v) => (2, v.len() as u32),
+                CaDataArrayValue::I8(v) => (4, v.len() as u32),
+                CaDataArrayValue::Bool(v) => (4, v.len() as u32),
+                CaDataArrayValue::I32(v) => (5, v.len() as u32),
+                CaDataArrayValue::F64(v) => (6, v.len() as u32),
+            },
+        }
+    }
+
+    fn now_ns() -> u64 {
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        SEC * ts.as_secs() + ts.subsec_nanos() as u64
+    }
+}
+
+impl Drop for MockIoc {
+    fn drop(&mut self) {
+        self.disconnect_all();
+        for jh in &self.jhs {
+            jh.abort();
+        }
+    }
+}
+
+// Stands in for the postgres series lookup, hands out a new series id for every query.
+fn start_fake_series_lookup(rx: Receiver<ChannelInfoQuery>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut next = 1000;
+        while let Ok(q) = rx.recv().await {
+            next += 1;
+            let res = ChannelInfoResult {
+                backend: q.backend.clone(),
+                channel: q.channel.clone(),
+                series: Existence::Existing(SeriesId::new(next)),
+            };
+            if q.tx.make_send(Ok(res)).await.is_err() {
+                warn!("fake series lookup can not reply");
+            }
+        }
+    })
+}
+
+fn ramp_f64(i: u64) -> CaDataValue {
+    CaDataValue::Scalar(CaDataScalarValue::F64(i as f64 * 0.5))
+}
+
+#[test]
+fn mock_ioc_found_by_search() {
+    let fut = async {
+        let ioc = MockIoc::start(vec![MockChannel::new("MOCK:A", ramp_f64)], Duration::from_millis(50)).await?;
+        let mut finder = FindIocStream::new(vec![ioc.udp_addr()], Duration::from_millis(500), 4, 8);
+        finder.push("MOCK:A".into());
+        finder.push("MOCK:NOT-THERE".into());
+        let mut found = Vec::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(4000);
+        while found.len() < 2 {
+            let item = tokio::time::timeout_at(deadline, finder.next())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("search timeout"))?;
+            match item {
+                Some(Ok(items)) => found.extend(items),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        found.sort_by(|a, b| a.channel.cmp(&b.channel));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].channel, "MOCK:A");
+        assert_eq!(
+            found[0].addr,
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, ioc.tcp_addr().port()))
+        );
+        assert_eq!(found[1].addr, None);
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn mock_ioc_found_by_name_server() {
+    let fut = async {
+        let ioc = MockIoc::start(vec![MockChannel::new("MOCK:A", ramp_f64)], Duration::from_millis(50)).await?;
+        let mut finder = FindIocStream::new(Vec::new(), Duration::from_millis(500), 4, 8);
+        finder.set_name_servers(vec![ioc.tcp_addr()]);
+        finder.push("MOCK:A".into());
+        finder.push("MOCK:NOT-THERE".into());
+        let mut found = Vec::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(4000);
+        while found.len() < 2 {
+            let item = tokio::time::timeout_at(deadline, finder.next())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("search timeout"))?;
+            match item {
+                Some(Ok(items)) => found.extend(items),
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        found.sort_by(|a, b| a.channel.cmp(&b.channel));
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].channel, "MOCK:A");
+        assert_eq!(found[0].response_addr, Some(ioc.tcp_addr()));
+        assert_eq!(found[0].addr, Some(ioc.tcp_addr()));
+        assert_eq!(found[1].addr, None);
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn connset_inserts_from_mock_ioc() {
+    let fut = async {
+        // Slow enough that CaConn does not rate limit the first inserts.
+        let ioc = MockIoc::start(vec![MockChannel::new("MOCK:A", ramp_f64)], Duration::from_millis(200)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = CaConnSet::start(
+            "testbackend".into(),
+            "testhost".into(),
+            storage_tx,
+            query_tx,
+            FinderSource::Search {
+                udp: vec![ioc.udp_addr()],
+                name_servers: Vec::new(),
+            },
+            None,
+        );
+        ctrl.add_channel(
+            "testbackend".into(),
+            "MOCK:A".into(),
+            "testhost".into(),
+            ArchivingPolicy::default(),
+        )
+        .await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        let mut inserts = Vec::new();
+        while inserts.len() < 3 {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no inserts from mock ioc"))??;
+            if let QueryItem::Insert(item) = item {
+                inserts.push(item);
+            }
+        }
+        // Every subscription starts the ramp at zero.
+        let vals: Vec<_> = inserts
+            .iter()
+            .map(|item| match &item.val {
+                DataValue::Scalar(ScalarValue::F64(v)) => *v,
+                x => panic!("unexpected value {x:?}"),
+            })
+            .collect();
+        assert_eq!(vals, vec![0., 0.5, 1.]);
+        for item in &inserts {
+            assert_eq!(item.series, inserts[0].series);
+        }
+
+        // The connection loss must be recorded as channel status.
+        ioc.disconnect_all();
+        let mut closed = false;
+        while !closed {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no status after disconnect"))??;
+            if let QueryItem::ChannelStatus(item) = item {
+                closed = matches!(item.status, ChannelStatus::Closed(_));
+            }
+        }
+
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn connset_scan_policy_reads() {
+    let fut = async {
+        // The monitor period is long, any insert in time must come from the scan reads.
+        let ioc = MockIoc::start(vec![MockChannel::new("MOCK:S", ramp_f64)], Duration::from_millis(60000)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = CaConnSet::start(
+            "testbackend".into(),
+            "testhost".into(),
+            storage_tx,
+            query_tx,
+            FinderSource::Search {
+                udp: vec![ioc.udp_addr()],
+                name_servers: Vec::new(),
+            },
+            None,
+        );
+        let policy = ArchivingPolicy::Scan {
+            period: Duration::from_millis(500),
+        };
+        ctrl.add_channel("testbackend".into(), "MOCK:S".into(), "testhost".into(), policy)
+            .await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        let mut n = 0;
+        while n < 2 {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no scan inserts from mock ioc"))??;
+            if let QueryItem::Insert(_) = item {
+                n += 1;
+            }
+        }
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn connset_channel_remove_closes() {
+    let fut = async {
+        let ioc = MockIoc::start(vec![MockChannel::new("MOCK:R", ramp_f64)], Duration::from_millis(20)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = CaConnSet::start(
+            "testbackend".into(),
+            "testhost".into(),
+            storage_tx,
+            query_tx,
+            FinderSource::Search {
+                udp: vec![ioc.udp_addr()],
+                name_servers: Vec::new(),
+            },
+            None,
+        );
+        ctrl.add_channel(
+            "testbackend".into(),
+            "MOCK:R".into(),
+            "testhost".into(),
+            ArchivingPolicy::default(),
+        )
+        .await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        loop {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no inserts from mock ioc"))??;
+            if let QueryItem::Insert(_) = item {
+                break;
+            }
+        }
+        ctrl.remove_channel("MOCK:R".into()).await?;
+        let mut closed = false;
+        while !closed {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no status after remove"))??;
+            if let QueryItem::ChannelStatus(item) = item {
+                closed = matches!(
+                    item.status,
+                    ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove)
+                );
+            }
+        }
+        // It was the only channel, the connection closes but the IOC must still see
+        // the subscription cancel and the channel clear.
+        loop {
+            let cmds = ioc.received_cmds();
+            let cancel = cmds.iter().position(|&x| x == 2);
+            let clear = cmds.iter().position(|&x| x == 12);
+            if let (Some(cancel), Some(clear)) = (cancel, clear) {
+                assert!(cancel < clear);
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                panic!("no cancel and clear on the ioc  {cmds:?}");
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
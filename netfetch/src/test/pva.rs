@@ -0,0 +1,597 @@
+// Mock pvAccess server for the search, PvaConn and PvaConnSet code paths.
+
+use super::start_fake_series_lookup;
+use crate::pva::connset::PvaConnSet;
+use crate::pva::proto::monitor_data_body;
+use crate::pva::proto::parse_msgs;
+use crate::pva::proto::MonitorReq;
+use crate::pva::proto::MonitorRes;
+use crate::pva::proto::PvaMsg;
+use crate::pva::proto::SearchRes;
+use crate::pva::proto::Status;
+use crate::pva::pvdata::BitSet;
+use crate::pva::pvdata::FieldDesc;
+use crate::pva::pvdata::PvArray;
+use crate::pva::pvdata::PvScalar;
+use crate::pva::pvdata::PvValue;
+use crate::pva::pvdata::ScalarKind;
+use crate::pva::pvdata::StructDesc;
+use crate::pva::pvdata::TypeCache;
+use crate::pva::search::pva_search;
+use err::Error;
+use log::*;
+use netpod::ScalarType;
+use netpod::Shape;
+use scywr::iteminsertqueue::ArrayValue;
+use scywr::iteminsertqueue::ChannelStatus;
+use scywr::iteminsertqueue::ChannelStatusClosedReason;
+use scywr::iteminsertqueue::DataValue;
+use scywr::iteminsertqueue::QueryItem;
+use scywr::iteminsertqueue::ScalarValue;
+use series::SeriesId;
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use taskrun::tokio;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+pub type PvValueGen = Arc<dyn Fn(u64) -> PvValue + Send + Sync>;
+
+#[derive(Clone)]
+pub struct MockPvaChannel {
+    pub name: String,
+    pub value_desc: FieldDesc,
+    pub gen: PvValueGen,
+}
+
+impl MockPvaChannel {
+    pub fn new<F>(name: &str, value_desc: FieldDesc, gen: F) -> Self
+    where
+        F: Fn(u64) -> PvValue + Send + Sync + 'static,
+    {
+        Self {
+            name: name.into(),
+            value_desc,
+            gen: Arc::new(gen),
+        }
+    }
+
+    // The value wrapped into a normative type with alarm and time stamp.
+    fn desc(&self) -> FieldDesc {
+        let alarm_t = StructDesc::new(
+            "alarm_t",
+            vec![
+                ("severity", FieldDesc::Scalar(ScalarKind::I32)),
+                ("status", FieldDesc::Scalar(ScalarKind::I32)),
+                ("message", FieldDesc::Scalar(ScalarKind::String)),
+            ],
+        );
+        let time_t = StructDesc::new(
+            "time_t",
+            vec![
+                ("secondsPastEpoch", FieldDesc::Scalar(ScalarKind::I64)),
+                ("nanoseconds", FieldDesc::Scalar(ScalarKind::I32)),
+                ("userTag", FieldDesc::Scalar(ScalarKind::I32)),
+            ],
+        );
+        FieldDesc::Struct(StructDesc::new(
+            "epics:nt/NTScalar:1.0",
+            vec![
+                ("value", self.value_desc.clone()),
+                ("alarm", FieldDesc::Struct(alarm_t)),
+                ("timeStamp", FieldDesc::Struct(time_t)),
+            ],
+        ))
+    }
+
+    fn value(&self, i: u64) -> PvValue {
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        PvValue::Struct(vec![
+            (self.gen)(i),
+            PvValue::Struct(vec![
+                PvValue::Scalar(PvScalar::I32(0)),
+                PvValue::Scalar(PvScalar::I32(0)),
+                PvValue::Scalar(PvScalar::String(String::new())),
+            ]),
+            PvValue::Struct(vec![
+                PvValue::Scalar(PvScalar::I64(ts.as_secs() as i64)),
+                PvValue::Scalar(PvScalar::I32(ts.subsec_nanos() as i32)),
+                PvValue::Scalar(PvScalar::I32(0)),
+            ]),
+        ])
+    }
+
+    // The first update carries the whole structure, later ones only value and time stamp.
+    fn changed(&self, i: u64) -> BitSet {
+        let mut ret = BitSet::new();
+        if i == 0 {
+            ret.set(0);
+        } else {
+            ret.set(1);
+            ret.set(1 + self.value_desc.offset_count() + 4);
+        }
+        ret
+    }
+}
+
+struct Subscription {
+    channel: usize,
+    sid: u32,
+    started: bool,
+    credits: u32,
+    count: u64,
+}
+
+// Answers searches over UDP and serves monitors on TCP, one update per subscription each period
+// as long as the client has acked enough of the earlier ones.
+pub struct MockPvaServer {
+    udp_addr: SocketAddrV4,
+    conns: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    jhs: Vec<JoinHandle<()>>,
+}
+
+impl MockPvaServer {
+    pub async fn start(channels: Vec<MockPvaChannel>, period: Duration) -> Result<Self, Error> {
+        let udp = UdpSocket::bind("127.0.0.1:0").await?;
+        let tcp = TcpListener::bind("127.0.0.1:0").await?;
+        let udp_addr = Self::addr_v4(udp.local_addr()?)?;
+        let tcp_addr = Self::addr_v4(tcp.local_addr()?)?;
+        let channels = Arc::new(channels);
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let jh1 = tokio::spawn(Self::run_udp(udp, tcp_addr.port(), channels.clone()));
+        let jh2 = tokio::spawn(Self::run_tcp(tcp, channels, period, conns.clone()));
+        let ret = Self {
+            udp_addr,
+            conns,
+            jhs: vec![jh1, jh2],
+        };
+        Ok(ret)
+    }
+
+    pub fn udp_addr(&self) -> SocketAddrV4 {
+        self.udp_addr
+    }
+
+    pub fn disconnect_all(&self) {
+        for jh in self.conns.lock().unwrap().drain(..) {
+            jh.abort();
+        }
+    }
+
+    fn addr_v4(addr: std::net::SocketAddr) -> Result<SocketAddrV4, Error> {
+        match addr {
+            std::net::SocketAddr::V4(x) => Ok(x),
+            _ => Err(Error::with_msg_no_trace("expect ipv4")),
+        }
+    }
+
+    async fn run_udp(udp: UdpSocket, tcp_port: u16, channels: Arc<Vec<MockPvaChannel>>) {
+        let mut buf = vec![0; 1024 * 16];
+        let mut cache = TypeCache::new();
+        loop {
+            let (n, src) = match udp.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("mock pva udp recv {e}");
+                    break;
+                }
+            };
+            let msgs = match parse_msgs(&buf[..n], &mut cache) {
+                Ok(x) => x.0,
+                Err(e) => {
+                    error!("mock pva udp parse {e}");
+                    continue;
+                }
+            };
+            for (_, msg) in msgs {
+                if let PvaMsg::Search(search) = msg {
+                    let ids: Vec<_> = search
+                        .channels
+                        .iter()
+                        .filter(|x| channels.iter().any(|c| c.name == x.1))
+                        .map(|x| x.0)
+                        .collect();
+                    if ids.is_empty() {
+                        continue;
+                    }
+                    let res = PvaMsg::SearchRes(SearchRes {
+                        guid: [7; 12],
+                        seq: search.seq,
+                        server_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, tcp_port),
+                        found: true,
+                        ids,
+                    });
+                    // Like a real server, answer to the response address of the request.
+                    let ip = if search.response_addr.ip().is_unspecified() {
+                        match src {
+                            std::net::SocketAddr::V4(x) => *x.ip(),
+                            _ => continue,
+                        }
+                    } else {
+                        *search.response_addr.ip()
+                    };
+                    let dst = SocketAddrV4::new(ip, search.response_addr.port());
+                    if let Err(e) = udp.send_to(&res.to_vec(true), dst).await {
+                        error!("mock pva udp send {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_tcp(
+        tcp: TcpListener,
+        channels: Arc<Vec<MockPvaChannel>>,
+        period: Duration,
+        conns: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    ) {
+        loop {
+            match tcp.accept().await {
+                Ok((stream, _)) => {
+                    let fut = Self::run_conn(stream, channels.clone(), period);
+                    let jh = tokio::spawn(async move {
+                        if let Err(e) = fut.await {
+                            debug!("mock pva conn done {e}");
+                        }
+                    });
+                    conns.lock().unwrap().push(jh);
+                }
+                Err(e) => {
+                    error!("mock pva accept {e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn run_conn(
+        mut stream: TcpStream,
+        channels: Arc<Vec<MockPvaChannel>>,
+        period: Duration,
+    ) -> Result<(), Error> {
+        let mut out = PvaMsg::SetByteOrder.to_vec(true);
+        out.extend(
+            PvaMsg::ValidationReq {
+                buffer_size: 1024 * 16,
+                registry_size: 512,
+                auth: vec!["anonymous".into()],
+            }
+            .to_vec(true),
+        );
+        stream.write_all(&out).await?;
+        let mut cache = TypeCache::new();
+        let mut inbuf = Vec::new();
+        let mut buf = vec![0; 1024 * 16];
+        let mut sids: BTreeMap<u32, usize> = BTreeMap::new();
+        let mut subs: BTreeMap<u32, Subscription> = BTreeMap::new();
+        let mut tick = tokio::time::interval(period);
+        loop {
+            let mut out = Vec::new();
+            tokio::select! {
+                x = stream.read(&mut buf) => {
+                    let n = x?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    inbuf.extend_from_slice(&buf[..n]);
+                    let (msgs, n) = parse_msgs(&inbuf, &mut cache).map_err(Error::from_string)?;
+                    inbuf.drain(..n);
+                    for (_, msg) in msgs {
+                        let res = Self::handle_msg(msg, &channels, &mut sids, &mut subs);
+                        for msg in res {
+                            out.extend(msg.to_vec(true));
+                        }
+                    }
+                }
+                _ = tick.tick() => {
+                    for (ioid, sub) in subs.iter_mut() {
+                        if !sub.started || sub.credits == 0 {
+                            continue;
+                        }
+                        let ch = &channels[sub.channel];
+                        let body = monitor_data_body(&ch.value(sub.count), &ch.changed(sub.count));
+                        let msg = PvaMsg::MonitorRes(MonitorRes::Data {
+                            ioid: *ioid,
+                            body,
+                            big_endian: true,
+                        });
+                        out.extend(msg.to_vec(true));
+                        sub.credits -= 1;
+                        sub.count += 1;
+                    }
+                }
+            }
+            if !out.is_empty() {
+                stream.write_all(&out).await?;
+            }
+        }
+    }
+
+    fn handle_msg(
+        msg: PvaMsg,
+        channels: &[MockPvaChannel],
+        sids: &mut BTreeMap<u32, usize>,
+        subs: &mut BTreeMap<u32, Subscription>,
+    ) -> Vec<PvaMsg> {
+        let mut ret = Vec::new();
+        match msg {
+            PvaMsg::ValidationRes { .. } => ret.push(PvaMsg::Validated(Status::Ok)),
+            PvaMsg::Echo(x) => ret.push(PvaMsg::Echo(x)),
+            PvaMsg::CreateChannel(list) => {
+                for (cid, name) in list {
+                    match channels.iter().position(|x| x.name == name) {
+                        Some(i) => {
+                            let sid = sids.keys().last().map_or(1, |x| x + 1);
+                            sids.insert(sid, i);
+                            ret.push(PvaMsg::CreateChannelRes {
+                                cid,
+                                sid,
+                                status: Status::Ok,
+                            });
+                        }
+                        None => ret.push(PvaMsg::CreateChannelRes {
+                            cid,
+                            sid: 0,
+                            status: Status::Error(format!("no channel {name}")),
+                        }),
+                    }
+                }
+            }
+            PvaMsg::MonitorReq(req) => match req {
+                MonitorReq::Init { sid, ioid, queue_size } => {
+                    if let Some(&channel) = sids.get(&sid) {
+                        let sub = Subscription {
+                            channel,
+                            sid,
+                            started: false,
+                            credits: queue_size,
+                            count: 0,
+                        };
+                        subs.insert(ioid, sub);
+                        ret.push(PvaMsg::MonitorRes(MonitorRes::Init {
+                            ioid,
+                            status: Status::Ok,
+                            desc: Some(channels[channel].desc()),
+                        }));
+                    }
+                }
+                MonitorReq::Start { ioid, .. } => {
+                    if let Some(sub) = subs.get_mut(&ioid) {
+                        sub.started = true;
+                    }
+                }
+                MonitorReq::Stop { ioid, .. } => {
+                    if let Some(sub) = subs.get_mut(&ioid) {
+                        sub.started = false;
+                    }
+                }
+                MonitorReq::Ack { ioid, nfree, .. } => {
+                    if let Some(sub) = subs.get_mut(&ioid) {
+                        sub.credits += nfree;
+                    }
+                }
+                MonitorReq::Destroy { ioid, .. } => {
+                    subs.remove(&ioid);
+                }
+            },
+            PvaMsg::DestroyRequest { ioid, .. } => {
+                subs.remove(&ioid);
+            }
+            PvaMsg::DestroyChannel { sid, cid } => {
+                sids.remove(&sid);
+                subs.retain(|_, x| x.sid != sid);
+                ret.push(PvaMsg::DestroyChannel { sid, cid });
+            }
+            _ => {}
+        }
+        ret
+    }
+}
+
+impl Drop for MockPvaServer {
+    fn drop(&mut self) {
+        self.disconnect_all();
+        for jh in &self.jhs {
+            jh.abort();
+        }
+    }
+}
+
+fn scalar_f64() -> MockPvaChannel {
+    MockPvaChannel::new("PVA:A", FieldDesc::Scalar(ScalarKind::F64), |i| {
+        PvValue::Scalar(PvScalar::F64(i as f64 * 0.5))
+    })
+}
+
+fn array_f32() -> MockPvaChannel {
+    MockPvaChannel::new("PVA:W", FieldDesc::Array(ScalarKind::F32), |i| {
+        PvValue::Array(PvArray::F32(vec![i as f32; 4]))
+    })
+}
+
+#[test]
+fn pva_search_finds_mock_server() {
+    let fut = async {
+        let server = MockPvaServer::start(vec![scalar_f64()], Duration::from_millis(50)).await?;
+        let channels = vec!["PVA:A".to_string(), "PVA:NOT-THERE".to_string()];
+        let res = pva_search(&[server.udp_addr()], &channels, Duration::from_millis(400)).await?;
+        assert_eq!(res.len(), 2);
+        let addr = res[0].1.ok_or_else(|| Error::with_msg_no_trace("PVA:A not found"))?;
+        assert_eq!(addr.ip(), &Ipv4Addr::LOCALHOST);
+        assert_ne!(addr.port(), server.udp_addr().port());
+        assert_eq!(res[1].1, None);
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn pva_connset_inserts_from_mock_server() {
+    let fut = async {
+        let server = MockPvaServer::start(vec![scalar_f64(), array_f32()], Duration::from_millis(5)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = PvaConnSet::start("testbackend".into(), storage_tx, query_tx, vec![server.udp_addr()]);
+        ctrl.add_channel("PVA:A".into()).await?;
+        ctrl.add_channel("PVA:W".into()).await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        // More than the monitor queue size, the server only continues if the client acks.
+        let mut scalars = Vec::new();
+        let mut arrays = Vec::new();
+        let mut opened = 0;
+        while scalars.len() < 40 || arrays.len() < 3 || opened < 2 {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no inserts from mock pva server"))??;
+            match item {
+                QueryItem::Insert(item) => match &item.val {
+                    DataValue::Scalar(ScalarValue::F64(_)) => scalars.push(item),
+                    DataValue::Array(ArrayValue::F32(v)) => {
+                        assert_eq!(v.len(), 4);
+                        assert_eq!(item.shape, Shape::Wave(4));
+                        arrays.push(item);
+                    }
+                    x => panic!("unexpected value {x:?}"),
+                },
+                QueryItem::ChannelStatus(item) => {
+                    if let ChannelStatus::Opened = item.status {
+                        opened += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let series: Vec<SeriesId> = scalars.iter().map(|x| x.series.clone()).collect();
+        assert!(series.iter().all(|x| *x == series[0]));
+        assert_ne!(arrays[0].series, series[0]);
+        for (i, item) in scalars.iter().enumerate() {
+            assert_eq!(item.scalar_type, ScalarType::F64);
+            assert_eq!(item.shape, Shape::Scalar);
+            match &item.val {
+                DataValue::Scalar(ScalarValue::F64(v)) => assert_eq!(*v, i as f64 * 0.5),
+                x => panic!("unexpected value {x:?}"),
+            }
+        }
+
+        // The connection loss must be recorded as channel status.
+        server.disconnect_all();
+        let mut closed = false;
+        while !closed {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no status after disconnect"))??;
+            if let QueryItem::ChannelStatus(item) = item {
+                closed = matches!(item.status, ChannelStatus::Closed(_));
+            }
+        }
+
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn pva_variable_array_grows_series() {
+    let fut = async {
+        const LENS: [usize; 6] = [2, 4, 3, 4, 6, 1];
+        let ch = MockPvaChannel::new("PVA:V", FieldDesc::Array(ScalarKind::F32), |i| {
+            PvValue::Array(PvArray::F32(vec![i as f32; LENS[i as usize % LENS.len()]]))
+        });
+        let server = MockPvaServer::start(vec![ch], Duration::from_millis(5)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = PvaConnSet::start("testbackend".into(), storage_tx, query_tx, vec![server.udp_addr()]);
+        ctrl.add_channel("PVA:V".into()).await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        let mut arrays = Vec::new();
+        while arrays.len() < 20 {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no inserts from mock pva server"))??;
+            if let QueryItem::Insert(item) = item {
+                arrays.push(item);
+            }
+        }
+        // Every array fits the shape of its series, a longer array starts a new series.
+        let mut shape_by_series = BTreeMap::new();
+        let mut n_last = 0;
+        for item in &arrays {
+            let (v, n) = match (&item.val, &item.shape) {
+                (DataValue::Array(ArrayValue::F32(v)), Shape::Wave(n)) => (v, *n),
+                x => panic!("unexpected {x:?}"),
+            };
+            assert_eq!(v.len(), LENS[v[0] as usize % LENS.len()]);
+            assert!(v.len() <= n as usize);
+            assert!(n >= n_last);
+            n_last = n;
+            let shape = shape_by_series.entry(item.series.clone()).or_insert(n);
+            assert_eq!(*shape, n);
+        }
+        assert_eq!(n_last, 6);
+        assert!(shape_by_series.len() >= 2);
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
+
+#[test]
+fn pva_connset_channel_remove_closes() {
+    let fut = async {
+        let server = MockPvaServer::start(vec![scalar_f64()], Duration::from_millis(20)).await?;
+        let (query_tx, query_rx) = async_channel::bounded(64);
+        let (storage_tx, storage_rx) = async_channel::bounded(4096);
+        let lookup_jh = start_fake_series_lookup(query_rx);
+        let ctrl = PvaConnSet::start("testbackend".into(), storage_tx, query_tx, vec![server.udp_addr()]);
+        ctrl.add_channel("PVA:A".into()).await?;
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(8000);
+        loop {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no inserts from mock pva server"))??;
+            if let QueryItem::Insert(_) = item {
+                break;
+            }
+        }
+        ctrl.remove_channel("PVA:A".into()).await?;
+        let mut closed = false;
+        while !closed {
+            let item = tokio::time::timeout_at(deadline, storage_rx.recv())
+                .await
+                .map_err(|_| Error::with_msg_no_trace("no status after remove"))??;
+            if let QueryItem::ChannelStatus(item) = item {
+                closed = matches!(
+                    item.status,
+                    ChannelStatus::Closed(ChannelStatusClosedReason::ChannelRemove)
+                );
+            }
+        }
+        ctrl.shutdown().await?;
+        tokio::time::timeout(Duration::from_millis(4000), ctrl.join())
+            .await
+            .map_err(|_| Error::with_msg_no_trace("connset did not stop"))??;
+        lookup_jh.abort();
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
@@ -32,6 +32,7 @@ struct TickParams<'a> {
     tb: &'a mut Box<dyn TimeBinner>,
     pc: &'a mut PatchCollect,
     iiq: &'a mut VecDeque<QueryItem>,
+    flush: bool,
 }
 
 pub struct ConnTimeBin {
@@ -130,6 +131,10 @@ impl ConnTimeBin {
                         self.tick_fn = Box::new(tick::<ST>);
                         self.did_setup = true;
                     }
+                    STRING => {
+                        // Strings are not binned, `push` and `tick` stay no-ops.
+                        debug!("no time binning for {:?}  {:?}", scalar_type, shape);
+                    }
                     _ => {
                         warn!("TODO  setup_event_acc  {:?}  {:?}", scalar_type, shape);
                     }
@@ -138,6 +143,9 @@ impl ConnTimeBin {
             Shape::Wave(..) => {
                 //type Cont<T> = EventsDim1<T>;
                 match scalar_type {
+                    STRING => {
+                        debug!("no time binning for {:?}  {:?}", scalar_type, shape);
+                    }
                     _ => {
                         warn!("TODO  setup_event_acc  {:?}  {:?}", scalar_type, shape);
                     }
@@ -160,6 +168,15 @@ impl ConnTimeBin {
     }
 
     pub fn tick(&mut self, insert_item_queue: &mut VecDeque<QueryItem>) -> Result<(), Error> {
+        self.tick_inner(insert_item_queue, false)
+    }
+
+    // Like tick, but also emits the bin in progress. Used before the binner is dropped.
+    pub fn flush(&mut self, insert_item_queue: &mut VecDeque<QueryItem>) -> Result<(), Error> {
+        self.tick_inner(insert_item_queue, true)
+    }
+
+    fn tick_inner(&mut self, insert_item_queue: &mut VecDeque<QueryItem>, flush: bool) -> Result<(), Error> {
         if !self.did_setup {
             return Ok(());
         }
@@ -170,6 +187,7 @@ impl ConnTimeBin {
             tb: self.events_binner.as_mut().unwrap(),
             pc: &mut self.patch_collect,
             iiq: insert_item_queue,
+            flush,
         };
         f(params)
     }
@@ -250,43 +268,45 @@ where
             //info!("push events  len {}", c.len());
             tb.ingest(c);
             c.reset();
-            if tb.bins_ready_count() >= 1 {
-                info!("store bins len {}", tb.bins_ready_count());
-                if let Some(mut bins) = tb.bins_ready() {
-                    //info!("store bins  {bins:?}");
-                    let mut bins = bins.to_simple_bins_f32();
-                    pc.ingest(bins.as_mut())?;
-                    if pc.outq_len() != 0 {
-                        store_patch(params.series.clone(), pc, iiq)?;
-                        for item in pc.take_outq() {
-                            if let Some(k) = item.as_any_ref().downcast_ref::<BinsDim0<f32>>() {
-                                // TODO
-                                //let off_msp =
-                                let item = TimeBinPatchSimpleF32 {
-                                    series: params.series.clone(),
-                                    bin_len_sec: (pc.bin_len().ns() / SEC) as u32,
-                                    bin_count: pc.bin_count() as u32,
-                                    off_msp: 0,
-                                    off_lsp: 0,
-                                    counts: k.counts.iter().map(|x| *x as i64).collect(),
-                                    mins: k.mins.iter().map(|x| *x).collect(),
-                                    maxs: k.maxs.iter().map(|x| *x).collect(),
-                                    avgs: k.avgs.iter().map(|x| *x).collect(),
-                                };
-                                let item = QueryItem::TimeBinPatchSimpleF32(item);
-                                iiq.push_back(item);
-                            } else {
-                                error!("unexpected container!");
-                            }
+        }
+        if params.flush {
+            // Emit the partially filled bin, e.g. when the channel gets removed.
+            tb.push_in_progress(false);
+        }
+        if tb.bins_ready_count() >= 1 {
+            info!("store bins len {}", tb.bins_ready_count());
+            if let Some(mut bins) = tb.bins_ready() {
+                //info!("store bins  {bins:?}");
+                let mut bins = bins.to_simple_bins_f32();
+                pc.ingest(bins.as_mut())?;
+                if pc.outq_len() != 0 {
+                    store_patch(params.series.clone(), pc, iiq)?;
+                    for item in pc.take_outq() {
+                        if let Some(k) = item.as_any_ref().downcast_ref::<BinsDim0<f32>>() {
+                            // TODO
+                            //let off_msp =
+                            let item = TimeBinPatchSimpleF32 {
+                                series: params.series.clone(),
+                                bin_len_sec: (pc.bin_len().ns() / SEC) as u32,
+                                bin_count: pc.bin_count() as u32,
+                                off_msp: 0,
+                                off_lsp: 0,
+                                counts: k.counts.iter().map(|x| *x as i64).collect(),
+                                mins: k.mins.iter().map(|x| *x).collect(),
+                                maxs: k.maxs.iter().map(|x| *x).collect(),
+                                avgs: k.avgs.iter().map(|x| *x).collect(),
+                            };
+                            let item = QueryItem::TimeBinPatchSimpleF32(item);
+                            iiq.push_back(item);
+                        } else {
+                            error!("unexpected container!");
                         }
                     }
-                    Ok(())
-                } else {
-                    error!("have bins but none returned");
-                    Err(Error::with_msg_no_trace("have bins but none returned"))
                 }
-            } else {
                 Ok(())
+            } else {
+                error!("have bins but none returned");
+                Err(Error::with_msg_no_trace("have bins but none returned"))
             }
         } else {
             Ok(())
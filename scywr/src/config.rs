@@ -1 +1,163 @@
+use scylla::statement::Consistency;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ScyllaConfig {
+    pub hosts: Vec<String>,
+    pub keyspace: String,
+    // Used only when the keyspace gets created.
+    #[serde(default)]
+    pub replication: ScyllaReplication,
+    pub consistency_read: Option<ScyllaConsistency>,
+    pub consistency_write: Option<ScyllaConsistency>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<ScyllaTls>,
+}
+
+// The config gets logged at start up, the password must not show up there.
+impl fmt::Debug for ScyllaConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ScyllaConfig")
+            .field("hosts", &self.hosts)
+            .field("keyspace", &self.keyspace)
+            .field("replication", &self.replication)
+            .field("consistency_read", &self.consistency_read)
+            .field("consistency_write", &self.consistency_write)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+impl ScyllaConfig {
+    pub fn consistency_read(&self) -> Consistency {
+        self.consistency_read
+            .clone()
+            .unwrap_or(ScyllaConsistency::LocalOne)
+            .into()
+    }
+
+    pub fn consistency_write(&self) -> Consistency {
+        self.consistency_write
+            .clone()
+            .unwrap_or(ScyllaConsistency::LocalOne)
+            .into()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "class")]
+pub enum ScyllaReplication {
+    SimpleStrategy { replication_factor: u32 },
+    NetworkTopologyStrategy { datacenters: BTreeMap<String, u32> },
+}
+
+impl Default for ScyllaReplication {
+    fn default() -> Self {
+        ScyllaReplication::SimpleStrategy { replication_factor: 2 }
+    }
+}
+
+impl ScyllaReplication {
+    // The replication map as used in `create keyspace`.
+    pub fn cql(&self) -> String {
+        match self {
+            ScyllaReplication::SimpleStrategy { replication_factor } => {
+                format!("{{ 'class': 'SimpleStrategy', 'replication_factor': {replication_factor} }}")
+            }
+            ScyllaReplication::NetworkTopologyStrategy { datacenters } => {
+                let mut s = String::from("{ 'class': 'NetworkTopologyStrategy'");
+                for (dc, rf) in datacenters {
+                    s.push_str(&format!(", '{dc}': {rf}"));
+                }
+                s.push_str(" }");
+                s
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScyllaConsistency {
+    Any,
+    One,
+    Two,
+    Three,
+    Quorum,
+    All,
+    LocalQuorum,
+    EachQuorum,
+    LocalOne,
+}
+
+impl From<ScyllaConsistency> for Consistency {
+    fn from(value: ScyllaConsistency) -> Self {
+        match value {
+            ScyllaConsistency::Any => Consistency::Any,
+            ScyllaConsistency::One => Consistency::One,
+            ScyllaConsistency::Two => Consistency::Two,
+            ScyllaConsistency::Three => Consistency::Three,
+            ScyllaConsistency::Quorum => Consistency::Quorum,
+            ScyllaConsistency::All => Consistency::All,
+            ScyllaConsistency::LocalQuorum => Consistency::LocalQuorum,
+            ScyllaConsistency::EachQuorum => Consistency::EachQuorum,
+            ScyllaConsistency::LocalOne => Consistency::LocalOne,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScyllaTls {
+    // CA certificate to verify the nodes.
+    pub ca: PathBuf,
+    // Client certificate and key, if the cluster requires them.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+#[test]
+fn parse_scylla_config() {
+    let conf = r###"{
+        "hosts": ["sf-nube-11:19042"],
+        "keyspace": "ks1",
+        "replication": {
+            "class": "NetworkTopologyStrategy",
+            "datacenters": { "dc1": 3, "dc2": 2 }
+        },
+        "consistency_write": "local_quorum",
+        "username": "daqingest",
+        "password": "secret",
+        "tls": { "ca": "/etc/scylla/ca.pem" }
+    }"###;
+    let conf: ScyllaConfig = serde_json::from_str(conf).unwrap();
+    assert_eq!(
+        conf.replication.cql(),
+        "{ 'class': 'NetworkTopologyStrategy', 'dc1': 3, 'dc2': 2 }"
+    );
+    assert_eq!(conf.consistency_write(), Consistency::LocalQuorum);
+    assert_eq!(conf.consistency_read(), Consistency::LocalOne);
+    assert_eq!(conf.tls.unwrap().cert, None);
+    let conf: ScyllaConfig = serde_json::from_str(r#"{"hosts": [], "keyspace": "ks1"}"#).unwrap();
+    assert_eq!(
+        conf.replication,
+        ScyllaReplication::SimpleStrategy { replication_factor: 2 }
+    );
+}
+
+#[test]
+fn scylla_config_debug_hides_password() {
+    let conf: ScyllaConfig = serde_json::from_str(
+        r#"{"hosts": ["sf-nube-11:19042"], "keyspace": "ks1", "username": "daq", "password": "secret-pw"}"#,
+    )
+    .unwrap();
+    let s = format!("{conf:?}");
+    assert!(s.contains("daq"));
+    assert!(!s.contains("secret-pw"));
+}
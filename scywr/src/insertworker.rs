@@ -1,15 +1,13 @@
-use crate::iteminsertqueue::insert_channel_status;
-use crate::iteminsertqueue::insert_connection_status;
-use crate::iteminsertqueue::insert_item;
 use crate::iteminsertqueue::QueryItem;
-use crate::store::DataStore;
+use crate::sink::StorageSink;
+use crate::spool::Spool;
+use crate::spool::SpoolOpts;
 use async_channel::Receiver;
 use async_channel::Sender;
 use err::Error;
 use log::*;
 use netpod::timeunits::MS;
 use netpod::timeunits::SEC;
-use netpod::ScyllaConfig;
 use stats::CaConnStats;
 use std::sync::atomic;
 use std::sync::atomic::AtomicU64;
@@ -17,9 +15,16 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use taskrun::tokio;
 use taskrun::tokio::task::JoinHandle;
 
+// After an insert error, wait this long before the spool gets replayed again.
+const STORE_HEALTH_QUIET_MS: u64 = 4000;
+// Upper limit of items per blocking spool read or write.
+const SPOOL_BATCH_MAX: usize = 256;
+
 fn stats_inc_for_err(stats: &stats::CaConnStats, err: &crate::iteminsertqueue::Error) {
     use crate::iteminsertqueue::Error;
     match err {
@@ -41,6 +46,9 @@ fn stats_inc_for_err(stats: &stats::CaConnStats, err: &crate::iteminsertqueue::E
         Error::QueryError(_) => {
             stats.store_worker_insert_error_inc();
         }
+        Error::Io(_) => {
+            stats.store_worker_insert_error_inc();
+        }
     }
 }
 
@@ -130,12 +138,213 @@ fn rate_limiter(
     rx
 }
 
+pub struct StoreHealth {
+    ts_ok_last: AtomicU64,
+    ts_err_last: AtomicU64,
+}
+
+impl StoreHealth {
+    pub fn new() -> Self {
+        Self {
+            ts_ok_last: AtomicU64::new(0),
+            ts_err_last: AtomicU64::new(0),
+        }
+    }
+
+    fn now_ms() -> u64 {
+        let dt = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        dt.as_millis() as u64
+    }
+
+    fn ok(&self) {
+        self.ts_ok_last.store(Self::now_ms(), Ordering::Release);
+    }
+
+    fn err(&self) {
+        self.ts_err_last.store(Self::now_ms(), Ordering::Release);
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        let ok = self.ts_ok_last.load(Ordering::Acquire);
+        let err = self.ts_err_last.load(Ordering::Acquire);
+        ok > err || Self::now_ms() >= err + STORE_HEALTH_QUIET_MS
+    }
+}
+
+// Spool file I/O blocks, it runs on the blocking thread pool and the spool
+// moves there and back with each call.
+async fn spool_io<F, T>(mut spool: Spool, f: F) -> Result<(Spool, T), Error>
+where
+    F: FnOnce(&mut Spool) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let jh = tokio::task::spawn_blocking(move || {
+        let ret = f(&mut spool);
+        (spool, ret)
+    });
+    jh.await.map_err(|e| Error::with_msg_no_trace(format!("spool io {e}")))
+}
+
+async fn spool_push(spool: Spool, items: Vec<QueryItem>, stats: &CaConnStats) -> Result<Spool, Error> {
+    let (spool, res) = spool_io(spool, move |spool| {
+        let mut pushed = 0;
+        let mut dropped = 0;
+        for item in &items {
+            match spool.push(item) {
+                Ok(true) => pushed += 1,
+                Ok(false) => dropped += 1,
+                Err(e) => {
+                    error!("spool push {e}");
+                    dropped += 1;
+                }
+            }
+        }
+        (pushed, dropped)
+    })
+    .await?;
+    stats.spool_push_add(res.0);
+    stats.spool_drop_add(res.1);
+    Ok(spool)
+}
+
+async fn spool_push_front(spool: Spool, items: Vec<QueryItem>, stats: &CaConnStats) -> Result<Spool, Error> {
+    let n = items.len() as u64;
+    let (spool, res) = spool_io(spool, move |spool| spool.push_front(&items)).await?;
+    match res {
+        Ok(dropped) => {
+            stats.spool_push_add(n - dropped);
+            stats.spool_drop_add(dropped);
+        }
+        Err(e) => {
+            error!("spool push front {e}");
+            stats.spool_drop_add(n);
+        }
+    }
+    Ok(spool)
+}
+
+// Takes whatever else is already queued, to write it to the spool in one go.
+fn drain_batch(first: QueryItem, inp: &Receiver<QueryItem>) -> Vec<QueryItem> {
+    let mut ret = vec![first];
+    while ret.len() < SPOOL_BATCH_MAX {
+        match inp.try_recv() {
+            Ok(item) => ret.push(item),
+            Err(_) => break,
+        }
+    }
+    ret
+}
+
+async fn spool_front_worker(
+    inp: Receiver<QueryItem>,
+    failed: Receiver<QueryItem>,
+    tx: Sender<QueryItem>,
+    spool: Spool,
+    health: Arc<StoreHealth>,
+    stats: Arc<CaConnStats>,
+) -> Result<(), Error> {
+    let mut spool = spool;
+    let mut ts_rate_last = Instant::now();
+    let mut replay_count = 0;
+    loop {
+        if spool.depth() != 0 && health.is_healthy() {
+            let n = tx.capacity().unwrap_or(SPOOL_BATCH_MAX).saturating_sub(tx.len());
+            let n = n.min(SPOOL_BATCH_MAX);
+            if n != 0 {
+                let (spool2, res) = spool_io(spool, move |spool| spool.pop_batch(n)).await?;
+                spool = spool2;
+                match res {
+                    Ok(items) => {
+                        for item in items {
+                            // This task is the only sender, there is room for the batch.
+                            if tx.try_send(item).is_err() {
+                                stats.spool_drop_inc();
+                            } else {
+                                stats.spool_replay_inc();
+                                replay_count += 1;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("spool pop {e}");
+                    }
+                }
+            }
+        }
+        stats.spool_depth_set(spool.depth());
+        stats.spool_bytes_set(spool.bytes());
+        let tsnow = Instant::now();
+        let dt = tsnow.duration_since(ts_rate_last);
+        if dt >= Duration::from_millis(1000) {
+            let rate = replay_count as f32 / dt.as_secs_f32();
+            stats.spool_replay_rate_set(rate as u64);
+            replay_count = 0;
+            ts_rate_last = tsnow;
+        }
+        let wait = if spool.depth() != 0 {
+            Duration::from_millis(50)
+        } else {
+            Duration::from_millis(500)
+        };
+        tokio::select! {
+            x = failed.recv() => {
+                if let Ok(item) = x {
+                    // Failed items were taken before anything still in the spool, they go in front.
+                    let items = drain_batch(item, &failed);
+                    spool = spool_push_front(spool, items, &stats).await?;
+                }
+            }
+            x = inp.recv() => match x {
+                Ok(item) => {
+                    // Keep the order: as long as the spool is not drained, new items go there as well.
+                    if spool.depth() != 0 {
+                        let items = drain_batch(item, &inp);
+                        spool = spool_push(spool, items, &stats).await?;
+                    } else {
+                        match tx.try_send(item) {
+                            Ok(()) => {}
+                            Err(async_channel::TrySendError::Full(item)) => {
+                                spool = spool_push(spool, vec![item], &stats).await?;
+                            }
+                            Err(async_channel::TrySendError::Closed(_)) => break,
+                        }
+                    }
+                }
+                Err(_) => break,
+            },
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+    tx.close();
+    info!("spool front done  depth {}", spool.depth());
+    Ok(())
+}
+
+fn spool_front(
+    inp: Receiver<QueryItem>,
+    spool: Spool,
+    cap: usize,
+    health: Arc<StoreHealth>,
+    stats: Arc<CaConnStats>,
+) -> (Receiver<QueryItem>, Sender<QueryItem>) {
+    let (tx, rx) = async_channel::bounded(cap);
+    let (failed_tx, failed_rx) = async_channel::bounded(cap);
+    tokio::spawn(async move {
+        if let Err(e) = spool_front_worker(inp, failed_rx, tx, spool, health, stats).await {
+            error!("spool front {e}");
+        }
+    });
+    (rx, failed_tx)
+}
+
 async fn worker(
     worker_ix: usize,
     item_inp: Receiver<QueryItem>,
+    spool_tx: Option<Sender<QueryItem>>,
+    health: Arc<StoreHealth>,
     ttls: Ttls,
     insert_worker_opts: Arc<InsertWorkerOpts>,
-    data_store: Arc<DataStore>,
+    sink: Arc<dyn StorageSink>,
     stats: Arc<CaConnStats>,
 ) -> Result<(), Error> {
     insert_worker_opts
@@ -151,144 +360,30 @@ async fn worker(
         } else {
             break;
         };
-        match item {
-            QueryItem::ConnectionStatus(item) => {
-                match insert_connection_status(item, ttls.index, &data_store, &stats).await {
-                    Ok(_) => {
-                        stats.connection_status_insert_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &e);
-                        back_off_sleep(&mut backoff).await;
-                    }
-                }
-            }
-            QueryItem::ChannelStatus(item) => {
-                match insert_channel_status(item, ttls.index, &data_store, &stats).await {
-                    Ok(_) => {
-                        stats.channel_status_insert_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &e);
-                        back_off_sleep(&mut backoff).await;
-                    }
-                }
-            }
-            QueryItem::Insert(item) => {
-                let insert_frac = insert_worker_opts.insert_frac.load(Ordering::Acquire);
-                if i1 % 1000 < insert_frac {
-                    match insert_item(item, ttls.index, ttls.d0, ttls.d1, &data_store, &stats).await {
-                        Ok(_) => {
-                            stats.store_worker_insert_done_inc();
-                            backoff = backoff_0;
-                        }
-                        Err(e) => {
-                            stats_inc_for_err(&stats, &e);
-                            back_off_sleep(&mut backoff).await;
-                        }
-                    }
-                } else {
-                    stats.store_worker_fraction_drop_inc();
-                }
-                i1 += 1;
-            }
-            QueryItem::Mute(item) => {
-                let values = (
-                    (item.series.id() & 0xff) as i32,
-                    item.series.id() as i64,
-                    item.ts as i64,
-                    item.ema,
-                    item.emd,
-                    ttls.index.as_secs() as i32,
-                );
-                let qres = data_store.scy.execute(&data_store.qu_insert_muted, values).await;
-                match qres {
-                    Ok(_) => {
-                        stats.mute_insert_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &crate::iteminsertqueue::Error::QueryError(e));
-                        back_off_sleep(&mut backoff).await;
-                    }
-                }
+        if let QueryItem::Insert(_) = &item {
+            let insert_frac = insert_worker_opts.insert_frac.load(Ordering::Acquire);
+            let keep = i1 % 1000 < insert_frac;
+            i1 += 1;
+            if !keep {
+                stats.store_worker_fraction_drop_inc();
+                continue;
             }
-            QueryItem::Ivl(item) => {
-                let values = (
-                    (item.series.id() & 0xff) as i32,
-                    item.series.id() as i64,
-                    item.ts as i64,
-                    item.ema,
-                    item.emd,
-                    ttls.index.as_secs() as i32,
-                );
-                let qres = data_store
-                    .scy
-                    .execute(&data_store.qu_insert_item_recv_ivl, values)
-                    .await;
-                match qres {
-                    Ok(_) => {
-                        stats.ivl_insert_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &crate::iteminsertqueue::Error::QueryError(e));
-                        back_off_sleep(&mut backoff).await;
-                    }
-                }
-            }
-            QueryItem::ChannelInfo(item) => {
-                let params = (
-                    (item.series.id() & 0xff) as i32,
-                    item.ts_msp as i32,
-                    item.series.id() as i64,
-                    item.ivl,
-                    item.interest,
-                    item.evsize as i32,
-                    ttls.index.as_secs() as i32,
-                );
-                let qres = data_store.scy.execute(&data_store.qu_insert_channel_ping, params).await;
-                match qres {
-                    Ok(_) => {
-                        stats.channel_info_insert_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &crate::iteminsertqueue::Error::QueryError(e));
-                        back_off_sleep(&mut backoff).await;
-                    }
-                }
+        }
+        let item_keep = spool_tx.as_ref().map(|_| item.clone());
+        match sink.insert(item, &ttls, &stats).await {
+            Ok(()) => {
+                health.ok();
+                backoff = backoff_0;
             }
-            QueryItem::TimeBinPatchSimpleF32(item) => {
-                info!("have time bin patch to insert: {item:?}");
-                let params = (
-                    item.series.id() as i64,
-                    item.bin_len_sec as i32,
-                    item.bin_count as i32,
-                    item.off_msp as i32,
-                    item.off_lsp as i32,
-                    item.counts,
-                    item.mins,
-                    item.maxs,
-                    item.avgs,
-                    ttls.binned.as_secs() as i32,
-                );
-                let qres = data_store
-                    .scy
-                    .execute(&data_store.qu_insert_binned_scalar_f32_v01, params)
-                    .await;
-                match qres {
-                    Ok(_) => {
-                        stats.store_worker_insert_binned_done_inc();
-                        backoff = backoff_0;
-                    }
-                    Err(e) => {
-                        stats_inc_for_err(&stats, &crate::iteminsertqueue::Error::QueryError(e));
-                        back_off_sleep(&mut backoff).await;
+            Err(e) => {
+                health.err();
+                stats_inc_for_err(&stats, &e);
+                if let (Some(tx), Some(item)) = (&spool_tx, item_keep) {
+                    if tx.send(item).await.is_err() {
+                        stats.spool_drop_inc();
                     }
                 }
+                back_off_sleep(&mut backoff).await;
             }
         }
     }
@@ -299,38 +394,100 @@ async fn worker(
     Ok(())
 }
 
-pub async fn spawn_scylla_insert_workers(
-    scyconf: ScyllaConfig,
-    insert_scylla_sessions: usize,
+pub async fn spawn_insert_workers(
+    sinks: Vec<Arc<dyn StorageSink>>,
     insert_worker_count: usize,
     item_inp: Receiver<QueryItem>,
     insert_worker_opts: Arc<InsertWorkerOpts>,
     store_stats: Arc<stats::CaConnStats>,
     use_rate_limit_queue: bool,
     ttls: Ttls,
+    spool_opts: Option<SpoolOpts>,
 ) -> Result<Vec<JoinHandle<Result<(), Error>>>, Error> {
     let item_inp = if use_rate_limit_queue {
         rate_limiter(item_inp, insert_worker_opts.clone(), store_stats.clone())
     } else {
         item_inp
     };
-    let mut jhs = Vec::new();
-    let mut data_stores = Vec::new();
-    for _ in 0..insert_scylla_sessions {
-        let data_store = Arc::new(DataStore::new(&scyconf).await.map_err(|e| Error::from(e.to_string()))?);
-        data_stores.push(data_store);
+    let health = Arc::new(StoreHealth::new());
+    let (item_inp, spool_tx) = if let Some(spool_opts) = spool_opts {
+        let spool = Spool::open(spool_opts).map_err(|e| Error::with_msg_no_trace(e.to_string()))?;
+        let cap = (insert_worker_count * 2).max(64);
+        let (rx, tx) = spool_front(item_inp, spool, cap, health.clone(), store_stats.clone());
+        (rx, Some(tx))
+    } else {
+        (item_inp, None)
+    };
+    if sinks.is_empty() {
+        return Err(Error::with_msg_no_trace("no storage sink"));
     }
+    let mut jhs = Vec::new();
     for worker_ix in 0..insert_worker_count {
-        let data_store = data_stores[worker_ix * data_stores.len() / insert_worker_count].clone();
+        let sink = sinks[worker_ix * sinks.len() / insert_worker_count].clone();
         let jh = tokio::spawn(worker(
             worker_ix,
             item_inp.clone(),
+            spool_tx.clone(),
+            health.clone(),
             ttls.clone(),
             insert_worker_opts.clone(),
-            data_store,
+            sink,
             store_stats.clone(),
         ));
         jhs.push(jh);
     }
     Ok(jhs)
 }
+
+#[test]
+fn insert_workers_into_memory_sink() {
+    use crate::iteminsertqueue::MuteItem;
+    use crate::sink::MemorySink;
+    use crate::sink::SinkOpts;
+    use series::SeriesId;
+    let fut = async {
+        let sink = Arc::new(MemorySink::new());
+        let sinks = SinkOpts::Memory(sink.clone()).build().await?;
+        let opts = Arc::new(InsertWorkerOpts {
+            store_workers_rate: Arc::new(AtomicU64::new(1000)),
+            insert_workers_running: Arc::new(AtomicU64::new(0)),
+            insert_frac: Arc::new(AtomicU64::new(1000)),
+        });
+        let stats = Arc::new(CaConnStats::new());
+        let ttls = Ttls {
+            index: Duration::from_secs(1),
+            d0: Duration::from_secs(1),
+            d1: Duration::from_secs(1),
+            binned: Duration::from_secs(1),
+        };
+        let (tx, rx) = async_channel::bounded(16);
+        let jhs = spawn_insert_workers(sinks, 3, rx, opts.clone(), stats.clone(), false, ttls, None).await?;
+        for i in 0..20 {
+            let item = QueryItem::Mute(MuteItem {
+                series: SeriesId::new(i),
+                ts: i,
+                ema: 0.,
+                emd: 0.,
+            });
+            tx.send(item).await.map_err(|_| Error::with_msg_no_trace("send"))?;
+        }
+        tx.close();
+        for jh in jhs {
+            jh.await.map_err(|e| Error::with_msg_no_trace(e.to_string()))??;
+        }
+        let mut ts: Vec<_> = sink
+            .take()
+            .into_iter()
+            .map(|x| match x {
+                QueryItem::Mute(x) => x.ts,
+                x => panic!("unexpected {x:?}"),
+            })
+            .collect();
+        ts.sort();
+        assert_eq!(ts, (0..20).collect::<Vec<_>>());
+        assert_eq!(stats.store_worker_insert_done.load(Ordering::Acquire), 20);
+        assert_eq!(opts.insert_workers_running.load(Ordering::Acquire), 0);
+        Ok::<_, Error>(())
+    };
+    taskrun::run(fut).unwrap();
+}
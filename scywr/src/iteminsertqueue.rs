@@ -5,12 +5,13 @@ use async_channel::Receiver;
 use async_channel::Sender;
 use err::thiserror;
 use err::ThisError;
-use log::*;
 use netpod::ScalarType;
 use netpod::Shape;
 use scylla::prepared_statement::PreparedStatement;
 use scylla::transport::errors::DbError;
 use scylla::transport::errors::QueryError;
+use serde::Deserialize;
+use serde::Serialize;
 use series::SeriesId;
 use stats::CaConnStats;
 use std::net::SocketAddrV4;
@@ -25,13 +26,19 @@ pub enum Error {
     DbUnavailable,
     DbError(#[from] DbError),
     QueryError(#[from] QueryError),
+    Io(#[from] std::io::Error),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ScalarValue {
     I8(i8),
     I16(i16),
     I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
     F32(f32),
     F64(f64),
     Enum(i16),
@@ -39,23 +46,29 @@ pub enum ScalarValue {
     Bool(bool),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ArrayValue {
     I8(Vec<i8>),
     I16(Vec<i16>),
     I32(Vec<i32>),
+    I64(Vec<i64>),
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+    U64(Vec<u64>),
     F32(Vec<f32>),
     F64(Vec<f64>),
+    String(Vec<String>),
     Bool(Vec<bool>),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum DataValue {
     Scalar(ScalarValue),
     Array(ArrayValue),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ConnectionStatus {
     ConnectError,
     ConnectTimeout,
@@ -97,14 +110,14 @@ impl ConnectionStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionStatusItem {
     pub ts: SystemTime,
     pub addr: SocketAddrV4,
     pub status: ConnectionStatus,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChannelStatusClosedReason {
     ShutdownCommand,
     ChannelRemove,
@@ -117,7 +130,7 @@ pub enum ChannelStatusClosedReason {
     ProtocolDone,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChannelStatus {
     AssignedToAddress,
     Opened,
@@ -170,14 +183,14 @@ impl ChannelStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelStatusItem {
     pub ts: SystemTime,
     pub series: SeriesId,
     pub status: ChannelStatus,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InsertItem {
     pub series: SeriesId,
     pub ts_msp: u64,
@@ -188,9 +201,19 @@ pub struct InsertItem {
     pub scalar_type: ScalarType,
     pub shape: Shape,
     pub val: DataValue,
+    // Channel Access alarm status and severity, zero if not available.
+    pub status: u16,
+    pub severity: u16,
 }
 
-#[derive(Debug)]
+// A pulse id and the global timestamp of that pulse.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PulseMapItem {
+    pub pulse: u64,
+    pub ts: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MuteItem {
     pub series: SeriesId,
     pub ts: u64,
@@ -198,7 +221,7 @@ pub struct MuteItem {
     pub emd: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IvlItem {
     pub series: SeriesId,
     pub ts: u64,
@@ -206,7 +229,7 @@ pub struct IvlItem {
     pub emd: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChannelInfoItem {
     pub ts_msp: u32,
     pub series: SeriesId,
@@ -215,7 +238,24 @@ pub struct ChannelInfoItem {
     pub evsize: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelMetaItem {
+    pub ts: SystemTime,
+    pub series: SeriesId,
+    pub units: String,
+    pub precision: i16,
+    pub upper_disp_limit: f64,
+    pub lower_disp_limit: f64,
+    pub upper_alarm_limit: f64,
+    pub upper_warning_limit: f64,
+    pub lower_warning_limit: f64,
+    pub lower_alarm_limit: f64,
+    pub upper_ctrl_limit: f64,
+    pub lower_ctrl_limit: f64,
+    pub enum_strs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeBinPatchSimpleF32 {
     pub series: SeriesId,
     pub bin_len_sec: u32,
@@ -228,7 +268,7 @@ pub struct TimeBinPatchSimpleF32 {
     pub avgs: Vec<f32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QueryItem {
     ConnectionStatus(ConnectionStatusItem),
     ChannelStatus(ChannelStatusItem),
@@ -236,7 +276,9 @@ pub enum QueryItem {
     Mute(MuteItem),
     Ivl(IvlItem),
     ChannelInfo(ChannelInfoItem),
+    ChannelMeta(ChannelMetaItem),
     TimeBinPatchSimpleF32(TimeBinPatchSimpleF32),
+    PulseMap(PulseMapItem),
 }
 
 pub struct CommonInsertItemQueueSender {
@@ -321,6 +363,8 @@ struct InsParCom {
     ts_msp: u64,
     ts_lsp: u64,
     pulse: u64,
+    status: u16,
+    severity: u16,
     ttl: u32,
 }
 
@@ -339,6 +383,8 @@ where
         par.ts_lsp as i64,
         par.pulse as i64,
         val,
+        par.status as i16,
+        par.severity as i16,
         par.ttl as i32,
     );
     let y = data_store.scy.execute(qu, params).await;
@@ -371,6 +417,8 @@ where
         par.ts_lsp as i64,
         par.pulse as i64,
         val,
+        par.status as i16,
+        par.severity as i16,
         par.ttl as i32,
     );
     data_store.scy.execute(qu, params).await?;
@@ -413,6 +461,8 @@ pub async fn insert_item(
                 ts_msp: item.ts_msp,
                 ts_lsp: item.ts_lsp,
                 pulse: item.pulse,
+                status: item.status,
+                severity: item.severity,
                 ttl: ttl_0d.as_secs() as _,
             };
             use ScalarValue::*;
@@ -421,10 +471,16 @@ pub async fn insert_item(
                 I16(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_i16, &data_store).await?,
                 Enum(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_i16, &data_store).await?,
                 I32(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_i32, &data_store).await?,
+                I64(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_i64, &data_store).await?,
+                // The unsigned types are stored bit-cast in the signed column of the same width.
+                U8(val) => insert_scalar_gen(par, val as i8, &data_store.qu_insert_scalar_u8, &data_store).await?,
+                U16(val) => insert_scalar_gen(par, val as i16, &data_store.qu_insert_scalar_u16, &data_store).await?,
+                U32(val) => insert_scalar_gen(par, val as i32, &data_store.qu_insert_scalar_u32, &data_store).await?,
+                U64(val) => insert_scalar_gen(par, val as i64, &data_store.qu_insert_scalar_u64, &data_store).await?,
                 F32(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_f32, &data_store).await?,
                 F64(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_f64, &data_store).await?,
-                String(_) => warn!("TODO string insert"),
-                Bool(_v) => warn!("TODO bool insert"),
+                String(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_string, &data_store).await?,
+                Bool(val) => insert_scalar_gen(par, val, &data_store.qu_insert_scalar_bool, &data_store).await?,
             }
         }
         Array(val) => {
@@ -433,6 +489,8 @@ pub async fn insert_item(
                 ts_msp: item.ts_msp,
                 ts_lsp: item.ts_lsp,
                 pulse: item.pulse,
+                status: item.status,
+                severity: item.severity,
                 ttl: ttl_1d.as_secs() as _,
             };
             use ArrayValue::*;
@@ -440,8 +498,26 @@ pub async fn insert_item(
                 I8(val) => insert_array_gen(par, val, &data_store.qu_insert_array_i8, &data_store).await?,
                 I16(val) => insert_array_gen(par, val, &data_store.qu_insert_array_i16, &data_store).await?,
                 I32(val) => insert_array_gen(par, val, &data_store.qu_insert_array_i32, &data_store).await?,
+                I64(val) => insert_array_gen(par, val, &data_store.qu_insert_array_i64, &data_store).await?,
+                U8(val) => {
+                    let val: Vec<i8> = val.into_iter().map(|x| x as i8).collect();
+                    insert_array_gen(par, val, &data_store.qu_insert_array_u8, &data_store).await?
+                }
+                U16(val) => {
+                    let val: Vec<i16> = val.into_iter().map(|x| x as i16).collect();
+                    insert_array_gen(par, val, &data_store.qu_insert_array_u16, &data_store).await?
+                }
+                U32(val) => {
+                    let val: Vec<i32> = val.into_iter().map(|x| x as i32).collect();
+                    insert_array_gen(par, val, &data_store.qu_insert_array_u32, &data_store).await?
+                }
+                U64(val) => {
+                    let val: Vec<i64> = val.into_iter().map(|x| x as i64).collect();
+                    insert_array_gen(par, val, &data_store.qu_insert_array_u64, &data_store).await?
+                }
                 F32(val) => insert_array_gen(par, val, &data_store.qu_insert_array_f32, &data_store).await?,
                 F64(val) => insert_array_gen(par, val, &data_store.qu_insert_array_f64, &data_store).await?,
+                String(val) => insert_array_gen(par, val, &data_store.qu_insert_array_string, &data_store).await?,
                 Bool(val) => insert_array_gen(par, val, &data_store.qu_insert_array_bool, &data_store).await?,
             }
         }
@@ -454,7 +530,6 @@ pub async fn insert_connection_status(
     item: ConnectionStatusItem,
     ttl: Duration,
     data_store: &DataStore,
-    _stats: &CaConnStats,
 ) -> Result<(), Error> {
     let tsunix = item.ts.duration_since(std::time::UNIX_EPOCH).unwrap_or(Duration::ZERO);
     let secs = tsunix.as_secs() * netpod::timeunits::SEC;
@@ -476,7 +551,6 @@ pub async fn insert_channel_status(
     item: ChannelStatusItem,
     ttl: Duration,
     data_store: &DataStore,
-    _stats: &CaConnStats,
 ) -> Result<(), Error> {
     let tsunix = item.ts.duration_since(std::time::UNIX_EPOCH).unwrap_or(Duration::ZERO);
     let secs = tsunix.as_secs() * netpod::timeunits::SEC;
@@ -510,3 +584,33 @@ pub async fn insert_channel_status(
         .await?;
     Ok(())
 }
+
+pub async fn insert_channel_meta(
+    item: ChannelMetaItem,
+    ttl: Duration,
+    data_store: &DataStore,
+) -> Result<(), Error> {
+    let tsunix = item.ts.duration_since(std::time::UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    let ts = tsunix.as_secs() * netpod::timeunits::SEC + tsunix.subsec_nanos() as u64;
+    let params = (
+        item.series.id() as i64,
+        ts as i64,
+        item.units,
+        item.precision as i32,
+        item.upper_disp_limit,
+        item.lower_disp_limit,
+        item.upper_alarm_limit,
+        item.upper_warning_limit,
+        item.lower_warning_limit,
+        item.lower_alarm_limit,
+        item.upper_ctrl_limit,
+        item.lower_ctrl_limit,
+        item.enum_strs,
+        ttl.as_secs() as i32,
+    );
+    data_store
+        .scy
+        .execute(&data_store.qu_insert_channel_meta, params)
+        .await?;
+    Ok(())
+}
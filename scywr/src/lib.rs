@@ -8,7 +8,13 @@ pub mod futinsert;
 pub mod futinsertloop;
 pub mod insertworker;
 pub mod iteminsertqueue;
+pub mod pulsemap;
+pub mod readevents;
 pub mod schema;
 pub mod session;
+pub mod sink;
+pub mod spool;
 pub mod store;
+#[cfg(test)]
+pub mod test;
 pub mod tools;
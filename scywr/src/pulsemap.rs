@@ -0,0 +1,78 @@
+// The pulse map is kept in two tables: `pulse` by time with the second as partition key,
+// and `ts_by_pulse` with partitions of 2^14 pulses.
+
+use crate::session::ScySession;
+use err::thiserror;
+use err::ThisError;
+use netpod::timeunits::SEC;
+use scylla::transport::errors::QueryError;
+
+const PULSE_B_BITS: u32 = 14;
+
+// How far back a time lookup searches for the preceding pulse.
+const TS_LOOKUP_SECONDS: i32 = 10;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    Query(#[from] QueryError),
+    BadRow(String),
+}
+
+pub fn ts_key(ts: u64) -> (i32, i32) {
+    ((ts / SEC) as i32, (ts % SEC) as i32)
+}
+
+pub fn pulse_key(pulse: u64) -> (i64, i32) {
+    (
+        (pulse >> PULSE_B_BITS) as i64,
+        (pulse & ((1 << PULSE_B_BITS) - 1)) as i32,
+    )
+}
+
+fn ts_from_key(tsa: i32, tsb: i32) -> u64 {
+    SEC * tsa as u32 as u64 + tsb as u32 as u64
+}
+
+pub async fn ts_by_pulse(scy: &ScySession, pulse: u64) -> Result<Option<u64>, Error> {
+    let (pulse_a, pulse_b) = pulse_key(pulse);
+    let cql = "select tsa, tsb from ts_by_pulse where pulse_a = ? and pulse_b = ?";
+    let res = scy.query(cql, (pulse_a, pulse_b)).await?;
+    match res.rows_typed_or_empty::<(i32, i32)>().next() {
+        Some(row) => {
+            let row = row.map_err(|e| Error::BadRow(e.to_string()))?;
+            Ok(Some(ts_from_key(row.0, row.1)))
+        }
+        None => Ok(None),
+    }
+}
+
+// The last pulse at or before the given time, as pulse and its time.
+pub async fn pulse_by_ts(scy: &ScySession, ts: u64) -> Result<Option<(u64, u64)>, Error> {
+    let (tsa, tsb) = ts_key(ts);
+    let cql = "select tsb, pulse from pulse where tsa = ? and tsb <= ? order by tsb desc limit 1";
+    let res = scy.query(cql, (tsa, tsb)).await?;
+    if let Some(row) = res.rows_typed_or_empty::<(i32, i64)>().next() {
+        let row = row.map_err(|e| Error::BadRow(e.to_string()))?;
+        return Ok(Some((row.1 as u64, ts_from_key(tsa, row.0))));
+    }
+    let cql = "select tsb, pulse from pulse where tsa = ? order by tsb desc limit 1";
+    for tsa in (tsa - TS_LOOKUP_SECONDS..tsa).rev() {
+        let res = scy.query(cql, (tsa,)).await?;
+        if let Some(row) = res.rows_typed_or_empty::<(i32, i64)>().next() {
+            let row = row.map_err(|e| Error::BadRow(e.to_string()))?;
+            return Ok(Some((row.1 as u64, ts_from_key(tsa, row.0))));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn pulse_map_keys() {
+    let ts = 1_700_000_000 * SEC + 123_456_789;
+    let (tsa, tsb) = ts_key(ts);
+    assert_eq!((tsa, tsb), (1_700_000_000, 123_456_789));
+    assert_eq!(ts_from_key(tsa, tsb), ts);
+    let pulse = 20_000_000_123;
+    let (pulse_a, pulse_b) = pulse_key(pulse);
+    assert_eq!(((pulse_a as u64) << PULSE_B_BITS) + pulse_b as u64, pulse);
+}
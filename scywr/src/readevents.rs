@@ -0,0 +1,166 @@
+use crate::session::ScySession;
+use err::thiserror;
+use err::ThisError;
+use futures_util::StreamExt;
+use netpod::ScalarType;
+use scylla::frame::response::result::CqlValue;
+use scylla::transport::errors::QueryError;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    Query(#[from] QueryError),
+    ScalarType(String),
+    BadRow(String),
+}
+
+// Event value as read back, unsigned types are stored bit-cast in the signed cql types.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum EventValue {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<EventValue>),
+}
+
+impl EventValue {
+    fn from_cql(v: CqlValue, unsigned: bool) -> Result<Self, Error> {
+        use CqlValue::*;
+        let ret = match v {
+            TinyInt(x) if unsigned => Self::UInt(x as u8 as u64),
+            SmallInt(x) if unsigned => Self::UInt(x as u16 as u64),
+            Int(x) if unsigned => Self::UInt(x as u32 as u64),
+            BigInt(x) if unsigned => Self::UInt(x as u64),
+            TinyInt(x) => Self::Int(x as i64),
+            SmallInt(x) => Self::Int(x as i64),
+            Int(x) => Self::Int(x as i64),
+            BigInt(x) => Self::Int(x),
+            Float(x) => Self::Float(x as f64),
+            Double(x) => Self::Float(x),
+            Boolean(x) => Self::Bool(x),
+            Text(x) | Ascii(x) => Self::String(x),
+            List(x) => Self::Array(
+                x.into_iter()
+                    .map(|x| Self::from_cql(x, unsigned))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(Error::BadRow(format!("unexpected value {v:?}"))),
+        };
+        Ok(ret)
+    }
+}
+
+impl fmt::Display for EventValue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventValue::Int(x) => write!(fmt, "{x}"),
+            EventValue::UInt(x) => write!(fmt, "{x}"),
+            EventValue::Float(x) => write!(fmt, "{x}"),
+            EventValue::Bool(x) => write!(fmt, "{x}"),
+            EventValue::String(x) => write!(fmt, "{x}"),
+            EventValue::Array(x) => {
+                write!(fmt, "[")?;
+                for (i, v) in x.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{v}")?;
+                }
+                write!(fmt, "]")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EventRow {
+    pub ts: u64,
+    pub pulse: u64,
+    pub value: EventValue,
+    pub status: Option<i16>,
+    pub severity: Option<i16>,
+}
+
+// The events table of a series, from scalar type and shape as registered in postgres.
+pub fn events_table(scalar_type: i32, shape_dims: &[i32]) -> Result<String, Error> {
+    let sty = ScalarType::from_scylla_i32(scalar_type).map_err(|e| Error::ScalarType(e.to_string()))?;
+    let kind = if shape_dims.is_empty() { "scalar" } else { "array" };
+    Ok(format!("events_{kind}_{}", sty.to_variant_str()))
+}
+
+// Partitions which may hold events in [beg, end): the last one starting before beg and all within.
+pub async fn ts_msp_range(scy: &ScySession, series: u64, beg: u64, end: u64) -> Result<Vec<u64>, Error> {
+    let mut ret = Vec::new();
+    let cql = "select ts_msp from ts_msp where series = ? and ts_msp < ? order by ts_msp desc limit 1";
+    let res = scy.query(cql, (series as i64, beg as i64)).await?;
+    for row in res.rows_typed_or_empty::<(i64,)>() {
+        let row = row.map_err(|e| Error::BadRow(e.to_string()))?;
+        ret.push(row.0 as u64);
+    }
+    let cql = "select ts_msp from ts_msp where series = ? and ts_msp >= ? and ts_msp < ?";
+    let mut it = scy.query_iter(cql, (series as i64, beg as i64, end as i64)).await?;
+    while let Some(row) = it.next().await {
+        let row = row?.into_typed::<(i64,)>().map_err(|e| Error::BadRow(e.to_string()))?;
+        ret.push(row.0 as u64);
+    }
+    Ok(ret)
+}
+
+// Events of one partition within [beg, end), in time order.
+pub async fn read_partition(
+    scy: &ScySession,
+    table: &str,
+    series: u64,
+    ts_msp: u64,
+    beg: u64,
+    end: u64,
+) -> Result<Vec<EventRow>, Error> {
+    let unsigned = table.rsplit('_').next().map_or(false, |x| x.starts_with('u'));
+    let cql = format!(
+        "select ts_lsp, pulse, value, status, severity from {table} where series = ? and ts_msp = ? and ts_lsp >= ? and ts_lsp < ?"
+    );
+    let lsp_beg = beg.saturating_sub(ts_msp) as i64;
+    let lsp_end = end.saturating_sub(ts_msp) as i64;
+    let mut it = scy
+        .query_iter(cql, (series as i64, ts_msp as i64, lsp_beg, lsp_end))
+        .await?;
+    let mut ret = Vec::new();
+    while let Some(row) = it.next().await {
+        let mut cols = row?.columns.into_iter();
+        let mut next = || cols.next().flatten();
+        let ts_lsp = next().and_then(|x| x.as_bigint());
+        let pulse = next().and_then(|x| x.as_bigint());
+        let value = next();
+        let status = next().and_then(|x| x.as_smallint());
+        let severity = next().and_then(|x| x.as_smallint());
+        let (ts_lsp, value) = match (ts_lsp, value) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(Error::BadRow(format!("missing ts_lsp or value in {table}"))),
+        };
+        let row = EventRow {
+            ts: ts_msp + ts_lsp as u64,
+            pulse: pulse.unwrap_or(0) as u64,
+            value: EventValue::from_cql(value, unsigned)?,
+            status,
+            severity,
+        };
+        ret.push(row);
+    }
+    Ok(ret)
+}
+
+#[test]
+fn event_value_from_cql() {
+    let v = EventValue::from_cql(CqlValue::TinyInt(-1), true).unwrap();
+    assert_eq!(v, EventValue::UInt(255));
+    let v = EventValue::from_cql(CqlValue::TinyInt(-1), false).unwrap();
+    assert_eq!(v, EventValue::Int(-1));
+    let v = CqlValue::List(vec![CqlValue::Double(1.5), CqlValue::Double(-2.)]);
+    let v = EventValue::from_cql(v, false).unwrap();
+    assert_eq!(v.to_string(), "[1.5 -2]");
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[1.5,-2.0]");
+}
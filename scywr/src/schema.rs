@@ -1,18 +1,20 @@
+use crate::config::ScyllaConfig;
 use crate::session::create_session_no_ks;
 use crate::session::ScySession;
 use err::thiserror;
 use err::ThisError;
 use futures_util::StreamExt;
 use log::*;
-use netpod::ScyllaConfig;
 use scylla::transport::errors::DbError;
 use scylla::transport::errors::QueryError;
 use std::fmt;
 use std::time::Duration;
+use std::time::SystemTime;
 
 #[derive(Debug, ThisError)]
 pub enum Error {
     NoKeyspaceChosen,
+    NoKeyspace(String),
     Fmt(#[from] fmt::Error),
     Query(#[from] QueryError),
     NewSession(String),
@@ -22,6 +24,7 @@ impl From<crate::session::Error> for Error {
     fn from(value: crate::session::Error) -> Self {
         match value {
             crate::session::Error::NewSession(x) => Self::NewSession(x),
+            crate::session::Error::Tls(x) => Self::NewSession(x),
         }
     }
 }
@@ -74,23 +77,6 @@ pub async fn check_table_readable(name: &str, scy: &ScySession) -> Result<bool,
     }
 }
 
-pub async fn create_table_ts_msp(scy: &ScySession) -> Result<(), Error> {
-    use std::fmt::Write;
-    // seconds:
-    let default_time_to_live = 60 * 60 * 5;
-    // hours:
-    let twcs_window_index = 24 * 4;
-    let mut s = String::new();
-    s.write_str("create table ts_msp (series bigint, ts_msp bigint, primary key (series, ts_msp))")?;
-    write!(s, " with default_time_to_live = {}", default_time_to_live)?;
-    s.write_str(" and compaction = { 'class': 'TimeWindowCompactionStrategy'")?;
-    s.write_str(", 'compaction_window_unit': 'HOURS'")?;
-    write!(s, ", 'compaction_window_size': {}", twcs_window_index)?;
-    s.write_str(" }")?;
-    scy.query(s, ()).await?;
-    Ok(())
-}
-
 fn dhours(x: u64) -> Duration {
     Duration::from_secs(60 * 60 * x)
 }
@@ -197,7 +183,6 @@ impl GenTwcsTab {
     }
 
     async fn create_if_missing(&self, scy: &ScySession) -> Result<(), Error> {
-        // TODO check for more details (all columns, correct types, correct kinds, etc)
         if !has_table(self.name(), scy).await? {
             let cql = self.cql();
             info!("CREATE CQL: {cql}");
@@ -205,66 +190,454 @@ impl GenTwcsTab {
         }
         Ok(())
     }
+
+    // Kind of the column as reported by system_schema.columns
+    fn column_kind(&self, name: &str) -> &'static str {
+        if self.partition_keys.iter().any(|x| x == name) {
+            "partition_key"
+        } else if self.cluster_keys.iter().any(|x| x == name) {
+            "clustering"
+        } else {
+            "regular"
+        }
+    }
+
+    async fn verify(&self, keyspace: &str, scy: &ScySession) -> Result<Vec<SchemaDrift>, Error> {
+        let mut ret = Vec::new();
+        let have = get_columns(keyspace, self.name(), scy).await?;
+        if have.is_empty() {
+            ret.push(SchemaDrift::MissingTable(self.name().into()));
+            return Ok(ret);
+        }
+        for (name, ty) in self.col_names.iter().zip(self.col_types.iter()) {
+            match have.iter().find(|x| &x.name == name) {
+                Some(col) => {
+                    if &col.ty != ty {
+                        ret.push(SchemaDrift::ColumnType {
+                            table: self.name().into(),
+                            column: name.clone(),
+                            expect: ty.clone(),
+                            have: col.ty.clone(),
+                        });
+                    }
+                    let kind = self.column_kind(name);
+                    if col.kind != kind {
+                        ret.push(SchemaDrift::ColumnKind {
+                            table: self.name().into(),
+                            column: name.clone(),
+                            expect: kind.into(),
+                            have: col.kind.clone(),
+                        });
+                    }
+                }
+                None => ret.push(SchemaDrift::MissingColumn {
+                    table: self.name().into(),
+                    column: name.clone(),
+                }),
+            }
+        }
+        for col in &have {
+            if !self.col_names.contains(&col.name) {
+                ret.push(SchemaDrift::ExtraColumn {
+                    table: self.name().into(),
+                    column: col.name.clone(),
+                });
+            }
+        }
+        Ok(ret)
+    }
 }
 
-struct EvTabDim0 {
-    sty: String,
-    cqlsty: String,
-    // SCYLLA_TTL_EVENTS_DIM0
-    default_time_to_live: usize,
-    // TWCS_WINDOW_0D
-    compaction_window_size: usize,
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaDrift {
+    Version {
+        expect: i32,
+        have: i32,
+    },
+    MissingTable(String),
+    MissingColumn {
+        table: String,
+        column: String,
+    },
+    ExtraColumn {
+        table: String,
+        column: String,
+    },
+    ColumnType {
+        table: String,
+        column: String,
+        expect: String,
+        have: String,
+    },
+    ColumnKind {
+        table: String,
+        column: String,
+        expect: String,
+        have: String,
+    },
 }
 
-impl EvTabDim0 {
-    fn name(&self) -> String {
-        format!("events_scalar_{}", self.sty)
+impl fmt::Display for SchemaDrift {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaDrift::Version { expect, have } => write!(fmt, "schema version {have} but expect {expect}"),
+            SchemaDrift::MissingTable(table) => write!(fmt, "missing table {table}"),
+            SchemaDrift::MissingColumn { table, column } => write!(fmt, "missing column {table}.{column}"),
+            SchemaDrift::ExtraColumn { table, column } => write!(fmt, "unexpected column {table}.{column}"),
+            SchemaDrift::ColumnType {
+                table,
+                column,
+                expect,
+                have,
+            } => write!(fmt, "column {table}.{column} has type {have} but expect {expect}"),
+            SchemaDrift::ColumnKind {
+                table,
+                column,
+                expect,
+                have,
+            } => write!(fmt, "column {table}.{column} is {have} but expect {expect}"),
+        }
     }
+}
 
-    fn cql_create(&self) -> String {
-        use std::fmt::Write;
-        let mut s = String::new();
-        write!(s, "create table {}", self.name()).unwrap();
-        write!(s, " (series bigint, ts_msp bigint, ts_lsp bigint, pulse bigint, value {}, primary key ((series, ts_msp), ts_lsp))", self.cqlsty).unwrap();
-        write!(s, " with default_time_to_live = {}", self.default_time_to_live).unwrap();
-        s.write_str(" and compaction = { 'class': 'TimeWindowCompactionStrategy', 'compaction_window_unit': 'HOURS'")
-            .unwrap();
-        write!(s, ", 'compaction_window_size': {}", self.compaction_window_size).unwrap();
-        s.write_str(" }").unwrap();
-        s
+const EVENT_SCALAR_TYPES: [(&str, &str); 12] = [
+    ("u8", "tinyint"),
+    ("u16", "smallint"),
+    ("u32", "int"),
+    ("u64", "bigint"),
+    ("i8", "tinyint"),
+    ("i16", "smallint"),
+    ("i32", "int"),
+    ("i64", "bigint"),
+    ("f32", "float"),
+    ("f64", "double"),
+    ("bool", "boolean"),
+    ("string", "text"),
+];
+
+fn event_tables() -> Vec<GenTwcsTab> {
+    let mut ret = Vec::new();
+    for (sty, cqlsty) in EVENT_SCALAR_TYPES {
+        // ttl is set in actual data inserts
+        ret.push(GenTwcsTab::new(
+            format!("events_scalar_{sty}"),
+            &[
+                ("series", "bigint"),
+                ("ts_msp", "bigint"),
+                ("ts_lsp", "bigint"),
+                ("pulse", "bigint"),
+                ("value", cqlsty),
+                ("status", "smallint"),
+                ("severity", "smallint"),
+            ],
+            ["series", "ts_msp"],
+            ["ts_lsp"],
+            dhours(1),
+            dhours(48),
+        ));
+        let cqlsty = format!("frozen<list<{cqlsty}>>");
+        ret.push(GenTwcsTab::new(
+            format!("events_array_{sty}"),
+            &[
+                ("series", "bigint"),
+                ("ts_msp", "bigint"),
+                ("ts_lsp", "bigint"),
+                ("pulse", "bigint"),
+                ("value", cqlsty.as_str()),
+                ("status", "smallint"),
+                ("severity", "smallint"),
+            ],
+            ["series", "ts_msp"],
+            ["ts_lsp"],
+            dhours(1),
+            dhours(12),
+        ));
     }
+    ret
 }
 
-struct EvTabDim1 {
-    sty: String,
-    cqlsty: String,
-    // SCYLLA_TTL_EVENTS_DIM1
-    default_time_to_live: usize,
-    // TWCS_WINDOW_1D
-    compaction_window_size: usize,
+// Pulse id to time and back, ttl is set in the inserts.
+fn pulse_map_tables() -> Vec<GenTwcsTab> {
+    vec![
+        GenTwcsTab::new(
+            "pulse",
+            &[("tsa", "int"), ("tsb", "int"), ("pulse", "bigint")],
+            ["tsa"],
+            ["tsb"],
+            dhours(1),
+            dhours(48),
+        ),
+        GenTwcsTab::new(
+            "ts_by_pulse",
+            &[
+                ("pulse_a", "bigint"),
+                ("pulse_b", "int"),
+                ("tsa", "int"),
+                ("tsb", "int"),
+            ],
+            ["pulse_a"],
+            ["pulse_b"],
+            dhours(1),
+            dhours(48),
+        ),
+    ]
 }
 
-impl EvTabDim1 {
-    fn name(&self) -> String {
-        format!("events_array_{}", self.sty)
+// All tables as the current code expects them.
+fn tables_expected() -> Vec<GenTwcsTab> {
+    let mut ret = vec![GenTwcsTab::new(
+        "ts_msp",
+        &[("series", "bigint"), ("ts_msp", "bigint")],
+        ["series"],
+        ["ts_msp"],
+        dhours(5),
+        ddays(4),
+    )];
+    ret.extend(event_tables());
+    ret.push(GenTwcsTab::new(
+        "series_by_ts_msp",
+        &[
+            ("part", "int"),
+            ("ts_msp", "int"),
+            ("shape_kind", "int"),
+            ("scalar_type", "int"),
+            ("series", "bigint"),
+        ],
+        ["part", "ts_msp", "shape_kind", "scalar_type"],
+        ["series"],
+        dhours(5),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "connection_status",
+        &[
+            ("ts_msp", "bigint"),
+            ("ts_lsp", "bigint"),
+            ("kind", "int"),
+            ("addr", "text"),
+        ],
+        ["ts_msp"],
+        ["ts_lsp"],
+        dhours(1),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "channel_status",
+        &[
+            ("series", "bigint"),
+            ("ts_msp", "bigint"),
+            ("ts_lsp", "bigint"),
+            ("kind", "int"),
+        ],
+        ["series", "ts_msp"],
+        ["ts_lsp"],
+        dhours(1),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "channel_status_by_ts_msp",
+        &[
+            ("ts_msp", "bigint"),
+            ("ts_lsp", "bigint"),
+            ("series", "bigint"),
+            ("kind", "int"),
+        ],
+        ["ts_msp"],
+        ["ts_lsp"],
+        dhours(1),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "channel_ping",
+        &[
+            ("part", "int"),
+            ("ts_msp", "int"),
+            ("series", "bigint"),
+            ("ivl", "float"),
+            ("interest", "float"),
+            ("evsize", "int"),
+        ],
+        ["part", "ts_msp"],
+        ["series"],
+        dhours(1),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "channel_meta",
+        &[
+            ("series", "bigint"),
+            ("ts", "bigint"),
+            ("units", "text"),
+            ("precision", "int"),
+            ("upper_disp_limit", "double"),
+            ("lower_disp_limit", "double"),
+            ("upper_alarm_limit", "double"),
+            ("upper_warning_limit", "double"),
+            ("lower_warning_limit", "double"),
+            ("lower_alarm_limit", "double"),
+            ("upper_ctrl_limit", "double"),
+            ("lower_ctrl_limit", "double"),
+            ("enum_strs", "frozen<list<text>>"),
+        ],
+        ["series"],
+        ["ts"],
+        ddays(30),
+        ddays(4),
+    ));
+    ret.push(GenTwcsTab::new(
+        "muted",
+        &[
+            ("part", "int"),
+            ("series", "bigint"),
+            ("ts", "bigint"),
+            ("ema", "float"),
+            ("emd", "float"),
+        ],
+        ["part"],
+        ["series", "ts"],
+        dhours(4),
+        ddays(1),
+    ));
+    ret.push(GenTwcsTab::new(
+        "item_recv_ivl",
+        &[
+            ("part", "int"),
+            ("series", "bigint"),
+            ("ts", "bigint"),
+            ("ema", "float"),
+            ("emd", "float"),
+        ],
+        ["part"],
+        ["series", "ts"],
+        dhours(4),
+        ddays(1),
+    ));
+    ret.push(GenTwcsTab::new(
+        "binned_scalar_f32_v01",
+        &[
+            ("series", "bigint"),
+            ("bin_len_sec", "int"),
+            ("bin_count", "int"),
+            ("off_msp", "int"),
+            ("off_lsp", "int"),
+            ("counts", "frozen<list<bigint>>"),
+            ("mins", "frozen<list<float>>"),
+            ("maxs", "frozen<list<float>>"),
+            ("avgs", "frozen<list<float>>"),
+        ],
+        ["series", "bin_len_sec", "bin_count", "off_msp"],
+        ["off_lsp"],
+        ddays(30),
+        ddays(4),
+    ));
+    ret.extend(pulse_map_tables());
+    ret
+}
+
+enum MigrationStep {
+    CreateTables(Vec<GenTwcsTab>),
+    AddColumns(Vec<String>, Vec<(String, String)>),
+}
+
+impl MigrationStep {
+    async fn apply(&self, scy: &ScySession) -> Result<(), Error> {
+        match self {
+            MigrationStep::CreateTables(tabs) => {
+                for tab in tabs {
+                    tab.create_if_missing(scy).await?;
+                }
+            }
+            MigrationStep::AddColumns(tables, cols) => {
+                for table in tables {
+                    add_columns_if_missing(table, cols, scy).await?;
+                }
+            }
+        }
+        Ok(())
     }
+}
 
-    fn cql(&self) -> String {
-        use std::fmt::Write;
-        let mut s = String::new();
-        write!(s, "create table {}", self.name()).unwrap();
-        write!(s, " (series bigint, ts_msp bigint, ts_lsp bigint, pulse bigint, value {}, primary key ((series, ts_msp), ts_lsp))", self.cqlsty).unwrap();
-        write!(s, " with default_time_to_live = {}", self.default_time_to_live).unwrap();
-        s.write_str(" and compaction = { 'class': 'TimeWindowCompactionStrategy', 'compaction_window_unit': 'HOURS'")
-            .unwrap();
-        write!(s, ", 'compaction_window_size': {}", self.compaction_window_size).unwrap();
-        s.write_str(" }").unwrap();
-        s
+struct Migration {
+    version: i32,
+    descr: &'static str,
+    steps: Vec<MigrationStep>,
+}
+
+// Applied in order, each at most once per keyspace. Keyspaces from before the version table
+// may already contain any of these changes, therefore every step must be idempotent.
+// A change of `tables_expected` needs a new migration at the end of this list.
+fn migrations() -> Vec<Migration> {
+    let event_table_names = event_tables().iter().map(|x| x.name().to_string()).collect();
+    vec![
+        Migration {
+            version: 1,
+            descr: "create tables",
+            steps: vec![MigrationStep::CreateTables(tables_expected())],
+        },
+        Migration {
+            version: 2,
+            descr: "alarm status and severity for events",
+            steps: vec![MigrationStep::AddColumns(
+                event_table_names,
+                vec![
+                    ("status".into(), "smallint".into()),
+                    ("severity".into(), "smallint".into()),
+                ],
+            )],
+        },
+        Migration {
+            version: 3,
+            descr: "pulse map",
+            steps: vec![MigrationStep::CreateTables(pulse_map_tables())],
+        },
+    ]
+}
+
+fn schema_version_latest() -> i32 {
+    migrations().last().map_or(0, |x| x.version)
+}
+
+async fn create_version_table(scy: &ScySession) -> Result<(), Error> {
+    let cql = concat!(
+        "create table if not exists schema_version",
+        " (part int, version int, ts bigint, descr text, primary key (part, version))"
+    );
+    scy.query(cql, ()).await?;
+    Ok(())
+}
+
+async fn schema_version(scy: &ScySession) -> Result<i32, Error> {
+    let mut ret = 0;
+    if !has_table("schema_version", scy).await? {
+        return Ok(ret);
+    }
+    let cql = "select version from schema_version where part = 0";
+    let mut res = scy.query_iter(cql, ()).await?;
+    while let Some(row) = res.next().await {
+        let row = row?;
+        if let Some(v) = row.columns[0].as_ref().and_then(|x| x.as_int()) {
+            ret = ret.max(v);
+        }
     }
+    Ok(ret)
+}
+
+async fn set_schema_version(m: &Migration, scy: &ScySession) -> Result<(), Error> {
+    let ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    let cql = "insert into schema_version (part, version, ts, descr) values (0, ?, ?, ?)";
+    scy.query(cql, (m.version, ts.as_millis() as i64, m.descr)).await?;
+    Ok(())
+}
+
+struct ColumnInfo {
+    name: String,
+    kind: String,
+    ty: String,
 }
 
-#[allow(unused)]
-async fn get_columns(keyspace: &str, table: &str, scy: &ScySession) -> Result<Vec<String>, Error> {
+async fn get_columns(keyspace: &str, table: &str, scy: &ScySession) -> Result<Vec<ColumnInfo>, Error> {
     let mut ret = Vec::new();
     let cql = "select column_name, kind, type from system_schema.columns where keyspace_name = ? and table_name = ?";
     let params = (keyspace, table);
@@ -275,205 +648,127 @@ async fn get_columns(keyspace: &str, table: &str, scy: &ScySession) -> Result<Ve
         // column_name (text)
         // type (text): text, blob, int, ...
         let row = row?;
-        let name = row.columns[0].as_ref().unwrap().as_text().unwrap();
-        ret.push(name.into());
+        let text = |i: usize| -> String {
+            row.columns[i]
+                .as_ref()
+                .and_then(|x| x.as_text())
+                .map_or(String::new(), |x| x.clone())
+        };
+        let col = ColumnInfo {
+            name: text(0),
+            kind: text(1),
+            ty: text(2),
+        };
+        ret.push(col);
     }
     Ok(ret)
 }
 
-async fn check_event_tables(scy: &ScySession) -> Result<(), Error> {
-    let stys = [
-        "u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64", "f32", "f64", "bool", "string",
-    ];
-    let cqlstys = [
-        "tinyint", "smallint", "int", "bigint", "tinyint", "smallint", "int", "bigint", "float", "double", "boolean",
-        "text",
-    ];
-    for (sty, cqlsty) in stys.into_iter().zip(cqlstys) {
-        let desc = EvTabDim0 {
-            sty: sty.into(),
-            cqlsty: cqlsty.into(),
-            // ttl is set in actual data inserts
-            default_time_to_live: 60 * 60 * 1,
-            compaction_window_size: 48,
-        };
-        if !has_table(&desc.name(), scy).await? {
-            scy.query(desc.cql_create(), ()).await?;
-        }
-        let desc = EvTabDim1 {
-            sty: sty.into(),
-            cqlsty: format!("frozen<list<{}>>", cqlsty),
-            // ttl is set in actual data inserts
-            default_time_to_live: 60 * 60 * 1,
-            compaction_window_size: 12,
-        };
-        if !check_table_readable(&desc.name(), scy).await? {
-            scy.query(desc.cql(), ()).await?;
+async fn add_columns_if_missing(table: &str, cols: &[(String, String)], scy: &ScySession) -> Result<(), Error> {
+    let ks = scy.get_keyspace().ok_or_else(|| Error::NoKeyspaceChosen)?;
+    let have = get_columns(&ks, table, scy).await?;
+    for (name, ty) in cols {
+        if !have.iter().any(|x| &x.name == name) {
+            let cql = format!("alter table {} add {} {}", table, name, ty);
+            info!("ALTER CQL: {cql}");
+            scy.query(cql, ()).await?;
         }
     }
     Ok(())
 }
 
+async fn verify_tables(scy: &ScySession) -> Result<Vec<SchemaDrift>, Error> {
+    let ks = scy.get_keyspace().ok_or_else(|| Error::NoKeyspaceChosen)?;
+    let mut ret = Vec::new();
+    let have = schema_version(scy).await?;
+    let expect = schema_version_latest();
+    if have != expect {
+        ret.push(SchemaDrift::Version { expect, have });
+    }
+    for tab in tables_expected() {
+        ret.extend(tab.verify(&ks, scy).await?);
+    }
+    Ok(ret)
+}
+
 pub async fn migrate_scylla_data_schema(scyconf: &ScyllaConfig) -> Result<(), Error> {
     let scy2 = create_session_no_ks(scyconf).await?;
     let scy = &scy2;
 
     if !has_keyspace(&scyconf.keyspace, scy).await? {
-        let rf = 2;
-        let cql = format!("create keyspace {} with replication = {{ 'class': 'SimpleStrategy', 'replication_factor': {} }} and durable_writes = true;", scyconf.keyspace, rf);
+        let cql = format!(
+            "create keyspace {} with replication = {} and durable_writes = true;",
+            scyconf.keyspace,
+            scyconf.replication.cql()
+        );
         scy.query_iter(cql, ()).await?;
         info!("keyspace created");
     }
 
     scy.use_keyspace(&scyconf.keyspace, true).await?;
 
-    if !has_table("ts_msp", &scy).await? {
-        create_table_ts_msp(scy).await?;
-    }
-
-    check_event_tables(scy).await?;
-
-    if false {
-        info!("early abort schema");
-        return Ok(());
-    }
-    {
-        let tab = GenTwcsTab::new(
-            "series_by_ts_msp",
-            &[
-                ("part", "int"),
-                ("ts_msp", "int"),
-                ("shape_kind", "int"),
-                ("scalar_type", "int"),
-                ("series", "bigint"),
-            ],
-            ["part", "ts_msp", "shape_kind", "scalar_type"],
-            ["series"],
-            dhours(5),
-            ddays(4),
+    create_version_table(scy).await?;
+    let have = schema_version(scy).await?;
+    if have > schema_version_latest() {
+        warn!(
+            "schema version {have} is newer than the latest known {}",
+            schema_version_latest()
         );
-        tab.create_if_missing(scy).await?;
     }
-    {
-        let tab = GenTwcsTab::new(
-            "connection_status",
-            &[
-                ("ts_msp", "bigint"),
-                ("ts_lsp", "bigint"),
-                ("kind", "int"),
-                ("addr", "text"),
-            ],
-            ["ts_msp"],
-            ["ts_lsp"],
-            dhours(1),
-            ddays(4),
-        );
-        tab.create_if_missing(scy).await?;
-    }
-    {
-        let tab = GenTwcsTab::new(
-            "channel_status",
-            &[
-                ("series", "bigint"),
-                ("ts_msp", "bigint"),
-                ("ts_lsp", "bigint"),
-                ("kind", "int"),
-            ],
-            ["series", "ts_msp"],
-            ["ts_lsp"],
-            dhours(1),
-            ddays(4),
-        );
-        tab.create_if_missing(scy).await?;
+    for m in migrations() {
+        if m.version <= have {
+            continue;
+        }
+        info!("schema migration {}  {}", m.version, m.descr);
+        for step in &m.steps {
+            step.apply(scy).await?;
+        }
+        set_schema_version(&m, scy).await?;
     }
-    {
-        let tab = GenTwcsTab::new(
-            "channel_status_by_ts_msp",
-            &[
-                ("ts_msp", "bigint"),
-                ("ts_lsp", "bigint"),
-                ("series", "bigint"),
-                ("kind", "int"),
-            ],
-            ["ts_msp"],
-            ["ts_lsp"],
-            dhours(1),
-            ddays(4),
-        );
-        tab.create_if_missing(scy).await?;
+    for x in verify_tables(scy).await? {
+        warn!("schema drift: {x}");
     }
-    {
-        let tab = GenTwcsTab::new(
-            "channel_ping",
-            &[
-                ("part", "int"),
-                ("ts_msp", "int"),
-                ("series", "bigint"),
-                ("ivl", "float"),
-                ("interest", "float"),
-                ("evsize", "int"),
-            ],
-            ["part", "ts_msp"],
-            ["series"],
-            dhours(1),
-            ddays(4),
-        );
-        tab.create_if_missing(scy).await?;
+    Ok(())
+}
+
+// Compares the keyspace against the expected schema, without changes.
+pub async fn check_scylla_data_schema(scyconf: &ScyllaConfig) -> Result<Vec<SchemaDrift>, Error> {
+    let scy = create_session_no_ks(scyconf).await?;
+    if !has_keyspace(&scyconf.keyspace, &scy).await? {
+        return Err(Error::NoKeyspace(scyconf.keyspace.clone()));
     }
-    {
-        let tab = GenTwcsTab::new(
-            "muted",
-            &[
-                ("part", "int"),
-                ("series", "bigint"),
-                ("ts", "bigint"),
-                ("ema", "float"),
-                ("emd", "float"),
-            ],
-            ["part"],
-            ["series", "ts"],
-            dhours(4),
-            ddays(1),
-        );
-        tab.create_if_missing(scy).await?;
+    scy.use_keyspace(&scyconf.keyspace, true).await?;
+    verify_tables(&scy).await
+}
+
+// The expected schema as cql.
+pub fn dump_scylla_data_schema(scyconf: &ScyllaConfig) -> String {
+    let mut ret = format!(
+        "create keyspace {} with replication = {} and durable_writes = true;\n",
+        scyconf.keyspace,
+        scyconf.replication.cql()
+    );
+    ret.push_str(&format!("use {};\n", scyconf.keyspace));
+    for tab in tables_expected() {
+        ret.push_str(&tab.cql());
+        ret.push_str(";\n");
     }
-    {
-        let tab = GenTwcsTab::new(
-            "item_recv_ivl",
-            &[
-                ("part", "int"),
-                ("series", "bigint"),
-                ("ts", "bigint"),
-                ("ema", "float"),
-                ("emd", "float"),
-            ],
-            ["part"],
-            ["series", "ts"],
-            dhours(4),
-            ddays(1),
-        );
-        tab.create_if_missing(scy).await?;
+    ret.push_str(&format!("-- schema version {}\n", schema_version_latest()));
+    ret
+}
+
+#[test]
+fn migrations_ordered() {
+    let ms = migrations();
+    for (i, m) in ms.iter().enumerate() {
+        assert_eq!(m.version, i as i32 + 1);
     }
-    {
-        let tab = GenTwcsTab::new(
-            "binned_scalar_f32_v01",
-            &[
-                ("series", "bigint"),
-                ("bin_len_sec", "int"),
-                ("bin_count", "int"),
-                ("off_msp", "int"),
-                ("off_lsp", "int"),
-                ("counts", "frozen<list<bigint>>"),
-                ("mins", "frozen<list<float>>"),
-                ("maxs", "frozen<list<float>>"),
-                ("avgs", "frozen<list<float>>"),
-            ],
-            ["series", "bin_len_sec", "bin_count", "off_msp"],
-            ["off_lsp"],
-            ddays(30),
-            ddays(4),
-        );
-        tab.create_if_missing(scy).await?;
+    let tabs = tables_expected();
+    for (i, tab) in tabs.iter().enumerate() {
+        assert!(!tabs[..i].iter().any(|x| x.name() == tab.name()));
     }
-    Ok(())
+    let tab = tabs.iter().find(|x| x.name() == "events_scalar_f64").unwrap();
+    assert_eq!(tab.column_kind("ts_msp"), "partition_key");
+    assert_eq!(tab.column_kind("ts_lsp"), "clustering");
+    assert_eq!(tab.column_kind("severity"), "regular");
 }
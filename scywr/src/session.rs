@@ -1,17 +1,24 @@
-pub use netpod::ScyllaConfig;
+pub use crate::config::ScyllaConfig;
 pub use scylla::Session;
 pub use Session as ScySession;
 
 use err::thiserror;
 use err::ThisError;
+use openssl::ssl::SslContext;
+use openssl::ssl::SslContextBuilder;
+use openssl::ssl::SslFiletype;
+use openssl::ssl::SslMethod;
+use openssl::ssl::SslVerifyMode;
 use scylla::execution_profile::ExecutionProfileBuilder;
 use scylla::statement::Consistency;
 use scylla::transport::errors::NewSessionError;
+use scylla::SessionBuilder;
 use std::sync::Arc;
 
 #[derive(Debug, ThisError)]
 pub enum Error {
     NewSession(String),
+    Tls(String),
 }
 
 impl From<NewSessionError> for Error {
@@ -20,17 +27,49 @@ impl From<NewSessionError> for Error {
     }
 }
 
-pub async fn create_session_no_ks(scyconf: &ScyllaConfig) -> Result<Arc<Session>, Error> {
-    let scy = scylla::SessionBuilder::new()
+fn ssl_context(scyconf: &ScyllaConfig) -> Result<Option<SslContext>, Error> {
+    let tls = match &scyconf.tls {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let e = |e: openssl::error::ErrorStack| Error::Tls(e.to_string());
+    let mut b = SslContextBuilder::new(SslMethod::tls()).map_err(e)?;
+    b.set_ca_file(&tls.ca).map_err(e)?;
+    b.set_verify(SslVerifyMode::PEER);
+    match (&tls.cert, &tls.key) {
+        (Some(cert), Some(key)) => {
+            b.set_certificate_file(cert, SslFiletype::PEM).map_err(e)?;
+            b.set_private_key_file(key, SslFiletype::PEM).map_err(e)?;
+        }
+        (None, None) => {}
+        _ => return Err(Error::Tls("client certificate and key must be given together".into())),
+    }
+    Ok(Some(b.build()))
+}
+
+// Nodes, credentials and TLS from the config, used for every session we build.
+pub fn session_builder(scyconf: &ScyllaConfig, consistency: Consistency) -> Result<SessionBuilder, Error> {
+    let mut b = SessionBuilder::new()
         .known_nodes(&scyconf.hosts)
         .default_execution_profile_handle(
             ExecutionProfileBuilder::default()
-                .consistency(Consistency::LocalOne)
+                .consistency(consistency)
                 .build()
                 .into_handle(),
-        )
-        .build()
-        .await?;
+        );
+    match (&scyconf.username, &scyconf.password) {
+        (Some(user), Some(pass)) => {
+            b = b.user(user, pass);
+        }
+        (None, None) => {}
+        _ => return Err(Error::NewSession("username and password must be given together".into())),
+    }
+    b = b.ssl_context(ssl_context(scyconf)?);
+    Ok(b)
+}
+
+pub async fn create_session_no_ks(scyconf: &ScyllaConfig) -> Result<Arc<Session>, Error> {
+    let scy = session_builder(scyconf, scyconf.consistency_write())?.build().await?;
     let scy = Arc::new(scy);
     Ok(scy)
 }
@@ -0,0 +1,296 @@
+use crate::config::ScyllaConfig;
+use crate::insertworker::Ttls;
+use crate::iteminsertqueue::insert_channel_meta;
+use crate::iteminsertqueue::insert_channel_status;
+use crate::iteminsertqueue::insert_connection_status;
+use crate::iteminsertqueue::insert_item;
+use crate::iteminsertqueue::Error;
+use crate::iteminsertqueue::QueryItem;
+use crate::pulsemap::pulse_key;
+use crate::pulsemap::ts_key;
+use crate::store::DataStore;
+use futures_util::Future;
+use log::*;
+use stats::CaConnStats;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use taskrun::tokio;
+
+pub type SinkFut<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+// Destination of the items which the insert workers take from the queue.
+pub trait StorageSink: Send + Sync {
+    fn insert<'a>(&'a self, item: QueryItem, ttls: &'a Ttls, stats: &'a CaConnStats) -> SinkFut<'a>;
+}
+
+#[derive(Clone)]
+pub enum SinkOpts {
+    Scylla { scyconf: ScyllaConfig, sessions: usize },
+    File(PathBuf),
+    Memory(Arc<MemorySink>),
+}
+
+impl SinkOpts {
+    // Insert workers are distributed evenly over the returned sinks.
+    pub async fn build(&self) -> Result<Vec<Arc<dyn StorageSink>>, err::Error> {
+        let mut ret: Vec<Arc<dyn StorageSink>> = Vec::new();
+        match self {
+            SinkOpts::Scylla { scyconf, sessions } => {
+                for _ in 0..(*sessions).max(1) {
+                    let data_store = DataStore::new(scyconf).await.map_err(|e| e.to_string())?;
+                    ret.push(Arc::new(ScyllaSink::new(Arc::new(data_store))));
+                }
+            }
+            SinkOpts::File(path) => {
+                let sink = FileSink::open(path.clone()).map_err(|e| e.to_string())?;
+                ret.push(Arc::new(sink));
+            }
+            SinkOpts::Memory(sink) => {
+                ret.push(sink.clone());
+            }
+        }
+        Ok(ret)
+    }
+}
+
+pub struct ScyllaSink {
+    data_store: Arc<DataStore>,
+}
+
+impl ScyllaSink {
+    pub fn new(data_store: Arc<DataStore>) -> Self {
+        Self { data_store }
+    }
+
+    async fn insert_query_item(&self, item: QueryItem, ttls: &Ttls, stats: &CaConnStats) -> Result<(), Error> {
+        let data_store = self.data_store.as_ref();
+        match item {
+            QueryItem::ConnectionStatus(item) => {
+                insert_connection_status(item, ttls.index, data_store).await?;
+                stats.connection_status_insert_done_inc();
+            }
+            QueryItem::ChannelStatus(item) => {
+                insert_channel_status(item, ttls.index, data_store).await?;
+                stats.channel_status_insert_done_inc();
+            }
+            QueryItem::Insert(item) => {
+                insert_item(item, ttls.index, ttls.d0, ttls.d1, data_store, stats).await?;
+                stats.store_worker_insert_done_inc();
+            }
+            QueryItem::Mute(item) => {
+                let values = (
+                    (item.series.id() & 0xff) as i32,
+                    item.series.id() as i64,
+                    item.ts as i64,
+                    item.ema,
+                    item.emd,
+                    ttls.index.as_secs() as i32,
+                );
+                data_store.scy.execute(&data_store.qu_insert_muted, values).await?;
+                stats.mute_insert_done_inc();
+            }
+            QueryItem::Ivl(item) => {
+                let values = (
+                    (item.series.id() & 0xff) as i32,
+                    item.series.id() as i64,
+                    item.ts as i64,
+                    item.ema,
+                    item.emd,
+                    ttls.index.as_secs() as i32,
+                );
+                data_store
+                    .scy
+                    .execute(&data_store.qu_insert_item_recv_ivl, values)
+                    .await?;
+                stats.ivl_insert_done_inc();
+            }
+            QueryItem::ChannelInfo(item) => {
+                let params = (
+                    (item.series.id() & 0xff) as i32,
+                    item.ts_msp as i32,
+                    item.series.id() as i64,
+                    item.ivl,
+                    item.interest,
+                    item.evsize as i32,
+                    ttls.index.as_secs() as i32,
+                );
+                data_store
+                    .scy
+                    .execute(&data_store.qu_insert_channel_ping, params)
+                    .await?;
+                stats.channel_info_insert_done_inc();
+            }
+            QueryItem::ChannelMeta(item) => {
+                insert_channel_meta(item, ttls.index, data_store).await?;
+                stats.channel_meta_insert_done_inc();
+            }
+            QueryItem::TimeBinPatchSimpleF32(item) => {
+                info!("have time bin patch to insert: {item:?}");
+                let params = (
+                    item.series.id() as i64,
+                    item.bin_len_sec as i32,
+                    item.bin_count as i32,
+                    item.off_msp as i32,
+                    item.off_lsp as i32,
+                    item.counts,
+                    item.mins,
+                    item.maxs,
+                    item.avgs,
+                    ttls.binned.as_secs() as i32,
+                );
+                data_store
+                    .scy
+                    .execute(&data_store.qu_insert_binned_scalar_f32_v01, params)
+                    .await?;
+                stats.store_worker_insert_binned_done_inc();
+            }
+            QueryItem::PulseMap(item) => {
+                // As long as the events of the pulse.
+                let ttl = ttls.d0.max(ttls.d1).as_secs() as i32;
+                let (tsa, tsb) = ts_key(item.ts);
+                let (pulse_a, pulse_b) = pulse_key(item.pulse);
+                data_store
+                    .scy
+                    .execute(&data_store.qu_insert_pulse, (tsa, tsb, item.pulse as i64, ttl))
+                    .await?;
+                data_store
+                    .scy
+                    .execute(&data_store.qu_insert_ts_by_pulse, (pulse_a, pulse_b, tsa, tsb, ttl))
+                    .await?;
+                stats.pulse_map_insert_done_inc();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageSink for ScyllaSink {
+    fn insert<'a>(&'a self, item: QueryItem, ttls: &'a Ttls, stats: &'a CaConnStats) -> SinkFut<'a> {
+        Box::pin(self.insert_query_item(item, ttls, stats))
+    }
+}
+
+// Keeps all items, for tests.
+pub struct MemorySink {
+    items: Mutex<Vec<QueryItem>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self {
+            items: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn take(&self) -> Vec<QueryItem> {
+        std::mem::replace(&mut *self.items.lock().unwrap(), Vec::new())
+    }
+}
+
+impl StorageSink for MemorySink {
+    fn insert<'a>(&'a self, item: QueryItem, _ttls: &'a Ttls, stats: &'a CaConnStats) -> SinkFut<'a> {
+        self.items.lock().unwrap().push(item);
+        stats.store_worker_insert_done_inc();
+        Box::pin(futures_util::future::ready(Ok(())))
+    }
+}
+
+// Appends every item as one line of json to a local file.
+// The writes block, they run on the blocking thread pool.
+pub struct FileSink {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl FileSink {
+    pub fn open(path: PathBuf) -> Result<Self, io::Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!("file sink {}", path.display());
+        let ret = Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        };
+        Ok(ret)
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    async fn append(&self, item: &QueryItem) -> Result<(), Error> {
+        let mut buf = serde_json::to_vec(item).map_err(io::Error::from)?;
+        buf.push(b'\n');
+        let file = self.file.clone();
+        // One write per item so that concurrent workers do not interleave lines.
+        let jh = tokio::task::spawn_blocking(move || {
+            let mut file = file.lock().unwrap();
+            file.write_all(&buf)
+        });
+        jh.await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+        Ok(())
+    }
+}
+
+impl StorageSink for FileSink {
+    fn insert<'a>(&'a self, item: QueryItem, _ttls: &'a Ttls, stats: &'a CaConnStats) -> SinkFut<'a> {
+        let fut = async move {
+            self.append(&item).await?;
+            stats.store_worker_insert_done_inc();
+            Ok(())
+        };
+        Box::pin(fut)
+    }
+}
+
+#[test]
+fn file_sink_appends_json_lines() {
+    use crate::iteminsertqueue::MuteItem;
+    use series::SeriesId;
+    use std::time::Duration;
+    let path = crate::test::tmp_path("sink-test.jsonl");
+    let ttls = Ttls {
+        index: Duration::from_secs(1),
+        d0: Duration::from_secs(1),
+        d1: Duration::from_secs(1),
+        binned: Duration::from_secs(1),
+    };
+    let stats = CaConnStats::new();
+    let sink = FileSink::open(path.clone()).unwrap();
+    let fut = async {
+        for i in 0..3 {
+            let item = QueryItem::Mute(MuteItem {
+                series: SeriesId::new(i),
+                ts: i,
+                ema: 0.,
+                emd: 0.,
+            });
+            sink.insert(item, &ttls, &stats)
+                .await
+                .map_err(|e| err::Error::from(e.to_string()))?;
+        }
+        Ok::<_, err::Error>(())
+    };
+    taskrun::run(fut).unwrap();
+    drop(sink);
+    let s = std::fs::read_to_string(&path).unwrap();
+    let items: Vec<QueryItem> = s.lines().map(|x| serde_json::from_str(x).unwrap()).collect();
+    assert_eq!(items.len(), 3);
+    match &items[2] {
+        QueryItem::Mute(item) => assert_eq!(item.ts, 2),
+        x => panic!("unexpected {x:?}"),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,481 @@
+use crate::iteminsertqueue::QueryItem;
+use err::thiserror;
+use err::ThisError;
+use log::*;
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+const SEGMENT_EXT: &str = "spool";
+const POS_EXT: &str = "pos";
+// New spools start numbering here, leaves room to put retried items in front.
+const SEQ_START: u64 = 1 << 32;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Cbor(#[from] serde_cbor::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct SpoolOpts {
+    pub dir: PathBuf,
+    pub segment_max: u64,
+    pub total_max: u64,
+}
+
+struct Segment {
+    seq: u64,
+    // Bytes written and not yet replayed.
+    len: u64,
+    count: u64,
+    // File offset of the next record to replay.
+    read_pos: u64,
+    // Last read offset written to the pos file.
+    read_pos_saved: u64,
+}
+
+// Write-ahead spool of query items which could not be handed to the store.
+// Items are appended as length-prefixed cbor records to segment files and
+// replayed oldest first. The replay offset of a segment is kept in a pos file
+// next to it, so that a restart continues where the replay stopped. A fully
+// replayed segment file gets removed.
+//
+// The methods do blocking file I/O, callers on an async runtime must move
+// the spool to a blocking thread.
+pub struct Spool {
+    opts: SpoolOpts,
+    segments: VecDeque<Segment>,
+    // Appends to the last segment.
+    writer: Option<File>,
+    // Reads from the first segment.
+    reader: Option<BufReader<File>>,
+    depth: u64,
+    bytes: u64,
+}
+
+impl Spool {
+    pub fn open(opts: SpoolOpts) -> Result<Self, Error> {
+        fs::create_dir_all(&opts.dir)?;
+        let mut seqs = Vec::new();
+        let mut pos_files = Vec::new();
+        for e in fs::read_dir(&opts.dir)? {
+            let path = e?.path();
+            let seq = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| u64::from_str_radix(x, 16).ok());
+            if let Some(seq) = seq {
+                if path.extension().map_or(false, |x| x == SEGMENT_EXT) {
+                    seqs.push(seq);
+                } else if path.extension().map_or(false, |x| x == POS_EXT) {
+                    pos_files.push(seq);
+                }
+            }
+        }
+        seqs.sort();
+        let mut ret = Self {
+            opts,
+            segments: VecDeque::new(),
+            writer: None,
+            reader: None,
+            depth: 0,
+            bytes: 0,
+        };
+        for seq in pos_files {
+            if !seqs.contains(&seq) {
+                fs::remove_file(ret.pos_path(seq))?;
+            }
+        }
+        for seq in seqs {
+            let read_pos = ret.read_pos_file(seq)?;
+            let (len, count) = Self::scan_segment(&ret.segment_path(seq), read_pos)?;
+            ret.depth += count;
+            ret.bytes += len;
+            ret.segments.push_back(Segment {
+                seq,
+                len,
+                count,
+                read_pos,
+                read_pos_saved: read_pos,
+            });
+        }
+        if ret.depth != 0 {
+            info!(
+                "spool {}  found {} items in {} segments",
+                ret.opts.dir.display(),
+                ret.depth,
+                ret.segments.len()
+            );
+        }
+        Ok(ret)
+    }
+
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    // Returns false if the item does not fit into the configured size limit.
+    pub fn push(&mut self, item: &QueryItem) -> Result<bool, Error> {
+        let buf = serde_cbor::to_vec(item)?;
+        let n = 4 + buf.len() as u64;
+        if self.bytes + n > self.opts.total_max {
+            return Ok(false);
+        }
+        let rotate = match (&self.writer, self.segments.back()) {
+            (Some(_), Some(seg)) => seg.len != 0 && seg.len + n > self.opts.segment_max,
+            _ => true,
+        };
+        if rotate {
+            let seq = self.segments.back().map_or(SEQ_START, |x| x.seq + 1);
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.segment_path(seq))?;
+            self.writer = Some(file);
+            self.segments.push_back(Segment {
+                seq,
+                len: 0,
+                count: 0,
+                read_pos: 0,
+                read_pos_saved: 0,
+            });
+        }
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&Self::record(&buf))?;
+        let seg = self.segments.back_mut().unwrap();
+        seg.len += n;
+        seg.count += 1;
+        self.depth += 1;
+        self.bytes += n;
+        Ok(true)
+    }
+
+    // Puts items in front of everything else in the spool, in the given order.
+    // Used for items which were already replayed once, to keep them ahead of
+    // newer items of the same series. Returns the number of items which did not fit.
+    pub fn push_front(&mut self, items: &[QueryItem]) -> Result<u64, Error> {
+        let seq = match self.segments.front() {
+            Some(x) if x.seq == 0 => {
+                // Spool from an older version without room in front.
+                warn!("spool can not put items in front");
+                return self.push_all(items);
+            }
+            Some(x) => x.seq - 1,
+            None => return self.push_all(items),
+        };
+        let mut recs = Vec::new();
+        let mut count = 0;
+        let mut dropped = 0;
+        for item in items {
+            let buf = serde_cbor::to_vec(item)?;
+            let n = 4 + buf.len() as u64;
+            if self.bytes + recs.len() as u64 + n > self.opts.total_max {
+                dropped += 1;
+            } else {
+                recs.extend_from_slice(&Self::record(&buf));
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return Ok(dropped);
+        }
+        self.save_read_pos()?;
+        self.reader = None;
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(self.segment_path(seq))?;
+        file.write_all(&recs)?;
+        let len = recs.len() as u64;
+        self.segments.push_front(Segment {
+            seq,
+            len,
+            count,
+            read_pos: 0,
+            read_pos_saved: 0,
+        });
+        self.depth += count;
+        self.bytes += len;
+        Ok(dropped)
+    }
+
+    fn push_all(&mut self, items: &[QueryItem]) -> Result<u64, Error> {
+        let mut dropped = 0;
+        for item in items {
+            if !self.push(item)? {
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    // Pops up to max items and persists the new replay offset.
+    pub fn pop_batch(&mut self, max: usize) -> Result<Vec<QueryItem>, Error> {
+        let mut ret = Vec::new();
+        while ret.len() < max {
+            match self.pop()? {
+                Some(item) => ret.push(item),
+                None => break,
+            }
+        }
+        self.save_read_pos()?;
+        Ok(ret)
+    }
+
+    // The replay offset is only persisted by pop_batch and push_front.
+    pub fn pop(&mut self) -> Result<Option<QueryItem>, Error> {
+        loop {
+            let (seq, read_pos) = match self.segments.front() {
+                Some(x) => (x.seq, x.read_pos),
+                None => return Ok(None),
+            };
+            if self.segments.len() == 1 && self.writer.is_some() {
+                // Do not read from the file we append to, continue with a new segment instead.
+                self.writer = None;
+            }
+            if self.reader.is_none() {
+                let mut file = File::open(self.segment_path(seq))?;
+                file.seek(SeekFrom::Start(read_pos))?;
+                self.reader = Some(BufReader::new(file));
+            }
+            let reader = self.reader.as_mut().unwrap();
+            match Self::read_record(reader) {
+                Ok(Some(buf)) => {
+                    let n = 4 + buf.len() as u64;
+                    let seg = self.segments.front_mut().unwrap();
+                    seg.read_pos += n;
+                    seg.len = seg.len.saturating_sub(n);
+                    seg.count = seg.count.saturating_sub(1);
+                    self.depth = self.depth.saturating_sub(1);
+                    self.bytes = self.bytes.saturating_sub(n);
+                    match serde_cbor::from_slice(&buf) {
+                        Ok(item) => return Ok(Some(item)),
+                        Err(e) => {
+                            warn!("spool segment {seq:016x}  skip bad record  {e}");
+                        }
+                    }
+                }
+                Ok(None) => self.segment_done()?,
+                Err(e) => {
+                    warn!("spool segment {seq:016x}  read error  {e}");
+                    self.segment_done()?;
+                }
+            }
+        }
+    }
+
+    fn save_read_pos(&mut self) -> Result<(), Error> {
+        let (seq, read_pos) = match self.segments.front() {
+            Some(x) if x.read_pos != x.read_pos_saved => (x.seq, x.read_pos),
+            _ => return Ok(()),
+        };
+        // Replace atomically, a torn pos file would replay from the start.
+        let path = self.pos_path(seq);
+        let tmp = path.with_extension("pos.tmp");
+        fs::write(&tmp, read_pos.to_le_bytes())?;
+        fs::rename(&tmp, &path)?;
+        self.segments.front_mut().unwrap().read_pos_saved = read_pos;
+        Ok(())
+    }
+
+    fn read_pos_file(&self, seq: u64) -> Result<u64, Error> {
+        match fs::read(self.pos_path(seq)) {
+            Ok(buf) => match buf.as_slice().try_into() {
+                Ok(x) => Ok(u64::from_le_bytes(x)),
+                Err(_) => {
+                    warn!("spool segment {seq:016x}  bad pos file");
+                    Ok(0)
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn segment_done(&mut self) -> Result<(), Error> {
+        self.reader = None;
+        if let Some(seg) = self.segments.pop_front() {
+            if seg.count != 0 {
+                warn!("spool segment {:016x}  lost {} items", seg.seq, seg.count);
+            }
+            self.depth = self.depth.saturating_sub(seg.count);
+            self.bytes = self.bytes.saturating_sub(seg.len);
+            fs::remove_file(self.segment_path(seg.seq))?;
+            match fs::remove_file(self.pos_path(seg.seq)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn segment_path(&self, seq: u64) -> PathBuf {
+        self.opts.dir.join(format!("{seq:016x}.{SEGMENT_EXT}"))
+    }
+
+    fn pos_path(&self, seq: u64) -> PathBuf {
+        self.opts.dir.join(format!("{seq:016x}.{POS_EXT}"))
+    }
+
+    fn record(buf: &[u8]) -> Vec<u8> {
+        let mut rec = Vec::with_capacity(4 + buf.len());
+        rec.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        rec.extend_from_slice(buf);
+        rec
+    }
+
+    fn read_record<R: Read>(inp: &mut R) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut lenbuf = [0; 4];
+        match inp.read_exact(&mut lenbuf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(lenbuf) as usize;
+        let mut buf = vec![0; len];
+        inp.read_exact(&mut buf)?;
+        Ok(Some(buf))
+    }
+
+    fn scan_segment(path: &Path, read_pos: u64) -> Result<(u64, u64), Error> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(read_pos))?;
+        let mut inp = BufReader::new(file);
+        let mut len = 0;
+        let mut count = 0;
+        loop {
+            match Self::read_record(&mut inp) {
+                Ok(Some(buf)) => {
+                    len += 4 + buf.len() as u64;
+                    count += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // A partially written record at the end of a segment, e.g. after a crash.
+                    warn!("spool segment {}  truncated  {e}", path.display());
+                    break;
+                }
+            }
+        }
+        Ok((len, count))
+    }
+}
+
+#[test]
+fn spool_push_pop_in_order() {
+    use crate::iteminsertqueue::MuteItem;
+    use series::SeriesId;
+    let dir = crate::test::tmp_path("spool-test");
+    let opts = SpoolOpts {
+        dir: dir.clone(),
+        segment_max: 200,
+        total_max: 1024 * 64,
+    };
+    let mut spool = Spool::open(opts.clone()).unwrap();
+    for i in 0..40 {
+        let item = QueryItem::Mute(MuteItem {
+            series: SeriesId::new(i),
+            ts: i,
+            ema: 0.,
+            emd: 0.,
+        });
+        assert!(spool.push(&item).unwrap());
+    }
+    assert!(spool.segments.len() > 1);
+    drop(spool);
+    let mut spool = Spool::open(opts).unwrap();
+    assert_eq!(spool.depth(), 40);
+    for i in 0..40 {
+        match spool.pop().unwrap() {
+            Some(QueryItem::Mute(item)) => assert_eq!(item.ts, i),
+            x => panic!("unexpected {x:?}"),
+        }
+    }
+    assert!(spool.pop().unwrap().is_none());
+    assert_eq!(spool.depth(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+fn mute_item(i: u64) -> QueryItem {
+    use crate::iteminsertqueue::MuteItem;
+    use series::SeriesId;
+    QueryItem::Mute(MuteItem {
+        series: SeriesId::new(i),
+        ts: i,
+        ema: 0.,
+        emd: 0.,
+    })
+}
+
+#[cfg(test)]
+fn mute_ts(item: Option<QueryItem>) -> u64 {
+    match item {
+        Some(QueryItem::Mute(item)) => item.ts,
+        x => panic!("unexpected {x:?}"),
+    }
+}
+
+#[test]
+fn spool_restart_continues_after_replayed() {
+    let dir = crate::test::tmp_path("spool-test-offset");
+    let opts = SpoolOpts {
+        dir: dir.clone(),
+        segment_max: 1024 * 64,
+        total_max: 1024 * 64,
+    };
+    let mut spool = Spool::open(opts.clone()).unwrap();
+    for i in 0..10 {
+        assert!(spool.push(&mute_item(i)).unwrap());
+    }
+    let items = spool.pop_batch(4).unwrap();
+    assert_eq!(items.len(), 4);
+    drop(spool);
+    let mut spool = Spool::open(opts).unwrap();
+    assert_eq!(spool.depth(), 6);
+    for i in 4..10 {
+        assert_eq!(mute_ts(spool.pop().unwrap()), i);
+    }
+    assert!(spool.pop().unwrap().is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn spool_push_front_replays_first() {
+    let dir = crate::test::tmp_path("spool-test-front");
+    let opts = SpoolOpts {
+        dir: dir.clone(),
+        segment_max: 1024 * 64,
+        total_max: 1024 * 64,
+    };
+    let mut spool = Spool::open(opts.clone()).unwrap();
+    for i in 0..10 {
+        assert!(spool.push(&mute_item(i)).unwrap());
+    }
+    let items = spool.pop_batch(3).unwrap();
+    assert_eq!(spool.push_front(&items[1..]).unwrap(), 0);
+    assert_eq!(spool.depth(), 9);
+    drop(spool);
+    let mut spool = Spool::open(opts).unwrap();
+    let exp = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+    for i in exp {
+        assert_eq!(mute_ts(spool.pop().unwrap()), i);
+    }
+    assert!(spool.pop().unwrap().is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}
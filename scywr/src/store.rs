@@ -1,9 +1,8 @@
+use crate::config::ScyllaConfig;
+use crate::session::session_builder;
 use err::thiserror;
 use err::ThisError;
-use netpod::ScyllaConfig;
-use scylla::execution_profile::ExecutionProfileBuilder;
 use scylla::prepared_statement::PreparedStatement;
-use scylla::statement::Consistency;
 use scylla::transport::errors::NewSessionError;
 use scylla::transport::errors::QueryError;
 use scylla::Session as ScySession;
@@ -12,6 +11,7 @@ use std::sync::Arc;
 #[derive(Debug, ThisError)]
 pub enum Error {
     NewSessionError(#[from] NewSessionError),
+    Session(#[from] crate::session::Error),
     QueryError(#[from] QueryError),
 }
 
@@ -22,35 +22,43 @@ pub struct DataStore {
     pub qu_insert_scalar_i8: Arc<PreparedStatement>,
     pub qu_insert_scalar_i16: Arc<PreparedStatement>,
     pub qu_insert_scalar_i32: Arc<PreparedStatement>,
+    pub qu_insert_scalar_i64: Arc<PreparedStatement>,
+    pub qu_insert_scalar_u8: Arc<PreparedStatement>,
+    pub qu_insert_scalar_u16: Arc<PreparedStatement>,
+    pub qu_insert_scalar_u32: Arc<PreparedStatement>,
+    pub qu_insert_scalar_u64: Arc<PreparedStatement>,
     pub qu_insert_scalar_f32: Arc<PreparedStatement>,
     pub qu_insert_scalar_f64: Arc<PreparedStatement>,
+    pub qu_insert_scalar_bool: Arc<PreparedStatement>,
     pub qu_insert_scalar_string: Arc<PreparedStatement>,
     pub qu_insert_array_i8: Arc<PreparedStatement>,
     pub qu_insert_array_i16: Arc<PreparedStatement>,
     pub qu_insert_array_i32: Arc<PreparedStatement>,
+    pub qu_insert_array_i64: Arc<PreparedStatement>,
+    pub qu_insert_array_u8: Arc<PreparedStatement>,
+    pub qu_insert_array_u16: Arc<PreparedStatement>,
+    pub qu_insert_array_u32: Arc<PreparedStatement>,
+    pub qu_insert_array_u64: Arc<PreparedStatement>,
     pub qu_insert_array_f32: Arc<PreparedStatement>,
     pub qu_insert_array_f64: Arc<PreparedStatement>,
     pub qu_insert_array_bool: Arc<PreparedStatement>,
+    pub qu_insert_array_string: Arc<PreparedStatement>,
     pub qu_insert_muted: Arc<PreparedStatement>,
     pub qu_insert_item_recv_ivl: Arc<PreparedStatement>,
+    pub qu_insert_pulse: Arc<PreparedStatement>,
+    pub qu_insert_ts_by_pulse: Arc<PreparedStatement>,
     pub qu_insert_connection_status: Arc<PreparedStatement>,
     pub qu_insert_channel_status: Arc<PreparedStatement>,
     pub qu_insert_channel_status_by_ts_msp: Arc<PreparedStatement>,
     pub qu_insert_channel_ping: Arc<PreparedStatement>,
+    pub qu_insert_channel_meta: Arc<PreparedStatement>,
     pub qu_insert_binned_scalar_f32_v01: Arc<PreparedStatement>,
 }
 
 impl DataStore {
     pub async fn new(scyconf: &ScyllaConfig) -> Result<Self, Error> {
-        let scy = scylla::SessionBuilder::new()
-            .known_nodes(&scyconf.hosts)
+        let scy = session_builder(scyconf, scyconf.consistency_write())?
             .use_keyspace(&scyconf.keyspace, true)
-            .default_execution_profile_handle(
-                ExecutionProfileBuilder::default()
-                    .consistency(Consistency::LocalOne)
-                    .build()
-                    .into_handle(),
-            )
             .build()
             .await?;
         let scy = Arc::new(scy);
@@ -65,66 +73,175 @@ impl DataStore {
         let qu_insert_series_by_ts_msp = Arc::new(q);
 
         // scalar:
-        let cql =
-            "insert into events_scalar_i8 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_i8 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_i8 = Arc::new(q);
 
-        let cql =
-            "insert into events_scalar_i16 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_i16 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_i16 = Arc::new(q);
 
-        let cql =
-            "insert into events_scalar_i32 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_i32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_i32 = Arc::new(q);
 
-        let cql =
-            "insert into events_scalar_f32 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_i64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_i64 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_u8 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_u8 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_u16 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_u16 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_u32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_u32 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_u64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_u64 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_f32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_f32 = Arc::new(q);
 
-        let cql =
-            "insert into events_scalar_f64 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_f64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_f64 = Arc::new(q);
 
-        let cql="insert into events_scalar_string (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_scalar_bool (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_scalar_bool = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_scalar_string (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_scalar_string = Arc::new(q);
 
         // array
-        let cql =
-            "insert into events_array_i8 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_i8 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_i8 = Arc::new(q);
 
-        let cql =
-            "insert into events_array_i16 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_i16 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_i16 = Arc::new(q);
 
-        let cql =
-            "insert into events_array_i32 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_i32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_i32 = Arc::new(q);
 
-        let cql =
-            "insert into events_array_f32 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_i64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_i64 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_array_u8 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_u8 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_array_u16 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_u16 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_array_u32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_u32 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_array_u64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_u64 = Arc::new(q);
+
+        let cql = concat!(
+            "insert into events_array_f32 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_f32 = Arc::new(q);
 
-        let cql =
-            "insert into events_array_f64 (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_f64 (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_f64 = Arc::new(q);
 
-        let cql =
-            "insert into events_array_bool (series, ts_msp, ts_lsp, pulse, value) values (?, ?, ?, ?, ?) using ttl ?";
+        let cql = concat!(
+            "insert into events_array_bool (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
         let q = scy.prepare(cql).await?;
         let qu_insert_array_bool = Arc::new(q);
 
+        let cql = concat!(
+            "insert into events_array_string (series, ts_msp, ts_lsp, pulse, value, status, severity)",
+            " values (?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_array_string = Arc::new(q);
+
         // Others:
         let cql = "insert into muted (part, series, ts, ema, emd) values (?, ?, ?, ?, ?) using ttl ?";
         let q = scy.prepare(cql).await?;
@@ -134,6 +251,14 @@ impl DataStore {
         let q = scy.prepare(cql).await?;
         let qu_insert_item_recv_ivl = Arc::new(q);
 
+        let cql = "insert into pulse (tsa, tsb, pulse) values (?, ?, ?) using ttl ?";
+        let q = scy.prepare(cql).await?;
+        let qu_insert_pulse = Arc::new(q);
+
+        let cql = "insert into ts_by_pulse (pulse_a, pulse_b, tsa, tsb) values (?, ?, ?, ?) using ttl ?";
+        let q = scy.prepare(cql).await?;
+        let qu_insert_ts_by_pulse = Arc::new(q);
+
         // Connection status:
         let cql = "insert into connection_status (ts_msp, ts_lsp, kind, addr) values (?, ?, ?, ?) using ttl ?";
         let q = scy.prepare(cql).await?;
@@ -155,6 +280,16 @@ impl DataStore {
         let q = scy.prepare(cql).await?;
         let qu_insert_channel_ping = Arc::new(q);
 
+        let cql = concat!(
+            "insert into channel_meta (",
+            "series, ts, units, precision, upper_disp_limit, lower_disp_limit,",
+            " upper_alarm_limit, upper_warning_limit, lower_warning_limit, lower_alarm_limit,",
+            " upper_ctrl_limit, lower_ctrl_limit, enum_strs",
+            ") values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) using ttl ?"
+        );
+        let q = scy.prepare(cql).await?;
+        let qu_insert_channel_meta = Arc::new(q);
+
         let cql = concat!(
             "insert into binned_scalar_f32_v01 (",
             "series, bin_len_sec, bin_count, off_msp, off_lsp, counts, mins, maxs, avgs)",
@@ -169,21 +304,36 @@ impl DataStore {
             qu_insert_scalar_i8,
             qu_insert_scalar_i16,
             qu_insert_scalar_i32,
+            qu_insert_scalar_i64,
+            qu_insert_scalar_u8,
+            qu_insert_scalar_u16,
+            qu_insert_scalar_u32,
+            qu_insert_scalar_u64,
             qu_insert_scalar_f32,
             qu_insert_scalar_f64,
+            qu_insert_scalar_bool,
             qu_insert_scalar_string,
             qu_insert_array_i8,
             qu_insert_array_i16,
             qu_insert_array_i32,
+            qu_insert_array_i64,
+            qu_insert_array_u8,
+            qu_insert_array_u16,
+            qu_insert_array_u32,
+            qu_insert_array_u64,
             qu_insert_array_f32,
             qu_insert_array_f64,
             qu_insert_array_bool,
+            qu_insert_array_string,
             qu_insert_muted,
             qu_insert_item_recv_ivl,
+            qu_insert_pulse,
+            qu_insert_ts_by_pulse,
             qu_insert_connection_status,
             qu_insert_channel_status,
             qu_insert_channel_status_by_ts_msp,
             qu_insert_channel_ping,
+            qu_insert_channel_meta,
             qu_insert_binned_scalar_f32_v01,
         };
         Ok(ret)
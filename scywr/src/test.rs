@@ -0,0 +1,15 @@
+// Helpers shared by the tests of this crate.
+
+use std::path::PathBuf;
+
+// Fresh path below the system temp dir, unique per test name and process.
+// Anything left over from an earlier run gets removed.
+pub fn tmp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("daqingest-{name}-{}", std::process::id()));
+    if path.is_dir() {
+        let _ = std::fs::remove_dir_all(&path);
+    } else {
+        let _ = std::fs::remove_file(&path);
+    }
+    path
+}
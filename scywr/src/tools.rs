@@ -1,11 +1,9 @@
+use crate::config::ScyllaConfig;
+use crate::session::session_builder;
 use log::*;
-use netpod::ScyllaConfig;
-use scylla::execution_profile::ExecutionProfileBuilder;
-use scylla::statement::Consistency;
 use scylla::transport::errors::NewSessionError;
 use scylla::transport::errors::QueryError;
 use scylla::Session;
-use scylla::SessionBuilder;
 
 pub struct Error(err::Error);
 
@@ -21,6 +19,12 @@ impl From<NewSessionError> for Error {
     }
 }
 
+impl From<crate::session::Error> for Error {
+    fn from(e: crate::session::Error) -> Self {
+        Self(err::Error::with_msg_no_trace(format!("{e:?}")))
+    }
+}
+
 impl From<QueryError> for Error {
     fn from(e: QueryError) -> Self {
         Self(err::Error::with_msg_no_trace(format!("{e:?}")))
@@ -28,15 +32,8 @@ impl From<QueryError> for Error {
 }
 
 async fn make_scy_session(conf: &ScyllaConfig) -> Result<Session, Error> {
-    let scy = SessionBuilder::new()
-        .known_nodes(&conf.hosts)
+    let scy = session_builder(conf, conf.consistency_read())?
         .use_keyspace(&conf.keyspace, true)
-        .default_execution_profile_handle(
-            ExecutionProfileBuilder::default()
-                .consistency(Consistency::LocalOne)
-                .build()
-                .into_handle(),
-        )
         .build()
         .await?;
     Ok(scy)
@@ -45,7 +42,7 @@ async fn make_scy_session(conf: &ScyllaConfig) -> Result<Session, Error> {
 pub async fn list_pkey(scylla_conf: &ScyllaConfig) -> Result<(), Error> {
     let scy = make_scy_session(scylla_conf).await?;
     let query = scy
-        .prepare("select distinct token(pulse_a), pulse_a from pulse where token(pulse_a) >= ? and token(pulse_a) <= ?")
+        .prepare("select distinct token(pulse_a), pulse_a from ts_by_pulse where token(pulse_a) >= ? and token(pulse_a) <= ?")
         .await?;
     let td = i64::MAX / 27;
     let mut t1 = i64::MIN;
@@ -112,36 +109,3 @@ pub async fn list_pulses(scylla_conf: &ScyllaConfig) -> Result<(), Error> {
     }
     Ok(())
 }
-
-pub async fn fetch_events(backend: &str, channel: &str, scylla_conf: &ScyllaConfig) -> Result<(), Error> {
-    // TODO use the keyspace from commandline.
-    err::todo();
-    let scy = make_scy_session(scylla_conf).await?;
-    let qu_series = scy
-        .prepare(
-            "select series, scalar_type, shape_dims from series_by_channel where facility = ? and channel_name = ?",
-        )
-        .await?;
-    let qres = scy.execute(&qu_series, (backend, channel)).await?;
-    if let Some(rows) = qres.rows {
-        info!("Found {} matching series", rows.len());
-        for r in &rows {
-            info!("Got row: {r:?}");
-            if false {
-                if r.columns.len() < 2 {
-                    warn!("see {} columns", r.columns.len());
-                } else {
-                    let tsa_token = r.columns[0].as_ref().unwrap().as_bigint().unwrap();
-                    let tsa = r.columns[1].as_ref().unwrap().as_int().unwrap() as u32;
-                    let tsb = r.columns[2].as_ref().unwrap().as_int().unwrap() as u32;
-                    let pulse = r.columns[3].as_ref().unwrap().as_bigint().unwrap() as u64;
-                    info!("tsa_token {tsa_token:21}  tsa {tsa:12}  tsb {tsb:12}  pulse {pulse:21}");
-                }
-            }
-        }
-        let _row = rows.into_iter().next().unwrap();
-    } else {
-        warn!("No result from series lookup");
-    }
-    Ok(())
-}
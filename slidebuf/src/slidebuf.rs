@@ -268,6 +268,15 @@ impl SlideBuf {
         }
     }
 
+    // Grow the buffer to at least the given capacity, never shrinks.
+    pub fn grow(&mut self, cap: usize) {
+        check_invariants!(self);
+        if cap > self.buf.len() {
+            self.rewind();
+            self.buf.resize(cap, 0);
+        }
+    }
+
     #[inline(always)]
     pub fn rewind(&mut self) {
         self.buf.copy_within(self.rp..self.wp, 0);
@@ -439,3 +448,26 @@ impl fmt::Debug for SlideBuf {
             .finish()
     }
 }
+
+#[test]
+fn grow_keeps_unread_data() {
+    let mut buf = SlideBuf::new(16);
+    buf.put_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]).unwrap();
+    buf.adv(8).unwrap();
+    buf.grow(64);
+    assert_eq!(buf.cap(), 64);
+    assert_eq!(buf.data(), &[9, 10, 11, 12]);
+    assert_eq!(buf.wcap(), 60);
+    buf.put_slice(&[0; 60]).unwrap();
+    assert_eq!(buf.len(), 64);
+    assert_eq!(buf.read_u32_be().unwrap(), 0x090a0b0c);
+}
+
+#[test]
+fn grow_never_shrinks() {
+    let mut buf = SlideBuf::new(32);
+    buf.put_slice(&[1, 2, 3]).unwrap();
+    buf.grow(8);
+    assert_eq!(buf.cap(), 32);
+    assert_eq!(buf.data(), &[1, 2, 3]);
+}
@@ -221,6 +221,9 @@ stats_proc::stats_struct!((
             ca_conn_task_join_done_err,
             ca_conn_task_join_err,
             ca_conn_task_eos_non_exist,
+            beacon_anomaly,
+            beacon_anomaly_research,
+            ca_conn_cmd_drop,
         ),
     ),
     // agg(name(CaConnSetStatsAgg), parent(CaConnSetStats)),
@@ -239,6 +242,8 @@ stats_proc::stats_struct!((
             inserts_queue_push,
             inserts_queue_drop,
             channel_fast_item_drop,
+            channel_policy_drop,
+            caconn_scan_read,
             store_worker_recv_queue_len,
             // TODO maybe rename: this is now only the recv of the intermediate queue:
             store_worker_item_recv,
@@ -254,8 +259,13 @@ stats_proc::stats_struct!((
             connection_status_insert_done,
             channel_status_insert_done,
             channel_info_insert_done,
+            channel_meta_insert_done,
             ivl_insert_done,
             mute_insert_done,
+            pulse_map_insert_done,
+            spool_push,
+            spool_drop,
+            spool_replay,
             caconn_poll_count,
             caconn_loop1_count,
             caconn_loop2_count,
@@ -282,6 +292,7 @@ stats_proc::stats_struct!((
             ca_ts_off_4,
             inter_ivl_ema,
         ),
+        values(spool_replay_rate, spool_depth, spool_bytes),
     ),
     agg(name(CaConnStatsAgg), parent(CaConnStats)),
     diff(name(CaConnStatsAggDiff), input(CaConnStatsAgg)),